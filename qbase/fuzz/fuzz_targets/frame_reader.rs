@@ -0,0 +1,10 @@
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use qbase::frame::FrameReader;
+
+// 解密之后的包载荷仍是对端可控的，解析帧不能panic
+fuzz_target!(|data: &[u8]| {
+    FrameReader::new(Bytes::copy_from_slice(data)).for_each(drop);
+});
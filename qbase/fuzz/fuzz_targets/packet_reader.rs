@@ -0,0 +1,16 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use qbase::packet::PacketReader;
+
+// 数据报来自网络，任何内容都只能解析出错，而不能panic
+fuzz_target!(|data: &[u8]| {
+    for dcid_len in [0, 8, 20] {
+        for grease_quic_bit in [false, true] {
+            PacketReader::new(BytesMut::from(data), dcid_len)
+                .grease_quic_bit(grease_quic_bit)
+                .for_each(drop);
+        }
+    }
+});
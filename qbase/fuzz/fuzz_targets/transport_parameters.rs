@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qbase::config::ext::be_transport_parameters;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = be_transport_parameters(data);
+});
@@ -44,6 +44,17 @@ impl ConnectionId {
         res
     }
 
+    /// Generate a random connection ID with the given length.
+    pub fn random_gen(len: usize) -> Self {
+        debug_assert!(len <= MAX_CID_SIZE);
+        let mut res = Self {
+            len: len as u8,
+            bytes: [0; MAX_CID_SIZE],
+        };
+        rand::Rng::fill(&mut rand::thread_rng(), &mut res.bytes[..len]);
+        res
+    }
+
     pub fn from_buf(input: &[u8], len: usize) -> IResult<&[u8], Self> {
         debug_assert!(len <= MAX_CID_SIZE);
         let (input, bytes) = nom::bytes::complete::take(len)(input)?;
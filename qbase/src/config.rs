@@ -1,7 +1,7 @@
 use crate::cid::{ConnectionId, ResetToken};
 
 use super::varint::VarInt;
-use getset::{Getters, MutGetters, Setters};
+use getset::{CopyGetters, Getters, MutGetters, Setters};
 use std::{
     net::{SocketAddrV4, SocketAddrV6},
     time::Duration,
@@ -10,7 +10,7 @@ use std::{
 /// Ref. `<https://www.iana.org/assignments/quic/quic.xhtml>`
 
 // QUIC的config配置
-#[derive(Getters, Setters, MutGetters, Debug, PartialEq)]
+#[derive(Getters, CopyGetters, Setters, MutGetters, Debug, Clone, PartialEq)]
 pub struct TransportParameters {
     #[getset(get = "pub", set = "pub")]
     original_destination_connection_id: Option<ConnectionId>,
@@ -49,14 +49,29 @@ pub struct TransportParameters {
     #[getset(get = "pub", set = "pub")]
     retry_source_connection_id: Option<ConnectionId>,
     #[getset(get = "pub", set = "pub")]
-    version_information: Option<Vec<u8>>,
+    version_information: Option<VersionInformation>,
     #[getset(get_copy = "pub", set = "pub")]
     max_datagram_frame_size: VarInt,
     #[getset(get_copy = "pub", set = "pub")]
     grease_quic_bit: bool,
+    /// Whether the endpoint supports RESET_STREAM_AT frames, see
+    /// [draft-ietf-quic-reliable-stream-reset](https://datatracker.ietf.org/doc/draft-ietf-quic-reliable-stream-reset/).
+    #[getset(get_copy = "pub", set = "pub")]
+    reset_stream_at: bool,
+}
+
+/// The version_information transport parameter for compatible version negotiation, see
+/// [Section 3](https://www.rfc-editor.org/rfc/rfc9368.html#section-3) of RFC 9368.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInformation {
+    /// The version of the Initial packets that carry the transport parameters.
+    pub chosen_version: u32,
+    /// The versions the endpoint supports, in the order of preference for the client,
+    /// and with no particular order for the server.
+    pub available_versions: Vec<u32>,
 }
 
-#[derive(Getters, Setters, MutGetters, Debug, PartialEq)]
+#[derive(CopyGetters, Setters, MutGetters, Debug, Clone, Copy, PartialEq)]
 pub struct PreferredAddress {
     #[getset(get_copy = "pub", set = "pub")]
     address_v4: Option<SocketAddrV4>,
@@ -68,110 +83,186 @@ pub struct PreferredAddress {
     stateless_reset_token: ResetToken,
 }
 
+impl PreferredAddress {
+    pub fn new(
+        address_v4: Option<SocketAddrV4>,
+        address_v6: Option<SocketAddrV6>,
+        connection_id: ConnectionId,
+        stateless_reset_token: ResetToken,
+    ) -> Self {
+        Self {
+            address_v4,
+            address_v6,
+            connection_id,
+            stateless_reset_token,
+        }
+    }
+}
+
 pub mod ext {
     use std::time::Duration;
 
-    use bytes::BufMut as _;
-    use nom::{combinator::map, number::complete::be_u8};
+    use bytes::{BufMut as _, BytesMut};
+    use nom::{combinator::map, multi::length_data};
 
     use crate::{
-        cid::{
-            self, be_connection_id, be_reset_token, ConnectionId, ResetToken, WriteConnectionId,
-            WriteResetToken as _,
-        },
+        cid::{be_reset_token, ConnectionId, ResetToken, WriteConnectionId, WriteResetToken as _},
         varint::{
             ext::{be_varint, BufMutExt as _},
             VarInt,
         },
     };
 
-    use super::{PreferredAddress, TransportParameters};
-    pub fn be_transport_parameters(input: &[u8]) -> nom::IResult<&[u8], TransportParameters> {
-        let be_connection_id = |input| {
-            let (remain, cid) = cid::be_connection_id(input)?;
-            Ok((remain, Some(cid)))
-        };
+    use super::{PreferredAddress, TransportParameters, VersionInformation};
 
-        let be_max_idle_timeout = |input| {
-            let (remain, timeout) = be_varint(input)?;
-            Ok((remain, Duration::from_secs(timeout.0)))
-        };
+    /// Transport parameter ids of the form 31 * N + 27 are reserved to exercise the requirement
+    /// that unknown transport parameters be ignored, see [Section 18.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-18.1) of QUIC.
+    pub fn is_reserved_parameter(id: u64) -> bool {
+        id % 31 == 27
+    }
 
-        let be_preferred_address = |input| {
-            let (remain, addr) = be_preferred_address(input)?;
-            Ok((remain, Some(addr)))
-        };
+    /// The id of the reset_stream_at transport parameter, whose value is empty.
+    const RESET_STREAM_AT_PARAMETER: u64 = 0x17f7586d2cb571;
 
-        let be_reset_token = |input| {
-            let (remain, token) = be_reset_token(input)?;
-            Ok((remain, Some(token)))
-        };
+    fn verify_error(input: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+        nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Verify))
+    }
+
+    // 传输参数的值有长度，必须恰好被解析完
+    fn be_value<'a, T>(
+        value: &'a [u8],
+        mut parser: impl FnMut(&'a [u8]) -> nom::IResult<&'a [u8], T>,
+    ) -> Result<T, nom::Err<nom::error::Error<&'a [u8]>>> {
+        match parser(value)? {
+            ([], output) => Ok(output),
+            (remain, _) => Err(verify_error(remain)),
+        }
+    }
+
+    // 传输参数中的连接ID没有长度前缀，其长度就是值的长度
+    fn be_raw_connection_id(input: &[u8]) -> nom::IResult<&[u8], ConnectionId> {
+        if input.len() > crate::cid::MAX_CID_SIZE {
+            return Err(verify_error(input));
+        }
+        Ok((&[], ConnectionId::from_slice(input)))
+    }
 
+    fn be_empty(input: &[u8]) -> nom::IResult<&[u8], ()> {
+        nom::combinator::eof(input).map(|(remain, _)| (remain, ()))
+    }
+
+    /// The versions are all 32 bits, and the chosen version must exist.
+    pub fn be_version_information(input: &[u8]) -> nom::IResult<&[u8], VersionInformation> {
+        if input.len() < 4 || !input.len().is_multiple_of(4) {
+            return Err(verify_error(input));
+        }
+        let mut versions = input
+            .chunks_exact(4)
+            .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]));
+        let chosen_version = versions.next().unwrap();
+        Ok((
+            &[],
+            VersionInformation {
+                chosen_version,
+                available_versions: versions.collect(),
+            },
+        ))
+    }
+
+    /// Each transport parameter is encoded as an id, a length and a value, the unknown ones,
+    /// including the reserved ones, are ignored.
+    pub fn be_transport_parameters(input: &[u8]) -> nom::IResult<&[u8], TransportParameters> {
         let mut remain = input;
         let mut tp = TransportParameters::default();
         while !remain.is_empty() {
-            let tag: u8;
-            (remain, tag) = be_u8(remain)?;
-            match tag {
-                0x00 => (remain, tp.original_destination_connection_id) = be_connection_id(remain)?,
-                0x01 => (remain, tp.max_idle_timeout) = be_max_idle_timeout(remain)?,
-                0x02 => (remain, tp.statelss_reset_token) = be_reset_token(remain)?,
-                0x03 => (remain, tp.max_udp_payload_size) = be_varint(remain)?,
-                0x04 => (remain, tp.initial_max_data) = be_varint(remain)?,
-                0x05 => (remain, tp.initial_max_stream_data_bidi_local) = be_varint(remain)?,
-                0x06 => (remain, tp.initial_max_stream_data_bidi_remote) = be_varint(remain)?,
-                0x07 => (remain, tp.initial_max_stream_data_uni) = be_varint(remain)?,
-                0x08 => (remain, tp.initial_max_streams_bidi) = be_varint(remain)?,
-                0x09 => (remain, tp.initial_max_streams_uni) = be_varint(remain)?,
-                0x0a => (remain, tp.ack_delay_exponent) = be_varint(remain)?,
-                0x0b => (remain, tp.max_ack_delay) = be_varint(remain)?,
-                0x0c => tp.disable_active_migration = true,
-                0x0d => (remain, tp.preferred_address) = be_preferred_address(remain)?,
-                0x0e => (remain, tp.active_connection_id_limit) = be_varint(remain)?,
-                0x0f => (remain, tp.initial_source_connection_id) = be_connection_id(remain)?,
-                0x10 => (remain, tp.retry_source_connection_id) = be_connection_id(remain)?,
-                _ => {
-                    unreachable!("unknown transport parameter tag: {}", tag)
+            let (rest, id) = be_varint(remain)?;
+            let (rest, value) = length_data(be_varint)(rest)?;
+            remain = rest;
+            match id.into_inner() {
+                0x00 => {
+                    tp.original_destination_connection_id =
+                        Some(be_value(value, be_raw_connection_id)?)
+                }
+                0x01 => tp.max_idle_timeout = Duration::from_secs(be_value(value, be_varint)?.0),
+                0x02 => tp.statelss_reset_token = Some(be_value(value, be_reset_token)?),
+                0x03 => tp.max_udp_payload_size = be_value(value, be_varint)?,
+                0x04 => tp.initial_max_data = be_value(value, be_varint)?,
+                0x05 => tp.initial_max_stream_data_bidi_local = be_value(value, be_varint)?,
+                0x06 => tp.initial_max_stream_data_bidi_remote = be_value(value, be_varint)?,
+                0x07 => tp.initial_max_stream_data_uni = be_value(value, be_varint)?,
+                0x08 => tp.initial_max_streams_bidi = be_value(value, be_varint)?,
+                0x09 => tp.initial_max_streams_uni = be_value(value, be_varint)?,
+                0x0a => tp.ack_delay_exponent = be_value(value, be_varint)?,
+                0x0b => tp.max_ack_delay = be_value(value, be_varint)?,
+                0x0c => {
+                    be_value(value, be_empty)?;
+                    tp.disable_active_migration = true;
+                }
+                0x0d => tp.preferred_address = Some(be_value(value, be_preferred_address)?),
+                0x0e => tp.active_connection_id_limit = be_value(value, be_varint)?,
+                0x0f => {
+                    tp.initial_source_connection_id = Some(be_value(value, be_raw_connection_id)?)
+                }
+                0x10 => {
+                    tp.retry_source_connection_id = Some(be_value(value, be_raw_connection_id)?)
+                }
+                0x11 => tp.version_information = Some(be_value(value, be_version_information)?),
+                0x20 => tp.max_datagram_frame_size = be_value(value, be_varint)?,
+                0x2ab2 => {
+                    be_value(value, be_empty)?;
+                    tp.grease_quic_bit = true;
+                }
+                RESET_STREAM_AT_PARAMETER => {
+                    be_value(value, be_empty)?;
+                    tp.reset_stream_at = true;
                 }
+                // 未知的传输参数，包括保留的传输参数，都必须忽略
+                _ => {}
             }
         }
 
         Ok((remain, tp))
     }
 
-    pub(crate) trait BufMutExt {
+    pub trait BufMutExt {
         fn put_transport_parameters(&mut self, params: &TransportParameters);
         fn put_preferred_address(&mut self, addr: &super::PreferredAddress);
     }
 
-    impl BufMutExt for bytes::BytesMut {
+    impl BufMutExt for BytesMut {
         fn put_transport_parameters(&mut self, params: &TransportParameters) {
-            let put_varint = |buf: &mut Self, tag: u8, varint: VarInt| {
+            let put_parameter = |buf: &mut Self, id: u64, value: &[u8]| {
+                buf.put_varint(&VarInt(id));
+                buf.put_varint(&VarInt(value.len() as u64));
+                buf.put_slice(value);
+            };
+
+            let put_varint = |buf: &mut Self, id: u64, varint: VarInt| {
                 if varint.0 > 0 {
-                    buf.put_u8(tag);
-                    buf.put_varint(&varint);
+                    let mut value = BytesMut::new();
+                    value.put_varint(&varint);
+                    put_parameter(buf, id, &value);
                 }
             };
 
-            let put_connection_id = |buf: &mut Self, tag: u8, cid: &Option<ConnectionId>| {
+            let put_connection_id = |buf: &mut Self, id: u64, cid: &Option<ConnectionId>| {
                 if let Some(cid) = cid {
-                    buf.put_u8(tag);
-                    buf.put_connection_id(cid);
+                    put_parameter(buf, id, cid);
                 }
             };
 
-            let put_reset_token = |buf: &mut Self, tag: u8, token: &Option<ResetToken>| {
+            let put_reset_token = |buf: &mut Self, id: u64, token: &Option<ResetToken>| {
                 if let Some(token) = token {
-                    buf.put_u8(tag);
-                    buf.put_reset_token(token);
+                    put_parameter(buf, id, token);
                 }
             };
 
             let put_preferred_address =
-                |buf: &mut Self, tag: u8, addr: &Option<PreferredAddress>| {
+                |buf: &mut Self, id: u64, addr: &Option<PreferredAddress>| {
                     if let Some(addr) = addr {
-                        buf.put_u8(tag);
-                        buf.put_preferred_address(addr);
+                        let mut value = BytesMut::new();
+                        value.put_preferred_address(addr);
+                        put_parameter(buf, id, &value);
                     }
                 };
 
@@ -188,12 +279,32 @@ pub mod ext {
             put_varint(self, 0x0a, params.ack_delay_exponent);
             put_varint(self, 0x0b, params.max_ack_delay);
             if params.disable_active_migration {
-                self.put_u8(0x0c);
+                put_parameter(self, 0x0c, &[]);
             }
             put_preferred_address(self, 0x0d, &params.preferred_address);
             put_varint(self, 0x0e, params.active_connection_id_limit);
             put_connection_id(self, 0x0f, &params.initial_source_connection_id);
             put_connection_id(self, 0x10, &params.retry_source_connection_id);
+            if let Some(info) = &params.version_information {
+                let mut value = BytesMut::new();
+                value.put_u32(info.chosen_version);
+                for version in &info.available_versions {
+                    value.put_u32(*version);
+                }
+                put_parameter(self, 0x11, &value);
+            }
+            put_varint(self, 0x20, params.max_datagram_frame_size);
+            if params.grease_quic_bit {
+                put_parameter(self, 0x2ab2, &[]);
+            }
+            if params.reset_stream_at {
+                put_parameter(self, RESET_STREAM_AT_PARAMETER, &[]);
+            }
+            // 总是带上一个随机的保留传输参数，以免对方依赖于固定的传输参数集合
+            let reserved_id = 31 * u64::from(rand::random::<u16>()) + 27;
+            let reserved_value = rand::random::<[u8; 16]>();
+            let reserved_len = rand::random::<usize>() % (reserved_value.len() + 1);
+            put_parameter(self, reserved_id, &reserved_value[..reserved_len]);
         }
 
         fn put_preferred_address(&mut self, addr: &super::PreferredAddress) {
@@ -242,7 +353,7 @@ pub mod ext {
             address_v6 = None;
         }
 
-        let (input, connection_id) = be_connection_id(input)?;
+        let (input, connection_id) = crate::cid::be_connection_id(input)?;
         let (input, stateless_reset_token) = be_reset_token(input)?;
 
         Ok((
@@ -280,6 +391,7 @@ impl Default for TransportParameters {
             version_information: None,
             max_datagram_frame_size: VarInt(0),
             grease_quic_bit: false,
+            reset_stream_at: false,
         }
     }
 }
@@ -327,10 +439,13 @@ mod test {
             active_connection_id_limit: VarInt(0x1234),
             initial_source_connection_id: Some(init_cid),
             retry_source_connection_id: Some(init_cid),
-            // 下面三个字段 rfc 里没有？
-            version_information: None,
-            max_datagram_frame_size: VarInt(0),
-            grease_quic_bit: false,
+            version_information: Some(VersionInformation {
+                chosen_version: 1,
+                available_versions: vec![0x6b3343cf, 1],
+            }),
+            max_datagram_frame_size: VarInt(0x4b0),
+            grease_quic_bit: true,
+            reset_stream_at: true,
         };
 
         let mut buf = bytes::BytesMut::new();
@@ -338,4 +453,35 @@ mod test {
         let params2 = ext::be_transport_parameters(&buf).unwrap().1;
         assert_eq!(params, params2);
     }
+
+    #[test]
+    fn ignore_unknown_parameters() {
+        use crate::varint::ext::BufMutExt as _;
+        use bytes::BufMut as _;
+
+        assert!(ext::is_reserved_parameter(27));
+        assert!(ext::is_reserved_parameter(31 * 1000 + 27));
+        assert!(!ext::is_reserved_parameter(0x2ab2));
+
+        let mut params = TransportParameters::default();
+        params.set_initial_max_data(VarInt(0x1234));
+        let mut buf = bytes::BytesMut::new();
+        // 保留的和未知的传输参数，不论值是什么都被忽略
+        buf.put_varint(&VarInt(31 * 7 + 27));
+        buf.put_varint(&VarInt(3));
+        buf.put_slice(&[0xff, 0xff, 0xff]);
+        buf.put_transport_parameters(&params);
+        buf.put_varint(&VarInt(0x7777));
+        buf.put_varint(&VarInt(0));
+        let (remain, params2) = ext::be_transport_parameters(&buf).unwrap();
+        assert!(remain.is_empty());
+        assert_eq!(params, params2);
+
+        // 已知的传输参数，值的长度必须正确
+        let mut buf = bytes::BytesMut::new();
+        buf.put_varint(&VarInt(0x04));
+        buf.put_varint(&VarInt(3));
+        buf.put_slice(&[0x01, 0x02, 0x03]);
+        assert!(ext::be_transport_parameters(&buf).is_err());
+    }
 }
@@ -0,0 +1,167 @@
+//! The cryptographic abstraction of QUIC, including the handshake session, the packet
+//! protection and the header protection, see [QUIC-TLS](https://www.rfc-editor.org/rfc/rfc9001.html).
+//! rustls implements them by default, and the [`null`] provider does no encryption at all,
+//! which is for deterministic tests and packet dumps.
+use crate::{packet::QUIC_V1, streamid::Role};
+use thiserror::Error;
+
+pub mod null;
+pub mod rustls_impl;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("crypto error: {0}")]
+pub struct CryptoError(pub String);
+
+/// Protects the payload of packets, in one direction with one key phase.
+pub trait PacketKey: Send + Sync {
+    fn tag_len(&self) -> usize;
+
+    /// Encrypt the `payload` in place, whose last [`PacketKey::tag_len`] bytes are reserved
+    /// for the authentication tag.
+    fn encrypt_in_place(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &mut [u8],
+    ) -> Result<(), CryptoError>;
+
+    /// Decrypt the `payload` in place, returns the length of the plaintext at the start of it.
+    fn decrypt_in_place(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, CryptoError>;
+
+    /// The number of packets that can be encrypted with the key.
+    fn confidentiality_limit(&self) -> u64;
+
+    /// The number of packets that can fail to be decrypted with the key.
+    fn integrity_limit(&self) -> u64;
+}
+
+/// Protects the first byte and the packet number of packets, see
+/// [Section 5.4](https://www.rfc-editor.org/rfc/rfc9001.html#section-5.4) of QUIC-TLS.
+pub trait HeaderProtectionKey: Send + Sync {
+    fn sample_len(&self) -> usize;
+
+    fn encrypt_in_place(
+        &self,
+        sample: &[u8],
+        first: &mut u8,
+        packet_number: &mut [u8],
+    ) -> Result<(), CryptoError>;
+
+    fn decrypt_in_place(
+        &self,
+        sample: &[u8],
+        first: &mut u8,
+        packet_number: &mut [u8],
+    ) -> Result<(), CryptoError>;
+}
+
+pub struct DirectionalKeys {
+    pub header: Box<dyn HeaderProtectionKey>,
+    pub packet: Box<dyn PacketKey>,
+}
+
+/// The keys of an encryption level, `local` to encrypt and `remote` to decrypt.
+pub struct Keys {
+    pub local: DirectionalKeys,
+    pub remote: DirectionalKeys,
+}
+
+pub struct PacketKeySet {
+    pub local: Box<dyn PacketKey>,
+    pub remote: Box<dyn PacketKey>,
+}
+
+/// Derives the next generation of 1-RTT packet keys for key updates, the header protection
+/// keys are not updated, see [Section 6](https://www.rfc-editor.org/rfc/rfc9001.html#section-6) of QUIC-TLS.
+pub trait Secrets: Send {
+    fn next_packet_keys(&mut self) -> PacketKeySet;
+}
+
+pub enum KeyChange {
+    Handshake { keys: Keys },
+    OneRtt { keys: Keys, next: Box<dyn Secrets> },
+}
+
+/// The handshake session, which exchanges the handshake messages carried in CRYPTO frames,
+/// and produces the keys of each encryption level.
+pub trait Session: Send {
+    fn role(&self) -> Role;
+
+    /// The QUIC version, which determines the Initial keys and the labels to derive keys.
+    fn version(&self) -> u32 {
+        QUIC_V1
+    }
+
+    /// The Initial keys derived from the Destination Connection ID of the client's first
+    /// Initial packet, or the Source Connection ID of the Retry packet.
+    fn initial_keys(&self, client_dst_connection_id: &[u8]) -> Keys;
+
+    /// The Initial keys of another version, the server needs them to read the client's Initial
+    /// packets of the original version after choosing a compatible version.
+    fn initial_keys_of_version(
+        &self,
+        version: u32,
+        client_dst_connection_id: &[u8],
+    ) -> Option<Keys> {
+        (version == self.version()).then(|| self.initial_keys(client_dst_connection_id))
+    }
+
+    /// Switch to a compatible version during the handshake, when the client receives the server's
+    /// first Initial packet of the negotiated version, see [RFC 9368](https://www.rfc-editor.org/rfc/rfc9368.html).
+    /// Returns false if the session can't be upgraded, the packet should be dropped then.
+    fn upgrade_version(&mut self, version: u32) -> bool {
+        version == self.version()
+    }
+
+    fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), CryptoError>;
+
+    /// Write the handshake messages to send into `buf`. If new keys are returned, the
+    /// messages written in later calls are sent with the new keys.
+    fn write_hs(&mut self, buf: &mut Vec<u8>) -> Option<KeyChange>;
+
+    fn wants_write(&self) -> bool;
+
+    fn is_handshaking(&self) -> bool;
+
+    /// The encoded transport parameters of the peer.
+    fn transport_parameters(&self) -> Option<&[u8]>;
+
+    fn zero_rtt_keys(&self) -> Option<Keys> {
+        None
+    }
+
+    /// The description code of the fatal alert if the handshake failed, which becomes
+    /// the CRYPTO_ERROR code.
+    fn alert(&self) -> Option<u8>;
+
+    fn is_early_data_accepted(&self) -> bool {
+        false
+    }
+
+    fn reject_early_data(&mut self) {}
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// The DER-encoded certificate chain of the peer.
+    fn peer_certificates(&self) -> Option<Vec<Vec<u8>>> {
+        None
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), CryptoError>;
+}
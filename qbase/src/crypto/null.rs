@@ -0,0 +1,469 @@
+//! The null provider does no encryption, and its handshake only exchanges the transport
+//! parameters. It must never be used in production, but it makes the packets deterministic
+//! and readable, which is useful for tests and packet dumps.
+use super::{
+    CryptoError, DirectionalKeys, HeaderProtectionKey, KeyChange, Keys, PacketKey, PacketKeySet,
+    Secrets, Session,
+};
+use crate::{packet::QUIC_V1, streamid::Role};
+use std::sync::{Arc, Mutex};
+
+/// The alert of unexpected_message, the null session fails with it on any invalid message.
+const UNEXPECTED_MESSAGE: u8 = 10;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullPacketKey;
+
+impl PacketKey for NullPacketKey {
+    fn tag_len(&self) -> usize {
+        0
+    }
+
+    fn encrypt_in_place(
+        &self,
+        _packet_number: u64,
+        _header: &[u8],
+        _payload: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        Ok(())
+    }
+
+    fn decrypt_in_place(
+        &self,
+        _packet_number: u64,
+        _header: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, CryptoError> {
+        Ok(payload.len())
+    }
+
+    fn confidentiality_limit(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn integrity_limit(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullHeaderProtectionKey;
+
+impl HeaderProtectionKey for NullHeaderProtectionKey {
+    fn sample_len(&self) -> usize {
+        0
+    }
+
+    fn encrypt_in_place(
+        &self,
+        _sample: &[u8],
+        _first: &mut u8,
+        _packet_number: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        Ok(())
+    }
+
+    fn decrypt_in_place(
+        &self,
+        _sample: &[u8],
+        _first: &mut u8,
+        _packet_number: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullSecrets;
+
+impl Secrets for NullSecrets {
+    fn next_packet_keys(&mut self) -> PacketKeySet {
+        PacketKeySet {
+            local: Box::new(NullPacketKey),
+            remote: Box::new(NullPacketKey),
+        }
+    }
+}
+
+fn null_directional_keys() -> DirectionalKeys {
+    DirectionalKeys {
+        header: Box::new(NullHeaderProtectionKey),
+        packet: Box::new(NullPacketKey),
+    }
+}
+
+pub fn null_keys() -> Keys {
+    Keys {
+        local: null_directional_keys(),
+        remote: null_directional_keys(),
+    }
+}
+
+// 握手消息的类型，每个消息是 类型(1字节) + 长度(2字节) + 内容
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const SERVER_FINISHED: u8 = 3;
+const CLIENT_FINISHED: u8 = 4;
+// 握手完成后，服务端在1RTT的CRYPTO帧中发送
+const NEW_SESSION_TICKET: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // 客户端
+    SendClientHello,
+    WaitServerHello,
+    GotServerHello,
+    // ServerFinished可能在客户端安装Handshake密钥之前就读到了
+    GotServerFinished,
+    WaitServerFinished,
+    SendClientFinished,
+    // 服务端
+    WaitClientHello,
+    SendServerHello,
+    SendServerFinished,
+    WaitClientFinished,
+    Done,
+}
+
+/// A trivial handshake in the same flights as TLS 1.3, the client sends its transport
+/// parameters in ClientHello, the server replies with its own in ServerHello, then both
+/// sides send an empty Finished, and the keys of each level are all null keys. After the
+/// handshake, the server may send a NewSessionTicket, whose content is an opaque ticket.
+#[derive(Debug)]
+pub struct NullSession {
+    role: Role,
+    version: u32,
+    state: State,
+    params: Vec<u8>,
+    peer_params: Option<Vec<u8>>,
+    // 尚未凑成完整消息的数据
+    buffer: Vec<u8>,
+    alert: Option<u8>,
+    // 服务端待发送的票据
+    ticket: Option<Vec<u8>>,
+    // 客户端收到的票据，与外部共享，就像rustls::ClientConfig中的会话缓存
+    ticket_store: Option<Arc<Mutex<Vec<Vec<u8>>>>>,
+}
+
+impl NullSession {
+    pub fn new_client(params: Vec<u8>) -> Self {
+        Self::new(Role::Client, State::SendClientHello, params)
+    }
+
+    pub fn new_server(params: Vec<u8>) -> Self {
+        Self::new(Role::Server, State::WaitClientHello, params)
+    }
+
+    /// The null session starts with QUIC version 1 by default.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// For the server, send `ticket` in a NewSessionTicket message once the handshake is done.
+    pub fn with_ticket(mut self, ticket: Vec<u8>) -> Self {
+        self.ticket = Some(ticket);
+        self
+    }
+
+    /// For the client, keep the tickets received after the handshake in `store`.
+    pub fn with_ticket_store(mut self, store: Arc<Mutex<Vec<Vec<u8>>>>) -> Self {
+        self.ticket_store = Some(store);
+        self
+    }
+
+    fn new(role: Role, state: State, params: Vec<u8>) -> Self {
+        Self {
+            role,
+            version: QUIC_V1,
+            state,
+            params,
+            peer_params: None,
+            buffer: Vec::new(),
+            alert: None,
+            ticket: None,
+            ticket_store: None,
+        }
+    }
+
+    fn put_message(buf: &mut Vec<u8>, ty: u8, body: &[u8]) {
+        buf.push(ty);
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend_from_slice(body);
+    }
+
+    fn on_message(&mut self, ty: u8, body: Vec<u8>) -> Result<(), CryptoError> {
+        self.state = match (self.state, ty) {
+            (State::WaitClientHello, CLIENT_HELLO) => {
+                self.peer_params = Some(body);
+                State::SendServerHello
+            }
+            (State::WaitServerHello, SERVER_HELLO) => {
+                self.peer_params = Some(body);
+                State::GotServerHello
+            }
+            (State::GotServerHello, SERVER_FINISHED) => State::GotServerFinished,
+            (State::WaitServerFinished, SERVER_FINISHED) => State::SendClientFinished,
+            (State::WaitClientFinished, CLIENT_FINISHED) => State::Done,
+            (State::Done, NEW_SESSION_TICKET) if self.role == Role::Client => {
+                if let Some(store) = self.ticket_store.as_ref() {
+                    store.lock().unwrap().push(body);
+                }
+                State::Done
+            }
+            _ => {
+                self.alert = Some(UNEXPECTED_MESSAGE);
+                return Err(CryptoError(format!(
+                    "unexpected message {ty} in state {:?}",
+                    self.state
+                )));
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Session for NullSession {
+    fn role(&self) -> Role {
+        self.role
+    }
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn initial_keys(&self, _client_dst_connection_id: &[u8]) -> Keys {
+        null_keys()
+    }
+
+    fn initial_keys_of_version(
+        &self,
+        _version: u32,
+        _client_dst_connection_id: &[u8],
+    ) -> Option<Keys> {
+        Some(null_keys())
+    }
+
+    // 空会话的握手消息与版本无关，只要客户端还没收到ServerHello，就可以切换到兼容版本
+    fn upgrade_version(&mut self, version: u32) -> bool {
+        if self.role == Role::Client
+            && matches!(self.state, State::SendClientHello | State::WaitServerHello)
+            && crate::packet::version::is_compatible(self.version, version)
+        {
+            self.version = version;
+        }
+        self.version == version
+    }
+
+    fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), CryptoError> {
+        if self.alert.is_some() {
+            return Err(CryptoError("handshake already failed".to_string()));
+        }
+        self.buffer.extend_from_slice(plaintext);
+        while self.buffer.len() >= 3 {
+            let len = u16::from_be_bytes([self.buffer[1], self.buffer[2]]) as usize;
+            if self.buffer.len() < 3 + len {
+                break;
+            }
+            let ty = self.buffer[0];
+            let body = self.buffer[3..3 + len].to_vec();
+            self.buffer.drain(..3 + len);
+            self.on_message(ty, body)?;
+        }
+        Ok(())
+    }
+
+    fn write_hs(&mut self, buf: &mut Vec<u8>) -> Option<KeyChange> {
+        match self.state {
+            State::SendClientHello => {
+                Self::put_message(buf, CLIENT_HELLO, &self.params);
+                self.state = State::WaitServerHello;
+                None
+            }
+            State::GotServerHello => {
+                self.state = State::WaitServerFinished;
+                Some(KeyChange::Handshake { keys: null_keys() })
+            }
+            State::GotServerFinished => {
+                self.state = State::SendClientFinished;
+                Some(KeyChange::Handshake { keys: null_keys() })
+            }
+            State::SendClientFinished => {
+                Self::put_message(buf, CLIENT_FINISHED, &[]);
+                self.state = State::Done;
+                Some(KeyChange::OneRtt {
+                    keys: null_keys(),
+                    next: Box::new(NullSecrets),
+                })
+            }
+            State::SendServerHello => {
+                Self::put_message(buf, SERVER_HELLO, &self.params);
+                self.state = State::SendServerFinished;
+                Some(KeyChange::Handshake { keys: null_keys() })
+            }
+            State::SendServerFinished => {
+                Self::put_message(buf, SERVER_FINISHED, &[]);
+                self.state = State::WaitClientFinished;
+                Some(KeyChange::OneRtt {
+                    keys: null_keys(),
+                    next: Box::new(NullSecrets),
+                })
+            }
+            State::Done if self.role == Role::Server => {
+                if let Some(ticket) = self.ticket.take() {
+                    Self::put_message(buf, NEW_SESSION_TICKET, &ticket);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn wants_write(&self) -> bool {
+        matches!(
+            self.state,
+            State::SendClientHello
+                | State::GotServerHello
+                | State::GotServerFinished
+                | State::SendClientFinished
+                | State::SendServerHello
+                | State::SendServerFinished
+        ) || (self.state == State::Done && self.ticket.is_some())
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.state != State::Done
+    }
+
+    fn transport_parameters(&self) -> Option<&[u8]> {
+        self.peer_params.as_deref()
+    }
+
+    fn alert(&self) -> Option<u8> {
+        self.alert
+    }
+
+    fn export_keying_material(
+        &self,
+        _output: &mut [u8],
+        _label: &[u8],
+        _context: Option<&[u8]>,
+    ) -> Result<(), CryptoError> {
+        Err(CryptoError(
+            "the null session can't export keying material".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 返回每次调用write_hs写出的数据，以及是否产生了新密钥
+    fn write_all(session: &mut NullSession) -> Vec<(Vec<u8>, bool)> {
+        let mut flights = Vec::new();
+        while session.wants_write() {
+            let mut buf = Vec::new();
+            let key_change = session.write_hs(&mut buf);
+            flights.push((buf, key_change.is_some()));
+        }
+        flights
+    }
+
+    #[test]
+    fn test_null_handshake() {
+        let mut client = NullSession::new_client(b"client params".to_vec());
+        let mut server = NullSession::new_server(b"server params".to_vec());
+
+        // ClientHello在Initial级别，此时还没有新密钥
+        let flights = write_all(&mut client);
+        assert_eq!(flights.len(), 1);
+        assert!(!flights[0].1);
+        // 分成两段读入，也能凑成完整消息
+        let (first, second) = flights[0].0.split_at(5);
+        server.read_hs(first).unwrap();
+        assert!(server.transport_parameters().is_none());
+        server.read_hs(second).unwrap();
+        assert_eq!(server.transport_parameters(), Some(&b"client params"[..]));
+
+        // ServerHello之后是Handshake密钥，Finished之后是1RTT密钥
+        let flights = write_all(&mut server);
+        assert_eq!(flights.len(), 2);
+        assert!(flights.iter().all(|(_, key_change)| *key_change));
+        for (data, _) in flights.iter() {
+            client.read_hs(data).unwrap();
+            write_all(&mut client)
+                .into_iter()
+                .for_each(|(data, _)| server.read_hs(&data).unwrap());
+        }
+        assert_eq!(client.transport_parameters(), Some(&b"server params"[..]));
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+
+        // 握手完成后再收到握手消息就是错误
+        assert!(server.read_hs(&[CLIENT_HELLO, 0, 0]).is_err());
+        assert_eq!(server.alert(), Some(UNEXPECTED_MESSAGE));
+    }
+
+    #[test]
+    fn test_null_session_ticket() {
+        let store = Arc::new(Mutex::new(Vec::new()));
+        let mut client = NullSession::new_client(Vec::new()).with_ticket_store(store.clone());
+        let mut server = NullSession::new_server(Vec::new()).with_ticket(b"ticket".to_vec());
+        let flights = write_all(&mut client);
+        server.read_hs(&flights[0].0).unwrap();
+        for (data, _) in write_all(&mut server) {
+            client.read_hs(&data).unwrap();
+        }
+        // 握手完成之前，服务端不会发送票据
+        assert!(!server.wants_write());
+        for (data, _) in write_all(&mut client) {
+            server.read_hs(&data).unwrap();
+        }
+
+        let flights = write_all(&mut server);
+        assert_eq!(flights.len(), 1);
+        assert!(!flights[0].1);
+        client.read_hs(&flights[0].0).unwrap();
+        assert_eq!(*store.lock().unwrap(), vec![b"ticket".to_vec()]);
+        assert!(!server.wants_write());
+        // 服务端不接受票据
+        assert!(server.read_hs(&flights[0].0).is_err());
+    }
+
+    #[test]
+    fn test_null_upgrade_version() {
+        use crate::packet::QUIC_V2;
+
+        let mut client = NullSession::new_client(Vec::new());
+        let mut server = NullSession::new_server(Vec::new()).with_version(QUIC_V2);
+        let flights = write_all(&mut client);
+        server.read_hs(&flights[0].0).unwrap();
+        // 收到服务端v2的Initial包之前，客户端可以升级到v2
+        assert!(client.upgrade_version(QUIC_V2));
+        assert_eq!(client.version(), QUIC_V2);
+        assert!(!server.upgrade_version(QUIC_V1));
+
+        let flights = write_all(&mut server);
+        client.read_hs(&flights[0].0).unwrap();
+        assert!(!client.upgrade_version(QUIC_V1));
+        assert!(client.upgrade_version(QUIC_V2));
+    }
+
+    #[test]
+    fn test_null_keys() {
+        let keys = null_keys();
+        let mut payload = b"plaintext".to_vec();
+        keys.local
+            .packet
+            .encrypt_in_place(0, b"header", &mut payload)
+            .unwrap();
+        assert_eq!(payload, b"plaintext");
+        let len = keys
+            .remote
+            .packet
+            .decrypt_in_place(0, b"header", &mut payload)
+            .unwrap();
+        assert_eq!(&payload[..len], b"plaintext");
+    }
+}
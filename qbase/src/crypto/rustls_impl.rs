@@ -0,0 +1,336 @@
+use super::{
+    CryptoError, DirectionalKeys, HeaderProtectionKey, KeyChange, Keys, PacketKey, PacketKeySet,
+    Secrets, Session,
+};
+use crate::{
+    packet::{QUIC_V1, QUIC_V2},
+    streamid::Role,
+};
+use rustls::{quic, AlertDescription, Side};
+use std::sync::Arc;
+
+fn crypto_error(e: rustls::Error) -> CryptoError {
+    CryptoError(e.to_string())
+}
+
+/// The TLS alert for a rustls error, which becomes the CRYPTO_ERROR code, see
+/// [Section 4.8](https://www.rfc-editor.org/rfc/rfc9001.html#section-4.8) of QUIC-TLS.
+pub fn tls_alert(e: &rustls::Error) -> AlertDescription {
+    use rustls::Error;
+    match e {
+        Error::InappropriateMessage { .. } | Error::InappropriateHandshakeMessage { .. } => {
+            AlertDescription::UnexpectedMessage
+        }
+        Error::InvalidMessage(_) => AlertDescription::DecodeError,
+        Error::NoCertificatesPresented => AlertDescription::CertificateRequired,
+        Error::DecryptError => AlertDescription::DecryptError,
+        Error::PeerIncompatible(_) => AlertDescription::HandshakeFailure,
+        Error::PeerMisbehaved(_) => AlertDescription::IllegalParameter,
+        // 对端发来的告警，原样作为错误码
+        Error::AlertReceived(alert) => *alert,
+        Error::InvalidCertificate(e) => e.clone().into(),
+        Error::PeerSentOversizedRecord => AlertDescription::RecordOverflow,
+        Error::NoApplicationProtocol => AlertDescription::NoApplicationProtocol,
+        _ => AlertDescription::InternalError,
+    }
+}
+
+impl From<rustls::Error> for crate::error::Error {
+    fn from(e: rustls::Error) -> Self {
+        Self::new(
+            crate::error::ErrorKind::Crypto(tls_alert(&e).get_u8()),
+            crate::frame::FrameType::Crypto,
+            e.to_string(),
+        )
+    }
+}
+
+impl PacketKey for quic::PacketKey {
+    fn tag_len(&self) -> usize {
+        quic::PacketKey::tag_len(self)
+    }
+
+    fn encrypt_in_place(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        let tag_len = quic::PacketKey::tag_len(self);
+        if payload.len() < tag_len {
+            return Err(CryptoError("no room for the tag".to_string()));
+        }
+        let (body, tag_room) = payload.split_at_mut(payload.len() - tag_len);
+        let tag = quic::PacketKey::encrypt_in_place(self, packet_number, header, body)
+            .map_err(crypto_error)?;
+        tag_room.copy_from_slice(tag.as_ref());
+        Ok(())
+    }
+
+    fn decrypt_in_place(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, CryptoError> {
+        quic::PacketKey::decrypt_in_place(self, packet_number, header, payload)
+            .map(|plaintext| plaintext.len())
+            .map_err(crypto_error)
+    }
+
+    fn confidentiality_limit(&self) -> u64 {
+        quic::PacketKey::confidentiality_limit(self)
+    }
+
+    fn integrity_limit(&self) -> u64 {
+        quic::PacketKey::integrity_limit(self)
+    }
+}
+
+impl HeaderProtectionKey for quic::HeaderProtectionKey {
+    fn sample_len(&self) -> usize {
+        quic::HeaderProtectionKey::sample_len(self)
+    }
+
+    fn encrypt_in_place(
+        &self,
+        sample: &[u8],
+        first: &mut u8,
+        packet_number: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        quic::HeaderProtectionKey::encrypt_in_place(self, sample, first, packet_number)
+            .map_err(crypto_error)
+    }
+
+    fn decrypt_in_place(
+        &self,
+        sample: &[u8],
+        first: &mut u8,
+        packet_number: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        quic::HeaderProtectionKey::decrypt_in_place(self, sample, first, packet_number)
+            .map_err(crypto_error)
+    }
+}
+
+impl From<quic::DirectionalKeys> for DirectionalKeys {
+    fn from(keys: quic::DirectionalKeys) -> Self {
+        Self {
+            header: Box::new(keys.header),
+            packet: Box::new(keys.packet),
+        }
+    }
+}
+
+impl From<quic::Keys> for Keys {
+    fn from(keys: quic::Keys) -> Self {
+        Self {
+            local: keys.local.into(),
+            remote: keys.remote.into(),
+        }
+    }
+}
+
+impl Secrets for quic::Secrets {
+    fn next_packet_keys(&mut self) -> PacketKeySet {
+        let keys = quic::Secrets::next_packet_keys(self);
+        PacketKeySet {
+            local: Box::new(keys.local),
+            remote: Box::new(keys.remote),
+        }
+    }
+}
+
+/// The handshake session with rustls.
+pub struct RustlsSession {
+    connection: quic::Connection,
+    version: quic::Version,
+    // 客户端连接时指定的服务端名称，即SNI
+    server_name: Option<String>,
+    // rustls没有发出告警的错误，比如收到对端的告警，也要有对应的CRYPTO_ERROR
+    error_alert: Option<AlertDescription>,
+}
+
+/// The QUIC version in rustls, None if rustls doesn't support it.
+pub fn rustls_version(version: u32) -> Option<quic::Version> {
+    match version {
+        QUIC_V1 => Some(quic::Version::V1),
+        QUIC_V2 => Some(quic::Version::V2),
+        _ => None,
+    }
+}
+
+/// The Initial keys of `version` for `role`, without a session, such as for the server
+/// endpoint to read or close a connection attempt before creating the connection.
+/// None if rustls doesn't support the version.
+pub fn initial_keys(version: u32, client_dst_connection_id: &[u8], role: Role) -> Option<Keys> {
+    let side = match role {
+        Role::Client => Side::Client,
+        Role::Server => Side::Server,
+    };
+    rustls_version(version)
+        .map(|version| quic::Keys::initial(version, client_dst_connection_id, side).into())
+}
+
+impl RustlsSession {
+    /// rustls fixes `version` once the connection is created, and derives the handshake keys with
+    /// it, so the client session can't be upgraded to a compatible version chosen by the server,
+    /// its [`Session::upgrade_version`] always fails for other versions.
+    pub fn new_client(
+        config: Arc<rustls::ClientConfig>,
+        version: quic::Version,
+        server_name: rustls::ServerName,
+        params: Vec<u8>,
+    ) -> Result<Self, rustls::Error> {
+        let name = match &server_name {
+            rustls::ServerName::DnsName(name) => Some(name.as_ref().to_string()),
+            _ => None,
+        };
+        let connection = quic::ClientConnection::new(config, version, server_name, params)?;
+        Ok(Self {
+            connection: quic::Connection::Client(connection),
+            version,
+            server_name: name,
+            error_alert: None,
+        })
+    }
+
+    pub fn new_server(
+        config: Arc<rustls::ServerConfig>,
+        version: quic::Version,
+        params: Vec<u8>,
+    ) -> Result<Self, rustls::Error> {
+        let connection = quic::ServerConnection::new(config, version, params)?;
+        Ok(Self {
+            connection: quic::Connection::Server(connection),
+            version,
+            server_name: None,
+            error_alert: None,
+        })
+    }
+}
+
+impl Session for RustlsSession {
+    fn role(&self) -> Role {
+        match self.connection {
+            quic::Connection::Client(_) => Role::Client,
+            quic::Connection::Server(_) => Role::Server,
+        }
+    }
+
+    fn version(&self) -> u32 {
+        match self.version {
+            quic::Version::V2 => QUIC_V2,
+            _ => QUIC_V1,
+        }
+    }
+
+    fn initial_keys(&self, client_dst_connection_id: &[u8]) -> Keys {
+        let side = match self.connection {
+            quic::Connection::Client(_) => Side::Client,
+            quic::Connection::Server(_) => Side::Server,
+        };
+        quic::Keys::initial(self.version, client_dst_connection_id, side).into()
+    }
+
+    fn initial_keys_of_version(
+        &self,
+        version: u32,
+        client_dst_connection_id: &[u8],
+    ) -> Option<Keys> {
+        initial_keys(version, client_dst_connection_id, self.role())
+    }
+
+    // rustls在创建连接时就确定了版本，并用其派生握手密钥，因此客户端无法在握手中途升级版本，
+    // 只能作为服务端被动地选择兼容版本，见RustlsSession::new_client
+
+    fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), CryptoError> {
+        self.connection.read_hs(plaintext).map_err(|e| {
+            self.error_alert.get_or_insert(tls_alert(&e));
+            crypto_error(e)
+        })
+    }
+
+    fn write_hs(&mut self, buf: &mut Vec<u8>) -> Option<KeyChange> {
+        self.connection
+            .write_hs(buf)
+            .map(|key_change| match key_change {
+                quic::KeyChange::Handshake { keys } => KeyChange::Handshake { keys: keys.into() },
+                quic::KeyChange::OneRtt { keys, next } => KeyChange::OneRtt {
+                    keys: keys.into(),
+                    next: Box::new(next),
+                },
+            })
+    }
+
+    fn wants_write(&self) -> bool {
+        self.connection.wants_write()
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.connection.is_handshaking()
+    }
+
+    fn transport_parameters(&self) -> Option<&[u8]> {
+        self.connection.quic_transport_parameters()
+    }
+
+    fn zero_rtt_keys(&self) -> Option<Keys> {
+        // 0RTT数据只能由客户端发往服务端，两个方向使用同一套密钥
+        let local = self.connection.zero_rtt_keys()?;
+        let remote = self.connection.zero_rtt_keys()?;
+        Some(Keys {
+            local: local.into(),
+            remote: remote.into(),
+        })
+    }
+
+    fn alert(&self) -> Option<u8> {
+        self.connection
+            .alert()
+            .or(self.error_alert)
+            .map(|alert| alert.get_u8())
+    }
+
+    fn is_early_data_accepted(&self) -> bool {
+        match &self.connection {
+            quic::Connection::Client(connection) => connection.is_early_data_accepted(),
+            quic::Connection::Server(_) => false,
+        }
+    }
+
+    fn reject_early_data(&mut self) {
+        if let quic::Connection::Server(connection) = &mut self.connection {
+            connection.reject_early_data();
+        }
+    }
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.connection.alpn_protocol()
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        match &self.connection {
+            quic::Connection::Client(_) => self.server_name.as_deref(),
+            quic::Connection::Server(connection) => connection.server_name(),
+        }
+    }
+
+    fn peer_certificates(&self) -> Option<Vec<Vec<u8>>> {
+        self.connection
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|cert| cert.0.clone()).collect())
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), CryptoError> {
+        self.connection
+            .export_keying_material(output, label, context)
+            .map(|_| ())
+            .map_err(crypto_error)
+    }
+}
@@ -21,6 +21,7 @@ pub enum ErrorKind {
     KeyUpdate,
     AeadLimitReached,
     NoViablePath,
+    VersionNegotiation,
     Crypto(u8),
 }
 
@@ -44,6 +45,7 @@ impl Display for ErrorKind {
             ErrorKind::KeyUpdate => "key update error",
             ErrorKind::AeadLimitReached => "the endpoint has reached the confidentiality or integrity limit for the AEAD algorithm",
             ErrorKind::NoViablePath => "no viable network path exists",
+            ErrorKind::VersionNegotiation => "the version negotiation was not correctly performed, such as a downgrade attack",
             ErrorKind::Crypto(x) => return write!(f, "crypto error: {}", x),
         })
     }
@@ -75,6 +77,7 @@ impl TryFrom<VarInt> for ErrorKind {
             0x0e => ErrorKind::KeyUpdate,
             0x0f => ErrorKind::AeadLimitReached,
             0x10 => ErrorKind::NoViablePath,
+            0x11 => ErrorKind::VersionNegotiation,
             0x0100..=0x01ff => ErrorKind::Crypto((value.into_inner() & 0xff) as u8),
             other => return Err(InvalidErrorKind(other)),
         })
@@ -101,6 +104,7 @@ impl From<ErrorKind> for VarInt {
             ErrorKind::KeyUpdate => VarInt(0x0e),
             ErrorKind::AeadLimitReached => VarInt(0x0f),
             ErrorKind::NoViablePath => VarInt(0x10),
+            ErrorKind::VersionNegotiation => VarInt(0x11),
             ErrorKind::Crypto(x) => VarInt(0x0100 + x as u64),
         }
     }
@@ -142,20 +146,106 @@ impl From<std::convert::Infallible> for Error {
     }
 }
 
-impl From<Error> for crate::frame::ConnectionCloseFrame {
-    fn from(e: Error) -> Self {
+/// The error with which the application closes the connection, the error code is defined by
+/// the application protocol, and is carried in a CONNECTION_CLOSE frame of type 0x1d.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("application error {error_code}, reason: {reason}")]
+pub struct AppError {
+    pub error_code: VarInt,
+    pub reason: Cow<'static, str>,
+}
+
+impl AppError {
+    pub fn new<T: Into<Cow<'static, str>>>(error_code: VarInt, reason: T) -> Self {
         Self {
-            error_kind: e.kind,
-            frame_type: Some(e.frame_type),
-            reason: e.reason,
+            error_code,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Which endpoint closed the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Local,
+    Remote,
+}
+
+/// The reason why a connection dies, which is given to the application.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConnectionError {
+    #[error("connection closed by the {0:?} QUIC layer: {1}")]
+    Transport(Origin, Error),
+    #[error("connection closed by the {0:?} application: {1}")]
+    Application(Origin, AppError),
+}
+
+impl ConnectionError {
+    pub fn origin(&self) -> Origin {
+        match self {
+            Self::Transport(origin, _) | Self::Application(origin, _) => *origin,
+        }
+    }
+}
+
+impl From<Error> for ConnectionError {
+    fn from(e: Error) -> Self {
+        Self::Transport(Origin::Local, e)
+    }
+}
+
+impl From<AppError> for ConnectionError {
+    fn from(e: AppError) -> Self {
+        Self::Application(Origin::Local, e)
+    }
+}
+
+/// 收到对端的CONNECTION_CLOSE帧，连接被对端关闭
+impl From<crate::frame::ConnectionCloseFrame> for ConnectionError {
+    fn from(frame: crate::frame::ConnectionCloseFrame) -> Self {
+        use crate::frame::ConnectionCloseFrame;
+        match frame {
+            ConnectionCloseFrame::Quic {
+                error_kind,
+                frame_type,
+                reason,
+            } => Self::Transport(Origin::Remote, Error::new(error_kind, frame_type, reason)),
+            ConnectionCloseFrame::App { error_code, reason } => {
+                Self::Application(Origin::Remote, AppError::new(error_code, reason))
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::frame::ConnectionCloseFrame;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_connection_error_origin() {
+        let error = Error::new(
+            ErrorKind::FlowControl,
+            FrameType::Stream(0),
+            "exceed max data",
+        );
+        let local = ConnectionError::from(error.clone());
+        assert_eq!(local.origin(), Origin::Local);
+
+        let remote = ConnectionError::from(ConnectionCloseFrame::from(error.clone()));
+        assert_eq!(remote, ConnectionError::Transport(Origin::Remote, error));
+
+        let app_error = AppError::new(VarInt(0x10c), "request cancelled");
+        let remote = ConnectionError::from(ConnectionCloseFrame::from(app_error.clone()));
+        assert_eq!(
+            remote,
+            ConnectionError::Application(Origin::Remote, app_error)
+        );
+        assert_eq!(remote.origin(), Origin::Remote);
+    }
 }
@@ -34,6 +34,7 @@ mod path_challenge;
 mod path_response;
 mod ping;
 mod reset_stream;
+mod reset_stream_at;
 mod retire_connection_id;
 mod stop_sending;
 mod stream;
@@ -59,6 +60,7 @@ pub use path_challenge::PathChallengeFrame;
 pub use path_response::PathResponseFrame;
 pub use ping::PingFrame;
 pub use reset_stream::ResetStreamFrame;
+pub use reset_stream_at::ResetStreamAtFrame;
 pub use retire_connection_id::RetireConnectionIdFrame;
 pub use stop_sending::StopSendingFrame;
 pub use stream::{ShouldCarryLength, StreamFrame};
@@ -90,6 +92,7 @@ pub enum FrameType {
     PathResponse,
     ConnectionClose(u8),
     HandshakeDone,
+    ResetStreamAt,
 }
 
 impl TryFrom<VarInt> for FrameType {
@@ -119,9 +122,10 @@ impl TryFrom<VarInt> for FrameType {
             0x19 => FrameType::RetireConnectionId,
             0x1a => FrameType::PathChallenge,
             0x1b => FrameType::PathResponse,
-            // The last bit is the layer flag bit, 0 indicates application layer, 1 indicates transport layer.
+            // The last bit is the layer flag bit, 0 indicates transport layer, 1 indicates application layer.
             ty @ (0x1c | 0x1d) => FrameType::ConnectionClose(ty as u8 & 0x1),
             0x1e => FrameType::HandshakeDone,
+            0x24 => FrameType::ResetStreamAt,
             _ => return Err(Self::Error::InvalidType(frame_type)),
         })
     }
@@ -150,6 +154,7 @@ impl From<FrameType> for VarInt {
             FrameType::PathResponse => VarInt(0x1b),
             FrameType::ConnectionClose(layer) => VarInt(0x1c | layer as u64),
             FrameType::HandshakeDone => VarInt(0x1e),
+            FrameType::ResetStreamAt => VarInt(0x24),
         }
     }
 }
@@ -175,6 +180,7 @@ pub enum StreamCtlFrame {
     MaxStreams(MaxStreamsFrame),
     StreamDataBlocked(StreamDataBlockedFrame),
     StreamsBlocked(StreamsBlockedFrame),
+    ResetStreamAt(ResetStreamAtFrame),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -249,6 +255,7 @@ pub mod ext {
         new_connection_id::ext::be_new_connection_id_frame, new_token::ext::be_new_token_frame,
         new_token::ext::WriteNewTokenFrame, path_challenge::ext::be_path_challenge_frame,
         path_response::ext::be_path_response_frame, reset_stream::ext::be_reset_stream_frame,
+        reset_stream_at::ext::be_reset_stream_at_frame,
         retire_connection_id::ext::be_retire_connection_id_frame,
         stop_sending::ext::be_stop_sending_frame, stream::ext::stream_frame_with_flag,
         stream_data_blocked::ext::be_stream_data_blocked_frame,
@@ -299,6 +306,9 @@ pub mod ext {
             FrameType::ResetStream => map(be_reset_stream_frame, |f| {
                 Frame::Pure(PureFrame::Stream(f.into()))
             })(input),
+            FrameType::ResetStreamAt => map(be_reset_stream_at_frame, |f| {
+                Frame::Pure(PureFrame::Stream(f.into()))
+            })(input),
             FrameType::StopSending => map(be_stop_sending_frame, |f| {
                 Frame::Pure(PureFrame::Stream(f.into()))
             })(input),
@@ -344,9 +354,9 @@ pub mod ext {
         let input = raw.as_ref();
         let (remain, fty) = be_varint(input).map_err(|e| match e {
             ne @ nom::Err::Incomplete(_) => nom::Err::Error(Error::IncompleteType(ne.to_string())),
-            _ => unreachable!(
-                "parsing frame type which is a varint never generates error or failure"
-            ),
+            nom::Err::Error(ne) | nom::Err::Failure(ne) => {
+                nom::Err::Error(Error::Malformed(ne.code.description().to_owned()))
+            }
         })?;
         let frame_type = FrameType::try_from(fty).map_err(nom::Err::Error)?;
         let (remain, frame) =
@@ -354,16 +364,15 @@ pub mod ext {
                 ne @ nom::Err::Incomplete(_) => {
                     nom::Err::Error(Error::IncompleteFrame(frame_type, ne.to_string()))
                 }
-                nom::Err::Error(ne) => {
+                nom::Err::Error(ne) | nom::Err::Failure(ne) => {
                     // may be TooLarge in MaxStreamsFrame/CryptoFrame/StreamFrame,
-                    // or may be Verify in NewConnectionIdFrame,
+                    // or may be Verify in NewConnectionIdFrame/AckFrame/ResetStreamAtFrame,
                     // or may be Alt in ConnectionCloseFrame
                     nom::Err::Error(Error::ParseError(
                         frame_type,
                         ne.code.description().to_owned(),
                     ))
                 }
-                _ => unreachable!("parsing frame never fails"),
             })?;
         Ok((input.len() - remain.len(), frame))
     }
@@ -373,7 +382,7 @@ pub mod ext {
         max_stream_data::ext::WriteMaxStreamDataFrame, max_streams::ext::WriteMaxStreamsFrame,
         new_connection_id::ext::WriteNewConnectionIdFrame,
         path_challenge::ext::WritePathChallengeFrame, path_response::ext::WritePathResponseFrame,
-        reset_stream::ext::WriteResetStreamFrame,
+        reset_stream::ext::WriteResetStreamFrame, reset_stream_at::ext::WriteResetStreamAtFrame,
         retire_connection_id::ext::WriteRetireConnectionIdFrame,
         stop_sending::ext::WriteStopSendingFrame,
         stream_data_blocked::ext::WriteStreamDataBlockedFrame,
@@ -419,6 +428,7 @@ pub mod ext {
                     self.put_stream_data_blocked_frame(frame)
                 }
                 StreamCtlFrame::StreamsBlocked(frame) => self.put_streams_blocked_frame(frame),
+                StreamCtlFrame::ResetStreamAt(frame) => self.put_reset_stream_at_frame(frame),
             }
         }
     }
@@ -466,8 +476,155 @@ pub mod ext {
 
 #[cfg(test)]
 mod tests {
+    use super::{ext::*, *};
+    use crate::{
+        cid::{ConnectionId, ResetToken},
+        error::ErrorKind,
+        streamid::StreamId,
+    };
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_malformed_frames() {
+        // 每种帧类型之后跟随机的内容，只会解析出错，不会panic
+        for _ in 0..64 {
+            for ty in 0..0x20u8 {
+                let len = rand::random::<usize>() % 64;
+                let mut payload = vec![ty];
+                payload.extend((0..len).map(|_| rand::random::<u8>()));
+                FrameReader::new(Bytes::from(payload)).for_each(drop);
+            }
+        }
+        // CRYPTO帧的偏移加长度超出了2^62-1
+        let payload = [
+            0x06, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0,
+        ];
+        let mut reader = FrameReader::new(Bytes::copy_from_slice(&payload));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(Error::ParseError(FrameType::Crypto, _)))
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_pure_frame_encoding_size() {
+        let sid = StreamId::from(VarInt(0x4000));
+        let frames: Vec<PureFrame> = vec![
+            ConnFrame::Close(ConnectionCloseFrame::new_quic(
+                ErrorKind::FlowControl,
+                FrameType::Stream(0b110),
+                "flow control",
+            ))
+            .into(),
+            ConnFrame::Close(ConnectionCloseFrame::new_app(
+                VarInt(0x1234),
+                "x".repeat(100),
+            ))
+            .into(),
+            ConnFrame::NewToken(NewTokenFrame {
+                token: vec![0; 100],
+            })
+            .into(),
+            ConnFrame::MaxData(MaxDataFrame {
+                max_data: VarInt(1 << 40),
+            })
+            .into(),
+            ConnFrame::DataBlocked(DataBlockedFrame { limit: VarInt(63) }).into(),
+            ConnFrame::NewConnectionId(NewConnectionIdFrame {
+                sequence: VarInt(64),
+                retire_prior_to: VarInt(3),
+                id: ConnectionId::from_slice(&[1; 8]),
+                reset_token: ResetToken::new_with(&[2; 16]),
+            })
+            .into(),
+            ConnFrame::RetireConnectionId(RetireConnectionIdFrame {
+                sequence: VarInt(16384),
+            })
+            .into(),
+            ConnFrame::HandshakeDone(HandshakeDoneFrame).into(),
+            StreamCtlFrame::ResetStream(ResetStreamFrame {
+                stream_id: sid,
+                app_error_code: VarInt(1),
+                final_size: VarInt(1 << 30),
+            })
+            .into(),
+            StreamCtlFrame::ResetStreamAt(ResetStreamAtFrame {
+                stream_id: sid,
+                app_error_code: VarInt(1),
+                final_size: VarInt(1 << 30),
+                reliable_size: VarInt(64),
+            })
+            .into(),
+            StreamCtlFrame::StopSending(StopSendingFrame {
+                stream_id: sid,
+                app_err_code: VarInt(300),
+            })
+            .into(),
+            StreamCtlFrame::MaxStreamData(MaxStreamDataFrame {
+                stream_id: sid,
+                max_stream_data: VarInt(1 << 20),
+            })
+            .into(),
+            StreamCtlFrame::MaxStreams(MaxStreamsFrame::Uni(VarInt(100))).into(),
+            StreamCtlFrame::StreamDataBlocked(StreamDataBlockedFrame {
+                stream_id: sid,
+                maximum_stream_data: VarInt(0),
+            })
+            .into(),
+            StreamCtlFrame::StreamsBlocked(StreamsBlockedFrame::Bi(StreamId::from(VarInt(
+                1 << 16,
+            ))))
+            .into(),
+            PathFrame::Challenge(PathChallengeFrame { data: [3; 8] }).into(),
+            PathFrame::Response(PathResponseFrame { data: [4; 8] }).into(),
+        ];
+        for frame in frames {
+            let mut buf = Vec::new();
+            buf.put_frame(&frame);
+            assert_eq!(buf.len(), frame.encoding_size(), "{frame:?}");
+            assert!(
+                frame.max_encoding_size() >= frame.encoding_size(),
+                "{frame:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_ack_and_data_frame_encoding_size() {
+        let ack = AckFrame {
+            largest: VarInt(100000),
+            delay: VarInt(70),
+            first_range: VarInt(2),
+            ranges: vec![(VarInt(1), VarInt(64)); 70],
+            ecn: Some(ack::EcnCounts {
+                ect0: VarInt(1),
+                ect1: VarInt(0),
+                ce: VarInt(20000),
+            }),
+        };
+        let mut buf = Vec::new();
+        buf.put_ack_frame(&ack);
+        assert_eq!(buf.len(), ack.encoding_size());
+        assert!(ack.max_encoding_size() >= ack.encoding_size());
+
+        let data = [0u8; 200];
+        let crypto = CryptoFrame {
+            offset: VarInt(1 << 14),
+            length: VarInt(200),
+        };
+        let mut buf = Vec::new();
+        buf.put_crypto_frame(&crypto, &data);
+        assert_eq!(buf.len(), crypto.encoding_size());
+
+        let mut stream = StreamFrame::new(StreamId::from(VarInt(4)), 1 << 20, 200);
+        stream.carry_length();
+        let mut buf = Vec::new();
+        buf.put_stream_frame(&stream, &data);
+        assert_eq!(buf.len(), stream.encoding_size());
+    }
 }
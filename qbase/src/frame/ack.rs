@@ -38,12 +38,13 @@ impl super::BeFrame for AckFrame {
     }
 
     fn max_encoding_size(&self) -> usize {
-        1 + 8 + 8 + 8 + self.ranges.len() * 16 + if self.ecn.is_some() { 24 } else { 0 }
+        1 + 8 + 8 + 8 + 8 + self.ranges.len() * 16 + if self.ecn.is_some() { 24 } else { 0 }
     }
 
     fn encoding_size(&self) -> usize {
         1 + self.largest.encoding_size()
             + self.delay.encoding_size()
+            + VarInt(self.ranges.len() as u64).encoding_size()
             + self.first_range.encoding_size()
             + self
                 .ranges
@@ -140,10 +141,22 @@ pub(super) mod ext {
         move |input: &[u8]| {
             let (mut input, (largest, delay, count, first_range)) =
                 tuple((be_varint, be_varint, be_varint, be_varint))(input)?;
+            // 计算出的包号不能为负，否则是FRAME_ENCODING_ERROR，见RFC 9000 Section 19.3.1
+            let verify_error = move || {
+                nom::Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Verify))
+            };
+            let mut smallest = largest
+                .into_inner()
+                .checked_sub(first_range.into_inner())
+                .ok_or_else(verify_error)?;
             let mut ranges = Vec::new();
-            let mut count = count.into_inner() as usize;
+            let mut count = count.into_inner();
             while count > 0 {
                 let (i, (gap, ack)) = tuple((be_varint, be_varint))(input)?;
+                smallest = smallest
+                    .checked_sub(gap.into_inner() + 2)
+                    .and_then(|largest| largest.checked_sub(ack.into_inner()))
+                    .ok_or_else(verify_error)?;
                 ranges.push((gap, ack));
                 count -= 1;
                 input = i;
@@ -241,7 +254,7 @@ mod tests {
 
     #[test]
     fn test_read_ack_frame() {
-        let input = vec![0x02, 0x52, 0x34, 0x52, 0x34, 0x01, 0x52, 0x34, 3, 20];
+        let input = vec![0x02, 0x52, 0x34, 0x52, 0x34, 0x01, 0x10, 3, 20];
         let (input, ack_frame) = flat_map(be_varint, |frame_type| {
             if frame_type.into_inner() as u8 == ACK_FRAME_TYPE {
                 ack_frame_with_flag(frame_type.into_inner() as u8)
@@ -256,13 +269,22 @@ mod tests {
             AckFrame {
                 largest: VarInt(0x1234),
                 delay: VarInt(0x1234),
-                first_range: VarInt(0x1234),
+                first_range: VarInt(0x10),
                 ranges: vec![(VarInt(3), VarInt(20))],
                 ecn: None,
             }
         );
     }
 
+    #[test]
+    fn test_read_negative_ack_range() {
+        // 第一个范围超过了最大包号
+        assert!(ack_frame_with_flag(0)(&[5, 0, 0, 6]).is_err());
+        // 5..=5之后，间隔0，意味着最大为3，范围4则小于0
+        assert!(ack_frame_with_flag(0)(&[5, 0, 1, 0, 0, 4]).is_err());
+        assert!(ack_frame_with_flag(0)(&[5, 0, 1, 0, 0, 3]).is_ok());
+    }
+
     #[test]
     fn test_write_ecn_count() {
         let mut buf = Vec::new();
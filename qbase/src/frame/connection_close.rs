@@ -7,34 +7,45 @@
 // }
 
 use super::FrameType;
-use crate::{error::ErrorKind, varint::VarInt, SpaceId};
+use crate::{
+    error::{AppError, ConnectionError, Error, ErrorKind},
+    varint::VarInt,
+    SpaceId,
+};
 use std::borrow::Cow;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ConnectionCloseFrame {
-    pub error_kind: ErrorKind,
-    pub frame_type: Option<FrameType>,
-    pub reason: Cow<'static, str>, //String,
+pub enum ConnectionCloseFrame {
+    /// Type 0x1c, signals errors at the QUIC layer, or the absence of errors.
+    Quic {
+        error_kind: ErrorKind,
+        frame_type: FrameType,
+        reason: Cow<'static, str>,
+    },
+    /// Type 0x1d, signals an error with the application that uses QUIC.
+    App {
+        error_code: VarInt,
+        reason: Cow<'static, str>,
+    },
 }
 
 const CONNECTION_CLOSE_FRAME_TYPE: u8 = 0x1c;
 
-const QUIC_LAYER: u8 = 1;
-const APP_LAYER: u8 = 0;
+const QUIC_LAYER: u8 = 0;
+const APP_LAYER: u8 = 1;
 
 impl super::BeFrame for ConnectionCloseFrame {
     fn frame_type(&self) -> FrameType {
-        FrameType::ConnectionClose(if self.frame_type.is_some() {
-            QUIC_LAYER
-        } else {
-            APP_LAYER
+        FrameType::ConnectionClose(match self {
+            Self::Quic { .. } => QUIC_LAYER,
+            Self::App { .. } => APP_LAYER,
         })
     }
 
     fn belongs_to(&self, space_id: SpaceId) -> bool {
         // ih01: Only a CONNECTION_CLOSE frame of type 0x1c can appear in Initial or Handshake packets.
         if (space_id == SpaceId::Initial || space_id == SpaceId::Handshake)
-            && self.frame_type.is_none()
+            && matches!(self, Self::App { .. })
         {
             return false;
         }
@@ -43,32 +54,93 @@ impl super::BeFrame for ConnectionCloseFrame {
 
     fn max_encoding_size(&self) -> usize {
         // reason's length could not exceed 16KB
-        1 + 8 + if self.frame_type.is_some() { 8 } else { 0 } + 2 + self.reason.len()
+        1 + 8 + if self.is_app() { 0 } else { 8 } + 2 + self.reason().len()
     }
 
     fn encoding_size(&self) -> usize {
-        1 + VarInt::from(self.error_kind).encoding_size()
-            + if let Some(frame_type) = self.frame_type {
-                VarInt::from(frame_type).encoding_size()
-            } else {
-                0
+        let codes_size = match self {
+            Self::Quic {
+                error_kind,
+                frame_type,
+                ..
+            } => {
+                VarInt::from(*error_kind).encoding_size()
+                    + VarInt::from(*frame_type).encoding_size()
             }
-            // reason's length could not exceed 16KB
-            + VarInt(self.reason.len() as u64).encoding_size()
-            + self.reason.len()
+            Self::App { error_code, .. } => error_code.encoding_size(),
+        };
+        // reason's length could not exceed 16KB
+        1 + codes_size + VarInt(self.reason().len() as u64).encoding_size() + self.reason().len()
     }
 }
 
 impl ConnectionCloseFrame {
-    pub fn new(
+    pub fn new_quic(
         error_kind: ErrorKind,
-        frame_type: Option<FrameType>,
-        reason: Cow<'static, str>,
+        frame_type: FrameType,
+        reason: impl Into<Cow<'static, str>>,
     ) -> Self {
-        Self {
+        Self::Quic {
             error_kind,
             frame_type,
-            reason,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn new_app(error_code: VarInt, reason: impl Into<Cow<'static, str>>) -> Self {
+        Self::App {
+            error_code,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn is_app(&self) -> bool {
+        matches!(self, Self::App { .. })
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            Self::Quic { reason, .. } | Self::App { reason, .. } => reason,
+        }
+    }
+
+    /// Initial和Handshake包中只能携带0x1c类型的CONNECTION_CLOSE帧，应用层的关闭要转换成
+    /// APPLICATION_ERROR，并且清空原因以免泄露应用的状态，
+    /// see [Section 10.2.3](https://www.rfc-editor.org/rfc/rfc9000.html#section-10.2.3) of QUIC.
+    pub fn for_space(self, space_id: SpaceId) -> Self {
+        match self {
+            Self::App { .. } if space_id == SpaceId::Initial || space_id == SpaceId::Handshake => {
+                Self::new_quic(ErrorKind::Application, FrameType::Padding, "")
+            }
+            frame => frame,
+        }
+    }
+}
+
+impl From<Error> for ConnectionCloseFrame {
+    fn from(e: Error) -> Self {
+        Self::Quic {
+            error_kind: e.kind,
+            frame_type: e.frame_type,
+            reason: e.reason,
+        }
+    }
+}
+
+impl From<AppError> for ConnectionCloseFrame {
+    fn from(e: AppError) -> Self {
+        Self::App {
+            error_code: e.error_code,
+            reason: e.reason,
+        }
+    }
+}
+
+impl From<ConnectionError> for ConnectionCloseFrame {
+    fn from(e: ConnectionError) -> Self {
+        match e {
+            ConnectionError::Transport(_, e) => e.into(),
+            ConnectionError::Application(_, e) => e.into(),
         }
     }
 }
@@ -86,33 +158,34 @@ pub(super) mod ext {
         use std::borrow::Cow;
         move |input: &[u8]| {
             let (remain, error_code) = be_varint(input)?;
-            let kind = ErrorKind::try_from(error_code).map_err(|_e| {
-                nom::Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Alt))
-            })?;
-            let (remain, frame_type) = if layer == QUIC_LAYER {
+            let (remain, kind_and_frame_type) = if layer == QUIC_LAYER {
+                let kind = ErrorKind::try_from(error_code).map_err(|_e| {
+                    nom::Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Alt))
+                })?;
                 let (remain, frame_type) = be_varint(remain)?;
-                (
-                    remain,
-                    Some(FrameType::try_from(frame_type).map_err(|_e| {
-                        nom::Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Alt))
-                    })?),
-                )
+                let frame_type = FrameType::try_from(frame_type).map_err(|_e| {
+                    nom::Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Alt))
+                })?;
+                (remain, Some((kind, frame_type)))
             } else {
+                // 应用层的错误码由应用协议定义，任意值都是合法的
                 (remain, None)
             };
             let (remain, rease_length) = be_varint(remain)?;
             let (remain, reason) = take(rease_length.into_inner() as usize)(remain)?;
-            let cow = String::from_utf8_lossy(reason).into_owned();
-            Ok((
-                remain,
-                ConnectionCloseFrame {
-                    error_kind: kind,
+            let reason = Cow::Owned(String::from_utf8_lossy(reason).into_owned());
+            let frame = match kind_and_frame_type {
+                Some((error_kind, frame_type)) => ConnectionCloseFrame::Quic {
+                    error_kind,
                     frame_type,
-                    reason: Cow::Owned(cow),
+                    reason,
                 },
-            ))
+                None => ConnectionCloseFrame::App { error_code, reason },
+            };
+            Ok((remain, frame))
         }
     }
+
     pub trait WriteConnectionCloseFrame {
         fn put_connection_close_frame(&mut self, frame: &ConnectionCloseFrame);
     }
@@ -120,25 +193,36 @@ pub(super) mod ext {
     impl<T: bytes::BufMut> WriteConnectionCloseFrame for T {
         fn put_connection_close_frame(&mut self, frame: &ConnectionCloseFrame) {
             use crate::varint::{ext::BufMutExt as VarIntBufMutExt, VarInt};
-            let layer = if frame.frame_type.is_some() {
-                QUIC_LAYER
-            } else {
-                APP_LAYER
-            };
-            self.put_u8(CONNECTION_CLOSE_FRAME_TYPE | layer);
-            self.put_varint(&frame.error_kind.into());
-            if let Some(frame_type) = frame.frame_type {
-                self.put_varint(&frame_type.into());
+            match frame {
+                ConnectionCloseFrame::Quic {
+                    error_kind,
+                    frame_type,
+                    ..
+                } => {
+                    self.put_u8(CONNECTION_CLOSE_FRAME_TYPE | QUIC_LAYER);
+                    self.put_varint(&(*error_kind).into());
+                    self.put_varint(&(*frame_type).into());
+                }
+                ConnectionCloseFrame::App { error_code, .. } => {
+                    self.put_u8(CONNECTION_CLOSE_FRAME_TYPE | APP_LAYER);
+                    self.put_varint(error_code);
+                }
             }
-            self.put_varint(&VarInt::from_u32(frame.reason.len() as u32));
-            self.put_slice(frame.reason.as_bytes());
+            self.put_varint(&VarInt::from_u32(frame.reason().len() as u32));
+            self.put_slice(frame.reason().as_bytes());
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::error::ErrorKind;
+    use super::{ConnectionCloseFrame, APP_LAYER, CONNECTION_CLOSE_FRAME_TYPE, QUIC_LAYER};
+    use crate::{
+        error::{AppError, Error, ErrorKind},
+        frame::{BeFrame, FrameType},
+        varint::VarInt,
+        SpaceId,
+    };
 
     #[test]
     fn test_read_connection_close_frame() {
@@ -146,8 +230,9 @@ mod tests {
         use crate::varint::ext::be_varint;
         use nom::combinator::flat_map;
         let buf = vec![
-            super::CONNECTION_CLOSE_FRAME_TYPE,
-            0x0c,
+            CONNECTION_CLOSE_FRAME_TYPE | APP_LAYER,
+            0x52,
+            0x34,
             5,
             b'w',
             b'r',
@@ -156,38 +241,35 @@ mod tests {
             b'g',
         ];
         let (input, frame) = flat_map(be_varint, |frame_type| {
-            if frame_type.into_inner() == super::CONNECTION_CLOSE_FRAME_TYPE as u64 {
-                connection_close_frame_at_layer(0)
+            if frame_type.into_inner() == (CONNECTION_CLOSE_FRAME_TYPE | APP_LAYER) as u64 {
+                connection_close_frame_at_layer(APP_LAYER)
             } else {
                 panic!("wrong frame type: {}", frame_type)
             }
         })(buf.as_ref())
         .unwrap();
         assert_eq!(input, &[][..]);
+        // 应用层的错误码不受QUIC错误码的限制
         assert_eq!(
             frame,
-            super::ConnectionCloseFrame {
-                error_kind: ErrorKind::Application,
-                frame_type: None,
-                reason: "wrong".into(),
-            }
+            ConnectionCloseFrame::new_app(VarInt(0x1234), "wrong")
         );
     }
 
     #[test]
     fn test_write_connection_close_frame() {
-        use super::{ext::WriteConnectionCloseFrame, FrameType};
+        use super::ext::WriteConnectionCloseFrame;
         let mut buf = Vec::<u8>::new();
-        let frame = super::ConnectionCloseFrame {
-            error_kind: ErrorKind::FlowControl,
-            frame_type: Some(FrameType::Stream(0b110)),
-            reason: "wrong".into(),
-        };
+        let frame = ConnectionCloseFrame::new_quic(
+            ErrorKind::FlowControl,
+            FrameType::Stream(0b110),
+            "wrong",
+        );
         buf.put_connection_close_frame(&frame);
         assert_eq!(
             buf,
             vec![
-                super::CONNECTION_CLOSE_FRAME_TYPE | super::QUIC_LAYER,
+                CONNECTION_CLOSE_FRAME_TYPE | QUIC_LAYER,
                 0x03,
                 0xe,
                 5,
@@ -198,5 +280,30 @@ mod tests {
                 b'g',
             ]
         );
+        assert_eq!(buf.len(), frame.encoding_size());
+    }
+
+    #[test]
+    fn test_close_frame_from_errors() {
+        let frame = ConnectionCloseFrame::from(Error::new(
+            ErrorKind::StreamState,
+            FrameType::ResetStream,
+            "reset a receive-only stream",
+        ));
+        assert_eq!(frame.frame_type(), FrameType::ConnectionClose(QUIC_LAYER));
+        assert!(frame.belongs_to(SpaceId::Initial));
+        assert_eq!(frame.clone().for_space(SpaceId::Handshake), frame);
+
+        let frame = ConnectionCloseFrame::from(AppError::new(VarInt(0x101), "no such resource"));
+        assert_eq!(frame.frame_type(), FrameType::ConnectionClose(APP_LAYER));
+        assert!(!frame.belongs_to(SpaceId::Handshake));
+        assert_eq!(frame.clone().for_space(SpaceId::OneRtt), frame);
+        // 握手期间应用层的关闭转换为APPLICATION_ERROR，原因被清空
+        let frame = frame.for_space(SpaceId::Initial);
+        assert_eq!(
+            frame,
+            ConnectionCloseFrame::new_quic(ErrorKind::Application, FrameType::Padding, "")
+        );
+        assert!(frame.belongs_to(SpaceId::Initial));
     }
 }
@@ -77,7 +77,7 @@ pub(super) mod ext {
         use crate::varint::{ext::be_varint, VARINT_MAX};
         let (remain, offset) = be_varint(input)?;
         let (remain, length) = be_varint(remain)?;
-        if offset.into_inner() + offset.into_inner() > VARINT_MAX {
+        if offset.into_inner() + length.into_inner() > VARINT_MAX {
             return Err(nom::Err::Error(nom::error::make_error(
                 input,
                 nom::error::ErrorKind::TooLarge,
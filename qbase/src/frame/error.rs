@@ -15,14 +15,16 @@ pub enum Error {
     IncompleteFrame(FrameType, String),
     #[error("Error occurred when parsing frame {0:?}: {1}")]
     ParseError(FrameType, String),
+    #[error("Malformed frames: {0}")]
+    Malformed(String),
 }
 
 use crate::error::Error as TransportError;
 use crate::error::ErrorKind as TransportErrorKind;
 
 impl From<std::convert::Infallible> for Error {
-    fn from(_: std::convert::Infallible) -> Self {
-        unreachable!("never happen.")
+    fn from(infallible: std::convert::Infallible) -> Self {
+        match infallible {}
     }
 }
 
@@ -51,6 +53,11 @@ impl From<Error> for TransportError {
             Error::ParseError(fty, _) => {
                 Self::new(TransportErrorKind::FrameEncoding, fty, e.to_string())
             }
+            Error::Malformed(_) => Self::new(
+                TransportErrorKind::FrameEncoding,
+                FrameType::Padding,
+                e.to_string(),
+            ),
         }
     }
 }
@@ -58,18 +65,16 @@ impl From<Error> for TransportError {
 impl From<nom::Err<Error>> for Error {
     fn from(value: nom::Err<Error>) -> Self {
         match value {
-            nom::Err::Incomplete(_needed) => {
-                unreachable!("Because the parsing of QUIC packets and frames is not stream-based.")
-            }
+            // QUIC的包和帧都是完整到达的，不完整即是格式错误
+            ne @ nom::Err::Incomplete(_) => Self::Malformed(ne.to_string()),
             nom::Err::Error(err) | nom::Err::Failure(err) => err,
         }
     }
 }
 
 impl nom::error::ParseError<&[u8]> for Error {
-    fn from_error_kind(_input: &[u8], _kind: NomErrorKind) -> Self {
-        debug_assert_eq!(_kind, NomErrorKind::ManyTill);
-        unreachable!("QUIC frame parser must always consume")
+    fn from_error_kind(_input: &[u8], kind: NomErrorKind) -> Self {
+        Self::Malformed(kind.description().to_owned())
     }
 
     fn append(_input: &[u8], _kind: NomErrorKind, source: Self) -> Self {
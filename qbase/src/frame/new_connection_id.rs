@@ -8,7 +8,7 @@
 // }
 
 use crate::{
-    cid::{ConnectionId, ResetToken},
+    cid::{ConnectionId, ResetToken, MAX_CID_SIZE, RESET_TOKEN_SIZE},
     varint::VarInt,
     SpaceId,
 };
@@ -34,11 +34,15 @@ impl super::BeFrame for NewConnectionIdFrame {
     }
 
     fn encoding_size(&self) -> usize {
-        todo!()
+        1 + self.sequence.encoding_size()
+            + self.retire_prior_to.encoding_size()
+            + 1
+            + self.id.len()
+            + RESET_TOKEN_SIZE
     }
 
     fn max_encoding_size(&self) -> usize {
-        todo!()
+        1 + 8 + 8 + 1 + MAX_CID_SIZE + RESET_TOKEN_SIZE
     }
 }
 
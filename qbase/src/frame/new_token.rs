@@ -4,7 +4,7 @@
 //   Token (..),
 // }
 
-use crate::SpaceId;
+use crate::{varint::VarInt, SpaceId};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NewTokenFrame {
@@ -24,12 +24,11 @@ impl super::BeFrame for NewTokenFrame {
     }
 
     fn max_encoding_size(&self) -> usize {
-        // token's length could not exceed 20
-        1 + 1 + self.token.len()
+        1 + 8 + self.token.len()
     }
 
     fn encoding_size(&self) -> usize {
-        1 + 1 + self.token.len()
+        1 + VarInt(self.token.len() as u64).encoding_size() + self.token.len()
     }
 }
 
@@ -0,0 +1,131 @@
+// RESET_STREAM_AT Frame {
+//   Type (i) = 0x24,
+//   Stream ID (i),
+//   Application Protocol Error Code (i),
+//   Final Size (i),
+//   Reliable Size (i),
+// }
+
+use crate::{streamid::StreamId, varint::VarInt, SpaceId};
+
+/// Reset a stream while the data before the reliable size is still delivered reliably, see
+/// [draft-ietf-quic-reliable-stream-reset](https://datatracker.ietf.org/doc/draft-ietf-quic-reliable-stream-reset/).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResetStreamAtFrame {
+    pub stream_id: StreamId,
+    pub app_error_code: VarInt,
+    pub final_size: VarInt,
+    pub reliable_size: VarInt,
+}
+
+const RESET_STREAM_AT_FRAME_TYPE: u8 = 0x24;
+
+impl super::BeFrame for ResetStreamAtFrame {
+    fn frame_type(&self) -> super::FrameType {
+        super::FrameType::ResetStreamAt
+    }
+
+    fn belongs_to(&self, space_id: SpaceId) -> bool {
+        // __01
+        space_id == SpaceId::ZeroRtt || space_id == SpaceId::OneRtt
+    }
+
+    fn max_encoding_size(&self) -> usize {
+        1 + 8 + 8 + 8 + 8
+    }
+
+    fn encoding_size(&self) -> usize {
+        1 + self.stream_id.encoding_size()
+            + self.app_error_code.encoding_size()
+            + self.final_size.encoding_size()
+            + self.reliable_size.encoding_size()
+    }
+}
+
+pub(super) mod ext {
+    use super::ResetStreamAtFrame;
+
+    // nom parser for RESET_STREAM_AT_FRAME
+    pub fn be_reset_stream_at_frame(input: &[u8]) -> nom::IResult<&[u8], ResetStreamAtFrame> {
+        use crate::{streamid::ext::be_streamid, varint::ext::be_varint};
+        use nom::{
+            combinator::{map, verify},
+            sequence::tuple,
+        };
+        // 可靠大小不能超过最终大小，否则就是FRAME_ENCODING_ERROR
+        map(
+            verify(
+                tuple((be_streamid, be_varint, be_varint, be_varint)),
+                |(_, _, final_size, reliable_size)| reliable_size <= final_size,
+            ),
+            |(stream_id, app_error_code, final_size, reliable_size)| ResetStreamAtFrame {
+                stream_id,
+                app_error_code,
+                final_size,
+                reliable_size,
+            },
+        )(input)
+    }
+
+    // BufMut write extension for RESET_STREAM_AT_FRAME
+    pub trait WriteResetStreamAtFrame {
+        fn put_reset_stream_at_frame(&mut self, frame: &ResetStreamAtFrame);
+    }
+
+    impl<T: bytes::BufMut> WriteResetStreamAtFrame for T {
+        fn put_reset_stream_at_frame(&mut self, frame: &ResetStreamAtFrame) {
+            use crate::{
+                streamid::ext::BufMutExt as SidBufMutExt, varint::ext::BufMutExt as VarIntBufMutExt,
+            };
+            self.put_u8(super::RESET_STREAM_AT_FRAME_TYPE);
+            self.put_streamid(&frame.stream_id);
+            self.put_varint(&frame.app_error_code);
+            self.put_varint(&frame.final_size);
+            self.put_varint(&frame.reliable_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ext::{be_reset_stream_at_frame, WriteResetStreamAtFrame},
+        ResetStreamAtFrame, RESET_STREAM_AT_FRAME_TYPE,
+    };
+    use crate::{frame::BeFrame, varint::VarInt};
+
+    #[test]
+    fn test_reset_stream_at_frame() {
+        let frame = ResetStreamAtFrame {
+            stream_id: VarInt(0x1234).into(),
+            app_error_code: VarInt(0x10c),
+            final_size: VarInt(0x9abc),
+            reliable_size: VarInt(0x20),
+        };
+        let mut buf = Vec::new();
+        buf.put_reset_stream_at_frame(&frame);
+        assert_eq!(
+            buf,
+            vec![
+                RESET_STREAM_AT_FRAME_TYPE,
+                0x52,
+                0x34,
+                0x41,
+                0x0c,
+                0x80,
+                0,
+                0x9a,
+                0xbc,
+                0x20
+            ]
+        );
+        assert_eq!(buf.len(), frame.encoding_size());
+        assert_eq!(be_reset_stream_at_frame(&buf[1..]), Ok((&[][..], frame)));
+    }
+
+    #[test]
+    fn test_reliable_size_exceeds_final_size() {
+        let buf = [0x04, 0x00, 0x10, 0x11];
+        assert!(be_reset_stream_at_frame(&buf).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 pub mod cid;
 pub mod config;
+pub mod crypto;
 pub mod error;
 pub mod frame;
 pub mod packet;
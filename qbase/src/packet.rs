@@ -7,6 +7,7 @@ pub mod signal;
 pub use signal::{KeyPhaseBit, SpinBit};
 
 pub mod r#type;
+pub use r#type::long::{QUIC_V1, QUIC_V2, SUPPORTED_VERSIONS};
 use r#type::{GetPacketNumberLength, LongClearBits, ShortClearBits};
 
 pub mod header;
@@ -20,9 +21,15 @@ pub use number::{take_pn_len, PacketNumber, WritePacketNumber};
 
 use self::header::GetDcid;
 
+pub mod builder;
+pub use builder::{PacketBuilder, PayloadBuf};
+
 pub mod decrypt;
 pub mod encrypt;
 pub mod keys;
+pub mod retry;
+pub use retry::RetryPacket;
+pub mod version;
 
 #[derive(Debug, Clone, Deref, DerefMut)]
 pub struct PacketWrapper<H> {
@@ -56,10 +63,16 @@ impl GetDcid for SpacePacket {
     }
 }
 
+impl<H: header::GetScid> header::GetScid for PacketWrapper<H> {
+    fn get_scid(&self) -> &crate::cid::ConnectionId {
+        self.header.get_scid()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Packet {
     VN(VersionNegotiationHeader),
-    Retry(RetryHeader),
+    Retry(RetryPacket),
     Space(SpacePacket),
 }
 
@@ -70,12 +83,25 @@ pub enum Packet {
 pub struct PacketReader {
     raw: BytesMut,
     dcid_len: usize,
+    // 本端通告了grease_quic_bit传输参数后，必须接受固定位为0的包
+    grease_quic_bit: bool,
     // TODO: 添加level，各种包类型顺序不能错乱，否则失败
 }
 
 impl PacketReader {
     pub fn new(raw: BytesMut, dcid_len: usize) -> Self {
-        Self { raw, dcid_len }
+        Self {
+            raw,
+            dcid_len,
+            grease_quic_bit: false,
+        }
+    }
+
+    /// Accept packets whose QUIC bit is 0, which is required once the grease_quic_bit transport
+    /// parameter is advertised, see [RFC 9287](https://www.rfc-editor.org/rfc/rfc9287.html).
+    pub fn grease_quic_bit(mut self, advertised: bool) -> Self {
+        self.grease_quic_bit = advertised;
+        self
     }
 }
 
@@ -87,9 +113,10 @@ impl Iterator for PacketReader {
             return None;
         }
 
-        match ext::be_packet(&self.raw, self.dcid_len) {
+        match ext::be_packet(&self.raw, self.dcid_len, self.grease_quic_bit) {
             Ok((consumed, packet)) => {
-                self.raw.truncate(consumed);
+                // 一个数据报中可能有多个包，跳过已解析的包
+                let _ = self.raw.split_to(consumed);
                 Some(Ok(packet))
             }
             Err(e) => {
@@ -116,7 +143,6 @@ pub mod ext {
         input: &[u8],
     ) -> Result<(&[u8], usize, BytesMut), Error> {
         let pn_offset = raw_data.len() - input.len();
-        let packet_length = pn_offset + length;
         if length < 20 {
             // The payload needs at least 20 bytes to have enough samples to remove the packet header protection.
             return Err(Error::UnderSampling(length));
@@ -125,24 +151,45 @@ pub mod ext {
             // Insufficient payload data
             return Err(Error::IncompletePacket(packet_type, length, input.len()));
         }
-        raw_data.truncate(packet_length);
+        raw_data.truncate(pn_offset + length);
         Ok((&input[length..], pn_offset, raw_data))
     }
 
-    pub fn be_packet(datagram: &BytesMut, dcid_len: usize) -> Result<(usize, Packet), Error> {
+    /// `grease_quic_bit` is whether the packets with a zero QUIC bit are accepted.
+    pub fn be_packet(
+        datagram: &BytesMut,
+        dcid_len: usize,
+        grease_quic_bit: bool,
+    ) -> Result<(usize, Packet), Error> {
         let input = datagram.as_ref();
         let (remain, pkty) = be_packet_type(input).map_err(|e| match e {
             ne @ nom::Err::Incomplete(_) => Error::IncompleteType(ne.to_string()),
-            nom::Err::Error(e) => e,
-            _ => unreachable!("parsing packet type never generates failure"),
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
         })?;
+        // The next bit (0x40) of byte 0 is set to 1, unless the packet is a Version Negotiation
+        // packet. Packets containing a zero value for this bit are not valid packets in this
+        // version and MUST be discarded. A value of 1 for this bit allows QUIC to coexist with
+        // other protocols; see [RFC7983].
+        let is_vn = matches!(pkty, Type::Long(r#type::long::Type::VersionNegotiation));
+        if !is_vn && !grease_quic_bit && input[0] & r#type::FIXED_BIT == 0 {
+            return Err(Error::InvalidFixedBit);
+        }
         let (remain, header) = be_header(pkty, dcid_len, remain).map_err(|e| match e {
             ne @ nom::Err::Incomplete(_) => Error::IncompleteHeader(pkty, ne.to_string()),
-            _ => unreachable!("parsing packet header never generates error or failure"),
+            // 比如连接ID过长
+            nom::Err::Error(e) | nom::Err::Failure(e) => {
+                Error::InvalidHeader(pkty, e.code.description().to_owned())
+            }
         })?;
         match header {
             Header::VN(header) => Ok((datagram.len() - remain.len(), Packet::VN(header))),
-            Header::Retry(header) => Ok((datagram.len() - remain.len(), Packet::Retry(header))),
+            Header::Retry(header) => {
+                // The integrity tag is computed over the raw Retry packet, so keep it.
+                let consumed = datagram.len() - remain.len();
+                let mut raw_data = datagram.clone();
+                raw_data.truncate(consumed);
+                Ok((consumed, Packet::Retry(RetryPacket { header, raw_data })))
+            }
             Header::Initial(header) => {
                 let (remain, pn_offset, raw_data) =
                     complete(pkty, header.get_length(), datagram.clone(), remain)?;
@@ -197,9 +244,71 @@ pub mod ext {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    // 空连接ID、20字节载荷的Handshake包
+    fn handshake_packet(first_byte: u8) -> Vec<u8> {
+        let mut packet = vec![first_byte, 0, 0, 0, 1, 0, 0, 20];
+        packet.resize(packet.len() + 20, 0);
+        packet
+    }
+
+    #[test]
+    fn test_read_coalesced_packets() {
+        let datagram = [handshake_packet(0xe0), handshake_packet(0xe0)].concat();
+        let reader = PacketReader::new(BytesMut::from(&datagram[..]), 0);
+        let packets = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(packets.len(), 2);
+        assert!(packets
+            .iter()
+            .all(|p| matches!(p, Packet::Space(SpacePacket::Handshake(_)))));
+    }
+
+    #[test]
+    fn test_grease_quic_bit() {
+        let datagram = BytesMut::from(&handshake_packet(0xa0)[..]);
+        let mut reader = PacketReader::new(datagram.clone(), 0);
+        assert_eq!(
+            reader.next().unwrap().unwrap_err(),
+            error::Error::InvalidFixedBit
+        );
+        let mut reader = PacketReader::new(datagram, 0).grease_quic_bit(true);
+        assert!(matches!(
+            reader.next(),
+            Some(Ok(Packet::Space(SpacePacket::Handshake(_))))
+        ));
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        // 连接ID过长
+        let mut datagram = vec![0xe0, 0, 0, 0, 1, 21];
+        datagram.resize(64, 0);
+        let mut reader = PacketReader::new(BytesMut::from(&datagram[..]), 0);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(error::Error::InvalidHeader(..)))
+        ));
+        assert!(reader.next().is_none());
+        // Length字段远远超出了数据报
+        let mut datagram = vec![0xe0, 0, 0, 0, 1, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        datagram.extend_from_slice(&[0xff, 0xff]);
+        datagram.resize(64, 0);
+        let mut reader = PacketReader::new(BytesMut::from(&datagram[..]), 0);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(error::Error::IncompletePacket(..)))
+        ));
+        // 随机的数据报，只会解析出错，不会panic
+        for _ in 0..4096 {
+            let len = rand::random::<usize>() % 128;
+            let datagram = (0..len).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+            PacketReader::new(BytesMut::from(&datagram[..]), 8).for_each(drop);
+        }
+    }
 }
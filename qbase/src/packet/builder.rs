@@ -0,0 +1,117 @@
+//! Assemble the payload of a packet within the MTU.
+//! 包头、包号和AEAD tag的空间先预留出来，剩下的才是负载的容量；帧按照精确的编码长度放入，
+//! 放不下的帧留给下一个包，而不会让包超出MTU。
+use crate::frame::{
+    ext::{WriteFrame, WritePaddingFrame},
+    BeFrame,
+};
+use bytes::{buf::Limit, BufMut, BytesMut};
+
+/// The buffer of the payload, whose `remaining_mut` is exactly the room left in the packet.
+pub type PayloadBuf = Limit<BytesMut>;
+
+/// Header protection samples 16 bytes starting 4 bytes after the packet number offset, so the
+/// packet number and the payload together must be at least 4 bytes long, assuming the AEAD tag
+/// is 16 bytes. See [Section 5.4.2 of RFC 9001](https://www.rfc-editor.org/rfc/rfc9001.html#section-5.4.2).
+const MIN_PN_AND_PAYLOAD_LEN: usize = 4;
+
+#[derive(Debug)]
+pub struct PacketBuilder {
+    pn_len: usize,
+    payload: PayloadBuf,
+}
+
+impl PacketBuilder {
+    /// `header_len` is the length of the header without the packet number. Returns None if the
+    /// MTU cannot even accommodate the header, the packet number and the AEAD tag.
+    pub fn new(mtu: usize, header_len: usize, pn_len: usize, tag_len: usize) -> Option<Self> {
+        let capacity = mtu.checked_sub(header_len + pn_len + tag_len)?;
+        Some(Self {
+            pn_len,
+            payload: BytesMut::with_capacity(capacity).limit(capacity),
+        })
+    }
+
+    /// The number of bytes that can still be put into the payload.
+    pub fn remaining(&self) -> usize {
+        self.payload.remaining_mut()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.get_ref().is_empty()
+    }
+
+    /// The payload buffer, for the senders that put frames with their own size estimation,
+    /// such as the CRYPTO and STREAM frames whose data are cut to fit the room left.
+    pub fn payload_mut(&mut self) -> &mut PayloadBuf {
+        &mut self.payload
+    }
+
+    /// Put a frame if there is enough room for it, otherwise leave it to the next packet and
+    /// return false.
+    pub fn put_frame<F>(&mut self, frame: &F) -> bool
+    where
+        F: BeFrame,
+        PayloadBuf: WriteFrame<F>,
+    {
+        if frame.encoding_size() > self.remaining() {
+            return false;
+        }
+        self.payload.put_frame(frame);
+        true
+    }
+
+    /// Finish the payload, padding it if it is too short for header protection to sample.
+    /// Returns None if nothing has been put.
+    pub fn finish(mut self) -> Option<BytesMut> {
+        if self.is_empty() {
+            return None;
+        }
+        while self.pn_len + self.payload.get_ref().len() < MIN_PN_AND_PAYLOAD_LEN {
+            self.payload.put_padding_frame();
+        }
+        Some(self.payload.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frame::{
+            ConnFrame, HandshakeDoneFrame, MaxDataFrame, PathChallengeFrame, PathFrame, PureFrame,
+        },
+        varint::VarInt,
+    };
+
+    #[test]
+    fn test_packet_builder() {
+        assert!(PacketBuilder::new(20, 10, 4, 16).is_none());
+        let builder = PacketBuilder::new(40, 20, 1, 16).unwrap();
+        assert_eq!(builder.remaining(), 3);
+        assert!(builder.finish().is_none());
+
+        let mut builder = PacketBuilder::new(40, 20, 1, 16).unwrap();
+        let done = PureFrame::Conn(ConnFrame::HandshakeDone(HandshakeDoneFrame));
+        assert!(builder.put_frame(&done));
+        // 包号和负载不足4字节，需要填充
+        assert_eq!(builder.finish().unwrap().as_ref(), &[0x1e, 0, 0]);
+
+        let mut builder = PacketBuilder::new(1200, 40, 2, 16).unwrap();
+        let challenge = PureFrame::Path(PathFrame::Challenge(PathChallengeFrame { data: [1; 8] }));
+        let max_data = PureFrame::Conn(ConnFrame::MaxData(MaxDataFrame {
+            max_data: VarInt(1 << 20),
+        }));
+        let mut count = 0;
+        while builder.put_frame(&challenge) {
+            count += 1;
+        }
+        // 1142字节的负载，每个PATH_CHALLENGE帧9字节，能放126个，还剩8个字节
+        assert_eq!(count, 126);
+        assert_eq!(builder.remaining(), 8);
+        // 放不下的帧被跳过，更小的帧仍然可以放入
+        assert!(builder.put_frame(&max_data));
+        assert_eq!(builder.remaining(), 3);
+        assert_eq!(builder.finish().unwrap().len(), 1139);
+    }
+}
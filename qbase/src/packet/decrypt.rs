@@ -4,11 +4,11 @@ use super::{
     take_pn_len, GetPacketNumberLength, KeyPhaseBit, LongClearBits, OneRttHeader, PacketNumber,
     PacketWrapper, ShortClearBits,
 };
+use crate::crypto::{HeaderProtectionKey, PacketKey};
 use bytes::Bytes;
-use rustls::quic::{HeaderProtectionKey, PacketKey};
 
 pub trait RemoteProtection {
-    fn remove_protection(&mut self, header_protection_key: &HeaderProtectionKey) -> bool;
+    fn remove_protection(&mut self, header_protection_key: &dyn HeaderProtectionKey) -> bool;
 }
 
 pub trait DecodeHeader {
@@ -23,15 +23,23 @@ pub trait DecryptPacket {
         self,
         pkt_id: u64,
         pn_size: usize,
-        packet_key: &PacketKey,
+        packet_key: &dyn PacketKey,
     ) -> Result<Bytes, Error>;
 }
 
 impl<H: Protect> RemoteProtection for PacketWrapper<H> {
-    fn remove_protection(&mut self, header_protection_key: &HeaderProtectionKey) -> bool {
+    fn remove_protection(&mut self, header_protection_key: &dyn HeaderProtectionKey) -> bool {
         let (header, payload) = self.raw_data.split_at_mut(self.pn_offset);
-        let first_byte = &mut header[0];
+        // 采样固定从包号后4字节开始，长度由密钥决定，多余的字节不能一并传入
+        let sample_len = header_protection_key.sample_len();
+        let Some(first_byte) = header.first_mut() else {
+            return false;
+        };
+        if payload.len() < 4 + sample_len {
+            return false;
+        }
         let (pn_bytes, sample) = payload.split_at_mut(4);
+        let sample = &sample[..sample_len];
         // Decryption failure is not a fatal error. When facing a key upgrade,
         // you need to try again with the next key. If it still fails, it may be forged
         // and should be discarded. In any case, it won't cause a connection error!
@@ -49,7 +57,7 @@ impl DecodeHeader for PacketWrapper<OneRttHeader> {
         let clear_bits = ShortClearBits::from(self.raw_data[0]);
         let pn_len = clear_bits.pn_len()?;
         let pn_bytes = &self.raw_data[self.pn_offset..self.pn_offset + pn_len as usize];
-        let (_, pn) = take_pn_len(pn_len)(pn_bytes).unwrap();
+        let (_, pn) = take_pn_len(pn_len)(pn_bytes).map_err(|e| Error::Malformed(e.to_string()))?;
         Ok((pn, clear_bits.key_phase_bit()))
     }
 }
@@ -61,7 +69,7 @@ impl<S> DecodeHeader for PacketWrapper<LongHeader<S>> {
         let clear_bits = LongClearBits::from(self.raw_data[0]);
         let pn_len = clear_bits.pn_len()?;
         let pn_bytes = &self.raw_data[self.pn_offset..self.pn_offset + pn_len as usize];
-        let (_, pn) = take_pn_len(pn_len)(pn_bytes).unwrap();
+        let (_, pn) = take_pn_len(pn_len)(pn_bytes).map_err(|e| Error::Malformed(e.to_string()))?;
         Ok(pn)
     }
 }
@@ -71,16 +79,18 @@ impl<H: Protect> DecryptPacket for PacketWrapper<H> {
         self,
         pktid: u64,
         pn_size: usize,
-        remote_keys: &PacketKey,
+        remote_keys: &dyn PacketKey,
     ) -> Result<Bytes, Error> {
         // decrypt packet
         let mut raw_data = self.raw_data;
         let header_offset = self.pn_offset + pn_size;
         let mut body = raw_data.split_off(header_offset);
         let header = raw_data;
-        remote_keys
+        let plaintext_len = remote_keys
             .decrypt_in_place(pktid, &header, &mut body)
             .map_err(|_| Error::DecryptPacketFailure)?;
+        // 去掉末尾的认证标签
+        body.truncate(plaintext_len);
         Ok(body.freeze())
     }
 }
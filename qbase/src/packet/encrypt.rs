@@ -1,16 +1,16 @@
 use super::{
     header::{long::LongHeader, GetType, Protect},
-    r#type::ext::WritePacketType,
+    r#type::{ext::WritePacketType, FIXED_BIT},
     KeyPhaseBit, LongClearBits, OneRttHeader, PacketNumber, PacketWrapper, ShortClearBits,
     WritePacketNumber,
 };
-use rustls::quic::{HeaderProtectionKey, PacketKey};
+use crate::crypto::{HeaderProtectionKey, PacketKey};
 use std::ops::Deref;
 
-/// 有一个Packet了
-/// 1. 先填写完整 complete PacketNumber ClearBits
-/// 2. 有了头部之后，加密body
-/// 3. 添加头部保护
+// 有一个Packet了
+// 1. 先填写完整 complete PacketNumber ClearBits
+// 2. 有了头部之后，加密body
+// 3. 添加头部保护
 
 pub trait EncodeHeader {
     type Params;
@@ -18,11 +18,12 @@ pub trait EncodeHeader {
 }
 
 pub trait EncryptPacket {
-    fn encrypt_packet(&mut self, packet_number: u64, pn_len: usize, packet_key: &PacketKey);
+    /// The last [`PacketKey::tag_len`] bytes of the packet must be reserved for the tag.
+    fn encrypt_packet(&mut self, packet_number: u64, pn_len: usize, packet_key: &dyn PacketKey);
 }
 
 pub trait ProtectHeader {
-    fn protect_header(&mut self, pn_len: usize, header_protection_key: &HeaderProtectionKey);
+    fn protect_header(&mut self, pn_len: usize, header_protection_key: &dyn HeaderProtectionKey);
 }
 
 impl EncodeHeader for PacketWrapper<OneRttHeader> {
@@ -60,7 +61,7 @@ where
 }
 
 impl<H: Protect> EncryptPacket for PacketWrapper<H> {
-    fn encrypt_packet(&mut self, packet_number: u64, pn_len: usize, packet_key: &PacketKey) {
+    fn encrypt_packet(&mut self, packet_number: u64, pn_len: usize, packet_key: &dyn PacketKey) {
         let header_len = self.pn_offset + pn_len;
         let (header, body) = self.raw_data.split_at_mut(header_len);
         packet_key
@@ -70,7 +71,7 @@ impl<H: Protect> EncryptPacket for PacketWrapper<H> {
 }
 
 impl<H: Protect> ProtectHeader for PacketWrapper<H> {
-    fn protect_header(&mut self, pn_len: usize, header_protection_key: &HeaderProtectionKey) {
+    fn protect_header(&mut self, pn_len: usize, header_protection_key: &dyn HeaderProtectionKey) {
         let (header, payload) = self.raw_data.split_at_mut(self.pn_offset);
         let first_byte = &mut header[0];
         let (pn_bytes, sample) = payload.split_at_mut(4);
@@ -81,10 +82,56 @@ impl<H: Protect> ProtectHeader for PacketWrapper<H> {
     }
 }
 
+/// Once the peer has advertised the grease_quic_bit transport parameter, the QUIC bit of the
+/// packets sent can be set to an unpredictable value, see [RFC 9287](https://www.rfc-editor.org/rfc/rfc9287.html).
+/// It must be done after encoding the header and before encrypting the packet, because the
+/// header is authenticated.
+pub trait GreaseQuicBit {
+    fn grease_quic_bit(&mut self);
+}
+
+impl<H> GreaseQuicBit for PacketWrapper<H> {
+    fn grease_quic_bit(&mut self) {
+        if rand::random::<bool>() {
+            self.raw_data[0] &= !FIXED_BIT;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::{
+        cid::ConnectionId,
+        packet::{header::LongHeaderBuilder, HandshakePacket},
+    };
+    use bytes::BytesMut;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_grease_quic_bit() {
+        let header = LongHeaderBuilder::with_cid(ConnectionId::default(), ConnectionId::default())
+            .wrap(Default::default());
+        let mut packet = HandshakePacket {
+            header,
+            raw_data: BytesMut::zeroed(32),
+            pn_offset: 7,
+        };
+        packet.encode_header(PacketNumber::encode(0, None));
+        assert_ne!(packet.raw_data[0] & FIXED_BIT, 0);
+        // 固定位是随机的，其余位不变
+        let first_byte = packet.raw_data[0];
+        let mut cleared = false;
+        for _ in 0..64 {
+            packet.raw_data[0] = first_byte;
+            packet.grease_quic_bit();
+            assert_eq!(packet.raw_data[0] | FIXED_BIT, first_byte);
+            cleared |= packet.raw_data[0] & FIXED_BIT == 0;
+        }
+        assert!(cleared);
+    }
 }
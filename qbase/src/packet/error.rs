@@ -12,6 +12,8 @@ pub enum Error {
     IncompleteType(String),
     #[error("Incomplete packet header {0:?}: {1}")]
     IncompleteHeader(Type, String),
+    #[error("Invalid packet header {0:?}: {1}")]
+    InvalidHeader(Type, String),
     #[error("Incomplete packet {0:?}: Need {1} bytes, but only {2} bytes left in the packet")]
     IncompletePacket(Type, usize, usize),
     #[error("Sampling of packet content less than 20 bytes, only {0} bytes available")]
@@ -22,12 +24,13 @@ pub enum Error {
     InvalidReservedBits(u8),
     #[error("Fail to decrypt packet")]
     DecryptPacketFailure,
+    #[error("Malformed packet: {0}")]
+    Malformed(String),
 }
 
 impl nom::error::ParseError<&[u8]> for Error {
-    fn from_error_kind(_input: &[u8], _kind: NomErrorKind) -> Self {
-        debug_assert_eq!(_kind, NomErrorKind::ManyTill);
-        unreachable!("QUIC frame parser must always consume")
+    fn from_error_kind(_input: &[u8], kind: NomErrorKind) -> Self {
+        Self::Malformed(kind.description().to_owned())
     }
 
     fn append(_input: &[u8], _kind: NomErrorKind, source: Self) -> Self {
@@ -45,7 +48,11 @@ impl From<Error> for crate::error::Error {
                 crate::error::ErrorKind::ProtocolViolation,
                 e.to_string(),
             ),
-            _ => unreachable!(),
+            // 其余错误的包通常直接丢弃即可，若一定要关闭连接，也视为违反协议
+            _ => crate::error::Error::new_with_default_fty(
+                crate::error::ErrorKind::ProtocolViolation,
+                e.to_string(),
+            ),
         }
     }
 }
@@ -11,7 +11,7 @@ pub use long::{
 pub use short::OneRttHeader;
 
 use super::r#type::{
-    long::{v1, Type as LongType, Version},
+    long::{v1, Type as LongType, Version, QUIC_V1, QUIC_V2},
     short::OneRtt,
     Type,
 };
@@ -26,11 +26,22 @@ pub trait GetLength {
     fn get_length(&self) -> usize;
 }
 
+/// The length of the packet number and the payload, which is known only after the payload
+/// is assembled, so it is set right before the header is written.
+pub trait SetLength {
+    fn set_length(&mut self, length: usize);
+}
+
 #[enum_dispatch]
 pub trait GetDcid {
     fn get_dcid(&self) -> &ConnectionId;
 }
 
+/// Only the long headers carry the Source Connection ID.
+pub trait GetScid {
+    fn get_scid(&self) -> &ConnectionId;
+}
+
 #[derive(Debug, Clone)]
 #[enum_dispatch(GetDcid)]
 pub enum Header {
@@ -68,7 +79,11 @@ pub mod ext {
             Type::Long(long_ty) => {
                 let (remain, dcid) = be_connection_id(input)?;
                 let (remain, scid) = be_connection_id(remain)?;
-                let builder = LongHeaderBuilder { dcid, scid };
+                let builder = LongHeaderBuilder {
+                    version: long_ty.version(),
+                    dcid,
+                    scid,
+                };
                 builder.parse(long_ty, remain)
             }
             Type::Short(OneRtt(spin)) => {
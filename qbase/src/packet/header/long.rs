@@ -54,6 +54,12 @@ macro_rules! protect {
                     self.length.to_usize()
                 }
             }
+
+            impl super::SetLength for $type {
+                fn set_length(&mut self, length: usize) {
+                    self.length = VarInt::from_u64(length as u64).expect("packet too large");
+                }
+            }
         )*
     };
 }
@@ -62,6 +68,8 @@ protect!(Initial, ZeroRtt, Handshake);
 
 #[derive(Debug, Default, Clone, Deref, DerefMut)]
 pub struct LongHeader<T> {
+    /// The version determines how the packet type is encoded, and how the packet is protected.
+    pub version: u32,
     pub dcid: ConnectionId,
     pub scid: ConnectionId,
     #[deref]
@@ -74,6 +82,12 @@ impl<T> super::GetDcid for LongHeader<T> {
     }
 }
 
+impl<T> super::GetScid for LongHeader<T> {
+    fn get_scid(&self) -> &ConnectionId {
+        &self.scid
+    }
+}
+
 pub type VersionNegotiationHeader = LongHeader<VersionNegotiation>;
 pub type RetryHeader = LongHeader<Retry>;
 
@@ -85,12 +99,21 @@ impl Protect for InitialHeader {}
 impl Protect for ZeroRttHeader {}
 impl Protect for HandshakeHeader {}
 
+impl GetType for VersionNegotiationHeader {
+    fn get_type(&self) -> Type {
+        Type::Long(LongType::VersionNegotiation)
+    }
+}
+
 macro_rules! bind_type {
     ($($type:ty => $value:expr),*) => {
         $(
             impl GetType for $type {
                 fn get_type(&self) -> Type {
-                    $value
+                    match self.version {
+                        QUIC_V2 => Type::Long(LongType::V2(Version($value))),
+                        _ => Type::Long(LongType::V1(Version($value))),
+                    }
                 }
             }
         )*
@@ -98,16 +121,13 @@ macro_rules! bind_type {
 }
 
 bind_type!(
-    VersionNegotiationHeader => Type::Long(LongType::VersionNegotiation),
-    RetryHeader => Type::Long(LongType::V1(Version::<1, _>(v1::Type::Retry))),
-    InitialHeader => Type::Long(LongType::V1(Version::<1, _>(v1::Type::Initial))),
-    ZeroRttHeader => Type::Long(LongType::V1(Version::<1, _>(v1::Type::ZeroRtt))),
-    HandshakeHeader => Type::Long(LongType::V1(Version::<1, _>(v1::Type::Handshake)))
+    RetryHeader => v1::Type::Retry,
+    InitialHeader => v1::Type::Initial,
+    ZeroRttHeader => v1::Type::ZeroRtt,
+    HandshakeHeader => v1::Type::Handshake
 );
 
-pub(super) mod ext {
-    use std::ops::Deref;
-
+pub mod ext {
     use super::*;
     use crate::{
         cid::WriteConnectionId,
@@ -157,17 +177,29 @@ pub(super) mod ext {
     }
 
     pub struct LongHeaderBuilder {
+        pub(crate) version: u32,
         pub(crate) dcid: ConnectionId,
         pub(crate) scid: ConnectionId,
     }
 
     impl LongHeaderBuilder {
+        /// The header is of QUIC version 1 by default, see [`LongHeaderBuilder::version`].
         pub fn with_cid(dcid: ConnectionId, scid: ConnectionId) -> Self {
-            Self { dcid, scid }
+            Self {
+                version: QUIC_V1,
+                dcid,
+                scid,
+            }
+        }
+
+        pub fn version(mut self, version: u32) -> Self {
+            self.version = version;
+            self
         }
 
         pub fn wrap<T>(self, specific: T) -> LongHeader<T> {
             LongHeader {
+                version: self.version,
                 dcid: self.dcid,
                 scid: self.scid,
                 specific,
@@ -180,7 +212,7 @@ pub(super) mod ext {
                     let (remain, versions) = be_version_negotiation(input)?;
                     Ok((remain, Header::VN(self.wrap(versions))))
                 }
-                LongType::V1(ty) => match ty.deref() {
+                LongType::V1(Version(ty)) | LongType::V2(Version(ty)) => match ty {
                     LongV1Type::Retry => {
                         let (remain, retry) = be_retry(input)?;
                         Ok((remain, Header::Retry(self.wrap(retry))))
@@ -216,15 +248,16 @@ pub(super) mod ext {
 
     impl<T: BufMut> Write<Retry> for T {
         fn put_specific(&mut self, specific: &Retry) {
-            self.put_slice(&specific.integrity);
             self.put_slice(&specific.token);
+            self.put_slice(&specific.integrity);
         }
     }
 
     impl<T: BufMut> Write<Initial> for T {
         fn put_specific(&mut self, specific: &Initial) {
-            self.put_varint(&specific.length);
+            self.put_varint(&VarInt::from_u32(specific.token.len() as u32));
             self.put_slice(&specific.token);
+            self.put_varint(&specific.length);
         }
     }
 
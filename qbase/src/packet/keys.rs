@@ -1,10 +1,14 @@
 use super::KeyPhaseBit;
-use rustls::quic::{HeaderProtectionKey, Keys, PacketKey, Secrets};
+use crate::{
+    crypto::{HeaderProtectionKey, Keys, PacketKey, Secrets},
+    error::{Error, ErrorKind},
+};
 use std::{
     future::Future,
     pin::Pin,
     sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
 #[derive(Clone)]
@@ -40,6 +44,15 @@ impl ArcKeys {
         GetLocalKeys(self.0.clone())
     }
 
+    /// The keys to encrypt packets right now, or None if they are not ready yet or have been
+    /// discarded. The sender tries the spaces one by one, and never waits for any of them.
+    pub fn local_keys(&self) -> Option<Arc<Keys>> {
+        match &*self.0.lock().unwrap() {
+            KeysState::Ready(keys) => Some(keys.clone()),
+            _ => None,
+        }
+    }
+
     pub fn set_keys(&self, keys: Keys) {
         let mut state = self.0.lock().unwrap();
         match &mut *state {
@@ -57,6 +70,21 @@ impl ArcKeys {
         }
     }
 
+    /// Only the Initial keys can be replaced, which happens when the client receives
+    /// a Retry packet, and the Initial keys are re-derived from the new Destination
+    /// Connection ID chosen by the server.
+    pub fn replace_keys(&self, keys: Keys) {
+        let mut state = self.0.lock().unwrap();
+        match &mut *state {
+            KeysState::Ready(_) => *state = KeysState::Ready(Arc::new(keys)),
+            KeysState::Invalid => {}
+            KeysState::Pending { .. } => {
+                drop(state);
+                self.set_keys(keys);
+            }
+        }
+    }
+
     pub fn invalid(&self) {
         let mut state = self.0.lock().unwrap();
         match &mut *state {
@@ -119,35 +147,120 @@ enum OneRttKeysState {
         tx_waker: Option<Waker>,
     },
     Ready {
-        psk: (Arc<HeaderProtectionKey>, Arc<HeaderProtectionKey>),
+        psk: (Arc<dyn HeaderProtectionKey>, Arc<dyn HeaderProtectionKey>),
         pk: Arc<Mutex<OneRttPacketKeys>>,
     },
     Invalid,
 }
 
+/// Initiate a key update after this many packets are sent with the same keys by default.
+pub const DEFAULT_KEY_UPDATE_INTERVAL: u64 = 1 << 20;
+/// Old keys are retained for 3 times of PTO after a key update, so that delayed or
+/// reordered packets can still be decrypted, see [Section 6.5](https://www.rfc-editor.org/rfc/rfc9001.html#section-6.5) of QUIC-TLS.
+const OLD_KEYS_PTO_FACTOR: u32 = 3;
+
 pub struct OneRttPacketKeys {
     cur_key_phase: KeyPhaseBit,
-    secrets: Secrets,
-    remote: [Option<Arc<PacketKey>>; 2],
-    local: Arc<PacketKey>,
+    secrets: Box<dyn Secrets>,
+    remote: [Option<Arc<dyn PacketKey>>; 2],
+    local: Arc<dyn PacketKey>,
+    // 下一代密钥，对方发起密钥更新时，只有用它成功解密了包，才会正式启用
+    next: Option<(Arc<dyn PacketKey>, Arc<dyn PacketKey>)>,
+    // 当前密钥阶段收到的最小包号，用于检测对方违规使用旧密钥，也表明本次密钥更新已被对方确认
+    lowest_pn_in_cur_phase: Option<u64>,
+    old_keys_expire_at: Option<Instant>,
+    // 当前本地密钥加密的包数，以及所有密钥解密失败的包数
+    encrypted_packets: u64,
+    failed_decryptions: u64,
+    key_update_interval: u64,
+    // 握手确认之前，不能发起密钥更新
+    handshake_confirmed: bool,
 }
 
 impl OneRttPacketKeys {
-    fn new(remote: PacketKey, local: PacketKey, secrets: Secrets) -> Self {
+    fn new(
+        remote: Box<dyn PacketKey>,
+        local: Box<dyn PacketKey>,
+        secrets: Box<dyn Secrets>,
+    ) -> Self {
         Self {
             cur_key_phase: KeyPhaseBit::default(),
             secrets,
-            remote: [Some(Arc::new(remote)), None],
-            local: Arc::new(local),
+            remote: [Some(Arc::from(remote)), None],
+            local: Arc::from(local),
+            next: None,
+            lowest_pn_in_cur_phase: None,
+            old_keys_expire_at: None,
+            encrypted_packets: 0,
+            failed_decryptions: 0,
+            key_update_interval: DEFAULT_KEY_UPDATE_INTERVAL,
+            handshake_confirmed: false,
         }
     }
 
-    /// Key actively upgrades, which occurs when we want to actively change the key.
-    pub fn update(&mut self) {
+    /// Key updates are not allowed until the handshake is confirmed, see
+    /// [Section 6.1](https://www.rfc-editor.org/rfc/rfc9001.html#section-6.1) of QUIC-TLS.
+    pub fn on_handshake_confirmed(&mut self) {
+        self.handshake_confirmed = true;
+    }
+
+    /// The tag length of the local keys, which is the same for all the key phases. It is used
+    /// to reserve room for the tag before the packet is assembled, without counting as a
+    /// packet sent like [`OneRttPacketKeys::get_local`].
+    pub fn tag_len(&self) -> usize {
+        self.local.tag_len()
+    }
+
+    pub fn set_key_update_interval(&mut self, packets: u64) {
+        self.key_update_interval = packets;
+    }
+
+    fn next_keys(&mut self) -> (Arc<dyn PacketKey>, Arc<dyn PacketKey>) {
+        if self.next.is_none() {
+            let key_set = self.secrets.next_packet_keys();
+            self.next = Some((Arc::from(key_set.remote), Arc::from(key_set.local)));
+        }
+        self.next.clone().unwrap()
+    }
+
+    fn switch_to_next_keys(&mut self, pto: Duration) {
+        let (remote, local) = self.next_keys();
+        self.next = None;
         self.cur_key_phase.toggle();
-        let key_set = self.secrets.next_packet_keys();
-        self.remote[self.cur_key_phase.index()] = Some(Arc::new(key_set.remote));
-        self.local = Arc::new(key_set.local);
+        self.remote[self.cur_key_phase.index()] = Some(remote);
+        self.local = local;
+        self.lowest_pn_in_cur_phase = None;
+        self.encrypted_packets = 0;
+        self.old_keys_expire_at = Some(Instant::now() + pto * OLD_KEYS_PTO_FACTOR);
+    }
+
+    fn phase_out_expired_keys(&mut self) {
+        if self
+            .old_keys_expire_at
+            .is_some_and(|expire_at| expire_at <= Instant::now())
+        {
+            self.phase_out();
+        }
+    }
+
+    /// An endpoint can initiate a key update only after the handshake is confirmed, the old keys
+    /// are phased out, and the previous key update is confirmed by receiving packets with the
+    /// current keys, see [Section 6.1](https://www.rfc-editor.org/rfc/rfc9001.html#section-6.1) of QUIC-TLS.
+    pub fn can_update(&self) -> bool {
+        self.handshake_confirmed
+            && self.remote[(!self.cur_key_phase).index()].is_none()
+            && self.lowest_pn_in_cur_phase.is_some()
+    }
+
+    /// Key actively upgrades, which occurs when we want to actively change the key.
+    /// Returns false if a key update is not allowed now.
+    pub fn update(&mut self, pto: Duration) -> bool {
+        self.phase_out_expired_keys();
+        if !self.can_update() {
+            return false;
+        }
+        self.switch_to_next_keys(pto);
+        true
     }
 
     /// Old key must be phased out within a certain period of time. If the old one don't go,
@@ -156,27 +269,101 @@ impl OneRttPacketKeys {
     /// received from the other party.
     pub fn phase_out(&mut self) {
         self.remote[(!self.cur_key_phase).index()].take();
+        self.old_keys_expire_at = None;
+    }
+
+    /// Get the remote key to decrypt the incoming packet, see [Section 6.5](https://www.rfc-editor.org/rfc/rfc9001.html#section-6.5) of QUIC-TLS.
+    /// If the key phase is different from the current one, a packet with a lower packet number
+    /// than any packet of the current phase is a delayed one protected with the old keys,
+    /// otherwise the peer may have initiated a key update, the next keys are returned, but
+    /// they are not committed until [`OneRttPacketKeys::on_packet_decrypted`] is called.
+    /// Returning `Arc<dyn PacketKey>` is to encrypt and decrypt packets at the same time.
+    /// Compared to `&'a PacketKey`, `Arc<dyn PacketKey>` does not occupy mutable borrowing `&mut self`.
+    pub fn get_remote(&mut self, key_phase: KeyPhaseBit, pkt_id: u64) -> Arc<dyn PacketKey> {
+        self.phase_out_expired_keys();
+        if key_phase == self.cur_key_phase {
+            return self.remote[key_phase.index()].clone().unwrap();
+        }
+        match &self.remote[key_phase.index()] {
+            Some(old) if self.is_older_than_cur_phase(pkt_id) => old.clone(),
+            _ => self.next_keys().0,
+        }
+    }
+
+    fn is_older_than_cur_phase(&self, pkt_id: u64) -> bool {
+        self.lowest_pn_in_cur_phase
+            .is_none_or(|lowest| pkt_id < lowest)
     }
 
-    /// Get the remote key to decrypt the incoming packet.
-    /// If the key phase is not the current key phase, update the key.
-    /// Returning Arc<PacketKey> is to encrypt and decrypt packets at the same time.
-    /// Compared to &'a PacketKey, Arc<PacketKey> does not occupy mutable borrowing &mut self.
-    pub fn get_remote(&mut self, key_phase: KeyPhaseBit, _pkt_id: u64) -> Arc<PacketKey> {
-        if key_phase != self.cur_key_phase && self.remote[key_phase.index()].is_none() {
-            self.update();
+    /// Called when a packet is decrypted successfully with the key from [`OneRttPacketKeys::get_remote`].
+    /// A peer-initiated key update is committed here, and the old keys are kept for 3 times of `pto`.
+    /// Returns KEY_UPDATE_ERROR if the peer updates the keys again before any packet is sent
+    /// with the keys it updated last time, see [Section 6.2](https://www.rfc-editor.org/rfc/rfc9001.html#section-6.2) of QUIC-TLS.
+    pub fn on_packet_decrypted(
+        &mut self,
+        key_phase: KeyPhaseBit,
+        pkt_id: u64,
+        pto: Duration,
+    ) -> Result<(), Error> {
+        if key_phase == self.cur_key_phase {
+            let lowest = self.lowest_pn_in_cur_phase.get_or_insert(pkt_id);
+            *lowest = (*lowest).min(pkt_id);
+        } else if self.remote[key_phase.index()].is_some() && self.is_older_than_cur_phase(pkt_id) {
+            // 用旧密钥解密的、延迟到达的包
+        } else {
+            if self.remote[key_phase.index()].is_some() && self.encrypted_packets == 0 {
+                return Err(Error::new_with_default_fty(
+                    ErrorKind::KeyUpdate,
+                    "consecutive key updates",
+                ));
+            }
+            self.switch_to_next_keys(pto);
+            self.lowest_pn_in_cur_phase = Some(pkt_id);
         }
-        self.remote[key_phase.index()].clone().unwrap()
+        Ok(())
     }
 
-    /// Get the local key with the current key phase to encrypt the outgoing packet.
-    /// Returning Arc<PacketKey> is to encrypt and decrypt packets at the same time.
-    /// Compared to &'a PacketKey, Arc<PacketKey> does not occupy mutable borrowing &mut self.
-    pub fn get_local(&self) -> (KeyPhaseBit, Arc<PacketKey>) {
-        (self.cur_key_phase, self.local.clone())
+    /// Called when a packet fails to be decrypted. Returns AEAD_LIMIT_REACHED if the
+    /// integrity limit is reached, the connection must be closed then, see
+    /// [Section 6.6](https://www.rfc-editor.org/rfc/rfc9001.html#section-6.6) of QUIC-TLS.
+    pub fn on_decryption_failed(&mut self) -> Result<(), Error> {
+        self.failed_decryptions += 1;
+        if self.failed_decryptions >= self.local.integrity_limit() {
+            return Err(Error::new_with_default_fty(
+                ErrorKind::AeadLimitReached,
+                "integrity limit reached",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the local key with the current key phase to encrypt the outgoing packet, which
+    /// counts as one packet sent. A key update is initiated after every `key_update_interval`
+    /// packets, or when the confidentiality limit is approaching. If the confidentiality limit
+    /// is reached but the keys can't be updated, AEAD_LIMIT_REACHED is returned.
+    /// Returning `Arc<dyn PacketKey>` is to encrypt and decrypt packets at the same time.
+    /// Compared to `&'a PacketKey`, `Arc<dyn PacketKey>` does not occupy mutable borrowing `&mut self`.
+    pub fn get_local(&mut self, pto: Duration) -> Result<(KeyPhaseBit, Arc<dyn PacketKey>), Error> {
+        let limit = self.local.confidentiality_limit();
+        // 在达到机密性上限之前，留出足够的余量来完成密钥更新
+        let update_threshold = self.key_update_interval.min(limit - limit / 16);
+        if self.encrypted_packets >= update_threshold {
+            self.update(pto);
+        }
+        if self.encrypted_packets >= limit {
+            return Err(Error::new_with_default_fty(
+                ErrorKind::AeadLimitReached,
+                "confidentiality limit reached",
+            ));
+        }
+        self.encrypted_packets += 1;
+        Ok((self.cur_key_phase, self.local.clone()))
     }
 }
 
+/// The header protection key, which is never updated, and the packet keys of 1-RTT.
+pub type OneRttKeys = (Arc<dyn HeaderProtectionKey>, Arc<Mutex<OneRttPacketKeys>>);
+
 #[derive(Clone)]
 pub struct ArcOneRttKeys(Arc<Mutex<OneRttKeysState>>);
 
@@ -188,7 +375,7 @@ impl ArcOneRttKeys {
         })))
     }
 
-    pub fn set_keys(&self, keys: Keys, secrets: Secrets) {
+    pub fn set_keys(&self, keys: Keys, secrets: Box<dyn Secrets>) {
         let mut state = self.0.lock().unwrap();
         match &mut *state {
             OneRttKeysState::Pending { rx_waker, tx_waker } => {
@@ -198,7 +385,7 @@ impl ArcOneRttKeys {
                 if let Some(waker) = tx_waker.take() {
                     waker.wake();
                 }
-                let psk = (Arc::new(keys.remote.header), Arc::new(keys.local.header));
+                let psk = (Arc::from(keys.remote.header), Arc::from(keys.local.header));
                 let pk = Arc::new(Mutex::new(OneRttPacketKeys::new(
                     keys.remote.packet,
                     keys.local.packet,
@@ -232,6 +419,21 @@ impl ArcOneRttKeys {
         GetLocalOneRttKeys(self.0.clone())
     }
 
+    /// See [`ArcKeys::local_keys`].
+    pub fn local_keys(&self) -> Option<OneRttKeys> {
+        match &*self.0.lock().unwrap() {
+            OneRttKeysState::Ready { psk, pk } => Some((psk.1.clone(), pk.clone())),
+            _ => None,
+        }
+    }
+
+    /// See [`OneRttPacketKeys::on_handshake_confirmed`].
+    pub fn on_handshake_confirmed(&self) {
+        if let OneRttKeysState::Ready { pk, .. } = &*self.0.lock().unwrap() {
+            pk.lock().unwrap().on_handshake_confirmed();
+        }
+    }
+
     pub fn get_remote_keys(&self) -> GetRemoteOneRttKeys {
         GetRemoteOneRttKeys(self.0.clone())
     }
@@ -240,7 +442,7 @@ impl ArcOneRttKeys {
 pub struct GetRemoteOneRttKeys(Arc<Mutex<OneRttKeysState>>);
 
 impl Future for GetRemoteOneRttKeys {
-    type Output = Option<(Arc<HeaderProtectionKey>, Arc<Mutex<OneRttPacketKeys>>)>;
+    type Output = Option<OneRttKeys>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut keys = self.0.lock().unwrap();
@@ -259,7 +461,7 @@ impl Future for GetRemoteOneRttKeys {
 pub struct GetLocalOneRttKeys(Arc<Mutex<OneRttKeysState>>);
 
 impl Future for GetLocalOneRttKeys {
-    type Output = Option<(Arc<HeaderProtectionKey>, Arc<Mutex<OneRttPacketKeys>>)>;
+    type Output = Option<OneRttKeys>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut keys = self.0.lock().unwrap();
@@ -274,3 +476,172 @@ impl Future for GetLocalOneRttKeys {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{CryptoError, PacketKeySet};
+
+    const PTO: Duration = Duration::from_millis(100);
+
+    /// 用tag_len区分第几代密钥
+    struct TestKey {
+        generation: usize,
+        limit: u64,
+    }
+
+    impl PacketKey for TestKey {
+        fn tag_len(&self) -> usize {
+            self.generation
+        }
+
+        fn encrypt_in_place(&self, _: u64, _: &[u8], _: &mut [u8]) -> Result<(), CryptoError> {
+            Ok(())
+        }
+
+        fn decrypt_in_place(
+            &self,
+            _: u64,
+            _: &[u8],
+            payload: &mut [u8],
+        ) -> Result<usize, CryptoError> {
+            Ok(payload.len())
+        }
+
+        fn confidentiality_limit(&self) -> u64 {
+            self.limit
+        }
+
+        fn integrity_limit(&self) -> u64 {
+            self.limit
+        }
+    }
+
+    struct TestSecrets {
+        generation: usize,
+        limit: u64,
+    }
+
+    impl Secrets for TestSecrets {
+        fn next_packet_keys(&mut self) -> PacketKeySet {
+            self.generation += 1;
+            PacketKeySet {
+                local: Box::new(TestKey {
+                    generation: self.generation,
+                    limit: self.limit,
+                }),
+                remote: Box::new(TestKey {
+                    generation: self.generation,
+                    limit: self.limit,
+                }),
+            }
+        }
+    }
+
+    fn one_rtt_keys(limit: u64) -> OneRttPacketKeys {
+        OneRttPacketKeys::new(
+            Box::new(TestKey {
+                generation: 0,
+                limit,
+            }),
+            Box::new(TestKey {
+                generation: 0,
+                limit,
+            }),
+            Box::new(TestSecrets {
+                generation: 0,
+                limit,
+            }),
+        )
+    }
+
+    #[test]
+    fn test_update() {
+        let mut keys = one_rtt_keys(u64::MAX);
+        keys.on_packet_decrypted(KeyPhaseBit::Off, 0, PTO).unwrap();
+        // 握手确认之前不能更新密钥
+        assert!(!keys.update(PTO));
+        keys.on_handshake_confirmed();
+        assert!(keys.update(PTO));
+        let (key_phase, key) = keys.get_local(PTO).unwrap();
+        assert_eq!((key_phase, key.tag_len()), (KeyPhaseBit::On, 1));
+        // 对方还没有用新密钥回应，不能再次更新
+        assert!(!keys.update(PTO));
+        keys.on_packet_decrypted(KeyPhaseBit::On, 5, PTO).unwrap();
+        assert!(!keys.update(PTO));
+        keys.phase_out();
+        assert!(keys.update(PTO));
+        assert_eq!(keys.get_local(PTO).unwrap().0, KeyPhaseBit::Off);
+    }
+
+    #[test]
+    fn test_get_local_auto_update() {
+        let mut keys = one_rtt_keys(u64::MAX);
+        keys.set_key_update_interval(3);
+        keys.on_packet_decrypted(KeyPhaseBit::Off, 0, PTO).unwrap();
+        // 握手确认之前，到了间隔也不会更新
+        for _ in 0..4 {
+            assert_eq!(keys.get_local(PTO).unwrap().0, KeyPhaseBit::Off);
+        }
+        keys.on_handshake_confirmed();
+        let (key_phase, key) = keys.get_local(PTO).unwrap();
+        assert_eq!((key_phase, key.tag_len()), (KeyPhaseBit::On, 1));
+    }
+
+    #[test]
+    fn test_confidentiality_limit() {
+        let mut keys = one_rtt_keys(3);
+        for _ in 0..3 {
+            assert!(keys.get_local(PTO).is_ok());
+        }
+        // 握手未确认，无法更新密钥，达到机密性上限
+        let Err(error) = keys.get_local(PTO) else {
+            panic!("the confidentiality limit is reached");
+        };
+        assert_eq!(error.kind, ErrorKind::AeadLimitReached);
+    }
+
+    #[test]
+    fn test_get_remote_by_pn() {
+        let mut keys = one_rtt_keys(u64::MAX);
+        keys.on_handshake_confirmed();
+        keys.on_packet_decrypted(KeyPhaseBit::Off, 10, PTO).unwrap();
+
+        // 对方发起密钥更新，包号更大的包使用下一代密钥
+        assert_eq!(keys.get_remote(KeyPhaseBit::On, 11).tag_len(), 1);
+        keys.on_packet_decrypted(KeyPhaseBit::On, 11, PTO).unwrap();
+        assert_eq!(keys.get_local(PTO).unwrap().0, KeyPhaseBit::On);
+        // 更新之前发送的、延迟到达的包，仍用旧密钥解密
+        assert_eq!(keys.get_remote(KeyPhaseBit::Off, 9).tag_len(), 0);
+        keys.on_packet_decrypted(KeyPhaseBit::Off, 9, PTO).unwrap();
+        assert_eq!(keys.get_remote(KeyPhaseBit::On, 12).tag_len(), 1);
+
+        // 包号更大的旧阶段包，只能是对方再次更新了密钥
+        assert_eq!(keys.get_remote(KeyPhaseBit::Off, 20).tag_len(), 2);
+        keys.on_packet_decrypted(KeyPhaseBit::Off, 20, PTO).unwrap();
+        assert_eq!(keys.get_local(PTO).unwrap().0, KeyPhaseBit::Off);
+    }
+
+    #[test]
+    fn test_consecutive_key_updates() {
+        let mut keys = one_rtt_keys(u64::MAX);
+        keys.on_handshake_confirmed();
+        keys.on_packet_decrypted(KeyPhaseBit::Off, 10, PTO).unwrap();
+        keys.on_packet_decrypted(KeyPhaseBit::On, 11, PTO).unwrap();
+        // 我方还没有用更新后的密钥发送任何包，对方又更新了密钥
+        assert_eq!(keys.get_remote(KeyPhaseBit::Off, 12).tag_len(), 2);
+        let error = keys
+            .on_packet_decrypted(KeyPhaseBit::Off, 12, PTO)
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::KeyUpdate);
+    }
+
+    #[test]
+    fn test_integrity_limit() {
+        let mut keys = one_rtt_keys(3);
+        assert!(keys.on_decryption_failed().is_ok());
+        assert!(keys.on_decryption_failed().is_ok());
+        let error = keys.on_decryption_failed().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::AeadLimitReached);
+    }
+}
@@ -38,20 +38,31 @@ pub fn take_pn_len(pn_len: u8) -> impl FnMut(&[u8]) -> nom::IResult<&[u8], Packe
         2 => map(be_u16, PacketNumber::U16)(input),
         3 => map(be_u24, PacketNumber::U24)(input),
         4 => map(be_u32, PacketNumber::U32)(input),
-        _ => unreachable!(),
+        _ => Err(nom::Err::Error(nom::error::make_error(
+            input,
+            nom::error::ErrorKind::Verify,
+        ))),
     }
 }
 
 impl PacketNumber {
-    pub fn encode(pn: u64, largest_acked: u64) -> Self {
-        let range = (pn - largest_acked) * 2;
-        if range < 1 << 8 {
+    /// Choose the shortest encoding whose range is at least twice the number of the packets
+    /// not yet acknowledged, so that the peer can decode it. `largest_acked` is the largest packet
+    /// number acknowledged by the peer in the same space, None if no packet has been acknowledged.
+    /// See [Appendix A.2](https://www.rfc-editor.org/rfc/rfc9000.html#appendix-A.2) of QUIC.
+    pub fn encode(pn: u64, largest_acked: Option<u64>) -> Self {
+        let num_unacked = match largest_acked {
+            Some(largest_acked) => pn.saturating_sub(largest_acked),
+            None => pn + 1,
+        };
+        let range = num_unacked * 2;
+        if range <= 1 << 8 {
             Self::U8(pn as u8)
-        } else if range < 1 << 16 {
+        } else if range <= 1 << 16 {
             Self::U16(pn as u16)
-        } else if range < 1 << 24 {
+        } else if range <= 1 << 24 {
             Self::U24(pn as u32)
-        } else if range < 1 << 32 {
+        } else if range <= 1 << 32 {
             Self::U32(pn as u32)
         } else {
             panic!("packet number too large to encode")
@@ -89,7 +100,7 @@ impl PacketNumber {
         // The following code calculates a candidate value and makes sure it's within the packet
         // number window.
         let candidate = (expected & !mask) | truncated;
-        if expected.checked_sub(hwin).map_or(false, |x| candidate <= x) {
+        if expected.checked_sub(hwin).is_some_and(|x| candidate <= x) {
             candidate + win
         } else if candidate > expected + hwin && candidate > win {
             candidate - win
@@ -101,8 +112,43 @@ impl PacketNumber {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_encode_packet_number() {
+        // RFC 9000 Appendix A.2的例子
+        assert_eq!(
+            PacketNumber::encode(0xac5c02, Some(0xabe8b3)),
+            PacketNumber::U16(0x5c02)
+        );
+        assert_eq!(
+            PacketNumber::encode(0xace8fe, Some(0xabe8b3)),
+            PacketNumber::U24(0xace8fe)
+        );
+        // 尚未有包被确认时，按全部已发送的包计算
+        assert_eq!(PacketNumber::encode(127, None), PacketNumber::U8(127));
+        assert_eq!(PacketNumber::encode(128, None), PacketNumber::U16(128));
+        assert_eq!(PacketNumber::encode(128, Some(0)), PacketNumber::U8(128));
+        assert_eq!(PacketNumber::encode(129, Some(0)), PacketNumber::U16(129));
+    }
+
+    #[test]
+    fn test_decode_packet_number() {
+        // RFC 9000 Appendix A.3的例子
+        assert_eq!(PacketNumber::U16(0x9b32).decode(0xa82f30eb), 0xa82f9b32);
+        for (pn, largest_acked) in [
+            (0u64, None),
+            (1000, Some(900)),
+            (0x1_0000_0100, Some(0x1_0000_0000)),
+        ] {
+            let encoded = PacketNumber::encode(pn, largest_acked);
+            let expected = largest_acked.map_or(0, |largest| largest + 1);
+            assert_eq!(encoded.decode(expected), pn);
+        }
+    }
 }
@@ -0,0 +1,217 @@
+use super::{
+    header::{ext::WriteHeader, GetType, LongHeaderBuilder},
+    r#type::{
+        ext::WritePacketType,
+        long::{v1, v2, QUIC_V2},
+    },
+    Header, RetryHeader,
+};
+use crate::cid::{ConnectionId, WriteConnectionId};
+use bytes::{BufMut, BytesMut};
+use deref_derive::Deref;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+
+pub const RETRY_INTEGRITY_TAG_SIZE: usize = 16;
+
+/// A Retry packet is not protected, but carries an integrity tag computed over
+/// the whole packet, including the unused bits of the first byte. So the raw
+/// bytes are kept along with the parsed header for verification.
+#[derive(Debug, Clone, Deref)]
+pub struct RetryPacket {
+    #[deref]
+    pub header: RetryHeader,
+    pub raw_data: BytesMut,
+}
+
+impl RetryPacket {
+    /// Verify the integrity tag with the Destination Connection ID of the first
+    /// Initial packet sent by the client. A client MUST discard a Retry packet
+    /// that fails this validation.
+    pub fn verify_integrity(&self, origin_dcid: &ConnectionId) -> bool {
+        let len = self.raw_data.len();
+        if len < RETRY_INTEGRITY_TAG_SIZE {
+            return false;
+        }
+        let (retry, tag) = self.raw_data.split_at(len - RETRY_INTEGRITY_TAG_SIZE);
+        let mut tag: [u8; RETRY_INTEGRITY_TAG_SIZE] = tag.try_into().unwrap();
+        let (key, nonce) = integrity_key(self.header.version);
+        key.open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(pseudo_packet(origin_dcid, retry)),
+            &mut tag,
+        )
+        .is_ok()
+    }
+}
+
+/// The key and nonce of the Retry Integrity Tag differ between versions.
+fn integrity_key(version: u32) -> (LessSafeKey, [u8; 12]) {
+    let (key, nonce) = match version {
+        QUIC_V2 => (v2::RETRY_INTEGRITY_KEY, v2::RETRY_INTEGRITY_NONCE),
+        _ => (v1::RETRY_INTEGRITY_KEY, v1::RETRY_INTEGRITY_NONCE),
+    };
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &key).unwrap());
+    (key, nonce)
+}
+
+/// The Retry Pseudo-Packet is the Retry packet without the integrity tag,
+/// prefixed with the Original Destination Connection ID.
+fn pseudo_packet(origin_dcid: &ConnectionId, retry_without_tag: &[u8]) -> Vec<u8> {
+    let mut pseudo = Vec::with_capacity(1 + origin_dcid.len() + retry_without_tag.len());
+    pseudo.put_connection_id(origin_dcid);
+    pseudo.put_slice(retry_without_tag);
+    pseudo
+}
+
+/// Compute the Retry Integrity Tag over a Retry packet of `version` without the tag.
+pub fn integrity_tag(
+    version: u32,
+    origin_dcid: &ConnectionId,
+    retry_without_tag: &[u8],
+) -> [u8; RETRY_INTEGRITY_TAG_SIZE] {
+    let (key, nonce) = integrity_key(version);
+    let tag = key
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(pseudo_packet(origin_dcid, retry_without_tag)),
+            &mut [],
+        )
+        .unwrap();
+    tag.as_ref().try_into().unwrap()
+}
+
+/// Build a complete Retry packet sent by the server, in response to an Initial
+/// packet of `version` with the Destination Connection ID `origin_dcid` from the client.
+/// - `dcid` is the Source Connection ID of the client's Initial packet.
+/// - `scid` is the new connection ID chosen by the server, which the client
+///   will use as the Destination Connection ID of its subsequent packets.
+pub fn build_retry_packet(
+    version: u32,
+    origin_dcid: &ConnectionId,
+    dcid: ConnectionId,
+    scid: ConnectionId,
+    token: Vec<u8>,
+) -> BytesMut {
+    let mut header: RetryHeader = LongHeaderBuilder::with_cid(dcid, scid)
+        .version(version)
+        .wrap(Default::default());
+    header.specific.token = token;
+
+    let mut buf = BytesMut::new();
+    buf.put_packet_type(&header.get_type());
+    buf.put_header(&Header::Retry(header));
+    let tag_offset = buf.len() - RETRY_INTEGRITY_TAG_SIZE;
+    let tag = integrity_tag(version, origin_dcid, &buf[..tag_offset]);
+    buf[tag_offset..].copy_from_slice(&tag);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ext::be_packet, r#type::long::QUIC_V1, Packet};
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // See [Appendix A.4](https://www.rfc-editor.org/rfc/rfc9001.html#appendix-A.4) of QUIC-TLS.
+    const RFC9001_RETRY: &str =
+        "ff000000010008f067a5502a4262b5746f6b656e04a265ba2eff4d829058fb3f0f2496ba";
+    const RFC9001_ODCID: &str = "8394c8f03e515708";
+    // See [Appendix A.4](https://www.rfc-editor.org/rfc/rfc9369.html#appendix-A.4) of QUIC v2.
+    const RFC9369_RETRY: &str =
+        "cf6b3343cf0008f067a5502a4262b5746f6b656ec8646ce8bfe33952d955543665dcc7b6";
+
+    fn parse_retry(raw: &[u8]) -> RetryPacket {
+        let datagram = BytesMut::from(raw);
+        match be_packet(&datagram, 0, false).unwrap() {
+            (consumed, Packet::Retry(retry)) => {
+                assert_eq!(consumed, raw.len());
+                retry
+            }
+            _ => panic!("not a retry packet"),
+        }
+    }
+
+    #[test]
+    fn test_rfc9001_retry_vector() {
+        let odcid = ConnectionId::from_slice(&from_hex(RFC9001_ODCID));
+        let raw = from_hex(RFC9001_RETRY);
+        let retry = parse_retry(&raw);
+        assert_eq!(retry.token, b"token");
+        assert_eq!(*retry.scid, from_hex("f067a5502a4262b5"));
+        assert_eq!(&retry.integrity[..], &raw[raw.len() - 16..]);
+        assert!(retry.verify_integrity(&odcid));
+
+        let tag = integrity_tag(QUIC_V1, &odcid, &raw[..raw.len() - 16]);
+        assert_eq!(&tag[..], &raw[raw.len() - 16..]);
+    }
+
+    #[test]
+    fn test_rfc9369_retry_vector() {
+        let odcid = ConnectionId::from_slice(&from_hex(RFC9001_ODCID));
+        let raw = from_hex(RFC9369_RETRY);
+        let retry = parse_retry(&raw);
+        assert_eq!(retry.version, QUIC_V2);
+        assert_eq!(retry.token, b"token");
+        assert!(retry.verify_integrity(&odcid));
+
+        // v1的完整性密钥不能验证v2的Retry包
+        let mut v1_raw = from_hex(RFC9001_RETRY);
+        let tag_offset = v1_raw.len() - 16;
+        v1_raw[tag_offset..].copy_from_slice(&raw[raw.len() - 16..]);
+        assert!(!parse_retry(&v1_raw).verify_integrity(&odcid));
+
+        let scid = ConnectionId::from_slice(&from_hex("f067a5502a4262b5"));
+        let built = build_retry_packet(
+            QUIC_V2,
+            &odcid,
+            ConnectionId::default(),
+            scid,
+            b"token".to_vec(),
+        );
+        let retry = parse_retry(&built);
+        assert_eq!(retry.version, QUIC_V2);
+        assert!(retry.verify_integrity(&odcid));
+    }
+
+    #[test]
+    fn test_tampered_retry() {
+        let odcid = ConnectionId::from_slice(&from_hex(RFC9001_ODCID));
+        let mut raw = from_hex(RFC9001_RETRY);
+        raw[20] ^= 0x01;
+        assert!(!parse_retry(&raw).verify_integrity(&odcid));
+
+        let other_odcid = ConnectionId::from_slice(&from_hex("8394c8f03e515709"));
+        let raw = from_hex(RFC9001_RETRY);
+        assert!(!parse_retry(&raw).verify_integrity(&other_odcid));
+    }
+
+    #[test]
+    fn test_build_retry_packet() {
+        let odcid = ConnectionId::from_slice(&from_hex(RFC9001_ODCID));
+        let scid = ConnectionId::from_slice(&from_hex("f067a5502a4262b5"));
+        let raw = build_retry_packet(
+            QUIC_V1,
+            &odcid,
+            ConnectionId::default(),
+            scid,
+            b"token".to_vec(),
+        );
+        // Same as the RFC vector, except that the unused bits of the first byte are zero.
+        let mut expected = from_hex(RFC9001_RETRY);
+        expected[0] = 0xf0;
+        let tag = integrity_tag(QUIC_V1, &odcid, &expected[..expected.len() - 16]);
+        let tag_offset = expected.len() - 16;
+        expected[tag_offset..].copy_from_slice(&tag);
+        assert_eq!(&raw[..], &expected[..]);
+
+        let retry = parse_retry(&raw);
+        assert_eq!(retry.scid, scid);
+        assert!(retry.verify_integrity(&odcid));
+    }
+}
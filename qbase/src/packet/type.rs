@@ -8,7 +8,7 @@ pub mod short;
 /// header form bit
 const HEADER_FORM_MASK: u8 = 0x80;
 /// The next bit (0x40) of byte 0 is set to 1, unless the packet is a Version Negotiation packet.
-const FIXED_BIT: u8 = 0x40;
+pub(super) const FIXED_BIT: u8 = 0x40;
 
 /// After removing the packet header protection, the clear first byte part,
 /// 'R' represents the reserved bits. The long packet header is 0x0C, and
@@ -20,8 +20,9 @@ pub(super) type ShortClearBits = ClearBits<0x18>;
 pub(super) type LongClearBits = ClearBits<0xC>;
 
 impl<const R: u8> ClearBits<R> {
+    /// 保留位必须置0，只编码包号长度
     pub fn by(pn: &PacketNumber) -> Self {
-        Self(R | (pn.size() as u8 - 1))
+        Self(pn.size() as u8 - 1)
     }
 }
 
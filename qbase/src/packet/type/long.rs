@@ -1,7 +1,16 @@
 use deref_derive::Deref;
 
-/// Supports IQuic version 1, if other versions are supported in the future, add them here.
+/// Supports IQuic version 1 and version 2, if other versions are supported in the future,
+/// add them here.
 pub mod v1;
+pub mod v2;
+
+/// QUIC version 1, see [RFC 9000](https://www.rfc-editor.org/rfc/rfc9000.html).
+pub const QUIC_V1: u32 = 1;
+/// QUIC version 2, see [RFC 9369](https://www.rfc-editor.org/rfc/rfc9369.html).
+pub const QUIC_V2: u32 = 0x6b33_43cf;
+/// The supported versions, in the order of preference.
+pub const SUPPORTED_VERSIONS: [u32; 2] = [QUIC_V1, QUIC_V2];
 
 /// The long packet header contains version information, so the packet type of a certain
 /// version is considered as one type.
@@ -21,8 +30,18 @@ impl<const N: u32, Ty> GetVersion for Version<N, Ty> {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     VersionNegotiation,
-    V1(Version<1, v1::Type>),
-    // V2(v2::HeaderType),
+    V1(Version<QUIC_V1, v1::Type>),
+    V2(Version<QUIC_V2, v2::Type>),
+}
+
+impl Type {
+    pub fn version(&self) -> u32 {
+        match self {
+            Type::VersionNegotiation => 0,
+            Type::V1(ty) => ty.get_version(),
+            Type::V2(ty) => ty.get_version(),
+        }
+    }
 }
 
 const LONG_HEADER_BIT: u8 = 0x80;
@@ -36,26 +55,12 @@ pub mod ext {
 
     pub fn parse_long_type(ty: u8) -> impl FnMut(&[u8]) -> nom::IResult<&[u8], Type, Error> {
         move |input| {
-            // The next bit (0x40) of byte 0 is set to 1, unless the packet is a Version Negotiation
-            // packet. Packets containing a zero value for this bit are not valid packets in this
-            // version and MUST be discarded. A value of 1 for this bit allows QUIC to coexist with
-            // other protocols; see [RFC7983].
-            if ty & super::FIXED_BIT == 0 {
-                return Err(nom::Err::Error(Error::InvalidFixedBit));
-            }
-
+            // 固定位由PacketReader检查，因为对方可能按RFC 9287置0
             let (remain, version) = be_u32(input)?;
-            /*
-            let (remain, version) = be_u32::<_, ()>(input).map_err(|e| match e {
-                ne @ nom::Err::Incomplete(_) => {
-                    nom::Err::Error(Error::IncompleteType(ne.to_string()))
-                }
-                _ => unreachable!("parsing packet type never generates error or failure"),
-            })?;
-            */
             match version {
                 0 => Ok((remain, Type::VersionNegotiation)),
-                1 => Ok((remain, Type::V1(Version::<1, v1::Type>(ty.into())))),
+                QUIC_V1 => Ok((remain, Type::V1(Version::<QUIC_V1, v1::Type>(ty.into())))),
+                QUIC_V2 => Ok((remain, Type::V2(ty.into()))),
                 v => Err(nom::Err::Error(Error::UnsupportedVersion(v))),
             }
         }
@@ -72,10 +77,15 @@ pub mod ext {
                     self.put_u8(LONG_HEADER_BIT);
                     self.put_u32(0);
                 }
-                Type::V1(Version::<1, _>(ty)) => {
+                Type::V1(Version::<QUIC_V1, _>(ty)) => {
                     let ty: u8 = (*ty).into();
                     self.put_u8(LONG_HEADER_BIT | FIXED_BIT | ty);
-                    self.put_u32(1);
+                    self.put_u32(QUIC_V1);
+                }
+                Type::V2(ty) => {
+                    let ty: u8 = (*ty).into();
+                    self.put_u8(LONG_HEADER_BIT | FIXED_BIT | ty);
+                    self.put_u32(QUIC_V2);
                 }
             }
         }
@@ -84,8 +94,92 @@ pub mod ext {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use ring::hkdf::{KeyType, Prk, Salt, HKDF_SHA256};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    struct Len(usize);
+
+    impl KeyType for Len {
+        fn len(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn hkdf_expand_label(prk: &Prk, label: &[u8], len: usize) -> Vec<u8> {
+        let label = [b"tls13 ", label].concat();
+        let info = [
+            &(len as u16).to_be_bytes()[..],
+            &[label.len() as u8],
+            &label,
+            &[0],
+        ];
+        let mut out = vec![0u8; len];
+        prk.expand(&info, Len(len)).unwrap().fill(&mut out).unwrap();
+        out
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // 按照Appendix A.1的测试向量，从客户端的第一个目标连接ID推导客户端的Initial密钥
+    fn client_initial_keys(salt: &[u8], labels: [&[u8]; 3]) -> [Vec<u8>; 3] {
+        let dcid = from_hex("8394c8f03e515708");
+        let initial_secret = Salt::new(HKDF_SHA256, salt).extract(&dcid);
+        let client_secret = hkdf_expand_label(&initial_secret, b"client in", 32);
+        let client_secret = Prk::new_less_safe(HKDF_SHA256, &client_secret);
+        [
+            hkdf_expand_label(&client_secret, labels[0], 16),
+            hkdf_expand_label(&client_secret, labels[1], 12),
+            hkdf_expand_label(&client_secret, labels[2], 16),
+        ]
+    }
+
+    #[test]
+    fn test_initial_salt_and_labels() {
+        // See [Appendix A.1](https://www.rfc-editor.org/rfc/rfc9001.html#appendix-A.1) of QUIC-TLS.
+        let [key, iv, hp] = client_initial_keys(
+            &v1::INITIAL_SALT,
+            [v1::KEY_LABEL, v1::IV_LABEL, v1::HP_LABEL],
+        );
+        assert_eq!(key, from_hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(iv, from_hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(hp, from_hex("9f50449e04a0e810283a1e9933adedd2"));
+
+        // See [Appendix A.1](https://www.rfc-editor.org/rfc/rfc9369.html#appendix-A.1) of QUIC v2.
+        let [key, iv, hp] = client_initial_keys(
+            &v2::INITIAL_SALT,
+            [v2::KEY_LABEL, v2::IV_LABEL, v2::HP_LABEL],
+        );
+        assert_eq!(key, from_hex("8b1a0bc121284290a29e0971b5cd045d"));
+        assert_eq!(iv, from_hex("91f73e2351d8fa91660e909f"));
+        assert_eq!(hp, from_hex("45b95e15235d6f45a6b19cbcb0294ba9"));
+    }
+
+    #[test]
+    fn test_parse_long_type() {
+        let mut buf = Vec::new();
+        for ty in [
+            Type::V1(Version(v1::Type::Handshake)),
+            Type::V2(Version(v2::Type::Handshake)),
+        ] {
+            buf.clear();
+            ext::WriteLongType::put_long_type(&mut buf, &ty);
+            let (remain, parsed) = ext::parse_long_type(buf[0])(&buf[1..]).unwrap();
+            assert!(remain.is_empty());
+            assert_eq!(parsed, ty);
+        }
+        // 同一个Handshake包，v1与v2的类型位不同
+        assert_eq!(buf[0], 0xf0);
+        assert_eq!(&buf[1..], &QUIC_V2.to_be_bytes());
+        assert!(ext::parse_long_type(0xc0)(&[0, 0, 0, 3]).is_err());
+    }
 }
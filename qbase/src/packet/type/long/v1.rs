@@ -1,3 +1,24 @@
+/// The salt to derive the Initial secrets, see [Section 5.2](https://www.rfc-editor.org/rfc/rfc9001.html#section-5.2) of QUIC-TLS.
+pub const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// The labels of HKDF-Expand-Label to derive the packet protection keys from the secrets.
+pub const KEY_LABEL: &[u8] = b"quic key";
+pub const IV_LABEL: &[u8] = b"quic iv";
+pub const HP_LABEL: &[u8] = b"quic hp";
+pub const KU_LABEL: &[u8] = b"quic ku";
+
+/// The fixed key and nonce used to compute the Retry Integrity Tag,
+/// see [Section 5.8](https://www.rfc-editor.org/rfc/rfc9001.html#section-5.8) of QUIC-TLS.
+pub const RETRY_INTEGRITY_KEY: [u8; 16] = [
+    0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68, 0xc8, 0x4e,
+];
+pub const RETRY_INTEGRITY_NONCE: [u8; 12] = [
+    0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+];
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     Retry,
@@ -30,8 +51,8 @@ impl From<u8> for Type {
             INITIAL_PACKET_TYPE => Type::Initial,
             ZERO_RTT_PACKET_TYPE => Type::ZeroRtt,
             HANDSHAKE_PACKET_TYPE => Type::Handshake,
-            RETRY_PACKET_TYPE => Type::Retry,
-            _ => unreachable!(),
+            // 掩码之后只剩下RETRY_PACKET_TYPE
+            _ => Type::Retry,
         }
     }
 }
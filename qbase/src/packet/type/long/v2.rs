@@ -0,0 +1,75 @@
+//! QUIC version 2 is identical to version 1, except that the packet type codepoints are
+//! rotated, and the salts, labels and keys used by the cryptography are changed, see
+//! [RFC 9369](https://www.rfc-editor.org/rfc/rfc9369.html).
+use super::{Version, QUIC_V2};
+
+/// The packet types are the same as version 1, only encoded differently.
+pub use super::v1::Type;
+
+/// The salt to derive the Initial secrets, see [Section 3.3.1](https://www.rfc-editor.org/rfc/rfc9369.html#section-3.3.1) of QUIC v2.
+pub const INITIAL_SALT: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb,
+    0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+/// The labels of HKDF-Expand-Label, see [Section 3.3.2](https://www.rfc-editor.org/rfc/rfc9369.html#section-3.3.2) of QUIC v2.
+pub const KEY_LABEL: &[u8] = b"quicv2 key";
+pub const IV_LABEL: &[u8] = b"quicv2 iv";
+pub const HP_LABEL: &[u8] = b"quicv2 hp";
+pub const KU_LABEL: &[u8] = b"quicv2 ku";
+
+/// The fixed key and nonce used to compute the Retry Integrity Tag,
+/// see [Section 3.3.3](https://www.rfc-editor.org/rfc/rfc9369.html#section-3.3.3) of QUIC v2.
+pub const RETRY_INTEGRITY_KEY: [u8; 16] = [
+    0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce, 0xad, 0x7c, 0xcc, 0x92,
+];
+pub const RETRY_INTEGRITY_NONCE: [u8; 12] = [
+    0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+];
+
+/// The next two bits (those with a mask of 0x30) of byte 0 contain a packet type,
+/// see [Section 3.2](https://www.rfc-editor.org/rfc/rfc9369.html#section-3.2) of QUIC v2.
+const LONG_PACKET_TYPE_MASK: u8 = 0x30;
+const RETRY_PACKET_TYPE: u8 = 0x00;
+const INITIAL_PACKET_TYPE: u8 = 0x10;
+const ZERO_RTT_PACKET_TYPE: u8 = 0x20;
+const HANDSHAKE_PACKET_TYPE: u8 = 0x30;
+
+impl From<Version<QUIC_V2, Type>> for u8 {
+    fn from(value: Version<QUIC_V2, Type>) -> u8 {
+        match value.0 {
+            Type::Retry => RETRY_PACKET_TYPE,
+            Type::Initial => INITIAL_PACKET_TYPE,
+            Type::ZeroRtt => ZERO_RTT_PACKET_TYPE,
+            Type::Handshake => HANDSHAKE_PACKET_TYPE,
+        }
+    }
+}
+
+impl From<u8> for Version<QUIC_V2, Type> {
+    fn from(value: u8) -> Self {
+        Version(match value & LONG_PACKET_TYPE_MASK {
+            RETRY_PACKET_TYPE => Type::Retry,
+            INITIAL_PACKET_TYPE => Type::Initial,
+            ZERO_RTT_PACKET_TYPE => Type::ZeroRtt,
+            // 掩码之后只剩下HANDSHAKE_PACKET_TYPE
+            _ => Type::Handshake,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotated_types() {
+        for ty in [Type::Retry, Type::Initial, Type::ZeroRtt, Type::Handshake] {
+            let v1: u8 = ty.into();
+            let v2: u8 = Version::<QUIC_V2, _>(ty).into();
+            // v2的包类型是v1的包类型加1
+            assert_eq!(v2, (v1 + 0x10) & LONG_PACKET_TYPE_MASK);
+            assert_eq!(*Version::<QUIC_V2, Type>::from(0xc0 | v2), ty);
+        }
+    }
+}
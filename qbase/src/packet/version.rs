@@ -0,0 +1,301 @@
+//! Version negotiation, see [Section 6 of RFC 9000](https://www.rfc-editor.org/rfc/rfc9000.html#section-6),
+//! and compatible version negotiation, see [RFC 9368](https://www.rfc-editor.org/rfc/rfc9368.html).
+use super::{QUIC_V1, QUIC_V2};
+use crate::{
+    cid::{ConnectionId, WriteConnectionId, MAX_CID_SIZE},
+    config::VersionInformation,
+    error::{Error, ErrorKind},
+};
+use bytes::{BufMut, BytesMut};
+use thiserror::Error as ThisError;
+
+/// Versions that follow the pattern 0x?a?a?a?a are reserved for exercising version negotiation,
+/// see [Section 15 of RFC 9000](https://www.rfc-editor.org/rfc/rfc9000.html#section-15).
+pub fn is_grease_version(version: u32) -> bool {
+    version & 0x0f0f_0f0f == 0x0a0a_0a0a
+}
+
+/// Generate a random reserved version.
+pub fn grease_version() -> u32 {
+    rand::random::<u32>() & 0xf0f0_f0f0 | 0x0a0a_0a0a
+}
+
+/// QUIC v1 and v2 are compatible with each other, a ClientHello of one can be converted to the other.
+pub fn is_compatible(from: u32, to: u32) -> bool {
+    matches!(from, QUIC_V1 | QUIC_V2) && matches!(to, QUIC_V1 | QUIC_V2)
+}
+
+/// Read the version and connection IDs of a long header with the invariants of
+/// [RFC 8999](https://www.rfc-editor.org/rfc/rfc8999.html), the version may be unknown.
+/// Returns None if the datagram does not start with a long header, or the connection IDs are
+/// too long to be echoed back.
+pub fn peek_long_header(datagram: &[u8]) -> Option<(u32, ConnectionId, ConnectionId)> {
+    let (&first, remain) = datagram.split_first()?;
+    if first & 0x80 == 0 || remain.len() < 5 {
+        return None;
+    }
+    let version = u32::from_be_bytes([remain[0], remain[1], remain[2], remain[3]]);
+    let (dcid, remain) = peek_cid(&remain[4..])?;
+    let (scid, _) = peek_cid(remain)?;
+    Some((version, dcid, scid))
+}
+
+fn peek_cid(input: &[u8]) -> Option<(ConnectionId, &[u8])> {
+    let (&len, remain) = input.split_first()?;
+    let len = len as usize;
+    if len > MAX_CID_SIZE || remain.len() < len {
+        return None;
+    }
+    Some((ConnectionId::from_slice(&remain[..len]), &remain[len..]))
+}
+
+/// Build a Version Negotiation packet in response to a client's packet of an unsupported version.
+/// The `dcid` should be the client's source connection ID, and the `scid` should be the client's
+/// destination connection ID.
+pub fn build_version_negotiation_packet(
+    dcid: &ConnectionId,
+    scid: &ConnectionId,
+    versions: &[u32],
+) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(7 + dcid.len() + scid.len() + versions.len() * 4);
+    // 除了长包头标志，其余位是随机的，但0x40应当置1，以便与其他协议复用
+    buf.put_u8(0xc0 | (rand::random::<u8>() & 0x3f));
+    buf.put_u32(0);
+    buf.put_connection_id(dcid);
+    buf.put_connection_id(scid);
+    for version in versions {
+        buf.put_u32(*version);
+    }
+    buf
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, ThisError)]
+pub enum VnError {
+    /// The Version Negotiation packet lists the version the client attempted, it must be ignored.
+    #[error("the version negotiation packet lists the original version")]
+    Ignored,
+    #[error("no common version with the server")]
+    NoCommonVersion,
+}
+
+/// How the client reacts to a Version Negotiation packet: select the most preferred version of
+/// the client that the server supports. See [Section 6.2 of RFC 9000](https://www.rfc-editor.org/rfc/rfc9000.html#section-6.2).
+pub fn react_to_version_negotiation(
+    original_version: u32,
+    vn_versions: &[u32],
+    client_versions: &[u32],
+) -> Result<u32, VnError> {
+    if vn_versions.contains(&original_version) {
+        return Err(VnError::Ignored);
+    }
+    client_versions
+        .iter()
+        .find(|v| !is_grease_version(**v) && vn_versions.contains(v))
+        .copied()
+        .ok_or(VnError::NoCommonVersion)
+}
+
+/// The server chooses the negotiated version, the first version in its own preference that the
+/// client also supports and is compatible with the original version. Without the client's
+/// version information, the original version is kept.
+pub fn choose_compatible_version(
+    original_version: u32,
+    client_info: Option<&VersionInformation>,
+    server_versions: &[u32],
+) -> u32 {
+    let Some(client_info) = client_info else {
+        return original_version;
+    };
+    server_versions
+        .iter()
+        .find(|v| {
+            client_info.available_versions.contains(v) && is_compatible(original_version, **v)
+        })
+        .copied()
+        .unwrap_or(original_version)
+}
+
+/// The server validates the client's version information: the chosen version must be the one
+/// of the client's first Initial packet.
+pub fn validate_client_version_information(
+    original_version: u32,
+    client_info: Option<&VersionInformation>,
+) -> Result<(), Error> {
+    match client_info {
+        Some(info) if info.chosen_version != original_version => Err(Error::new_with_default_fty(
+            ErrorKind::VersionNegotiation,
+            format!(
+                "chosen version {:#x} mismatches the original version {original_version:#x}",
+                info.chosen_version
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The client validates the server's version information after the handshake:
+/// - the chosen version must be the negotiated version;
+/// - if the client has reacted to a Version Negotiation packet, the server's version information
+///   must be present, and the client would have chosen the same version from the server's available
+///   versions, otherwise the Version Negotiation packet was forged for a downgrade attack.
+pub fn validate_server_version_information(
+    original_version: u32,
+    negotiated_version: u32,
+    server_info: Option<&VersionInformation>,
+    client_versions_after_vn: Option<&[u32]>,
+) -> Result<(), Error> {
+    let error = |reason: String| Error::new_with_default_fty(ErrorKind::VersionNegotiation, reason);
+    if let Some(info) = server_info {
+        if info.chosen_version != negotiated_version {
+            return Err(error(format!(
+                "chosen version {:#x} mismatches the negotiated version {negotiated_version:#x}",
+                info.chosen_version
+            )));
+        }
+    }
+    if let Some(client_versions) = client_versions_after_vn {
+        let Some(info) = server_info else {
+            return Err(error(
+                "missing version information after version negotiation".to_string(),
+            ));
+        };
+        let expected = client_versions
+            .iter()
+            .find(|v| !is_grease_version(**v) && info.available_versions.contains(v));
+        if expected != Some(&original_version) {
+            return Err(error(format!(
+                "would have chosen {expected:#x?} instead of {original_version:#x} from the server's versions"
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Packet, PacketReader};
+
+    #[test]
+    fn test_grease_version() {
+        for _ in 0..16 {
+            let version = grease_version();
+            assert!(is_grease_version(version));
+        }
+        assert!(is_grease_version(0x1a2a3a4a));
+        assert!(!is_grease_version(QUIC_V1));
+        assert!(!is_grease_version(QUIC_V2));
+    }
+
+    #[test]
+    fn test_version_negotiation_packet() {
+        let dcid = ConnectionId::from_slice(&[1, 2, 3, 4]);
+        let scid = ConnectionId::from_slice(&[5, 6, 7, 8, 9]);
+        let grease = grease_version();
+        let buf = build_version_negotiation_packet(&dcid, &scid, &[QUIC_V1, QUIC_V2, grease]);
+        assert_eq!(peek_long_header(&buf), Some((0, dcid, scid)));
+
+        let mut reader = PacketReader::new(buf, 0);
+        match reader.next() {
+            Some(Ok(Packet::VN(vn))) => {
+                assert_eq!(vn.dcid, dcid);
+                assert_eq!(vn.scid, scid);
+                assert_eq!(vn.versions, vec![QUIC_V1, QUIC_V2, grease]);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_peek_unknown_version() {
+        let mut buf = vec![0xc0];
+        buf.extend_from_slice(&0x1a2a3a4au32.to_be_bytes());
+        buf.extend_from_slice(&[2, 1, 2, 1, 3]);
+        let (version, dcid, scid) = peek_long_header(&buf).unwrap();
+        assert_eq!(version, 0x1a2a3a4a);
+        assert_eq!(dcid, ConnectionId::from_slice(&[1, 2]));
+        assert_eq!(scid, ConnectionId::from_slice(&[3]));
+        // 短包头，以及过长的连接ID
+        assert_eq!(peek_long_header(&[0x40, 0, 0, 0, 1, 0, 0]), None);
+        assert_eq!(peek_long_header(&[0xc0, 0, 0, 0, 1, 21]), None);
+    }
+
+    #[test]
+    fn test_react_to_version_negotiation() {
+        let client_versions = [0x2a2a2a2a, QUIC_V2, QUIC_V1];
+        assert_eq!(
+            react_to_version_negotiation(QUIC_V1, &[QUIC_V1, QUIC_V2], &client_versions),
+            Err(VnError::Ignored)
+        );
+        assert_eq!(
+            react_to_version_negotiation(0x2a2a2a2a, &[QUIC_V1, QUIC_V2], &client_versions),
+            Ok(QUIC_V2)
+        );
+        assert_eq!(
+            react_to_version_negotiation(QUIC_V2, &[0xff00001d], &client_versions),
+            Err(VnError::NoCommonVersion)
+        );
+    }
+
+    #[test]
+    fn test_compatible_version() {
+        let client_info = VersionInformation {
+            chosen_version: QUIC_V1,
+            available_versions: vec![QUIC_V1, QUIC_V2],
+        };
+        assert_eq!(
+            choose_compatible_version(QUIC_V1, Some(&client_info), &[QUIC_V2, QUIC_V1]),
+            QUIC_V2
+        );
+        assert_eq!(
+            choose_compatible_version(QUIC_V1, None, &[QUIC_V2, QUIC_V1]),
+            QUIC_V1
+        );
+        assert!(validate_client_version_information(QUIC_V1, Some(&client_info)).is_ok());
+        assert!(validate_client_version_information(QUIC_V2, Some(&client_info)).is_err());
+    }
+
+    #[test]
+    fn test_downgrade_detection() {
+        let server_info = VersionInformation {
+            chosen_version: QUIC_V1,
+            available_versions: vec![QUIC_V1, QUIC_V2],
+        };
+        let client_versions = [QUIC_V2, QUIC_V1];
+        // 没有经过版本协商，只需选定的版本一致
+        assert!(validate_server_version_information(QUIC_V1, QUIC_V1, None, None).is_ok());
+        assert!(
+            validate_server_version_information(QUIC_V1, QUIC_V2, Some(&server_info), None)
+                .is_err()
+        );
+        // 伪造的版本协商包让客户端选择了v1，但服务器其实支持客户端更偏好的v2
+        let error = validate_server_version_information(
+            QUIC_V1,
+            QUIC_V1,
+            Some(&server_info),
+            Some(&client_versions),
+        )
+        .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::VersionNegotiation);
+        assert!(validate_server_version_information(
+            QUIC_V1,
+            QUIC_V1,
+            None,
+            Some(&client_versions)
+        )
+        .is_err());
+        // 服务器确实只支持v1
+        let server_info = VersionInformation {
+            chosen_version: QUIC_V1,
+            available_versions: vec![QUIC_V1],
+        };
+        assert!(validate_server_version_information(
+            QUIC_V1,
+            QUIC_V1,
+            Some(&server_info),
+            Some(&client_versions)
+        )
+        .is_ok());
+    }
+}
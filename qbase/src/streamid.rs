@@ -5,7 +5,7 @@ use std::{
 };
 use thiserror::Error;
 
-/**
+/*
  * QUIC有4种流类型，对应着4个流ID空间，分别是：
  * | 低2位 | 流类型 ｜
  * | 0x00 | 客户端创建的双向流 |
@@ -156,7 +156,7 @@ pub mod ext {
         })((input, 0))
         .map_err(|err| match err {
             nom::Err::Incomplete(needed) => {
-                nom::Err::Incomplete(needed.map(|n| (n.get() + 7) / 8 - input.len()))
+                nom::Err::Incomplete(needed.map(|n| n.get().div_ceil(8) - input.len()))
             }
             _ => unreachable!(),
         })
@@ -188,9 +188,9 @@ pub mod ext {
                 self.put_u8(x as u8);
             } else if x < 1u64 << 14 {
                 self.put_u16(0b01 << 14 | x as u16);
-            } else if x < 2u64 << 30 {
+            } else if x < 1u64 << 30 {
                 self.put_u32(0b10 << 30 | x as u32);
-            } else if x < 2u64 << 62 {
+            } else if x < 1u64 << 62 {
                 self.put_u64(0b11 << 62 | x);
             } else {
                 unreachable!("malformed VarInt")
@@ -241,4 +241,15 @@ mod tests {
         buf.put_u16(65535);
         assert_eq!(buf, vec![64, 255, 255, 255]);
     }
+
+    #[test]
+    fn test_varint_boundaries() {
+        for x in [63, 64, 16383, 16384, (1 << 30) - 1, 1 << 30, (1 << 62) - 1] {
+            let val = VarInt(x);
+            let mut buf = vec![];
+            buf.put_varint(&val);
+            assert_eq!(buf.len(), val.encoding_size());
+            assert_eq!(super::ext::be_varint(&buf), Ok((&[][..], val)));
+        }
+    }
 }
@@ -232,7 +232,8 @@ impl BBRState {
             return;
         }
 
-        match self.state {
+        let state = self.state;
+        match state {
             BBRStateMachine::ProbeBWDOWN => {
                 if self.check_time_to_probe_bw(now) {
                     // Already decided state transition.
@@ -248,21 +249,18 @@ impl BBRState {
                 self.check_time_to_probe_bw(now);
             }
 
-            BBRStateMachine::ProbeBWREFILL => {
-                // After one round of REFILL, start UP.
-                if self.round_start {
-                    self.bw_probe_samples = true;
+            // After one round of REFILL, start UP.
+            BBRStateMachine::ProbeBWREFILL if self.round_start => {
+                self.bw_probe_samples = true;
 
-                    self.start_probe_bw_up(now);
-                }
+                self.start_probe_bw_up(now);
             }
 
-            BBRStateMachine::ProbeBWUP => {
+            BBRStateMachine::ProbeBWUP
                 if self.has_elapsed_in_phase(self.min_rtt, now)
-                    && self.bytes_in_flight > self.inflight(self.max_bw, 1.25)
-                {
-                    self.start_probe_bw_down(now);
-                }
+                    && self.bytes_in_flight > self.inflight(self.max_bw, 1.25) =>
+            {
+                self.start_probe_bw_down(now);
             }
 
             _ => (),
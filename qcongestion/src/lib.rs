@@ -11,12 +11,15 @@ pub enum CongestionAlgorithm {
     Bbr,
 }
 
+// TODO: 尚未接入连接的收发，接入之前这些字段和方法都用不到
+#[allow(dead_code)]
 pub struct CongestionState {
     cc: Box<dyn CongestionControl>,
     sent_packets: [HashMap<u64, Sent>; 3],
     time_of_last_sent_ack_eliciting_pkt: [Option<Instant>; 3],
 }
 
+#[allow(dead_code)]
 impl CongestionState {
     fn new(algorithm: CongestionAlgorithm) -> Self {
         let cc = match algorithm {
@@ -64,7 +67,7 @@ impl CongestionState {
         }
     }
 
-    fn on_packet_acked(&mut self, packet_number: u64, pn_space: u8, ack_delay: Duration) {
+    fn on_packet_acked(&mut self, packet_number: u64, pn_space: u8, _ack_delay: Duration) {
         let now = Instant::now();
         let sent = self.sent_packets[pn_space as usize]
             .remove(&packet_number)
@@ -86,7 +89,7 @@ impl CongestionState {
         todo!("on_packet_acked")
     }
 
-    fn on_packet_lost(packet_number: u64, pn_space: u8) {
+    fn on_packet_lost(_packet_number: u64, _pn_space: u8) {
         todo!("on_packet_lost")
     }
 
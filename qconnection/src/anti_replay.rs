@@ -0,0 +1,93 @@
+use qbase::cid::ConnectionId;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The default window in which the replays of a ClientHello are detected, which covers
+/// the 60 seconds of ticket age skew tolerated by rustls, a ClientHello replayed later
+/// is not fresh and its early data is rejected by rustls.
+pub const DEFAULT_REPLAY_WINDOW: Duration = Duration::from_secs(60);
+
+/// 0-RTT data is not protected against replay, the server decides whether to accept the
+/// 0-RTT data of a new connection with this policy, see [Section 9.2](https://www.rfc-editor.org/rfc/rfc9001.html#section-9.2) of QUIC-TLS
+/// and [Section 8](https://www.rfc-editor.org/rfc/rfc8446.html#section-8) of TLS 1.3.
+///
+/// The policy works along with the TLS layer, the rustls::ServerConfig must allow early
+/// data by setting max_early_data_size to 0xffffffff, and issue single-use tickets from
+/// a stateful session storage rather than stateless tickets.
+pub trait AntiReplay: Send + Sync {
+    /// `origin_dcid` is the Destination Connection ID of the client's first Initial packet,
+    /// which is the same in a replayed Initial packet.
+    fn accept_0rtt(&self, origin_dcid: &ConnectionId, peer_addr: &SocketAddr) -> bool;
+}
+
+/// Rejects the 0-RTT data of a connection whose original Destination Connection ID has
+/// been seen in the recent window, once the capacity is reached, all 0-RTT data is rejected
+/// until some records expire.
+#[derive(Debug)]
+pub struct RecentOriginDcids {
+    seen: Mutex<HashMap<ConnectionId, Instant>>,
+    window: Duration,
+    capacity: usize,
+}
+
+impl RecentOriginDcids {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            seen: Mutex::new(HashMap::new()),
+            window,
+            capacity,
+        }
+    }
+}
+
+impl Default for RecentOriginDcids {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLAY_WINDOW, 1 << 16)
+    }
+}
+
+impl AntiReplay for RecentOriginDcids {
+    fn accept_0rtt(&self, origin_dcid: &ConnectionId, _peer_addr: &SocketAddr) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(at) = seen.get(origin_dcid) {
+            if at.elapsed() < self.window {
+                return false;
+            }
+        }
+        if seen.len() >= self.capacity {
+            seen.retain(|_, at| at.elapsed() < self.window);
+            if seen.len() >= self.capacity {
+                return false;
+            }
+        }
+        seen.insert(*origin_dcid, Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_origin_dcids() {
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let filter = RecentOriginDcids::new(DEFAULT_REPLAY_WINDOW, 2);
+        let odcid = ConnectionId::random_gen(8);
+        assert!(filter.accept_0rtt(&odcid, &peer_addr));
+        // 重放的Initial包，原始目标连接id相同
+        assert!(!filter.accept_0rtt(&odcid, &peer_addr));
+
+        assert!(filter.accept_0rtt(&ConnectionId::random_gen(8), &peer_addr));
+        // 容量已满，拒绝所有0RTT
+        assert!(!filter.accept_0rtt(&ConnectionId::random_gen(8), &peer_addr));
+
+        let filter = RecentOriginDcids::new(Duration::ZERO, 2);
+        assert!(filter.accept_0rtt(&odcid, &peer_addr));
+        assert!(filter.accept_0rtt(&odcid, &peer_addr));
+    }
+}
@@ -1,57 +1,81 @@
-use crate::{crypto::TlsIO, frame_queue::ArcFrameQueue, path::ArcPath};
+use crate::{
+    cid::ArcPeerCids,
+    connection::PeerMigration,
+    crypto::{ArcHandshakeCids, TlsIO},
+    error::ArcConnError,
+    frame_queue::ArcFrameQueue,
+    handshake::ArcHandshake,
+    path::ArcPath,
+    token::ArcTokenSink,
+};
 use futures::StreamExt;
 use qbase::{
+    crypto::KeyChange,
     error::{Error, ErrorKind},
-    frame::{BeFrame, ConnFrame, Frame, FrameReader, PureFrame},
+    frame::{BeFrame, ConnFrame, Frame, FrameReader, PureFrame, RetireConnectionIdFrame},
     packet::{
         decrypt::{DecodeHeader, DecryptPacket, RemoteProtection},
+        header::GetScid,
         keys::{ArcKeys, ArcOneRttKeys},
         OneRttPacket, PacketNumber,
     },
+    streamid::Role,
+    varint::VarInt,
     SpaceId,
 };
 use qrecovery::{
-    crypto::{CryptoStreamReader, CryptoStreamWriter},
-    space::{Receive, SpaceFrame},
+    crypto::{CryptoStream, CryptoStreamReader, CryptoStreamWriter},
+    space::{Receive, SpaceFrame, SpaceIO},
+    streams::Streams,
+};
+use rustls::AlertDescription;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
 };
-use rustls::quic::KeyChange;
-use tokio::sync::mpsc;
 
+/// Returns whether the packet is ack-eliciting, and whether it is a probing packet, which
+/// only contains PATH_CHALLENGE, PATH_RESPONSE, NEW_CONNECTION_ID and PADDING frames, see
+/// [Section 9.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-9.1) of QUIC.
 fn parse_packet_and_then_dispatch(
     payload: bytes::Bytes,
     space_id: SpaceId,
     path: &ArcPath,
     conn_frames: &ArcFrameQueue<ConnFrame>,
     space_frames: &ArcFrameQueue<SpaceFrame>,
-) -> Result<bool, Error> {
+) -> Result<(bool, bool), Error> {
     let mut space_frame_writer = space_frames.writer();
     let mut conn_frame_writer = conn_frames.writer();
-    let mut path_frame_writer = path.frames().writer();
+    // Path帧在整个包解析成功之后才交给Path处理，解析失败则丢弃
+    let mut path_frames = Vec::new();
     let mut frame_reader = FrameReader::new(payload);
     let mut is_ack_eliciting = false;
-    while let Some(result) = frame_reader.next() {
+    let mut is_probing = true;
+    for result in frame_reader.by_ref() {
         match result {
             Ok(frame) => match frame {
                 Frame::Padding => continue,
-                Frame::Ping(_) => is_ack_eliciting = true,
+                Frame::Ping(_) => {
+                    is_ack_eliciting = true;
+                    is_probing = false;
+                }
                 Frame::Ack(ack) => {
                     if !ack.belongs_to(space_id) {
                         space_frame_writer.rollback();
                         conn_frame_writer.rollback();
-                        path_frame_writer.rollback();
                         return Err(Error::new(
                             ErrorKind::ProtocolViolation,
                             ack.frame_type(),
                             format!("cann't be received in {}", space_id),
                         ));
                     }
+                    is_probing = false;
                     space_frame_writer.push(SpaceFrame::Ack(ack, path.rtt()));
                 }
                 Frame::Pure(f) => {
                     if !f.belongs_to(space_id) {
                         space_frame_writer.rollback();
                         conn_frame_writer.rollback();
-                        path_frame_writer.rollback();
                         return Err(Error::new(
                             ErrorKind::ProtocolViolation,
                             f.frame_type(),
@@ -61,16 +85,21 @@ fn parse_packet_and_then_dispatch(
 
                     is_ack_eliciting = true;
                     match f {
-                        PureFrame::Conn(f) => conn_frame_writer.push(f),
-                        PureFrame::Stream(f) => space_frame_writer.push(SpaceFrame::Stream(f)),
-                        PureFrame::Path(f) => path_frame_writer.push(f),
+                        PureFrame::Conn(f) => {
+                            is_probing &= matches!(f, ConnFrame::NewConnectionId(_));
+                            conn_frame_writer.push(f)
+                        }
+                        PureFrame::Stream(f) => {
+                            is_probing = false;
+                            space_frame_writer.push(SpaceFrame::Stream(f))
+                        }
+                        PureFrame::Path(f) => path_frames.push(f),
                     }
                 }
                 Frame::Data(f, data) => {
                     if !f.belongs_to(space_id) {
                         space_frame_writer.rollback();
                         conn_frame_writer.rollback();
-                        path_frame_writer.rollback();
                         return Err(Error::new(
                             ErrorKind::ProtocolViolation,
                             f.frame_type(),
@@ -79,6 +108,7 @@ fn parse_packet_and_then_dispatch(
                     }
 
                     is_ack_eliciting = true;
+                    is_probing = false;
                     space_frame_writer.push(SpaceFrame::Data(f, data));
                 }
             },
@@ -87,12 +117,14 @@ fn parse_packet_and_then_dispatch(
                 // as if this packet has never been received.
                 space_frame_writer.rollback();
                 conn_frame_writer.rollback();
-                path_frame_writer.rollback();
                 return Err(e.into());
             }
         }
     }
-    Ok(is_ack_eliciting)
+    for frame in path_frames {
+        path.recv_frame(frame);
+    }
+    Ok((is_ack_eliciting, is_probing))
 }
 
 /// This function concatenates the reading logic of all Spaces except the 1RTT Space. Just pass in the Space and Keys,
@@ -101,8 +133,14 @@ fn parse_packet_and_then_dispatch(
 ///   unpack, decode frames, and then write the frames into the corresponding receiving frame queue.
 /// - *Frame reading task*: Continuously read frames from the receiving frame queue, hand them over to Space for
 ///   processing, or handle Path frames with Path when encountered.
+///
 /// Finally, it returns the sending end of the packet receiving queue, which can be used to write packets into this
 /// queue when receiving packets for this space.
+///
+/// `peer_cids` is only given for the Initial space of the client, which switches to the
+/// Source Connection ID chosen by the server once its first Initial packet is processed,
+/// see [Section 7.2](https://www.rfc-editor.org/rfc/rfc9000.html#section-7.2) of QUIC.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn loop_read_long_packet_and_then_dispatch_to_space_frame_queue<P, S>(
     mut packet_rx: mpsc::UnboundedReceiver<(P, ArcPath)>,
     space_id: SpaceId,
@@ -110,23 +148,32 @@ pub(crate) async fn loop_read_long_packet_and_then_dispatch_to_space_frame_queue
     space: S,
     conn_frame_queue: ArcFrameQueue<ConnFrame>,
     space_frame_queue: ArcFrameQueue<SpaceFrame>,
-    need_close_space_frame_queue_at_end: bool,
+    handshake: ArcHandshake,
+    peer_cids: Option<ArcPeerCids>,
 ) where
     S: Receive,
-    P: DecodeHeader<Output = PacketNumber> + DecryptPacket + RemoteProtection,
+    P: DecodeHeader<Output = PacketNumber> + DecryptPacket + RemoteProtection + GetScid,
 {
     while let Some((mut packet, path)) = packet_rx.recv().await {
+        let scid = *packet.get_scid();
         if let Some(k) = keys.get_remote_keys().await {
-            let ok = packet.remove_protection(&k.as_ref().remote.header);
+            let ok = packet.remove_protection(&*k.remote.header);
             if !ok {
                 // Failed to remove packet header protection, just discard it.
                 continue;
             }
 
-            let pn = packet.decode_header().unwrap();
+            let Ok(pn) = packet.decode_header() else {
+                // 保留位不为0，本应在解密之后以PROTOCOL_VIOLATION关闭连接
+                // TODO: 解密成功后再以PROTOCOL_VIOLATION关闭连接，目前仅丢弃
+                continue;
+            };
             let pkt_id = pn.decode(space.expected_pn());
-            match packet.decrypt_packet(pkt_id, pn.size(), &k.as_ref().remote.packet) {
+            match packet.decrypt_packet(pkt_id, pn.size(), &*k.remote.packet) {
+                // 去除保护之后才能判断重复，重复的或者太旧的包直接丢弃，不能再次分发其中的帧
+                Ok(_) if space.is_duplicate(pkt_id) => continue,
                 Ok(payload) => {
+                    let is_first = space.expected_pn() == 0;
                     match parse_packet_and_then_dispatch(
                         payload,
                         space_id,
@@ -134,13 +181,24 @@ pub(crate) async fn loop_read_long_packet_and_then_dispatch_to_space_frame_queue
                         &conn_frame_queue,
                         &space_frame_queue,
                     ) {
-                        Ok(is_ack_eliciting) => space.record(pkt_id, is_ack_eliciting),
+                        Ok((is_ack_eliciting, _)) => space.record(pkt_id, is_ack_eliciting),
                         Err(_e) => {
                             // 解析包失败，丢弃
                             // TODO: 该包要认的话，还得向对方返回错误信息，并终止连接
                             continue;
                         }
                     }
+                    // 之后的Initial包中的连接id即便变了，也不再理会
+                    if let Some(peer_cids) = peer_cids.as_ref().filter(|_| is_first) {
+                        path.set_dcid(scid);
+                        peer_cids.set_initial(scid);
+                    }
+                    // A Handshake packet can only be processed by the peer who received our
+                    // Initial packet, which proves the peer owns the address.
+                    if space_id == SpaceId::Handshake {
+                        path.anti_amplifier().grant();
+                        handshake.on_handshake_keys_used();
+                    }
                 }
                 // Decryption failed, just ignore/discard it.
                 Err(_) => continue,
@@ -150,7 +208,8 @@ pub(crate) async fn loop_read_long_packet_and_then_dispatch_to_space_frame_queue
         }
     }
 
-    if need_close_space_frame_queue_at_end {
+    // 0RTT与1RTT共用收帧队列，由1RTT的收包任务负责关闭
+    if space_id != SpaceId::ZeroRtt {
         space_frame_queue.close();
     }
 }
@@ -161,22 +220,40 @@ pub(crate) async fn loop_read_short_packet_and_then_dispatch_to_space_frame_queu
     space: impl Receive,
     conn_frame_queue: ArcFrameQueue<ConnFrame>,
     space_frame_queue: ArcFrameQueue<SpaceFrame>,
+    error: ArcConnError,
+    migration: PeerMigration,
 ) {
     while let Some((mut packet, path)) = packet_rx.recv().await {
         // 1rtt空间的header protection key是固定的，packet key则是根据包头中的key_phase_bit变化的
         if let Some((hk, pk)) = keys.get_remote_keys().await {
-            let ok = packet.remove_protection(&hk.as_ref());
+            let ok = packet.remove_protection(hk.as_ref());
             if !ok {
                 // Failed to remove packet header protection, just discard it.
                 continue;
             }
 
-            let (pn, key_phase) = packet.decode_header().unwrap();
+            let Ok((pn, key_phase)) = packet.decode_header() else {
+                // TODO: 解密成功后再以PROTOCOL_VIOLATION关闭连接，目前仅丢弃
+                continue;
+            };
             let pkt_id = pn.decode(space.expected_pn());
             // 要根据key_phase_bit来获取packet key
             let pkt_key = pk.lock().unwrap().get_remote(key_phase, pkt_id);
-            match packet.decrypt_packet(pkt_id, pn.size(), &pkt_key.as_ref()) {
+            match packet.decrypt_packet(pkt_id, pn.size(), pkt_key.as_ref()) {
+                Ok(_) if space.is_duplicate(pkt_id) => continue,
                 Ok(payload) => {
+                    // 包通过认证之后，来自新地址的Path才会被创建和验证
+                    let path = migration.on_authenticated(path);
+                    // 成功解密之后，才能确认对方发起的密钥更新
+                    let pto = path.rtt().lock().unwrap().pto_base_duration(0);
+                    if let Err(e) = pk
+                        .lock()
+                        .unwrap()
+                        .on_packet_decrypted(key_phase, pkt_id, pto)
+                    {
+                        error.on_error(e);
+                        continue;
+                    }
                     match parse_packet_and_then_dispatch(
                         payload,
                         SpaceId::OneRtt,
@@ -184,7 +261,13 @@ pub(crate) async fn loop_read_short_packet_and_then_dispatch_to_space_frame_queu
                         &conn_frame_queue,
                         &space_frame_queue,
                     ) {
-                        Ok(is_ack_eliciting) => space.record(pkt_id, is_ack_eliciting),
+                        Ok((is_ack_eliciting, is_probing)) => {
+                            // 只有收到最大包号的非探测包时，才切换到该Path上
+                            if !is_probing && pkt_id >= space.expected_pn() {
+                                migration.on_non_probing(&path);
+                            }
+                            space.record(pkt_id, is_ack_eliciting)
+                        }
                         Err(_e) => {
                             // 解析包失败，丢弃
                             // TODO: 该包要认的话，还得向对方返回错误信息，并终止连接
@@ -192,8 +275,13 @@ pub(crate) async fn loop_read_short_packet_and_then_dispatch_to_space_frame_queu
                         }
                     }
                 }
-                // Decryption failed, just ignore/discard it.
-                Err(_) => continue,
+                // Decryption failed, just ignore/discard it, unless too many packets failed.
+                Err(_) => {
+                    if let Err(e) = pk.lock().unwrap().on_decryption_failed() {
+                        error.on_error(e);
+                    }
+                    continue;
+                }
             }
         } else {
             break;
@@ -215,50 +303,225 @@ pub(crate) async fn loop_read_space_frame_and_dispatch_to_space(
     }
 }
 
+/// Continuously read from the connection frame queue, and handle the frames that belong
+/// to the connection rather than any space or path.
+pub(crate) async fn loop_read_conn_frame_and_dispatch(
+    mut conn_frames_queue: ArcFrameQueue<ConnFrame>,
+    role: Role,
+    token_sink: ArcTokenSink,
+    peer_cids: ArcPeerCids,
+    data_space: SpaceIO<CryptoStream, Streams>,
+    handshake: ArcHandshake,
+    error: ArcConnError,
+) {
+    while let Some(frame) = conn_frames_queue.next().await {
+        match frame {
+            // 进入draining状态，不再发送任何包
+            ConnFrame::Close(close) => error.on_error(close),
+            ConnFrame::HandshakeDone(handshake_done) => match role {
+                Role::Client => handshake.confirm(),
+                // 客户端不能发送HANDSHAKE_DONE帧，见RFC 9000 19.20节
+                Role::Server => error.on_error(Error::new(
+                    ErrorKind::ProtocolViolation,
+                    handshake_done.frame_type(),
+                    "HANDSHAKE_DONE frame from the client",
+                )),
+            },
+            ConnFrame::NewConnectionId(new_cid) => match peer_cids.recv_new_cid(&new_cid) {
+                Ok(retired) => {
+                    for sequence in retired {
+                        data_space.write_frame(PureFrame::Conn(ConnFrame::RetireConnectionId(
+                            RetireConnectionIdFrame {
+                                sequence: VarInt::from_u64(sequence).unwrap(),
+                            },
+                        )));
+                    }
+                }
+                Err(_error) => {
+                    // TODO: 以相应的错误关闭连接
+                }
+            },
+            ConnFrame::NewToken(new_token) => match role {
+                Role::Client => token_sink.save(new_token.token),
+                // 客户端不能发送NEW_TOKEN帧，见RFC 9000 19.7节
+                Role::Server => error.on_error(Error::new(
+                    ErrorKind::ProtocolViolation,
+                    new_token.frame_type(),
+                    "NEW_TOKEN frame from the client",
+                )),
+            },
+            _ => {
+                // TODO: 处理其他连接级别的帧
+            }
+        }
+    }
+}
+
 async fn exchange_hs(
     tls_session: TlsIO,
     (stream_reader, stream_writer): (CryptoStreamReader, CryptoStreamWriter),
-) -> std::io::Result<KeyChange> {
+) -> std::io::Result<(KeyChange, CryptoStreamReader)> {
     let (tls_reader, tls_writer) = tls_session.split_io();
-    let loop_read = tls_reader.loop_read_from(stream_reader);
+    let mut loop_read = tls_reader.loop_read_from(stream_reader);
     let mut poll_writer = tls_writer.write_to(stream_writer);
-    let key_change = poll_writer.loop_write().await?;
-    loop_read.end().await?;
-    Ok(key_change)
+    let key_change = tokio::select! {
+        result = poll_writer.loop_write() => result?,
+        e = loop_read.stopped() => return Err(e),
+    };
+    let stream_reader = loop_read.end().await?;
+    Ok((key_change, stream_reader))
+}
+
+/// The server gets the 1-RTT keys after sending its Finished, but the handshake is not
+/// complete until the client's Finished is read from the Handshake crypto stream.
+async fn read_hs_until_complete(
+    tls_session: &TlsIO,
+    mut stream_reader: CryptoStreamReader,
+) -> std::io::Result<()> {
+    let (mut tls_reader, _) = tls_session.split_io();
+    let mut buf = vec![0u8; 1500];
+    while tls_session.is_handshaking() {
+        let n = stream_reader.read(&mut buf).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        tls_reader
+            .read_hs(&buf[..n])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(())
 }
 
+/// The server knows whether the 0-RTT data is accepted once the ClientHello is read,
+/// meanwhile the Handshake keys are available. The client installs its 0-RTT keys on
+/// its own when the connection is created.
+/// The connection is closed with a CRYPTO_ERROR if TLS fails, such as no application
+/// protocol in common.
 pub(crate) async fn exchange_initial_crypto_msg_until_getting_handshake_key(
     tls_session: TlsIO,
     handshake_keys: ArcKeys,
+    zero_rtt_keys: ArcKeys,
     initial_crypto_handler: (CryptoStreamReader, CryptoStreamWriter),
+    error: ArcConnError,
 ) {
-    match exchange_hs(tls_session, initial_crypto_handler).await {
-        Ok(key_change) => match key_change {
+    match exchange_hs(tls_session.clone(), initial_crypto_handler).await {
+        Ok((key_change, _)) => match key_change {
             KeyChange::Handshake { keys } => {
+                if tls_session.role() == Role::Server {
+                    match tls_session.zero_rtt_keys() {
+                        Some(keys) => zero_rtt_keys.set_keys(keys),
+                        // 拒绝了0RTT，收到的0RTT数据包都直接丢弃
+                        None => zero_rtt_keys.invalid(),
+                    }
+                }
                 handshake_keys.set_keys(keys);
             }
             _ => unreachable!(),
         },
-        Err(_) => {
-            todo!()
-        }
+        Err(e) => error.on_error(tls_session.crypto_error(&e)),
     }
 }
 
+/// For the client, `handshake_cids` is used to authenticate the connection IDs with the
+/// server's transport parameters, before the 1-RTT keys are installed. The connection is
+/// closed with a TRANSPORT_PARAMETER_ERROR or VERSION_NEGOTIATION_ERROR if the authentication
+/// fails, or with a CRYPTO_ERROR if TLS fails.
+/// It starts after the Handshake keys are installed, otherwise it would compete with the
+/// Initial exchange for the key change of the same TLS session.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn exchange_handshake_crypto_msg_until_getting_1rtt_key(
     tls_session: TlsIO,
+    handshake_keys: ArcKeys,
     one_rtt_keys: ArcOneRttKeys,
     handshake_crypto_handler: (CryptoStreamReader, CryptoStreamWriter),
+    handshake_cids: Option<ArcHandshakeCids>,
+    mut data_space: SpaceIO<CryptoStream, Streams>,
+    handshake: ArcHandshake,
+    error: ArcConnError,
 ) {
-    match exchange_hs(tls_session, handshake_crypto_handler).await {
-        Ok(key_change) => match key_change {
+    if handshake_keys.get_local_keys().await.is_none() {
+        return;
+    }
+    match exchange_hs(tls_session.clone(), handshake_crypto_handler).await {
+        Ok((key_change, stream_reader)) => match key_change {
             KeyChange::OneRtt { keys, next } => {
+                if let Some(cids) = handshake_cids {
+                    let result = match tls_session.peer_transport_parameters() {
+                        Some(Ok(params)) => cids
+                            .lock()
+                            .unwrap()
+                            .authenticate(&params, tls_session.version()),
+                        Some(Err(e)) => Err(e),
+                        None => Err(Error::new(
+                            ErrorKind::TransportParameter,
+                            qbase::frame::FrameType::Crypto,
+                            "missing transport parameters",
+                        )),
+                    };
+                    if let Err(e) = result {
+                        // 未经认证的对端，不再接收其1RTT数据包
+                        one_rtt_keys.invalid();
+                        error.on_error(e);
+                        return;
+                    }
+                }
+                if let Some(Ok(params)) = tls_session.peer_transport_parameters() {
+                    data_space.set_peer_reset_stream_at(params.reset_stream_at());
+                }
+                // Upgrade the data space before the keys are ready, so that the 1-RTT frames
+                // can be written into it as soon as the 1-RTT keys are available.
+                data_space.upgrade();
+                // 服务端拒绝了0RTT数据，0RTT包都视为丢失，其中的数据在1RTT包中重传
+                if tls_session.role() == Role::Client && !tls_session.is_early_data_accepted() {
+                    data_space.retransmit_all_inflight();
+                }
                 one_rtt_keys.set_keys(keys, next);
+                match read_hs_until_complete(&tls_session, stream_reader).await {
+                    Ok(()) => handshake.complete(),
+                    Err(e) => error.on_error(tls_session.crypto_error(&e)),
+                }
             }
             _ => unreachable!(),
         },
-        Err(_) => {
-            todo!()
+        Err(e) => error.on_error(tls_session.crypto_error(&e)),
+    }
+}
+
+/// After the handshake is complete, TLS messages are still exchanged in the 1-RTT CRYPTO
+/// frames for the whole lifetime of the connection, such as the NewSessionTicket messages
+/// from the server. The tickets received by the client are kept in the session store of
+/// its rustls::ClientConfig, for resuming the session and sending 0-RTT data later.
+/// Returns a CRYPTO_ERROR if TLS fails to process the messages.
+pub(crate) async fn exchange_post_handshake_crypto_msg(
+    tls_session: TlsIO,
+    handshake: ArcHandshake,
+    (mut stream_reader, mut stream_writer): (CryptoStreamReader, CryptoStreamWriter),
+) -> Result<(), Error> {
+    handshake.completed().await;
+    let (mut tls_reader, tls_writer) = tls_session.split_io();
+    let mut buf = vec![0u8; 1500];
+    loop {
+        tokio::select! {
+            result = stream_reader.read(&mut buf) => match result {
+                // 连接关闭，收帧队列也随之关闭
+                Ok(0) | Err(_) => return Ok(()),
+                Ok(n) => tls_reader
+                    .read_hs(&buf[..n])
+                    .map_err(|e| tls_session.crypto_error(&e))?,
+            },
+            (data, key_change) = tls_writer.clone() => {
+                if key_change.is_some() {
+                    // QUIC中不使用TLS的KeyUpdate消息，握手之后不会再有新的密钥
+                    return Err(Error::new(
+                        ErrorKind::Crypto(AlertDescription::UnexpectedMessage.get_u8()),
+                        qbase::frame::FrameType::Crypto,
+                        "unexpected key change after handshake",
+                    ));
+                }
+                if stream_writer.write_all(&data).await.is_err() {
+                    return Ok(());
+                }
+            },
         }
     }
 }
@@ -0,0 +1,199 @@
+use qbase::{
+    cid::{ConnectionId, ResetToken},
+    error::{Error, ErrorKind},
+    frame::{FrameType, NewConnectionIdFrame},
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// The connection IDs issued by the peer in NEW_CONNECTION_ID frames, which are used as
+/// the Destination Connection ID of the packets sent to the peer, see
+/// [Section 5.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-5.1) of QUIC.
+#[derive(Debug)]
+struct PeerCids {
+    // 序号 -> (连接id, 无状态重置令牌)，已退役的连接id会被移除
+    cids: BTreeMap<u64, (ConnectionId, ResetToken)>,
+    // 已经被某个Path使用过的连接id的序号，一个连接id不能在多个Path上使用
+    used: HashSet<u64>,
+    retire_prior_to: u64,
+    // 自己的传输参数active_connection_id_limit
+    limit: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArcPeerCids(Arc<Mutex<PeerCids>>);
+
+impl ArcPeerCids {
+    pub fn new(limit: u64) -> Self {
+        Self(Arc::new(Mutex::new(PeerCids {
+            cids: BTreeMap::new(),
+            used: HashSet::new(),
+            retire_prior_to: 0,
+            limit,
+        })))
+    }
+
+    /// The connection ID chosen by the peer during the handshake has sequence number 0,
+    /// and it is in use by the first path.
+    pub fn set_initial(&self, cid: ConnectionId) {
+        let mut guard = self.0.lock().unwrap();
+        guard.cids.insert(0, (cid, ResetToken::default()));
+        guard.used.insert(0);
+    }
+
+    /// The connection ID in the server's preferred_address transport parameter has
+    /// sequence number 1, and it is used by the path to the preferred address.
+    pub fn set_preferred(&self, cid: ConnectionId, reset_token: ResetToken) {
+        let mut guard = self.0.lock().unwrap();
+        guard.cids.insert(1, (cid, reset_token));
+        guard.used.insert(1);
+    }
+
+    /// Returns the sequence numbers of the connection IDs that need to be retired,
+    /// which should be sent in RETIRE_CONNECTION_ID frames.
+    pub fn recv_new_cid(&self, frame: &NewConnectionIdFrame) -> Result<Vec<u64>, Error> {
+        let mut guard = self.0.lock().unwrap();
+        let sequence = frame.sequence.into_inner();
+        let retire_prior_to = frame.retire_prior_to.into_inner();
+        if retire_prior_to > sequence {
+            return Err(Error::new(
+                ErrorKind::FrameEncoding,
+                FrameType::NewConnectionId,
+                "retire prior to is greater than sequence number",
+            ));
+        }
+        // A sequence number less than retire_prior_to is retired immediately.
+        if sequence < guard.retire_prior_to {
+            return Ok(vec![sequence]);
+        }
+        match guard.cids.get(&sequence) {
+            Some((cid, token)) if *cid != frame.id || *token != frame.reset_token => {
+                return Err(Error::new(
+                    ErrorKind::ProtocolViolation,
+                    FrameType::NewConnectionId,
+                    "same sequence number with different connection id",
+                ));
+            }
+            Some(_) => {}
+            None => {
+                guard.cids.insert(sequence, (frame.id, frame.reset_token));
+            }
+        }
+
+        let mut retired = Vec::new();
+        if retire_prior_to > guard.retire_prior_to {
+            guard.retire_prior_to = retire_prior_to;
+            let remain = guard.cids.split_off(&retire_prior_to);
+            retired = std::mem::replace(&mut guard.cids, remain)
+                .into_keys()
+                .collect();
+            // TODO: 正在使用中的连接id被要求退役，相应的Path要换用新的连接id
+        }
+        if guard.cids.len() as u64 > guard.limit {
+            return Err(Error::new(
+                ErrorKind::ConnectionIdLimit,
+                FrameType::NewConnectionId,
+                "too many connection ids",
+            ));
+        }
+        Ok(retired)
+    }
+
+    /// Take a connection ID that has never been used by any path. It returns None if
+    /// the peer hasn't provided enough connection IDs.
+    pub fn take_unused(&self) -> Option<ConnectionId> {
+        let mut guard = self.0.lock().unwrap();
+        let (&sequence, &(cid, _)) = guard
+            .cids
+            .iter()
+            .find(|(sequence, _)| !guard.used.contains(sequence))?;
+        guard.used.insert(sequence);
+        Some(cid)
+    }
+
+    /// Retire a connection ID that is no longer used, returns its sequence number,
+    /// which should be sent in a RETIRE_CONNECTION_ID frame.
+    pub fn retire(&self, cid: &ConnectionId) -> Option<u64> {
+        let mut guard = self.0.lock().unwrap();
+        let sequence = guard
+            .cids
+            .iter()
+            .find(|(_, (c, _))| c == cid)
+            .map(|(sequence, _)| *sequence)?;
+        guard.cids.remove(&sequence);
+        Some(sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qbase::varint::VarInt;
+
+    fn new_cid_frame(sequence: u32, retire_prior_to: u32) -> NewConnectionIdFrame {
+        NewConnectionIdFrame {
+            sequence: VarInt::from(sequence),
+            retire_prior_to: VarInt::from(retire_prior_to),
+            id: ConnectionId::random_gen(8),
+            reset_token: ResetToken::new_with(&[sequence as u8; 16]),
+        }
+    }
+
+    #[test]
+    fn test_peer_cids() {
+        let peer_cids = ArcPeerCids::new(2);
+        let initial = ConnectionId::random_gen(8);
+        peer_cids.set_initial(initial);
+        assert_eq!(peer_cids.take_unused(), None);
+
+        let frame = new_cid_frame(1, 0);
+        assert_eq!(peer_cids.recv_new_cid(&frame), Ok(vec![]));
+        // 重复的帧被忽略
+        assert_eq!(peer_cids.recv_new_cid(&frame), Ok(vec![]));
+        assert_eq!(peer_cids.take_unused(), Some(frame.id));
+        assert_eq!(peer_cids.take_unused(), None);
+        assert_eq!(peer_cids.retire(&initial), Some(0));
+        assert_eq!(peer_cids.retire(&initial), None);
+
+        let frame = new_cid_frame(2, 0);
+        assert!(peer_cids.recv_new_cid(&frame).is_ok());
+        let frame = new_cid_frame(3, 0);
+        assert_eq!(
+            peer_cids.recv_new_cid(&frame).unwrap_err().kind,
+            ErrorKind::ConnectionIdLimit
+        );
+    }
+
+    #[test]
+    fn test_retire_prior_to() {
+        let peer_cids = ArcPeerCids::new(4);
+        peer_cids.set_initial(ConnectionId::random_gen(8));
+        assert!(peer_cids.recv_new_cid(&new_cid_frame(1, 0)).is_ok());
+        assert_eq!(peer_cids.recv_new_cid(&new_cid_frame(2, 2)), Ok(vec![0, 1]));
+        assert_eq!(peer_cids.recv_new_cid(&new_cid_frame(1, 0)), Ok(vec![1]));
+
+        let mut frame = new_cid_frame(2, 2);
+        frame.id = ConnectionId::random_gen(8);
+        assert_eq!(
+            peer_cids.recv_new_cid(&frame).unwrap_err().kind,
+            ErrorKind::ProtocolViolation
+        );
+    }
+
+    #[test]
+    fn test_preferred_cid() {
+        let peer_cids = ArcPeerCids::new(2);
+        peer_cids.set_initial(ConnectionId::random_gen(8));
+        let preferred = ConnectionId::random_gen(8);
+        let reset_token = ResetToken::new_with(&[1; 16]);
+        peer_cids.set_preferred(preferred, reset_token);
+        // 首选地址的连接id已被使用，且与序号为1的NEW_CONNECTION_ID帧一致
+        assert_eq!(peer_cids.take_unused(), None);
+        let mut frame = new_cid_frame(1, 0);
+        frame.id = preferred;
+        assert_eq!(peer_cids.recv_new_cid(&frame), Ok(vec![]));
+        assert_eq!(peer_cids.retire(&preferred), Some(1));
+    }
+}
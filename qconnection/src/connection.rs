@@ -1,51 +1,160 @@
-use crate::{auto, crypto::TlsIO, frame_queue::ArcFrameQueue, path::ArcPath};
+use crate::{
+    auto,
+    cid::ArcPeerCids,
+    crypto::{ArcHandshakeCids, HandshakeCids, TlsIO},
+    error::ArcConnError,
+    frame_queue::ArcFrameQueue,
+    handshake::ArcHandshake,
+    path::{mtu::ArcSentPackets, validate::PathState, ArcPath, ArcPaths},
+    send::{self, SealingKeys, UnsealedHeader, UnsealedPacket, MIN_DATAGRAM_SIZE},
+    token::{ArcTokenSink, TokenStore},
+};
+use bytes::BytesMut;
 use qbase::{
+    cid::ConnectionId,
+    config::{PreferredAddress, TransportParameters},
+    crypto::{CryptoError, Keys},
+    error::{ConnectionError, Origin},
+    frame::{
+        ext::WritePingFrame, ConnFrame, ConnectionCloseFrame, HandshakeDoneFrame, NewTokenFrame,
+        PureFrame, RetireConnectionIdFrame,
+    },
     packet::{
+        header::{
+            long::{Handshake, Initial, ZeroRtt},
+            LongHeaderBuilder,
+        },
         keys::{ArcKeys, ArcOneRttKeys},
-        HandshakePacket, InitialPacket, OneRttPacket, SpinBit, ZeroRttPacket,
+        version::{is_compatible, react_to_version_negotiation, VnError},
+        HandshakePacket, InitialPacket, OneRttHeader, OneRttPacket, PacketBuilder, RetryPacket,
+        SpinBit, VersionNegotiationHeader, ZeroRttPacket,
     },
-    streamid::{Role, StreamIds},
+    streamid::{Dir, Role, StreamIds},
+    varint::VarInt,
     SpaceId,
 };
 use qrecovery::{
     crypto::CryptoStream,
-    space::SpaceIO,
-    streams::{NoStreams, Streams},
+    space::{Receive, SpaceIO},
+    streams::{Accept, NoStreams, Streams},
+    AppStream,
+};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
 };
+use thiserror::Error;
 use tokio::sync::mpsc;
 
 /// Option是为了能丢弃前期空间，包括这些空间的收包队列，
 /// 一旦丢弃，后续再收到该空间的包，直接丢弃。
-type RxPacketsQueue<T> = Option<mpsc::UnboundedSender<(T, ArcPath)>>;
+/// 外面包一层Arc<Mutex>，是为了能在握手推进的任务中丢弃。
+type Discardable<T> = Arc<Mutex<Option<T>>>;
+type RxPacketsQueue<T> = Discardable<mpsc::UnboundedSender<(T, ArcPath)>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum MigrationError {
+    #[error("only the client can initiate connection migration")]
+    NotClient,
+    #[error("connection migration is not allowed before the handshake is confirmed")]
+    HandshakeNotConfirmed,
+    #[error("the peer has disabled active migration")]
+    Disabled,
+    #[error("no unused connection id from the peer for the new path")]
+    NoConnectionId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum TokenError {
+    #[error("only the client can use the tokens issued by servers")]
+    NotClient,
+    #[error("only the server can issue tokens with NEW_TOKEN frame")]
+    NotServer,
+}
+
+/// The connections accepted by the endpoint are shared with the application.
+pub type ArcConnection = Arc<Mutex<Connection>>;
 
 pub struct Connection {
+    role: Role,
+    tls_session: TlsIO,
+    local_params: TransportParameters,
+    paths: ArcPaths,
+    // 对方通过NEW_CONNECTION_ID帧提供的连接id，迁移到新Path时要换用新的连接id
+    peer_cids: ArcPeerCids,
+    // 仅客户端有，用于Retry，以及握手时验证服务端的传输参数中的连接ID
+    handshake_cids: Option<ArcHandshakeCids>,
+    // 客户端收到Retry包后，后续的Initial包都要携带该令牌；或者是以往连接中服务端通过
+    // NEW_TOKEN帧下发的令牌，以便免去Retry验证地址的往返
+    initial_token: Vec<u8>,
+    // 客户端保存本连接中收到的NEW_TOKEN令牌，供以后的连接使用
+    token_sink: ArcTokenSink,
+    // 客户端第一个Initial包的版本，以及当前接收的Initial包的版本和派生Initial密钥的连接ID。
+    // 兼容版本协商后，双方的Initial包会从原版本切换到协商的版本
+    original_version: u32,
+    initial_version: u32,
+    initial_dcid: ConnectionId,
+
     initial_keys: ArcKeys,
     initial_pkt_queue: RxPacketsQueue<InitialPacket>,
     // 发送数据，也可以随着升级到Handshake空间而丢弃
-    initial_space: SpaceIO<CryptoStream, NoStreams>,
+    initial_space: Discardable<SpaceIO<CryptoStream, NoStreams>>,
 
     handshake_keys: ArcKeys,
     handshake_pkt_queue: RxPacketsQueue<HandshakePacket>,
-    // 发送数据，也可以随着升级到1RTT空间而丢弃
-    handshake_space: SpaceIO<CryptoStream, NoStreams>,
+    // 发送数据，也可以随着握手确认而丢弃
+    handshake_space: Discardable<SpaceIO<CryptoStream, NoStreams>>,
 
     zero_rtt_keys: ArcKeys,
     // 发送数据，也可以随着升级到1RTT空间而丢弃
     zero_rtt_pkt_queue: RxPacketsQueue<ZeroRttPacket>,
     one_rtt_pkt_queue: mpsc::UnboundedSender<(OneRttPacket, ArcPath)>,
+    one_rtt_keys: ArcOneRttKeys,
     data_space: SpaceIO<CryptoStream, Streams>,
+    // 1RTT包被确认或判定丢失时，交给发送它的Path的PMTU探测处理
+    sent_packets: ArcSentPackets,
+    handshake: ArcHandshake,
+    error: ArcConnError,
+    // 进入closing状态后，CONNECTION_CLOSE帧只发送一次
+    // TODO: closing状态下收到包时，应再次发送CONNECTION_CLOSE帧
+    close_sent: bool,
     spin: SpinBit,
 }
 
 impl Connection {
-    pub fn new(tls_session: TlsIO) -> Self {
+    /// `initial_dcid` is the Destination Connection ID of the client's first Initial packet,
+    /// from which the Initial keys are derived. For the client, it is chosen randomly; for
+    /// the server, it is taken from the Initial packet received.
+    /// `local_params` should be the same as the transport parameters of the `tls_session`.
+    pub fn new(
+        tls_session: TlsIO,
+        initial_dcid: ConnectionId,
+        local_params: TransportParameters,
+    ) -> Self {
+        let role = tls_session.role();
+        let version = tls_session.version();
         let rcvd_conn_frames = ArcFrameQueue::new();
+        let handshake = ArcHandshake::default();
+        let error = ArcConnError::default();
+        let peer_cids = ArcPeerCids::new(local_params.active_connection_id_limit().into_inner());
+        let handshake_cids = match role {
+            Role::Client => Some(Arc::new(Mutex::new(HandshakeCids {
+                origin_dcid: initial_dcid,
+                retry_scid: None,
+                original_version: version,
+                versions_after_vn: None,
+            }))),
+            Role::Server => None,
+        };
 
         let (initial_pkt_tx, initial_pkt_rx) =
             mpsc::unbounded_channel::<(InitialPacket, ArcPath)>();
-        let initial_crypto_stream = CryptoStream::new(1000_000, 1000_000);
+        let initial_crypto_stream = CryptoStream::new(1_000_000, 1_000_000);
         let initial_crypto_handler = initial_crypto_stream.split();
-        let initial_keys = ArcKeys::new_pending();
+        let initial_keys = ArcKeys::with_keys(tls_session.initial_keys(&initial_dcid));
         let initial_space_frame_queue = ArcFrameQueue::new();
         let initial_space = SpaceIO::new_initial(initial_crypto_stream);
         tokio::spawn(
@@ -56,7 +165,8 @@ impl Connection {
                 initial_space.clone(),
                 rcvd_conn_frames.clone(),
                 initial_space_frame_queue.clone(),
-                true,
+                handshake.clone(),
+                (role == Role::Client).then(|| peer_cids.clone()),
             ),
         );
         tokio::spawn(auto::loop_read_space_frame_and_dispatch_to_space(
@@ -66,11 +176,11 @@ impl Connection {
 
         let (handshake_pkt_tx, handshake_pkt_rx) =
             mpsc::unbounded_channel::<(HandshakePacket, ArcPath)>();
-        let handshake_crypto_stream = CryptoStream::new(1000_000, 1000_000);
+        let handshake_crypto_stream = CryptoStream::new(1_000_000, 1_000_000);
         let handshake_crypto_handler = handshake_crypto_stream.split();
         let handshake_keys = ArcKeys::new_pending();
         let handshake_space_frame_queue = ArcFrameQueue::new();
-        let handshake_space = SpaceIO::new_initial(handshake_crypto_stream);
+        let handshake_space = SpaceIO::new_handshake(handshake_crypto_stream);
         tokio::spawn(
             auto::loop_read_long_packet_and_then_dispatch_to_space_frame_queue(
                 handshake_pkt_rx,
@@ -79,29 +189,37 @@ impl Connection {
                 handshake_space.clone(),
                 rcvd_conn_frames.clone(),
                 handshake_space_frame_queue.clone(),
-                true,
+                handshake.clone(),
+                None,
             ),
         );
         tokio::spawn(auto::loop_read_space_frame_and_dispatch_to_space(
             handshake_space_frame_queue,
             handshake_space.clone(),
         ));
+        // 客户端恢复了以往的会话，且服务端允许0RTT，即可立即发送0RTT数据
+        let zero_rtt_keys = match (role, tls_session.zero_rtt_keys()) {
+            (Role::Client, Some(keys)) => ArcKeys::with_keys(keys),
+            _ => ArcKeys::new_pending(),
+        };
         tokio::spawn(
             auto::exchange_initial_crypto_msg_until_getting_handshake_key(
                 tls_session.clone(),
                 handshake_keys.clone(),
+                zero_rtt_keys.clone(),
                 initial_crypto_handler,
+                error.clone(),
             ),
         );
 
         let (zero_rtt_pkt_tx, zero_rtt_pkt_rx) =
             mpsc::unbounded_channel::<(ZeroRttPacket, ArcPath)>();
         let (one_rtt_pkt_tx, one_rtt_pkt_rx) = mpsc::unbounded_channel::<(OneRttPacket, ArcPath)>();
-        let zero_rtt_keys = ArcKeys::new_pending();
         let one_rtt_keys = ArcOneRttKeys::new_pending();
-        let one_rtt_crypto_stream = CryptoStream::new(1000_000, 1000_000);
-        let _one_rtt_crypto_handler = one_rtt_crypto_stream.split();
-        let streams = Streams::new(StreamIds::new(Role::Client, 20, 10));
+        let one_rtt_crypto_stream = CryptoStream::new(1_000_000, 1_000_000);
+        let one_rtt_crypto_handler = one_rtt_crypto_stream.split();
+        let streams = Streams::new(StreamIds::new(role, 20, 10))
+            .with_reset_stream_at(local_params.reset_stream_at());
         let data_space = SpaceIO::new(one_rtt_crypto_stream, streams);
         let data_space_frame_queue = ArcFrameQueue::new();
         tokio::spawn(
@@ -112,9 +230,11 @@ impl Connection {
                 data_space.clone(),
                 rcvd_conn_frames.clone(),
                 data_space_frame_queue.clone(),
-                true,
+                handshake.clone(),
+                None,
             ),
         );
+        let paths = ArcPaths::default();
         tokio::spawn(
             auto::loop_read_short_packet_and_then_dispatch_to_space_frame_queue(
                 one_rtt_pkt_rx,
@@ -122,6 +242,14 @@ impl Connection {
                 data_space.clone(),
                 rcvd_conn_frames.clone(),
                 data_space_frame_queue.clone(),
+                error.clone(),
+                PeerMigration {
+                    role,
+                    paths: paths.clone(),
+                    peer_cids: peer_cids.clone(),
+                    data_space: data_space.clone(),
+                    handshake: handshake.clone(),
+                },
             ),
         );
         tokio::spawn(auto::loop_read_space_frame_and_dispatch_to_space(
@@ -129,42 +257,765 @@ impl Connection {
             data_space.clone(),
         ));
         tokio::spawn(auto::exchange_handshake_crypto_msg_until_getting_1rtt_key(
-            tls_session,
-            one_rtt_keys,
+            tls_session.clone(),
+            handshake_keys.clone(),
+            one_rtt_keys.clone(),
             handshake_crypto_handler,
+            handshake_cids.clone(),
+            data_space.clone(),
+            handshake.clone(),
+            error.clone(),
+        ));
+
+        tokio::spawn({
+            let tls_session = tls_session.clone();
+            let handshake = handshake.clone();
+            let error = error.clone();
+            async move {
+                let result = auto::exchange_post_handshake_crypto_msg(
+                    tls_session,
+                    handshake,
+                    one_rtt_crypto_handler,
+                )
+                .await;
+                if let Err(e) = result {
+                    error.on_error(e);
+                }
+            }
+        });
+
+        let token_sink = ArcTokenSink::default();
+        tokio::spawn(auto::loop_read_conn_frame_and_dispatch(
+            rcvd_conn_frames,
+            role,
+            token_sink.clone(),
+            peer_cids.clone(),
+            data_space.clone(),
+            handshake.clone(),
+            error.clone(),
+        ));
+
+        let sent_packets = ArcSentPackets::default();
+        data_space.set_observer(Arc::new(sent_packets.clone()));
+        tokio::spawn(start_pmtud(
+            tls_session.clone(),
+            one_rtt_keys.clone(),
+            local_params.max_udp_payload_size().into_inner(),
+            paths.clone(),
+        ));
+        if role == Role::Client {
+            tokio::spawn(probe_preferred_address(
+                tls_session.clone(),
+                handshake.clone(),
+                paths.clone(),
+                peer_cids.clone(),
+                data_space.clone(),
+            ));
+        }
+
+        let initial_pkt_queue = Arc::new(Mutex::new(Some(initial_pkt_tx)));
+        let initial_space = Arc::new(Mutex::new(Some(initial_space)));
+        let handshake_pkt_queue = Arc::new(Mutex::new(Some(handshake_pkt_tx)));
+        let handshake_space = Arc::new(Mutex::new(Some(handshake_space)));
+        let zero_rtt_pkt_queue = Arc::new(Mutex::new(Some(zero_rtt_pkt_tx)));
+        tokio::spawn(drive_handshake(
+            role,
+            handshake.clone(),
+            (
+                initial_keys.clone(),
+                initial_pkt_queue.clone(),
+                initial_space.clone(),
+            ),
+            (
+                handshake_keys.clone(),
+                handshake_pkt_queue.clone(),
+                handshake_space.clone(),
+            ),
+            (zero_rtt_keys.clone(), zero_rtt_pkt_queue.clone()),
+            one_rtt_keys.clone(),
+            data_space.clone(),
         ));
 
         Self {
+            role,
+            tls_session,
+            local_params,
+            paths,
+            peer_cids,
+            handshake_cids,
+            initial_token: Vec::new(),
+            token_sink,
+            original_version: version,
+            initial_version: version,
+            initial_dcid,
             initial_keys,
-            initial_pkt_queue: Some(initial_pkt_tx),
+            initial_pkt_queue,
             initial_space,
             handshake_keys,
-            handshake_pkt_queue: Some(handshake_pkt_tx),
+            handshake_pkt_queue,
             handshake_space,
             zero_rtt_keys,
-            zero_rtt_pkt_queue: Some(zero_rtt_pkt_tx),
+            zero_rtt_pkt_queue,
             one_rtt_pkt_queue: one_rtt_pkt_tx,
+            one_rtt_keys,
             data_space,
+            sent_packets,
+            handshake,
+            error,
+            close_sent: false,
             spin: SpinBit::default(),
         }
     }
 
     pub fn recv_initial_packet(&mut self, pkt: InitialPacket, path: ArcPath) {
-        self.initial_pkt_queue.as_mut().map(|q| {
+        if pkt.version != self.initial_version && !self.switch_initial_version(pkt.version) {
+            return;
+        }
+        if let Some(q) = self.initial_pkt_queue.lock().unwrap().as_ref() {
             let _ = q.send((pkt, path));
+        }
+    }
+
+    /// The peer's Initial packet is of the compatible version negotiated, see [Section 2.1 of RFC 9368](https://www.rfc-editor.org/rfc/rfc9368.html#section-2.1).
+    /// The server switches once the client uses the negotiated version, and the client switches on
+    /// the server's first Initial packet if its crypto session can be upgraded. After switching,
+    /// Initial packets of the original version are discarded.
+    fn switch_initial_version(&mut self, version: u32) -> bool {
+        if self.initial_version != self.original_version
+            || !is_compatible(self.original_version, version)
+        {
+            return false;
+        }
+        let switched = match self.role {
+            Role::Server => version == self.tls_session.version(),
+            Role::Client => {
+                !self.has_received_initial() && self.tls_session.upgrade_version(version)
+            }
+        };
+        if switched {
+            self.initial_version = version;
+            self.initial_keys
+                .replace_keys(self.tls_session.initial_keys(&self.initial_dcid));
+        }
+        switched
+    }
+
+    // Initial空间已丢弃，也视为收到过对方的Initial包
+    fn has_received_initial(&self) -> bool {
+        !matches!(
+            self.initial_space.lock().unwrap().as_ref(),
+            Some(space) if space.expected_pn() == 0
+        )
+    }
+
+    /// For the server, the client's first Initial packet is of `version`, from which a compatible
+    /// version may have been chosen as the version of the `tls_session`. The server's Initial
+    /// packets are of the negotiated version, while the Initial packets of the original version
+    /// are still accepted, until the client switches to the negotiated version.
+    pub fn set_original_version(&mut self, version: u32) {
+        if self.role != Role::Server || version == self.original_version {
+            return;
+        }
+        if let Some(keys) = self
+            .tls_session
+            .initial_keys_of_version(version, &self.initial_dcid)
+        {
+            // 以协商版本的密钥加密发送，以原版本的密钥解密接收
+            let negotiated = self.tls_session.initial_keys(&self.initial_dcid);
+            self.initial_keys.replace_keys(Keys {
+                local: negotiated.local,
+                remote: keys.remote,
+            });
+            self.original_version = version;
+            self.initial_version = version;
+        }
+    }
+
+    /// The version of the client's first Initial packet, which the 0-RTT packets are of too.
+    pub fn original_version(&self) -> u32 {
+        self.original_version
+    }
+
+    /// A client reacts to a Version Negotiation packet only before it has received any packet from
+    /// the server, see [Section 6.2](https://www.rfc-editor.org/rfc/rfc9000.html#section-6.2) of QUIC.
+    /// Returns the version to restart the connection with, which is the first one of `client_versions`
+    /// supported by the server. The caller creates a new connection of that version, and tells it
+    /// by [`Connection::reacted_to_version_negotiation`] to detect downgrade attacks.
+    pub fn recv_version_negotiation(
+        &mut self,
+        vn: &VersionNegotiationHeader,
+        client_versions: &[u32],
+    ) -> Result<u32, VnError> {
+        let Some(cids) = self.handshake_cids.as_ref() else {
+            return Err(VnError::Ignored);
+        };
+        let cids = cids.lock().unwrap();
+        // 已收到过服务端的包，或者已经因版本协商重新发起过连接，都不再处理版本协商包；
+        // 版本协商包的源连接ID必须是客户端第一个Initial包的目标连接ID
+        if self.has_received_initial()
+            || cids.retry_scid.is_some()
+            || cids.versions_after_vn.is_some()
+            || vn.scid != cids.origin_dcid
+        {
+            return Err(VnError::Ignored);
+        }
+        react_to_version_negotiation(self.original_version, &vn.versions, client_versions)
+    }
+
+    /// The client restarts the connection after a Version Negotiation packet, whose reaction is
+    /// validated with the server's version information during the handshake.
+    pub fn reacted_to_version_negotiation(&mut self, client_versions: Vec<u32>) {
+        if let Some(cids) = self.handshake_cids.as_ref() {
+            cids.lock().unwrap().versions_after_vn = Some(client_versions);
+        }
+    }
+
+    /// A client processes at most one Retry packet, and only before it has received any
+    /// Initial packet from the server, see [Section 17.2.5.2](https://www.rfc-editor.org/rfc/rfc9000.html#section-17.2.5.2) of QUIC.
+    /// If the Retry packet is valid, the client switches to the new Destination Connection ID,
+    /// re-derives the Initial keys from it, and resends all the Initial data with the token.
+    pub fn recv_retry_packet(&mut self, pkt: RetryPacket, path: ArcPath) {
+        let Some(cids) = self.handshake_cids.as_ref() else {
+            // A server MUST NOT accept Retry packets.
+            return;
+        };
+        let mut cids = cids.lock().unwrap();
+        let initial_space = self.initial_space.lock().unwrap();
+        let Some(initial_space) = initial_space.as_ref() else {
+            return;
+        };
+        if cids.retry_scid.is_some() || initial_space.expected_pn() > 0 {
+            return;
+        }
+        // 版本不同的Retry包无法通过完整性校验，也不会被接受
+        if pkt.version != self.tls_session.version() {
+            return;
+        }
+        // A client MUST discard a Retry packet with a zero-length Retry Token field,
+        // or one that fails the integrity check.
+        if pkt.token.is_empty() || !pkt.verify_integrity(&cids.origin_dcid) {
+            return;
+        }
+
+        cids.retry_scid = Some(pkt.scid);
+        path.set_dcid(pkt.scid);
+        self.peer_cids.set_initial(pkt.scid);
+        self.initial_token = pkt.header.specific.token;
+        self.initial_dcid = pkt.header.scid;
+        self.initial_keys
+            .replace_keys(self.tls_session.initial_keys(&self.initial_dcid));
+        initial_space.retransmit_all_inflight();
+    }
+
+    /// The token carried in the Initial packets sent by the client, which is empty unless
+    /// a Retry packet was received.
+    pub fn initial_token(&self) -> &[u8] {
+        &self.initial_token
+    }
+
+    /// For the client, use the token issued by the server named `server_name` in a previous
+    /// connection, and save the tokens issued in this connection into the store.
+    pub fn use_token_store(
+        &mut self,
+        server_name: &str,
+        store: Arc<dyn TokenStore>,
+    ) -> Result<(), TokenError> {
+        if self.role != Role::Client {
+            return Err(TokenError::NotClient);
+        }
+        if self.initial_token.is_empty() {
+            if let Some(token) = store.take(server_name) {
+                self.initial_token = token;
+            }
+        }
+        self.token_sink.set(server_name.to_string(), store);
+        Ok(())
+    }
+
+    /// For the server, issue a token in NEW_TOKEN frame, which will be sent once
+    /// the handshake is complete.
+    pub fn issue_new_token(&self, token: Vec<u8>) -> Result<(), TokenError> {
+        if self.role != Role::Server {
+            return Err(TokenError::NotServer);
+        }
+        // 不与PMTU探测一同等待1RTT密钥，同一密钥只能有一个等待发送的任务
+        let handshake = self.handshake.clone();
+        let data_space = self.data_space.clone();
+        tokio::spawn(async move {
+            handshake.completed().await;
+            data_space.write_frame(PureFrame::Conn(ConnFrame::NewToken(NewTokenFrame {
+                token,
+            })));
+        });
+        Ok(())
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn handshake(&self) -> ArcHandshake {
+        self.handshake.clone()
+    }
+
+    /// The error that closes the connection, see [`ArcConnError`].
+    pub fn error(&self) -> ArcConnError {
+        self.error.clone()
+    }
+
+    /// The application protocol negotiated with ALPN, available once the handshake is done.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.tls_session.alpn_protocol()
+    }
+
+    /// The server name indicated by the client.
+    pub fn server_name(&self) -> Option<String> {
+        self.tls_session.server_name()
+    }
+
+    /// The QUIC version of the connection, which the long header packets must carry.
+    pub fn version(&self) -> u32 {
+        self.tls_session.version()
+    }
+
+    /// Whether the QUIC bit of the packets sent can be greased, which the peer allows by
+    /// advertising the grease_quic_bit transport parameter.
+    pub fn can_grease_quic_bit(&self) -> bool {
+        matches!(
+            self.tls_session.peer_transport_parameters(),
+            Some(Ok(params)) if params.grease_quic_bit()
+        )
+    }
+
+    /// Whether the streams can be reset reliably with RESET_STREAM_AT, which the peer allows
+    /// by advertising the reset_stream_at transport parameter.
+    pub fn can_reset_stream_at(&self) -> bool {
+        matches!(
+            self.tls_session.peer_transport_parameters(),
+            Some(Ok(params)) if params.reset_stream_at()
+        )
+    }
+
+    pub fn peer_certificates(&self) -> Option<Vec<rustls::Certificate>> {
+        self.tls_session.peer_certificates()
+    }
+
+    /// Export keying material for channel binding, see [`TlsIO::export_keying_material`].
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.tls_session.export_keying_material(label, context, len)
+    }
+
+    /// Set the path on which the connection is established, whose Destination Connection ID
+    /// is the one chosen by the peer during the handshake.
+    pub fn set_initial_path(&mut self, path: ArcPath) {
+        // 抗放大限制只约束服务端，客户端发起的连接无需验证服务端的地址
+        if self.role == Role::Client {
+            path.anti_amplifier().grant();
+        }
+        self.peer_cids.set_initial(path.dcid());
+        self.paths.insert(path);
+    }
+
+    pub fn active_path(&self) -> Option<ArcPath> {
+        self.paths.active()
+    }
+
+    /// Open a new stream. A client resuming a session can write data to the stream before
+    /// the handshake is complete, which will be sent in 0-RTT packets, and retransmitted
+    /// in 1-RTT packets if the server rejects the 0-RTT data.
+    pub fn open_stream(&self, dir: Dir) -> OpenStream {
+        OpenStream {
+            data_space: self.data_space.clone(),
+            dir,
+        }
+    }
+
+    pub fn accept_stream(&self) -> Accept {
+        self.data_space.stream_listener().accept()
+    }
+
+    /// Assemble a datagram by coalescing the packets of all the spaces with something to send,
+    /// see [Section 12.2](https://www.rfc-editor.org/rfc/rfc9000.html#section-12.2) of QUIC.
+    /// Packets are sent on the active path, unless another path has path frames to send, such
+    /// as the one being validated, which only carries 1-RTT packets. The datagram is within the
+    /// MTU and the anti-amplification limit of the path, and the bytes sent are counted.
+    ///
+    /// Returns None if there is nothing to send, or nothing can be sent until more bytes are
+    /// received on the path, see [`send_datagram`].
+    pub fn try_send(&mut self) -> Option<(BytesMut, ArcPath)> {
+        if let Some(error) = self.error.get() {
+            return self.try_send_close(error);
+        }
+        let path = self.paths.to_send()?;
+        if let Some(datagram) = self.try_send_probe(&path) {
+            return Some((datagram, path));
+        }
+        let max_size = path.mtu().min(path.anti_amplifier().balance_now());
+        let mut packets = Vec::new();
+        let mut remaining = max_size;
+        // 携带Initial包或者路径帧的数据报，要填充到至少1200字节
+        let mut min_size = 0;
+        if self
+            .paths
+            .active()
+            .is_some_and(|active| active.is_same(&path))
+        {
+            if let Some((packet, sent_bytes)) = self.assemble_initial(&path, remaining) {
+                if self.role == Role::Client || sent_bytes > 0 {
+                    min_size = MIN_DATAGRAM_SIZE;
+                }
+                remaining -= packet.size();
+                packets.push(packet);
+            }
+            if let Some(packet) = self.assemble_handshake(&path, remaining) {
+                remaining -= packet.size();
+                packets.push(packet);
+                // 客户端首次发送Handshake包时，就丢弃Initial密钥，见RFC 9001 4.9.1节
+                if self.role == Role::Client {
+                    discard_space(
+                        &self.initial_keys,
+                        &self.initial_pkt_queue,
+                        &self.initial_space,
+                    );
+                }
+            }
+        }
+        // 短包头没有长度字段，只能是数据报中的最后一个包
+        let mut one_rtt_pktid = None;
+        if let Some((packet, has_path_frames)) = self.assemble_data(&path, remaining) {
+            if has_path_frames {
+                min_size = MIN_DATAGRAM_SIZE;
+            }
+            one_rtt_pktid = packet.one_rtt_pktid();
+            packets.push(packet);
+        }
+        if packets.is_empty() {
+            return None;
+        }
+        let grease = self.can_grease_quic_bit();
+        let datagram = send::coalesce(packets, min_size.min(max_size), max_size, grease);
+        path.anti_amplifier().on_sent(datagram.len());
+        if let Some(pktid) = one_rtt_pktid {
+            self.sent_packets
+                .on_packet_sent(pktid, &path, datagram.len());
+        }
+        Some((datagram, path))
+    }
+
+    /// Send a PMTU probe in a datagram of its own on the active path once the handshake is
+    /// confirmed, see [`ArcPmtud`]. The probe is a 1-RTT packet of only PING and PADDING frames,
+    /// so nothing is retransmitted if it is lost, which only means the probe size is not
+    /// supported by the path.
+    ///
+    /// [`ArcPmtud`]: crate::path::mtu::ArcPmtud
+    fn try_send_probe(&self, path: &ArcPath) -> Option<BytesMut> {
+        // 握手期间的包优先，不能被探测包耽搁
+        if !self.handshake.is_confirmed()
+            || !self
+                .paths
+                .active()
+                .is_some_and(|active| active.is_same(path))
+        {
+            return None;
+        }
+        let (header_key, packet_keys) = self.one_rtt_keys.local_keys()?;
+        let size = path.pmtud().probe_size()?;
+        if size > path.anti_amplifier().balance_now() {
+            return None;
+        }
+        let header = self.one_rtt_header(path);
+        let tag_len = packet_keys.lock().unwrap().tag_len();
+        let (pktid, pn) = self.data_space.next_pn();
+        let mut builder = PacketBuilder::new(size, header.max_len(size), pn.size(), tag_len)?;
+        builder.payload_mut().put_ping_frame();
+        let payload = builder.finish()?;
+        let pto = path.rtt().lock().unwrap().pto_base_duration(0);
+        let (key_phase, packet_key) = match packet_keys.lock().unwrap().get_local(pto) {
+            Ok(key) => key,
+            Err(error) => {
+                self.error.on_error(error);
+                return None;
+            }
+        };
+        let sent_pktid = self.data_space.send_prewritten(size).ok()?;
+        debug_assert_eq!(sent_pktid, pktid);
+        let keys = SealingKeys::OneRtt(header_key, key_phase, packet_key);
+        let packet = UnsealedPacket::new(header, (pktid, pn), payload, keys);
+        // 填充到探测的尺寸
+        let grease = self.can_grease_quic_bit();
+        let datagram = send::coalesce(vec![packet], size, size, grease);
+        path.pmtud().on_probe_sent(pktid, datagram.len());
+        path.anti_amplifier().on_sent(datagram.len());
+        self.sent_packets
+            .on_packet_sent(pktid, path, datagram.len());
+        Some(datagram)
+    }
+
+    /// In the closing state, a CONNECTION_CLOSE frame is sent once in all the spaces whose keys
+    /// are available, since the peer may not have the later keys yet, see [Section 10.2.3](https://www.rfc-editor.org/rfc/rfc9000.html#section-10.2.3) of QUIC.
+    /// Nothing is sent in the draining state.
+    fn try_send_close(&mut self, error: ConnectionError) -> Option<(BytesMut, ArcPath)> {
+        if error.origin() == Origin::Remote || self.close_sent {
+            return None;
+        }
+        self.close_sent = true;
+        let path = self.paths.active()?;
+        let max_size = path.mtu().min(path.anti_amplifier().balance_now());
+        let frame = ConnectionCloseFrame::from(error);
+        let mut packets = Vec::new();
+        let mut remaining = max_size;
+        let mut min_size = 0;
+        let initial = self.initial_space.lock().unwrap().clone();
+        if let (Some(space), Some(keys)) = (initial, self.initial_keys.local_keys()) {
+            let header = self.initial_header(&path);
+            let frame = frame.clone().for_space(SpaceId::Initial);
+            let tag_len = keys.local.packet.tag_len();
+            if let Some(payload) = send::assemble_close(&space, &header, tag_len, remaining, frame)
+            {
+                if self.role == Role::Client {
+                    min_size = MIN_DATAGRAM_SIZE;
+                }
+                let keys = SealingKeys::Long(keys);
+                let packet = UnsealedPacket::new(header, payload.pn, payload.data, keys);
+                remaining -= packet.size();
+                packets.push(packet);
+            }
+        }
+        let handshake = self.handshake_space.lock().unwrap().clone();
+        if let (Some(space), Some(keys)) = (handshake, self.handshake_keys.local_keys()) {
+            let header = self.handshake_header(&path);
+            let frame = frame.clone().for_space(SpaceId::Handshake);
+            let tag_len = keys.local.packet.tag_len();
+            if let Some(payload) = send::assemble_close(&space, &header, tag_len, remaining, frame)
+            {
+                let keys = SealingKeys::Long(keys);
+                let packet = UnsealedPacket::new(header, payload.pn, payload.data, keys);
+                remaining -= packet.size();
+                packets.push(packet);
+            }
+        }
+        if let Some((header_key, packet_keys)) = self.one_rtt_keys.local_keys() {
+            let header = self.one_rtt_header(&path);
+            let tag_len = packet_keys.lock().unwrap().tag_len();
+            let pto = path.rtt().lock().unwrap().pto_base_duration(0);
+            // 达到机密性上限的密钥不能再用，也就无法在1RTT包中发送CONNECTION_CLOSE帧了
+            if let Some(payload) =
+                send::assemble_close(&self.data_space, &header, tag_len, remaining, frame)
+            {
+                if let Ok((key_phase, packet_key)) = packet_keys.lock().unwrap().get_local(pto) {
+                    let keys = SealingKeys::OneRtt(header_key, key_phase, packet_key);
+                    packets.push(UnsealedPacket::new(header, payload.pn, payload.data, keys));
+                }
+            }
+        }
+        if packets.is_empty() {
+            return None;
+        }
+        let grease = self.can_grease_quic_bit();
+        let datagram = send::coalesce(packets, min_size.min(max_size), max_size, grease);
+        path.anti_amplifier().on_sent(datagram.len());
+        Some((datagram, path))
+    }
+
+    fn initial_header(&self, path: &ArcPath) -> UnsealedHeader {
+        // 只有客户端的Initial包携带令牌
+        let token = match self.role {
+            Role::Client => self.initial_token.clone(),
+            Role::Server => Vec::new(),
+        };
+        // 客户端升级版本之前，与服务端选择兼容版本之后，都是发送当前会话的版本
+        UnsealedHeader::Initial(
+            LongHeaderBuilder::with_cid(path.dcid(), path.scid())
+                .version(self.version())
+                .wrap(Initial {
+                    token,
+                    ..Default::default()
+                }),
+        )
+    }
+
+    fn handshake_header(&self, path: &ArcPath) -> UnsealedHeader {
+        UnsealedHeader::Handshake(
+            LongHeaderBuilder::with_cid(path.dcid(), path.scid())
+                .version(self.tls_session.version())
+                .wrap(Handshake::default()),
+        )
+    }
+
+    fn one_rtt_header(&self, path: &ArcPath) -> UnsealedHeader {
+        UnsealedHeader::OneRtt(OneRttHeader {
+            spin: self.spin,
+            dcid: path.dcid(),
+        })
+    }
+
+    fn assemble_initial(&self, path: &ArcPath, max_size: usize) -> Option<(UnsealedPacket, usize)> {
+        let space = self.initial_space.lock().unwrap().clone()?;
+        let keys = self.initial_keys.local_keys()?;
+        let header = self.initial_header(path);
+        let tag_len = keys.local.packet.tag_len();
+        let payload = send::assemble_payload(&space, &header, tag_len, max_size, None)?;
+        let sent_bytes = payload.sent_bytes;
+        let packet = UnsealedPacket::new(header, payload.pn, payload.data, SealingKeys::Long(keys));
+        Some((packet, sent_bytes))
+    }
+
+    fn assemble_handshake(&self, path: &ArcPath, max_size: usize) -> Option<UnsealedPacket> {
+        let space = self.handshake_space.lock().unwrap().clone()?;
+        let keys = self.handshake_keys.local_keys()?;
+        let header = self.handshake_header(path);
+        let tag_len = keys.local.packet.tag_len();
+        let payload = send::assemble_payload(&space, &header, tag_len, max_size, None)?;
+        Some(UnsealedPacket::new(
+            header,
+            payload.pn,
+            payload.data,
+            SealingKeys::Long(keys),
+        ))
+    }
+
+    /// The data space is sent in 1-RTT packets once the 1-RTT keys are available, before
+    /// which the client resuming a session sends 0-RTT packets.
+    fn assemble_data(&self, path: &ArcPath, max_size: usize) -> Option<(UnsealedPacket, bool)> {
+        let Some((header_key, packet_keys)) = self.one_rtt_keys.local_keys() else {
+            if self.role == Role::Server {
+                return None;
+            }
+            let keys = self.zero_rtt_keys.local_keys()?;
+            let header = UnsealedHeader::ZeroRtt(
+                LongHeaderBuilder::with_cid(path.dcid(), path.scid())
+                    .version(self.original_version)
+                    .wrap(ZeroRtt::default()),
+            );
+            let tag_len = keys.local.packet.tag_len();
+            let payload =
+                send::assemble_payload(&self.data_space, &header, tag_len, max_size, None)?;
+            let packet =
+                UnsealedPacket::new(header, payload.pn, payload.data, SealingKeys::Long(keys));
+            return Some((packet, false));
+        };
+        let header = self.one_rtt_header(path);
+        let tag_len = packet_keys.lock().unwrap().tag_len();
+        let payload =
+            send::assemble_payload(&self.data_space, &header, tag_len, max_size, Some(path))?;
+        let pto = path.rtt().lock().unwrap().pto_base_duration(0);
+        // 只有真正发送的包才计入本地密钥加密的包数，达到机密性上限又无法更新密钥时，关闭连接
+        let (key_phase, packet_key) = match packet_keys.lock().unwrap().get_local(pto) {
+            Ok(key) => key,
+            Err(error) => {
+                self.error.on_error(error);
+                return None;
+            }
+        };
+        let keys = SealingKeys::OneRtt(header_key, key_phase, packet_key);
+        let packet = UnsealedPacket::new(header, payload.pn, payload.data, keys);
+        Some((packet, payload.has_path_frames))
+    }
+
+    /// Find the path that a packet of `space_id` from `peer_addr` to `local_addr` belongs to.
+    /// If the peer's address changed, such as NAT rebinding or the client migrated, a new path
+    /// is returned for the 1-RTT packet, which is not added to the connection until the packet
+    /// is authenticated, and the server switches to it only on the packet with the largest
+    /// packet number that is not a probing packet. The peer can't migrate before the handshake
+    /// is confirmed, so the packets of other spaces from a new address are discarded, see
+    /// [Section 9](https://www.rfc-editor.org/rfc/rfc9000.html#section-9) of QUIC.
+    ///
+    /// Returns None if the packet should be discarded.
+    pub fn path_for(
+        &mut self,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        space_id: SpaceId,
+    ) -> Option<ArcPath> {
+        if let Some(path) = self.paths.find(local_addr, peer_addr) {
+            return Some(path);
+        }
+        if space_id != SpaceId::OneRtt {
+            return None;
+        }
+        let active = self.paths.active()?;
+        // 服务端声明的首选地址，即便声明了disable_active_migration，客户端也可以迁移过来
+        let preferred = self
+            .local_params
+            .preferred_address()
+            .filter(|addr| self.role == Role::Server && is_preferred_address(addr, local_addr));
+        // 客户端必须丢弃来自未知服务端地址的包；服务端若声明了disable_active_migration，
+        // 对方仍迁移了，可以直接丢弃新地址上的包
+        if self.role == Role::Client
+            || (preferred.is_none() && self.local_params.disable_active_migration())
+        {
+            return None;
+        }
+        // 客户端发往首选地址的包，使用的是首选地址中的连接id；新Path的目标连接id在包通过
+        // 认证后才分配，以免伪造的包耗尽对方提供的连接id
+        let scid = preferred.map_or(active.scid(), |addr| addr.connection_id());
+        Some(ArcPath::new(local_addr, peer_addr, scid, active.dcid()))
+    }
+
+    /// For the client, migrate the connection to a new local address, such as switching
+    /// from Wi-Fi to cellular network. The new path is used once it is validated, and the
+    /// old path is abandoned then. The returned path can be used to wait for the validation.
+    pub fn migrate(&mut self, local_addr: SocketAddr) -> Result<ArcPath, MigrationError> {
+        if self.role != Role::Client {
+            return Err(MigrationError::NotClient);
+        }
+        if !self.handshake.is_confirmed() {
+            return Err(MigrationError::HandshakeNotConfirmed);
+        }
+        let Some(Ok(params)) = self.tls_session.peer_transport_parameters() else {
+            return Err(MigrationError::HandshakeNotConfirmed);
+        };
+        if params.disable_active_migration() {
+            return Err(MigrationError::Disabled);
+        }
+        let active = self
+            .paths
+            .active()
+            .ok_or(MigrationError::HandshakeNotConfirmed)?;
+        if let Some(path) = self.paths.find(local_addr, active.peer_addr()) {
+            return Ok(path);
+        }
+        // 从新的本地地址发送，必须使用新的连接id，否则会被关联起来
+        let dcid = self
+            .peer_cids
+            .take_unused()
+            .ok_or(MigrationError::NoConnectionId)?;
+        let path = ArcPath::new(local_addr, active.peer_addr(), active.scid(), dcid);
+        // 服务端地址早已验证过，新Path上无需受抗放大限制
+        path.anti_amplifier().grant();
+        self.paths.insert(path.clone());
+        path.validate();
+
+        let paths = self.paths.clone();
+        let peer_cids = self.peer_cids.clone();
+        let data_space = self.data_space.clone();
+        let new_path = path.clone();
+        tokio::spawn(async move {
+            if new_path.validated().await {
+                paths.switch_to(&new_path);
+                abandon_path(&paths, active, &new_path, &peer_cids, &data_space);
+            } else {
+                abandon_path(&paths, new_path, &active, &peer_cids, &data_space);
+            }
         });
+        Ok(path)
     }
 
     pub fn recv_handshake_packet(&mut self, pkt: HandshakePacket, path: ArcPath) {
-        self.handshake_pkt_queue.as_mut().map(|q| {
+        if let Some(q) = self.handshake_pkt_queue.lock().unwrap().as_ref() {
             let _ = q.send((pkt, path));
-        });
+        }
     }
 
     pub fn recv_0rtt_packet(&mut self, pkt: ZeroRttPacket, path: ArcPath) {
-        self.zero_rtt_pkt_queue.as_mut().map(|q| {
+        if let Some(q) = self.zero_rtt_pkt_queue.lock().unwrap().as_ref() {
             let _ = q.send((pkt, path));
-        });
+        }
     }
 
     pub fn recv_1rtt_packet(&mut self, pkt: OneRttPacket, path: ArcPath) {
@@ -186,10 +1037,1002 @@ impl Connection {
     }
 }
 
+/// Send a datagram of the connection with [`Connection::try_send`]. If the path has run out of
+/// the anti-amplification limit, wait until more bytes are received from the peer or the
+/// peer's address is validated. Returns None if there is nothing to send.
+pub async fn send_datagram(conn: &ArcConnection) -> Option<(BytesMut, SocketAddr)> {
+    loop {
+        let path = conn.lock().unwrap().paths.to_send()?;
+        if path.anti_amplifier().balance_now() == 0 {
+            path.anti_amplifier().balance().await;
+            continue;
+        }
+        let (datagram, path) = conn.lock().unwrap().try_send()?;
+        return Some((datagram, path.peer_addr()));
+    }
+}
+
+pub struct OpenStream {
+    data_space: SpaceIO<CryptoStream, Streams>,
+    dir: Dir,
+}
+
+impl Future for OpenStream {
+    /// None if the stream ids are exhausted.
+    type Output = Option<AppStream>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.data_space.poll_open_stream(cx, self.dir)
+    }
+}
+
+/// Drive the handshake to discard the keys and spaces no longer needed, see
+/// [Section 4.9](https://www.rfc-editor.org/rfc/rfc9001.html#section-4.9) of QUIC-TLS.
+/// - The server discards the Initial keys once a Handshake packet is processed, while the
+///   client discards them once it sends a Handshake packet in [`Connection::try_send`].
+/// - The server sends HANDSHAKE_DONE once the handshake is complete, which confirms the
+///   handshake for both endpoints, and then the Handshake keys are discarded.
+/// - The 0-RTT keys are no longer needed after the handshake is confirmed, and the 1-RTT
+///   keys can be updated since then.
+async fn drive_handshake(
+    role: Role,
+    handshake: ArcHandshake,
+    (initial_keys, initial_pkt_queue, initial_space): (
+        ArcKeys,
+        RxPacketsQueue<InitialPacket>,
+        Discardable<SpaceIO<CryptoStream, NoStreams>>,
+    ),
+    (handshake_keys, handshake_pkt_queue, handshake_space): (
+        ArcKeys,
+        RxPacketsQueue<HandshakePacket>,
+        Discardable<SpaceIO<CryptoStream, NoStreams>>,
+    ),
+    (zero_rtt_keys, zero_rtt_pkt_queue): (ArcKeys, RxPacketsQueue<ZeroRttPacket>),
+    one_rtt_keys: ArcOneRttKeys,
+    data_space: SpaceIO<CryptoStream, Streams>,
+) {
+    if role == Role::Server {
+        // 服务端首次成功处理Handshake包时丢弃Initial密钥，客户端则是在首次发送Handshake包时
+        handshake.handshake_keys_used().await;
+        discard_space(&initial_keys, &initial_pkt_queue, &initial_space);
+
+        handshake.completed().await;
+        data_space.write_frame(PureFrame::Conn(ConnFrame::HandshakeDone(
+            HandshakeDoneFrame,
+        )));
+        handshake.confirm();
+    } else {
+        handshake.confirmed().await;
+    }
+    one_rtt_keys.on_handshake_confirmed();
+    discard_space(&handshake_keys, &handshake_pkt_queue, &handshake_space);
+    zero_rtt_keys.invalid();
+    zero_rtt_pkt_queue.lock().unwrap().take();
+}
+
+/// Discard the keys of a space, and stop receiving and sending its packets.
+fn discard_space<P, S>(keys: &ArcKeys, pkt_queue: &RxPacketsQueue<P>, space: &Discardable<S>) {
+    keys.invalid();
+    pkt_queue.lock().unwrap().take();
+    space.lock().unwrap().take();
+}
+
+/// Once the peer's transport parameters are authenticated, search the PMTU of the paths
+/// up to the smaller max_udp_payload_size of both endpoints.
+async fn start_pmtud(
+    tls_session: TlsIO,
+    one_rtt_keys: ArcOneRttKeys,
+    local_max_udp_payload_size: u64,
+    paths: ArcPaths,
+) {
+    if one_rtt_keys.get_local_keys().await.is_none() {
+        return;
+    }
+    let Some(Ok(params)) = tls_session.peer_transport_parameters() else {
+        return;
+    };
+    let max_udp_payload_size = params
+        .max_udp_payload_size()
+        .into_inner()
+        .min(local_max_udp_payload_size);
+    paths.set_max_udp_payload_size(max_udp_payload_size as usize);
+}
+
+fn is_preferred_address(preferred: &PreferredAddress, local_addr: SocketAddr) -> bool {
+    match local_addr {
+        SocketAddr::V4(addr) => preferred.address_v4() == Some(addr),
+        SocketAddr::V6(addr) => preferred.address_v6() == Some(addr),
+    }
+}
+
+/// After the handshake is confirmed, the client probes the server's preferred address of the
+/// same address family, and migrates to it once validated. If the validation fails, the client
+/// keeps using the original path, see [Section 9.6](https://www.rfc-editor.org/rfc/rfc9000.html#section-9.6) of QUIC.
+async fn probe_preferred_address(
+    tls_session: TlsIO,
+    handshake: ArcHandshake,
+    paths: ArcPaths,
+    peer_cids: ArcPeerCids,
+    data_space: SpaceIO<CryptoStream, Streams>,
+) {
+    handshake.confirmed().await;
+    let Some(Ok(params)) = tls_session.peer_transport_parameters() else {
+        return;
+    };
+    let (Some(preferred), Some(active)) = (params.preferred_address(), paths.active()) else {
+        return;
+    };
+    let peer_addr = match active.peer_addr() {
+        SocketAddr::V4(_) => preferred.address_v4().map(SocketAddr::V4),
+        SocketAddr::V6(_) => preferred.address_v6().map(SocketAddr::V6),
+    };
+    let Some(peer_addr) = peer_addr.filter(|addr| *addr != active.peer_addr()) else {
+        return;
+    };
+
+    peer_cids.set_preferred(preferred.connection_id(), preferred.stateless_reset_token());
+    let path = ArcPath::new(
+        active.local_addr(),
+        peer_addr,
+        active.scid(),
+        preferred.connection_id(),
+    );
+    // 客户端主动探测服务端声明的地址，无需受抗放大限制
+    path.anti_amplifier().grant();
+    paths.insert(path.clone());
+    path.validate();
+    if path.validated().await {
+        paths.switch_to(&path);
+        abandon_path(&paths, active, &path, &peer_cids, &data_space);
+    } else {
+        abandon_path(&paths, path, &active, &peer_cids, &data_space);
+    }
+}
+
+/// Migrate the connection to the peer's new address for the server, once the 1-RTT packets
+/// from it are authenticated, see [Section 9.3](https://www.rfc-editor.org/rfc/rfc9000.html#section-9.3) of QUIC.
+#[derive(Clone)]
+pub(crate) struct PeerMigration {
+    role: Role,
+    paths: ArcPaths,
+    peer_cids: ArcPeerCids,
+    data_space: SpaceIO<CryptoStream, Streams>,
+    handshake: ArcHandshake,
+}
+
+impl PeerMigration {
+    /// A packet on `path` is authenticated. The path from a new address is added and validated,
+    /// with the anti-amplification limit until it is validated. Only the RTT estimate is carried
+    /// over when just the port changes; the congestion state is not reset, since the connection
+    /// has no congestion controller yet. Returns the path known by the connection, which may be
+    /// created by an earlier packet from the same address.
+    pub(crate) fn on_authenticated(&self, path: ArcPath) -> ArcPath {
+        if let Some(path) = self.paths.find(path.local_addr(), path.peer_addr()) {
+            return path;
+        }
+        let Some(active) = self.paths.active() else {
+            return path;
+        };
+        // 对方没有提供新的连接id时，比如NAT重绑定，可以在新地址上继续使用原来的连接id
+        if let Some(dcid) = self.peer_cids.take_unused() {
+            path.set_dcid(dcid);
+        }
+        // 仅端口变化，大概率是NAT重绑定，网络路径没有变化，RTT状态可以沿用
+        // TODO: 连接尚未接入qcongestion，迁移时不涉及拥塞状态的重置；接入后，地址变化时应重置拥塞控制器
+        if path.peer_addr().ip() == active.peer_addr().ip() {
+            path.inherit_rtt(&active);
+        }
+        self.paths.insert(path.clone());
+        path.validate();
+
+        // 验证失败时，若尚未切换过去，直接丢弃；已切换过去的，由切换时的任务回退
+        let paths = self.paths.clone();
+        let peer_cids = self.peer_cids.clone();
+        let data_space = self.data_space.clone();
+        let new_path = path.clone();
+        tokio::spawn(async move {
+            if new_path.validated().await {
+                return;
+            }
+            match paths.active() {
+                Some(active) if !active.is_same(&new_path) => {
+                    abandon_path(&paths, new_path, &active, &peer_cids, &data_space)
+                }
+                _ => {}
+            }
+        });
+        path
+    }
+
+    /// The packet with the largest packet number so far is received on `path`, and it is
+    /// not a probing packet. The server switches to the path once the handshake is confirmed,
+    /// and falls back to the previous path if the validation fails.
+    pub(crate) fn on_non_probing(&self, path: &ArcPath) {
+        // 客户端只会主动迁移，服务端不会迁移到新地址上
+        if self.role != Role::Server || !self.handshake.is_confirmed() {
+            return;
+        }
+        let Some(active) = self.paths.active() else {
+            return;
+        };
+        if active.is_same(path) || path.state() == PathState::Failed {
+            return;
+        }
+        self.paths.switch_to(path);
+
+        let paths = self.paths.clone();
+        let peer_cids = self.peer_cids.clone();
+        let data_space = self.data_space.clone();
+        let new_path = path.clone();
+        tokio::spawn(async move {
+            if new_path.validated().await {
+                abandon_path(&paths, active, &new_path, &peer_cids, &data_space);
+            } else if paths.active().is_some_and(|p| p.is_same(&new_path)) {
+                paths.switch_to(&active);
+                abandon_path(&paths, new_path, &active, &peer_cids, &data_space);
+            }
+        });
+    }
+}
+
+/// Drop the path no longer used, and retire its connection ID if it is not used by the
+/// remaining path.
+fn abandon_path(
+    paths: &ArcPaths,
+    path: ArcPath,
+    remaining: &ArcPath,
+    peer_cids: &ArcPeerCids,
+    data_space: &SpaceIO<CryptoStream, Streams>,
+) {
+    paths.remove(&path);
+    if path.dcid() == remaining.dcid() {
+        return;
+    }
+    if let Some(sequence) = peer_cids.retire(&path.dcid()) {
+        data_space.write_frame(PureFrame::Conn(ConnFrame::RetireConnectionId(
+            RetireConnectionIdFrame {
+                sequence: VarInt::from_u64(sequence).unwrap(),
+            },
+        )));
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::path::{
+        anti_amplifier::AMPLIFICATION_FACTOR,
+        mtu::{PmtudState, BASE_PLPMTU},
+    };
+    use qbase::{
+        config::{ext::BufMutExt, VersionInformation},
+        crypto::null::NullSession,
+        error::{Error, ErrorKind},
+        frame::FrameType,
+        packet::{
+            header::long::VersionNegotiation, Packet, PacketReader, SpacePacket, QUIC_V1, QUIC_V2,
+        },
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4)
     }
+
+    #[tokio::test]
+    async fn test_recv_version_negotiation() {
+        let tls_session = TlsIO::with_session(Box::new(NullSession::new_client(Vec::new())));
+        let initial_dcid = ConnectionId::random_gen(8);
+        let mut conn = Connection::new(tls_session, initial_dcid, TransportParameters::default());
+        let vn = |scid: ConnectionId, versions: Vec<u32>| {
+            LongHeaderBuilder::with_cid(ConnectionId::random_gen(8), scid)
+                .wrap(VersionNegotiation { versions })
+        };
+        let client_versions = [QUIC_V1, QUIC_V2];
+
+        // 列出了原版本的版本协商包，以及源连接ID不对的，都被忽略
+        assert_eq!(
+            conn.recv_version_negotiation(&vn(initial_dcid, vec![QUIC_V1]), &client_versions),
+            Err(VnError::Ignored)
+        );
+        assert_eq!(
+            conn.recv_version_negotiation(
+                &vn(ConnectionId::random_gen(8), vec![QUIC_V2]),
+                &client_versions
+            ),
+            Err(VnError::Ignored)
+        );
+        assert_eq!(
+            conn.recv_version_negotiation(&vn(initial_dcid, vec![0x1a2a3a4a]), &client_versions),
+            Err(VnError::NoCommonVersion)
+        );
+        assert_eq!(
+            conn.recv_version_negotiation(&vn(initial_dcid, vec![QUIC_V2]), &client_versions),
+            Ok(QUIC_V2)
+        );
+
+        // 重新发起的连接不再处理版本协商包
+        conn.reacted_to_version_negotiation(client_versions.to_vec());
+        assert_eq!(
+            conn.recv_version_negotiation(&vn(initial_dcid, vec![QUIC_V2]), &client_versions),
+            Err(VnError::Ignored)
+        );
+    }
+
+    const CID_LEN: usize = 8;
+
+    fn null_connection(
+        role: Role,
+        initial_dcid: ConnectionId,
+        params: TransportParameters,
+        with: impl FnOnce(NullSession) -> NullSession,
+    ) -> Connection {
+        let mut buf = BytesMut::new();
+        buf.put_transport_parameters(&params);
+        let session = match role {
+            Role::Client => NullSession::new_client(buf.to_vec()),
+            Role::Server => NullSession::new_server(buf.to_vec()),
+        };
+        let session = Box::new(with(session));
+        Connection::new(TlsIO::with_session(session), initial_dcid, params)
+    }
+
+    /// A client and a server connected with the null provider, whose datagrams are delivered
+    /// to each other directly, bypassing the endpoint.
+    fn null_connection_pair() -> (Connection, ArcPath, Connection, ArcPath) {
+        null_connection_pair_with(|client| client, |server| server)
+    }
+
+    /// Like [`null_connection_pair`], with the null sessions of both sides customized.
+    fn null_connection_pair_with(
+        client_session: impl FnOnce(NullSession) -> NullSession,
+        server_session: impl FnOnce(NullSession) -> NullSession,
+    ) -> (Connection, ArcPath, Connection, ArcPath) {
+        let client_addr: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let client_scid = ConnectionId::random_gen(CID_LEN);
+        let server_scid = ConnectionId::random_gen(CID_LEN);
+        let initial_dcid = ConnectionId::random_gen(CID_LEN);
+
+        let mut client = null_connection(
+            Role::Client,
+            initial_dcid,
+            TransportParameters::default(),
+            client_session,
+        );
+        let client_path = ArcPath::new(client_addr, server_addr, client_scid, initial_dcid);
+        client.set_initial_path(client_path.clone());
+
+        let mut params = TransportParameters::default();
+        params.set_original_destination_connection_id(Some(initial_dcid));
+        params.set_initial_source_connection_id(Some(server_scid));
+        let mut server = null_connection(Role::Server, initial_dcid, params, server_session);
+        let server_path = ArcPath::new(server_addr, client_addr, server_scid, client_scid);
+        server.set_initial_path(server_path.clone());
+        (client, client_path, server, server_path)
+    }
+
+    /// Deliver a datagram to the connection like the endpoint does.
+    fn deliver(conn: &mut Connection, datagram: BytesMut, path: &ArcPath) {
+        let len = datagram.len();
+        recv_packets(conn, datagram, path);
+        path.anti_amplifier().on_rcvd(len);
+    }
+
+    fn recv_packets(conn: &mut Connection, datagram: BytesMut, path: &ArcPath) {
+        for packet in PacketReader::new(datagram, CID_LEN) {
+            match packet.unwrap() {
+                Packet::Space(SpacePacket::Initial(packet)) => {
+                    conn.recv_initial_packet(packet, path.clone())
+                }
+                Packet::Space(SpacePacket::Handshake(packet)) => {
+                    conn.recv_handshake_packet(packet, path.clone())
+                }
+                Packet::Space(SpacePacket::ZeroRtt(packet)) => {
+                    conn.recv_0rtt_packet(packet, path.clone())
+                }
+                Packet::Space(SpacePacket::OneRtt(packet)) => {
+                    conn.recv_1rtt_packet(packet, path.clone())
+                }
+                packet => panic!("unexpected packet {packet:?}"),
+            }
+        }
+    }
+
+    /// Exchange the datagrams for some rounds, returns the sizes of the datagrams sent by
+    /// the client and the server respectively.
+    async fn exchange(
+        client: (&mut Connection, &ArcPath),
+        server: (&mut Connection, &ArcPath),
+        rounds: usize,
+    ) -> (Vec<usize>, Vec<usize>) {
+        exchange_within(client, server, rounds, usize::MAX).await
+    }
+
+    /// Like [`exchange`], over a network path whose MTU is `path_mtu`, on which the larger
+    /// datagrams are dropped.
+    async fn exchange_within(
+        (client, client_path): (&mut Connection, &ArcPath),
+        (server, server_path): (&mut Connection, &ArcPath),
+        rounds: usize,
+        path_mtu: usize,
+    ) -> (Vec<usize>, Vec<usize>) {
+        let mut sizes = (Vec::new(), Vec::new());
+        for _ in 0..rounds {
+            // 收包、解帧和推进握手都在后台任务中
+            for _ in 0..20 {
+                tokio::task::yield_now().await;
+            }
+            while let Some((datagram, _)) = client.try_send() {
+                sizes.0.push(datagram.len());
+                if datagram.len() <= path_mtu {
+                    deliver(server, datagram, server_path);
+                }
+            }
+            while let Some((datagram, _)) = server.try_send() {
+                sizes.1.push(datagram.len());
+                if datagram.len() <= path_mtu {
+                    deliver(client, datagram, client_path);
+                }
+            }
+        }
+        sizes
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_datagrams() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        let (client_sizes, server_sizes) =
+            exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(client.handshake().is_confirmed());
+        assert!(server.handshake().is_confirmed());
+        // 客户端携带Initial包的数据报都填充到了1200字节
+        assert_eq!(client_sizes[0], MIN_DATAGRAM_SIZE);
+        // 服务端验证客户端地址之前，发送的字节数不超过收到的3倍
+        assert!(server_sizes[0] <= client_sizes[0] * AMPLIFICATION_FACTOR);
+        assert!(server_path.anti_amplifier().is_validated());
+
+        // 握手完成后，流数据在1RTT包中传输
+        let Some(AppStream::ReadWrite(_, mut writer)) = client.open_stream(Dir::Bi).await else {
+            panic!("failed to open a stream");
+        };
+        writer.write_all(b"hello").await.unwrap();
+        exchange((&mut client, &client_path), (&mut server, &server_path), 2).await;
+        let Ok(AppStream::ReadWrite(mut reader, _)) = server.accept_stream().await else {
+            panic!("failed to accept a stream");
+        };
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_send_within_anti_amplification_limit() {
+        let (mut client, _, mut server, server_path) = null_connection_pair();
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        // 服务端在收到任何数据之前，什么都不能发送
+        assert!(server.try_send().is_none());
+        let (datagram, _) = client.try_send().unwrap();
+        let rcvd = datagram.len();
+        recv_packets(&mut server, datagram, &server_path);
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+
+        // 收到的字节数尚未计入，即便有数据要发送，也要等待额度
+        let server = Arc::new(Mutex::new(server));
+        let send = tokio::spawn({
+            let server = server.clone();
+            async move { send_datagram(&server).await }
+        });
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!send.is_finished());
+
+        server_path.anti_amplifier().on_rcvd(rcvd);
+        let (datagram, peer_addr) = send.await.unwrap().unwrap();
+        assert_eq!(peer_addr, server_path.peer_addr());
+        assert_eq!(datagram.len(), MIN_DATAGRAM_SIZE);
+        let mut sent = datagram.len();
+        while let Some((datagram, _)) = server.lock().unwrap().try_send() {
+            sent += datagram.len();
+        }
+        assert!(sent <= rcvd * AMPLIFICATION_FACTOR);
+        assert_eq!(
+            server_path.anti_amplifier().balance_now(),
+            rcvd * AMPLIFICATION_FACTOR - sent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_to_compatible_version() {
+        let (mut client, client_path, mut server, server_path) =
+            null_connection_pair_with(|client| client, |server| server.with_version(QUIC_V2));
+        // 服务端从客户端v1的第一个Initial包中选择了兼容的v2
+        server.set_original_version(QUIC_V1);
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let (datagram, _) = client.try_send().unwrap();
+        deliver(&mut server, datagram, &server_path);
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let (datagram, _) = server.try_send().unwrap();
+        let mut packets = PacketReader::new(datagram.clone(), CID_LEN);
+        match packets.next() {
+            Some(Ok(Packet::Space(SpacePacket::Initial(packet)))) => {
+                assert_eq!(packet.version, QUIC_V2)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        deliver(&mut client, datagram, &client_path);
+        // 客户端收到服务端v2的Initial包后随之升级
+        assert_eq!(client.version(), QUIC_V2);
+
+        exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(client.handshake().is_confirmed());
+        assert!(server.handshake().is_confirmed());
+        assert_eq!(client.original_version(), QUIC_V1);
+        assert_eq!(client.initial_version, QUIC_V2);
+        assert_eq!(server.initial_version, QUIC_V2);
+
+        let Some(AppStream::ReadWrite(_, mut writer)) = client.open_stream(Dir::Bi).await else {
+            panic!("failed to open a stream");
+        };
+        writer.write_all(b"hello").await.unwrap();
+        exchange((&mut client, &client_path), (&mut server, &server_path), 2).await;
+        let Ok(AppStream::ReadWrite(mut reader, _)) = server.accept_stream().await else {
+            panic!("failed to accept a stream");
+        };
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_session_ticket_after_handshake() {
+        let store = Arc::new(Mutex::new(Vec::new()));
+        let (mut client, client_path, mut server, server_path) = null_connection_pair_with(
+            |client| client.with_ticket_store(store.clone()),
+            |server| server.with_ticket(b"ticket".to_vec()),
+        );
+        exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(client.handshake().is_confirmed());
+        // NewSessionTicket在1RTT的CRYPTO帧中送达客户端的TLS会话
+        assert_eq!(*store.lock().unwrap(), vec![b"ticket".to_vec()]);
+        assert!(client.error().get().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_after_authenticated() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(server.handshake().is_confirmed());
+        let server_addr = server_path.local_addr();
+        let rebinding_addr: SocketAddr = "127.0.0.1:5555".parse().unwrap();
+        // 握手期间的长包头包，来自新地址时直接丢弃
+        assert!(server
+            .path_for(server_addr, rebinding_addr, SpaceId::Handshake)
+            .is_none());
+
+        let Some(AppStream::ReadWrite(_, mut writer)) = client.open_stream(Dir::Bi).await else {
+            panic!("failed to open a stream");
+        };
+        writer.write_all(b"hello").await.unwrap();
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let (datagram, _) = client.try_send().unwrap();
+        let rcvd = datagram.len();
+        let new_path = server
+            .path_for(server_addr, rebinding_addr, SpaceId::OneRtt)
+            .unwrap();
+        // 包通过认证之前，新Path并不存在
+        assert!(server.paths.find(server_addr, rebinding_addr).is_none());
+        deliver(&mut server, datagram, &new_path);
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        assert!(server.active_path().unwrap().is_same(&new_path));
+        assert_eq!(new_path.state(), PathState::Validating);
+
+        // 验证新Path期间，发送的字节数受抗放大限制
+        let (datagram, path) = server.try_send().unwrap();
+        assert!(path.is_same(&new_path));
+        assert!(datagram.len() <= rcvd * AMPLIFICATION_FACTOR);
+        assert!(!new_path.anti_amplifier().is_validated());
+        deliver(&mut client, datagram, &client_path);
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        while let Some((datagram, _)) = client.try_send() {
+            deliver(&mut server, datagram, &new_path);
+        }
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(new_path.state(), PathState::Validated);
+        // 旧Path在新Path验证通过后被丢弃
+        assert!(server
+            .paths
+            .find(server_addr, server_path.peer_addr())
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_discards_initial_on_sending_handshake() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let (datagram, _) = client.try_send().unwrap();
+        deliver(&mut server, datagram, &server_path);
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        while let Some((datagram, _)) = server.try_send() {
+            deliver(&mut client, datagram, &client_path);
+        }
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        // 收到服务端的Handshake包，还不能丢弃Initial密钥
+        assert!(client.initial_space.lock().unwrap().is_some());
+        assert!(client.initial_keys.local_keys().is_some());
+
+        let (datagram, _) = client.try_send().unwrap();
+        assert!(client.initial_space.lock().unwrap().is_none());
+        assert!(client.initial_keys.local_keys().is_none());
+        assert!(client.initial_pkt_queue.lock().unwrap().is_none());
+        // 同一数据报中，Initial包在Handshake包之前
+        let mut packets = PacketReader::new(datagram, CID_LEN);
+        assert!(matches!(
+            packets.next(),
+            Some(Ok(Packet::Space(SpacePacket::Initial(_))))
+        ));
+        assert!(matches!(
+            packets.next(),
+            Some(Ok(Packet::Space(SpacePacket::Handshake(_))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_initial_token_and_dcid() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        let store = Arc::new(crate::token::MemoryTokenStore::default());
+        store.insert("example.com", vec![1, 2, 3]);
+        client.use_token_store("example.com", store).unwrap();
+        let initial_dcid = client_path.dcid();
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let (datagram, _) = client.try_send().unwrap();
+        match PacketReader::new(datagram.clone(), CID_LEN).next() {
+            Some(Ok(Packet::Space(SpacePacket::Initial(packet)))) => {
+                assert_eq!(packet.token, vec![1, 2, 3]);
+                assert_eq!(packet.dcid, initial_dcid);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        deliver(&mut server, datagram, &server_path);
+        exchange((&mut client, &client_path), (&mut server, &server_path), 2).await;
+        // 收到服务端的第一个Initial包后，客户端改用服务端选择的连接id
+        assert_eq!(client_path.dcid(), server_path.scid());
+    }
+
+    #[tokio::test]
+    async fn test_close_with_connection_close_frame() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(client.handshake().is_confirmed());
+
+        // 比如收包任务检测到对方违规更新密钥
+        let error = Error::new(ErrorKind::KeyUpdate, FrameType::Padding, "key update");
+        client.error().on_error(error.clone());
+        let (datagram, _) = client.try_send().unwrap();
+        // CONNECTION_CLOSE帧只发送一次
+        assert!(client.try_send().is_none());
+        deliver(&mut server, datagram, &server_path);
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            server.error().get(),
+            Some(ConnectionError::Transport(Origin::Remote, error))
+        );
+        assert!(server.error().is_draining());
+        // draining状态下什么都不发送
+        assert!(server.try_send().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_close_on_new_token_from_client() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(client.handshake().is_confirmed());
+
+        client
+            .data_space
+            .write_frame(PureFrame::Conn(ConnFrame::NewToken(NewTokenFrame {
+                token: vec![1, 2, 3],
+            })));
+        exchange((&mut client, &client_path), (&mut server, &server_path), 3).await;
+        let error = Error::new(
+            ErrorKind::ProtocolViolation,
+            FrameType::NewToken,
+            "NEW_TOKEN frame from the client",
+        );
+        assert_eq!(
+            server.error().get(),
+            Some(ConnectionError::Transport(Origin::Local, error.clone()))
+        );
+        // 客户端收到了服务端的CONNECTION_CLOSE帧
+        assert_eq!(
+            client.error().get(),
+            Some(ConnectionError::Transport(Origin::Remote, error))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_on_handshake_done_from_client() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(server.handshake().is_confirmed());
+
+        client
+            .data_space
+            .write_frame(PureFrame::Conn(ConnFrame::HandshakeDone(
+                HandshakeDoneFrame,
+            )));
+        exchange((&mut client, &client_path), (&mut server, &server_path), 3).await;
+        let error = Error::new(
+            ErrorKind::ProtocolViolation,
+            FrameType::HandshakeDone,
+            "HANDSHAKE_DONE frame from the client",
+        );
+        assert_eq!(
+            server.error().get(),
+            Some(ConnectionError::Transport(Origin::Local, error.clone()))
+        );
+        assert_eq!(
+            client.error().get(),
+            Some(ConnectionError::Transport(Origin::Remote, error))
+        );
+    }
+
+    /// Replace the server of [`null_connection_pair`] with one whose transport parameters are
+    /// edited by `edit`, such as to fail the client's authentication.
+    fn null_server_with_params(
+        server_path: &ArcPath,
+        initial_dcid: ConnectionId,
+        edit: impl FnOnce(&mut TransportParameters),
+    ) -> Connection {
+        let mut params = TransportParameters::default();
+        params.set_original_destination_connection_id(Some(initial_dcid));
+        params.set_initial_source_connection_id(Some(server_path.scid()));
+        edit(&mut params);
+        let mut server = null_connection(Role::Server, initial_dcid, params, |server| server);
+        server.set_initial_path(server_path.clone());
+        server
+    }
+
+    #[tokio::test]
+    async fn test_close_on_crypto_error() {
+        use crate::tls::{tests::*, ClientTlsConfigBuilder, ServerTlsConfigBuilder};
+
+        // 双方没有共同的应用协议，服务端读取ClientHello时即失败
+        let client_config = ClientTlsConfigBuilder::new()
+            .add_root_pem(CA_CERT)
+            .unwrap()
+            .with_alpn_protocols(vec![b"h3".to_vec()])
+            .build()
+            .unwrap();
+        let server_config = ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .with_alpn_protocols(vec![b"rpc".to_vec()])
+            .build()
+            .unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let client_scid = ConnectionId::random_gen(CID_LEN);
+        let server_scid = ConnectionId::random_gen(CID_LEN);
+        let initial_dcid = ConnectionId::random_gen(CID_LEN);
+
+        let params = TransportParameters::default();
+        let server_name = "localhost".try_into().unwrap();
+        let tls_session = TlsIO::new_client(client_config, QUIC_V1, server_name, &params).unwrap();
+        let mut client = Connection::new(tls_session, initial_dcid, params);
+        let client_path = ArcPath::new(client_addr, server_addr, client_scid, initial_dcid);
+        client.set_initial_path(client_path.clone());
+
+        let mut params = TransportParameters::default();
+        params.set_original_destination_connection_id(Some(initial_dcid));
+        params.set_initial_source_connection_id(Some(server_scid));
+        let tls_session = TlsIO::new_server(server_config, QUIC_V1, &params).unwrap();
+        let mut server = Connection::new(tls_session, initial_dcid, params);
+        let server_path = ArcPath::new(server_addr, client_addr, server_scid, client_scid);
+        server.set_initial_path(server_path.clone());
+
+        exchange((&mut client, &client_path), (&mut server, &server_path), 3).await;
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        // no_application_protocol告警，即0x178
+        let kind = ErrorKind::Crypto(120);
+        assert_eq!(u64::from(VarInt::from(kind)), 0x178);
+        match server.error().get() {
+            Some(ConnectionError::Transport(Origin::Local, e)) => assert_eq!(e.kind, kind),
+            other => panic!("unexpected {other:?}"),
+        }
+        match client.error().get() {
+            Some(ConnectionError::Transport(Origin::Remote, e)) => assert_eq!(e.kind, kind),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_on_transport_parameter_error() {
+        let (mut client, client_path, _, server_path) = null_connection_pair();
+        // 服务端声明的原始目标连接id与客户端的不一致
+        let mut server = null_server_with_params(&server_path, client_path.dcid(), |params| {
+            params.set_original_destination_connection_id(Some(ConnectionId::random_gen(CID_LEN)));
+        });
+        exchange((&mut client, &client_path), (&mut server, &server_path), 5).await;
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let error = Error::new(
+            ErrorKind::TransportParameter,
+            FrameType::Crypto,
+            "original_destination_connection_id mismatch",
+        );
+        assert_eq!(
+            client.error().get(),
+            Some(ConnectionError::Transport(Origin::Local, error.clone()))
+        );
+        assert_eq!(
+            server.error().get(),
+            Some(ConnectionError::Transport(Origin::Remote, error))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_on_version_negotiation_error() {
+        let (mut client, client_path, _, server_path) = null_connection_pair();
+        // 服务端声明选择的版本并不是协商的版本
+        let mut server = null_server_with_params(&server_path, client_path.dcid(), |params| {
+            params.set_version_information(Some(VersionInformation {
+                chosen_version: QUIC_V2,
+                available_versions: vec![QUIC_V2, QUIC_V1],
+            }));
+        });
+        exchange((&mut client, &client_path), (&mut server, &server_path), 5).await;
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        match client.error().get() {
+            Some(ConnectionError::Transport(Origin::Local, e)) => {
+                assert_eq!(e.kind, ErrorKind::VersionNegotiation)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        match server.error().get() {
+            Some(ConnectionError::Transport(Origin::Remote, e)) => {
+                assert_eq!(e.kind, ErrorKind::VersionNegotiation)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pmtud_after_handshake() {
+        let (mut client, client_path, _, server_path) = null_connection_pair();
+        let mut server = null_server_with_params(&server_path, client_path.dcid(), |params| {
+            params.set_max_udp_payload_size(VarInt::from_u32(1452));
+        });
+        let (client_sizes, server_sizes) =
+            exchange((&mut client, &client_path), (&mut server, &server_path), 10).await;
+        assert!(client.handshake().is_confirmed());
+        // 探测双方max_udp_payload_size中较小的那个，探测包被确认后就可以使用
+        assert!(client_sizes.contains(&1452));
+        assert!(server_sizes.contains(&1452));
+        assert_eq!(client_path.mtu(), 1452);
+        assert_eq!(server_path.mtu(), 1452);
+        assert!(matches!(
+            client_path.pmtud().state(),
+            PmtudState::SearchComplete(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pmtud_with_lost_probes() {
+        let (mut client, client_path, mut server, server_path) = null_connection_pair();
+        // 大于1400字节的探测包都被丢弃，只有后续的包被确认后，才判定丢失
+        exchange_within(
+            (&mut client, &client_path),
+            (&mut server, &server_path),
+            5,
+            1400,
+        )
+        .await;
+        assert!(client.handshake().is_confirmed());
+        // 读端被丢弃时会发送STOP_SENDING帧，所以要一直持有
+        let Some(AppStream::ReadWrite(_client_reader, mut client_writer)) =
+            client.open_stream(Dir::Bi).await
+        else {
+            panic!("failed to open a stream");
+        };
+        client_writer.write_all(b"ping").await.unwrap();
+        exchange_within(
+            (&mut client, &client_path),
+            (&mut server, &server_path),
+            1,
+            1400,
+        )
+        .await;
+        let Ok(AppStream::ReadWrite(_server_reader, mut server_writer)) =
+            server.accept_stream().await
+        else {
+            panic!("failed to accept a stream");
+        };
+
+        for _ in 0..500 {
+            client_writer.write_all(b"ping").await.unwrap();
+            server_writer.write_all(b"pong").await.unwrap();
+            exchange_within(
+                (&mut client, &client_path),
+                (&mut server, &server_path),
+                1,
+                1400,
+            )
+            .await;
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            if matches!(client_path.pmtud().state(), PmtudState::SearchComplete(_))
+                && matches!(server_path.pmtud().state(), PmtudState::SearchComplete(_))
+            {
+                break;
+            }
+        }
+        for path in [&client_path, &server_path] {
+            assert!(matches!(
+                path.pmtud().state(),
+                PmtudState::SearchComplete(_)
+            ));
+            assert!(path.mtu() > BASE_PLPMTU && path.mtu() <= 1400);
+        }
+        assert!(client.error().get().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_token_role() {
+        let client = TlsIO::with_session(Box::new(NullSession::new_client(Vec::new())));
+        let mut client = Connection::new(
+            client,
+            ConnectionId::random_gen(8),
+            TransportParameters::default(),
+        );
+        let store = Arc::new(crate::token::MemoryTokenStore::default());
+        store.insert("example.com", vec![1, 2, 3]);
+        assert_eq!(client.issue_new_token(vec![0]), Err(TokenError::NotServer));
+        assert_eq!(client.use_token_store("example.com", store.clone()), Ok(()));
+        assert_eq!(client.initial_token, vec![1, 2, 3]);
+
+        let server = TlsIO::with_session(Box::new(NullSession::new_server(Vec::new())));
+        let mut server = Connection::new(
+            server,
+            ConnectionId::random_gen(8),
+            TransportParameters::default(),
+        );
+        assert_eq!(
+            server.use_token_store("example.com", store),
+            Err(TokenError::NotClient)
+        );
+        assert_eq!(server.issue_new_token(vec![0]), Ok(()));
+    }
 }
@@ -1,6 +1,22 @@
+use qbase::{
+    cid::ConnectionId,
+    config::{
+        ext::{be_transport_parameters, BufMutExt},
+        TransportParameters, VersionInformation,
+    },
+    crypto::{
+        rustls_impl::{rustls_version, RustlsSession},
+        CryptoError, KeyChange, Keys, Session,
+    },
+    error::{Error, ErrorKind},
+    frame::FrameType,
+    packet::version::validate_server_version_information,
+    streamid::Role,
+};
 use qrecovery::crypto::{CryptoStreamReader, CryptoStreamWriter};
-use rustls::quic::{Connection as TlsConnection, KeyChange};
+use rustls::AlertDescription;
 use std::{
+    fmt,
     future::Future,
     io,
     pin::Pin,
@@ -12,26 +28,233 @@ use tokio::{
     select,
 };
 
-#[derive(Debug)]
 pub(crate) struct TlsSession {
-    connection: TlsConnection,
+    session: Box<dyn Session>,
     wants_write: Option<Waker>,
 }
 
+impl fmt::Debug for TlsSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsSession")
+            .field("role", &self.session.role())
+            .field("is_handshaking", &self.session.is_handshaking())
+            .finish()
+    }
+}
+
 pub(crate) type ArcTlsSession = Arc<Mutex<TlsSession>>;
 
+fn unsupported_version(version: u32) -> rustls::Error {
+    rustls::Error::General(format!("unsupported QUIC version {version:#x}"))
+}
+
 #[derive(Debug, Clone)]
 pub struct TlsIO(ArcTlsSession);
 
 impl TlsIO {
-    pub fn new_client() -> Self {
-        /*
+    /// To resume a previous session and send 0-RTT data, the `config` should keep the
+    /// session tickets in its resumption store, and enable early data.
+    /// `version` is the QUIC version of the connection, such as [`qbase::packet::QUIC_V1`].
+    ///
+    /// The rustls client can't be upgraded to a compatible version during the handshake, see
+    /// [RFC 9368](https://www.rfc-editor.org/rfc/rfc9368.html), since rustls fixes the version once
+    /// the connection is created. Unless `params` carries its own version information, only
+    /// `version` is advertised as available, so that the server keeps using it. If `params`
+    /// advertises other versions and the server chooses one of them, the client discards the
+    /// server's Initial packets and the handshake fails. To upgrade, use [`TlsIO::with_session`]
+    /// with a session whose [`Session::upgrade_version`] supports it, such as the null session.
+    pub fn new_client(
+        config: Arc<rustls::ClientConfig>,
+        version: u32,
+        server_name: rustls::ServerName,
+        params: &TransportParameters,
+    ) -> Result<Self, rustls::Error> {
+        let quic_version = rustls_version(version).ok_or_else(|| unsupported_version(version))?;
+        // rustls客户端无法在握手中途升级版本，因此只声明当前版本可用
+        let mut params = params.clone();
+        if params.version_information().is_none() {
+            params.set_version_information(Some(VersionInformation {
+                chosen_version: version,
+                available_versions: vec![version],
+            }));
+        }
+        let mut buf = bytes::BytesMut::new();
+        buf.put_transport_parameters(&params);
+        let session = RustlsSession::new_client(config, quic_version, server_name, buf.to_vec())?;
+        Ok(Self::with_session(Box::new(session)))
+    }
+
+    /// The server uses the version of the client's first Initial packet.
+    pub fn new_server(
+        config: Arc<rustls::ServerConfig>,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Result<Self, rustls::Error> {
+        let version = rustls_version(version).ok_or_else(|| unsupported_version(version))?;
+        let mut buf = bytes::BytesMut::new();
+        buf.put_transport_parameters(params);
+        let session = RustlsSession::new_server(config, version, buf.to_vec())?;
+        Ok(Self::with_session(Box::new(session)))
+    }
+
+    /// Use another crypto provider than rustls, such as the null provider in
+    /// [`qbase::crypto::null`] for tests.
+    pub fn with_session(session: Box<dyn Session>) -> Self {
         Self(Arc::new(Mutex::new(TlsSession {
-            connection: todo!(),
+            session,
             wants_write: None,
         })))
-        */
-        todo!()
+    }
+
+    pub fn role(&self) -> Role {
+        self.0.lock().unwrap().session.role()
+    }
+
+    pub fn version(&self) -> u32 {
+        self.0.lock().unwrap().session.version()
+    }
+
+    /// The Initial keys derived from the Destination Connection ID of the client's first
+    /// Initial packet, or the Source Connection ID of the Retry packet.
+    pub fn initial_keys(&self, client_dst_connection_id: &[u8]) -> Keys {
+        let tls_session = self.0.lock().unwrap();
+        tls_session.session.initial_keys(client_dst_connection_id)
+    }
+
+    /// The Initial keys of another compatible version, see [`Session::initial_keys_of_version`].
+    pub fn initial_keys_of_version(
+        &self,
+        version: u32,
+        client_dst_connection_id: &[u8],
+    ) -> Option<Keys> {
+        let tls_session = self.0.lock().unwrap();
+        tls_session
+            .session
+            .initial_keys_of_version(version, client_dst_connection_id)
+    }
+
+    /// For the client, switch to the compatible version chosen by the server, see [`Session::upgrade_version`].
+    pub fn upgrade_version(&self, version: u32) -> bool {
+        self.0.lock().unwrap().session.upgrade_version(version)
+    }
+
+    /// The transport parameters of the peer, which are available once the peer's
+    /// EncryptedExtensions(for client) or ClientHello(for server) has been read.
+    /// For a client resuming a session, they are the parameters remembered from the
+    /// previous connection until the EncryptedExtensions is read, which limit the 0-RTT
+    /// data, see [Section 7.4.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-7.4.1) of QUIC.
+    pub fn peer_transport_parameters(&self) -> Option<Result<TransportParameters, Error>> {
+        let tls_session = self.0.lock().unwrap();
+        let raw = tls_session.session.transport_parameters()?;
+        Some(
+            be_transport_parameters(raw)
+                .map(|(_, params)| params)
+                .map_err(|e| {
+                    Error::new(
+                        ErrorKind::TransportParameter,
+                        FrameType::Crypto,
+                        e.to_string(),
+                    )
+                }),
+        )
+    }
+
+    /// Whether the TLS handshake is still in progress, it is complete once the Finished
+    /// messages are both sent and received.
+    pub fn is_handshaking(&self) -> bool {
+        self.0.lock().unwrap().session.is_handshaking()
+    }
+
+    /// The 0-RTT keys are available for the client once the ClientHello is written with
+    /// a resumed session that allows early data, or for the server once the ClientHello
+    /// is read and the early data is accepted.
+    pub fn zero_rtt_keys(&self) -> Option<Keys> {
+        self.0.lock().unwrap().session.zero_rtt_keys()
+    }
+
+    /// Only meaningful for the client after the handshake is complete. If the server
+    /// rejected the early data, all the 0-RTT packets should be considered lost.
+    pub fn is_early_data_accepted(&self) -> bool {
+        self.0.lock().unwrap().session.is_early_data_accepted()
+    }
+
+    /// The server rejects the early data of this connection, it must be called before
+    /// the ClientHello is read.
+    pub fn reject_early_data(&self) {
+        self.0.lock().unwrap().session.reject_early_data();
+    }
+
+    /// The application protocol negotiated with ALPN, available once the handshake is done.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        let tls_session = self.0.lock().unwrap();
+        tls_session.session.alpn_protocol().map(<[u8]>::to_vec)
+    }
+
+    /// The server name indicated by the client in SNI. For the client, it is the name
+    /// given when creating the session.
+    pub fn server_name(&self) -> Option<String> {
+        let tls_session = self.0.lock().unwrap();
+        tls_session.session.server_name().map(str::to_string)
+    }
+
+    /// The certificate chain of the peer, the end-entity certificate comes first. For the
+    /// server, it is None unless client authentication is required.
+    pub fn peer_certificates(&self) -> Option<Vec<rustls::Certificate>> {
+        let tls_session = self.0.lock().unwrap();
+        tls_session
+            .session
+            .peer_certificates()
+            .map(|certs| certs.into_iter().map(rustls::Certificate).collect())
+    }
+
+    /// Export keying material as defined in [RFC 5705](https://www.rfc-editor.org/rfc/rfc5705.html),
+    /// which is available once the handshake is done, such as for channel binding.
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let tls_session = self.0.lock().unwrap();
+        let mut output = vec![0u8; len];
+        tls_session
+            .session
+            .export_keying_material(&mut output, label, context)?;
+        Ok(output)
+    }
+
+    /// Map a TLS error to a CRYPTO_ERROR carrying the TLS alert, or internal_error if no
+    /// alert arises, see [Section 4.8](https://www.rfc-editor.org/rfc/rfc9001.html#section-4.8) of QUIC-TLS.
+    pub fn crypto_error(&self, error: &dyn std::error::Error) -> Error {
+        let alert = self
+            .0
+            .lock()
+            .unwrap()
+            .session
+            .alert()
+            .unwrap_or(AlertDescription::InternalError.get_u8());
+        Error::new(
+            ErrorKind::Crypto(alert),
+            FrameType::Crypto,
+            error.to_string(),
+        )
+    }
+
+    /// Take all the handshake data to send at once, one piece for each encryption level.
+    #[cfg(test)]
+    pub(crate) fn write_hs_now(&self) -> Vec<Vec<u8>> {
+        let mut tls_session = self.0.lock().unwrap();
+        let mut pieces = Vec::new();
+        loop {
+            let mut buf = Vec::new();
+            let key_change = tls_session.session.write_hs(&mut buf);
+            if !buf.is_empty() {
+                pieces.push(buf);
+            }
+            if key_change.is_none() {
+                return pieces;
+            }
+        }
     }
 
     pub fn split_io(&self) -> (TlsReader, TlsWriter) {
@@ -39,14 +262,63 @@ impl TlsIO {
     }
 }
 
+/// The connection IDs used during the handshake, which are authenticated with the
+/// transport parameters of the peer, see [Section 7.3](https://www.rfc-editor.org/rfc/rfc9000.html#section-7.3) of QUIC.
+#[derive(Debug, Default)]
+pub(crate) struct HandshakeCids {
+    // 客户端发送的第一个Initial包的目标连接ID
+    pub(crate) origin_dcid: ConnectionId,
+    // 客户端收到的Retry包的源连接ID，没收到Retry包则为None
+    pub(crate) retry_scid: Option<ConnectionId>,
+    // 客户端发送的第一个Initial包的版本
+    pub(crate) original_version: u32,
+    // 客户端因版本协商包而重新发起连接时，当时所支持的版本，用于检测降级攻击
+    pub(crate) versions_after_vn: Option<Vec<u32>>,
+}
+
+pub(crate) type ArcHandshakeCids = Arc<Mutex<HandshakeCids>>;
+
+impl HandshakeCids {
+    /// For the client, the server's transport parameters must carry the Destination
+    /// Connection ID of the first Initial packet, and the Source Connection ID of the
+    /// Retry packet if it was received, or must not carry it otherwise. The version information
+    /// must match the `negotiated_version` too, see [`validate_server_version_information`].
+    pub(crate) fn authenticate(
+        &self,
+        params: &TransportParameters,
+        negotiated_version: u32,
+    ) -> Result<(), Error> {
+        if params.original_destination_connection_id() != &Some(self.origin_dcid) {
+            return Err(Error::new(
+                ErrorKind::TransportParameter,
+                FrameType::Crypto,
+                "original_destination_connection_id mismatch",
+            ));
+        }
+        if params.retry_source_connection_id() != &self.retry_scid {
+            return Err(Error::new(
+                ErrorKind::TransportParameter,
+                FrameType::Crypto,
+                "retry_source_connection_id mismatch",
+            ));
+        }
+        validate_server_version_information(
+            self.original_version,
+            negotiated_version,
+            params.version_information().as_ref(),
+            self.versions_after_vn.as_deref(),
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TlsReader(ArcTlsSession);
 
 impl TlsReader {
-    pub fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), rustls::Error> {
+    pub fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), CryptoError> {
         let mut tls_session = self.0.lock().unwrap();
-        tls_session.connection.read_hs(plaintext)?;
-        if tls_session.connection.wants_write() {
+        tls_session.session.read_hs(plaintext)?;
+        if tls_session.session.wants_write() {
             if let Some(waker) = tls_session.wants_write.take() {
                 waker.wake();
             }
@@ -57,12 +329,17 @@ impl TlsReader {
     pub fn loop_read_from(mut self, mut stream_reader: CryptoStreamReader) -> HandshakeReader {
         let (close_tx, mut close_rx) = tokio::sync::oneshot::channel::<()>();
         let join_handler = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
             loop {
-                let mut buf = Vec::with_capacity(1500);
                 select! {
-                    _ = &mut close_rx => return Ok(()),
+                    _ = &mut close_rx => return Ok(stream_reader),
                     n = stream_reader.read(&mut buf)=> {
-                        self.read_hs(&buf[..n?]).expect("tls read hs failed");
+                        let n = n?;
+                        if n == 0 {
+                            return Err(io::ErrorKind::UnexpectedEof.into());
+                        }
+                        self.read_hs(&buf[..n])
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
                     },
                 }
             }
@@ -83,7 +360,7 @@ impl Future for TlsWriter {
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut buf = Vec::with_capacity(1200);
         let mut tls_session = self.0.lock().unwrap();
-        let key_change = tls_session.connection.write_hs(&mut buf);
+        let key_change = tls_session.session.write_hs(&mut buf);
         if key_change.is_none() && buf.is_empty() {
             tls_session.wants_write = Some(cx.waker().clone());
             Poll::Pending
@@ -108,16 +385,29 @@ impl TlsWriter {
 /// 的可能。
 pub struct HandshakeReader {
     close_tx: tokio::sync::oneshot::Sender<()>,
-    join_handler: tokio::task::JoinHandle<io::Result<()>>,
+    join_handler: tokio::task::JoinHandle<io::Result<CryptoStreamReader>>,
 }
 
 impl HandshakeReader {
-    pub async fn end(self) -> io::Result<()> {
-        self.close_tx
-            .send(())
-            .expect("close handshake reader failed");
+    /// Stop reading, and give back the crypto stream reader, since the server still
+    /// needs to read the client's Finished after getting the 1-RTT keys.
+    /// If reading has stopped meanwhile, such as the TLS handshake fails after producing
+    /// the keys, the error is returned instead.
+    pub async fn end(self) -> io::Result<CryptoStreamReader> {
+        // 读取任务已结束时，关闭信号无人接收，其结果就是读取失败的错误
+        let _ = self.close_tx.send(());
         self.join_handler.await?
     }
+
+    /// Resolves only if reading stops before [`HandshakeReader::end`], such as the TLS
+    /// handshake fails, after which no more keys will be produced.
+    pub async fn stopped(&mut self) -> io::Error {
+        match (&mut self.join_handler).await {
+            Ok(Ok(_)) => io::ErrorKind::UnexpectedEof.into(),
+            Ok(Err(e)) => e,
+            Err(e) => e.into(),
+        }
+    }
 }
 
 pub struct HandshakeWriter {
@@ -139,9 +429,36 @@ impl HandshakeWriter {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use qbase::crypto::null::NullSession;
 
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn null_tls_io(role: Role) -> TlsIO {
+        let mut buf = bytes::BytesMut::new();
+        buf.put_transport_parameters(&TransportParameters::default());
+        match role {
+            Role::Client => TlsIO::with_session(Box::new(NullSession::new_client(buf.to_vec()))),
+            Role::Server => TlsIO::with_session(Box::new(NullSession::new_server(buf.to_vec()))),
+        }
+    }
+
+    #[test]
+    fn test_null_provider() {
+        let client = null_tls_io(Role::Client);
+        let server = null_tls_io(Role::Server);
+        crate::tls::tests::handshake(&client, &server).unwrap();
+        assert!(matches!(client.peer_transport_parameters(), Some(Ok(_))));
+        assert!(matches!(server.peer_transport_parameters(), Some(Ok(_))));
+        assert!(client.export_keying_material(b"label", None, 32).is_err());
+
+        // 握手完成后的握手消息是意外消息，映射为CRYPTO_ERROR(0x10a)
+        let (mut reader, _) = server.split_io();
+        let error = reader.read_hs(&[1, 0, 0]).unwrap_err();
+        let error = server.crypto_error(&error);
+        assert_eq!(error.kind, ErrorKind::Crypto(10));
+    }
 }
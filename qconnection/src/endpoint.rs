@@ -1,31 +1,954 @@
-use crate::{connection::Connection, ReceiveProtectedPacket};
+use crate::{
+    anti_replay::AntiReplay,
+    connection::{ArcConnection, Connection},
+    crypto::TlsIO,
+    path::{mtu::BASE_PLPMTU, ArcPath},
+    send::{self, SealingKeys, UnsealedHeader, UnsealedPacket},
+    token::{
+        TokenKey, TokenKind, UsedTokens, DEFAULT_NEW_TOKEN_LIFETIME, DEFAULT_RETRY_TOKEN_LIFETIME,
+    },
+    ReceiveProtectedPacket,
+};
+use bytes::BytesMut;
 use qbase::{
-    cid::ConnectionId,
-    packet::{header::GetDcid, SpacePacket},
+    cid::{ConnectionId, ResetToken, RESET_TOKEN_SIZE},
+    config::{
+        ext::be_transport_parameters, PreferredAddress, TransportParameters, VersionInformation,
+    },
+    crypto::rustls_impl::initial_keys,
+    error::ErrorKind,
+    frame::{ConnectionCloseFrame, DataFrame, Frame, FrameReader, FrameType},
+    packet::{
+        decrypt::{DecodeHeader, DecryptPacket, RemoteProtection},
+        error::Error as PacketError,
+        header::{long::Initial, GetDcid, LongHeaderBuilder},
+        retry::build_retry_packet,
+        version::{
+            build_version_negotiation_packet, choose_compatible_version, grease_version,
+            peek_long_header, validate_client_version_information,
+        },
+        InitialPacket, Packet, PacketNumber, PacketReader, SpacePacket, SUPPORTED_VERSIONS,
+    },
+    streamid::Role,
+    SpaceId,
+};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+/// The length of the connection IDs chosen by this endpoint.
+const LOCAL_CID_LEN: usize = 8;
+
+/// The result of validating the address of a client, whose Initial packet
+/// doesn't belong to any existing connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressValidation {
+    /// The address is validated by a token. `retry_scid` is the Source Connection ID
+    /// of the Retry packet if the client came back with a Retry token.
+    Validated {
+        origin_dcid: ConnectionId,
+        retry_scid: Option<ConnectionId>,
+    },
+    /// The connection can be accepted without validation, but the anti-amplification
+    /// limit applies until the address is validated later.
+    Unvalidated,
+    /// The address needs to be validated by sending a Retry packet.
+    Retry,
+    /// The Retry token is invalid, the connection attempt is closed with INVALID_TOKEN.
+    InvalidToken,
+}
 
 pub struct Endpiont {
-    // 尚未实现连接迁移，多个连接id对应一个连接的功能尚未实现
-    connections: HashMap<ConnectionId, Connection>,
-    // 新连接的监听器
-    // listener: Listener,
+    // 尚未下发NEW_CONNECTION_ID，目前一个连接只有握手时的连接id和首选地址中的连接id
+    connections: HashMap<ConnectionId, ArcConnection>,
+    // 首选地址中的连接id、握手完成前客户端使用的原始目标连接id -> 握手时的连接id
+    cid_aliases: HashMap<ConnectionId, ConnectionId>,
+    // 连接握手完成后不再使用的原始目标连接id，以及关闭的连接，由后台任务通知移除
+    retired_tx: mpsc::UnboundedSender<ConnectionId>,
+    retired_rx: mpsc::UnboundedReceiver<ConnectionId>,
+    // 按照ALPN协商的应用协议，将新连接分发到不同的接收队列
+    routes: Vec<(Vec<u8>, mpsc::UnboundedSender<ArcConnection>)>,
+    server_config: Option<Arc<rustls::ServerConfig>>,
+    params: TransportParameters,
+    // 是否要求客户端先经过Retry验证地址，才创建新连接
+    require_retry: bool,
+    retry_token_lifetime: Duration,
+    // 新连接握手后，通过NEW_TOKEN帧下发令牌给客户端，供其以后的连接使用
+    issue_new_token: bool,
+    new_token_lifetime: Duration,
+    used_tokens: UsedTokens,
+    token_key: TokenKey,
+    // 服务端的首选地址，握手完成后客户端会迁移过去，比如从任播地址迁移到单播地址
+    preferred_address: (Option<SocketAddrV4>, Option<SocketAddrV6>),
+    // 0RTT数据可被重放，没有设置防重放策略时，一律拒绝0RTT
+    anti_replay: Option<Arc<dyn AntiReplay>>,
+    // 服务端支持的版本，按偏好排序，用于回应版本协商包和选择兼容版本
+    versions: Vec<u32>,
+    // 需要Endpoint直接发送的数据报，比如Retry包，由外部的socket取走并发送给对方
+    datagrams: mpsc::UnboundedSender<(BytesMut, SocketAddr)>,
 }
 
-impl ReceiveProtectedPacket for Endpiont {
-    fn receive_protected_packet(&mut self, protected_packet: SpacePacket) {
-        let dcid = protected_packet.get_dcid();
-        if let Some(conn) = self.connections.get_mut(dcid) {
-            // let _ = conn.receive_protected_packet(protected_packet);
+impl Endpiont {
+    /// Create an endpoint, which accepts new connections if `server_config` is given.
+    /// The returned receiver yields datagrams sent by the endpoint itself.
+    pub fn new(
+        server_config: Option<Arc<rustls::ServerConfig>>,
+        params: TransportParameters,
+    ) -> (Self, mpsc::UnboundedReceiver<(BytesMut, SocketAddr)>) {
+        let (datagrams_tx, datagrams_rx) = mpsc::unbounded_channel();
+        let (retired_tx, retired_rx) = mpsc::unbounded_channel();
+        let endpoint = Self {
+            connections: HashMap::new(),
+            cid_aliases: HashMap::new(),
+            retired_tx,
+            retired_rx,
+            routes: Vec::new(),
+            server_config,
+            params,
+            require_retry: false,
+            retry_token_lifetime: DEFAULT_RETRY_TOKEN_LIFETIME,
+            issue_new_token: true,
+            new_token_lifetime: DEFAULT_NEW_TOKEN_LIFETIME,
+            used_tokens: UsedTokens::default(),
+            token_key: TokenKey::random_gen(),
+            preferred_address: (None, None),
+            anti_replay: None,
+            versions: SUPPORTED_VERSIONS.to_vec(),
+            datagrams: datagrams_tx,
+        };
+        (endpoint, datagrams_rx)
+    }
+
+    /// Require clients to validate their addresses with a Retry packet before creating
+    /// any new connection, which is usually enabled when the server is under load.
+    pub fn require_retry(&mut self, enabled: bool) {
+        self.require_retry = enabled;
+    }
+
+    pub fn set_retry_token_lifetime(&mut self, lifetime: Duration) {
+        self.retry_token_lifetime = lifetime;
+    }
+
+    /// Whether to issue tokens in NEW_TOKEN frames to clients of the new connections.
+    pub fn issue_new_token(&mut self, enabled: bool) {
+        self.issue_new_token = enabled;
+    }
+
+    pub fn set_new_token_lifetime(&mut self, lifetime: Duration) {
+        self.new_token_lifetime = lifetime;
+    }
+
+    /// Share the key to seal and open tokens among multiple endpoints.
+    pub fn set_token_key(&mut self, token_key: TokenKey) {
+        self.token_key = token_key;
+    }
+
+    /// Advertise the preferred addresses to the clients of the new connections, which must
+    /// be addresses of this endpoint too. Passing both None stops advertising.
+    pub fn set_preferred_address(&mut self, v4: Option<SocketAddrV4>, v6: Option<SocketAddrV6>) {
+        self.preferred_address = (v4, v6);
+    }
+
+    /// Accept the 0-RTT data of the new connections that pass the anti-replay policy,
+    /// None rejects all 0-RTT data, which is the default.
+    pub fn set_anti_replay(&mut self, anti_replay: Option<Arc<dyn AntiReplay>>) {
+        self.anti_replay = anti_replay;
+    }
+
+    /// The versions supported by the server in the order of preference, which are listed in
+    /// Version Negotiation packets, and from which a compatible version is chosen for the clients
+    /// that support it. Unsupported versions are ignored.
+    pub fn set_versions(&mut self, versions: &[u32]) {
+        self.versions = versions
+            .iter()
+            .filter(|v| SUPPORTED_VERSIONS.contains(v))
+            .copied()
+            .collect();
+    }
+
+    /// Accept the connections whose ALPN negotiates `protocol`, several application
+    /// protocols can be served on one endpoint. Once any protocol is listened, the ALPN
+    /// protocols of the server config are replaced by the listened ones in order, and the
+    /// clients offering none of them are refused during the handshake.
+    pub fn listen(&mut self, protocol: &[u8]) -> mpsc::UnboundedReceiver<ArcConnection> {
+        let (conn_tx, conn_rx) = mpsc::unbounded_channel();
+        self.routes.retain(|(p, _)| p != protocol);
+        self.routes.push((protocol.to_vec(), conn_tx));
+        if let Some(server_config) = self.server_config.as_mut() {
+            let mut config = (**server_config).clone();
+            config.alpn_protocols = self.routes.iter().map(|(p, _)| p.clone()).collect();
+            *server_config = Arc::new(config);
+        }
+        conn_rx
+    }
+
+    pub fn validate_address(
+        &mut self,
+        packet: &InitialPacket,
+        peer_addr: &SocketAddr,
+    ) -> AddressValidation {
+        let without_token = if self.require_retry {
+            AddressValidation::Retry
         } else {
+            AddressValidation::Unvalidated
+        };
+        match self.token_key.kind_of(&packet.token) {
+            Some(TokenKind::Retry) => {}
+            Some(TokenKind::NewToken) => {
+                // An invalid token from NEW_TOKEN frame is possible, such as the client
+                // address changed. In that case, the server proceeds as if there is no token.
+                return match self.token_key.open_new_token(
+                    &packet.token,
+                    &peer_addr.ip(),
+                    self.new_token_lifetime,
+                ) {
+                    Ok(expire_at) if self.used_tokens.insert(&packet.token, expire_at) => {
+                        AddressValidation::Validated {
+                            origin_dcid: packet.dcid,
+                            retry_scid: None,
+                        }
+                    }
+                    _ => without_token,
+                };
+            }
+            // No token, or a token that is not issued by this server.
+            None => return without_token,
+        }
+
+        // The Destination Connection ID of the Initial packet after Retry is the
+        // Source Connection ID of the Retry packet, which the token is bound to.
+        match self.token_key.open_retry_token(
+            &packet.token,
+            peer_addr,
+            &packet.dcid,
+            self.retry_token_lifetime,
+        ) {
+            Ok(origin_dcid) => AddressValidation::Validated {
+                origin_dcid,
+                retry_scid: Some(packet.dcid),
+            },
+            Err(_) => AddressValidation::InvalidToken,
+        }
+    }
+
+    fn send_retry(&self, packet: &InitialPacket, peer_addr: SocketAddr) {
+        let retry_scid = ConnectionId::random_gen(LOCAL_CID_LEN);
+        let token = self
+            .token_key
+            .seal_retry_token(&peer_addr, &packet.dcid, &retry_scid);
+        let retry =
+            build_retry_packet(packet.version, &packet.dcid, packet.scid, retry_scid, token);
+        let _ = self.datagrams.send((retry, peer_addr));
+    }
+
+    /// Close the connection attempt of the client without creating the connection, in an Initial
+    /// packet protected with the Initial keys derived from its Destination Connection ID, such as
+    /// for an invalid Retry token, see [Section 8.1.3](https://www.rfc-editor.org/rfc/rfc9000.html#section-8.1.3) of QUIC.
+    fn send_close(
+        &self,
+        packet: &InitialPacket,
+        peer_addr: SocketAddr,
+        frame: ConnectionCloseFrame,
+    ) {
+        let Some(keys) = initial_keys(packet.version, &packet.dcid, Role::Server) else {
+            return;
+        };
+        let header = UnsealedHeader::Initial(
+            LongHeaderBuilder::with_cid(packet.scid, packet.dcid)
+                .version(packet.version)
+                .wrap(Initial::default()),
+        );
+        // 并不会创建连接，这是服务端在Initial空间发送的唯一一个包
+        let pn = PacketNumber::encode(0, None);
+        let tag_len = keys.local.packet.tag_len();
+        let Some(payload) = send::close_payload(&header, pn, tag_len, BASE_PLPMTU, frame) else {
+            return;
+        };
+        let keys = SealingKeys::Long(Arc::new(keys));
+        let packet = UnsealedPacket::new(header, (0, pn), payload, keys);
+        // 只含CONNECTION_CLOSE帧的包不引起确认，无需填充
+        let datagram = send::coalesce(vec![packet], 0, BASE_PLPMTU, false);
+        let _ = self.datagrams.send((datagram, peer_addr));
+    }
+
+    /// Remove the connection ID retired by the background tasks. Removing the Source Connection
+    /// ID of a connection removes the connection, and all the aliases of it.
+    fn remove_retired_cids(&mut self) {
+        while let Ok(cid) = self.retired_rx.try_recv() {
+            self.cid_aliases.remove(&cid);
+            if self.connections.remove(&cid).is_some() {
+                self.cid_aliases.retain(|_, scid| *scid != cid);
+            }
+        }
+    }
+
+    /// Receive a datagram from the socket, which may coalesce several packets. A client's
+    /// datagram of an unsupported version is answered with a Version Negotiation packet.
+    pub fn recv_datagram(
+        &mut self,
+        datagram: BytesMut,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) {
+        let reader = PacketReader::new(datagram.clone(), LOCAL_CID_LEN)
+            .grease_quic_bit(self.params.grease_quic_bit());
+        // 抗放大限制按整个数据报计算收到的字节数，包括合并的多个包和末尾的填充
+        let mut rcvd_path = None;
+        for (index, result) in reader.enumerate() {
+            match result {
+                Ok(Packet::Space(packet)) => {
+                    let path = self.dispatch(packet, local_addr, peer_addr);
+                    rcvd_path = rcvd_path.or(path);
+                }
+                Ok(Packet::VN(_) | Packet::Retry(_)) => {
+                    // 服务端不接受版本协商包和Retry包，客户端由连接的创建者处理
+                }
+                Err(PacketError::UnsupportedVersion(_)) if index == 0 => {
+                    self.send_version_negotiation(&datagram, peer_addr)
+                }
+                Err(_) => {}
+            }
+        }
+        if let Some(path) = rcvd_path {
+            path.anti_amplifier().on_rcvd(datagram.len());
+        }
+    }
+
+    /// See [Section 6.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-6.1) of QUIC.
+    fn send_version_negotiation(&self, datagram: &[u8], peer_addr: SocketAddr) {
+        // 客户端的第一个数据报至少1200字节，更小的不予回应，以免被用于放大攻击
+        if self.server_config.is_none() || datagram.len() < BASE_PLPMTU {
+            return;
+        }
+        // 版本协商包不能回应版本协商包
+        let Some((version, dcid, scid)) = peek_long_header(datagram) else {
+            return;
+        };
+        if version == 0 {
+            return;
+        }
+        // 带上一个保留版本，避免客户端依赖于固定的版本列表
+        let mut versions = self.versions.clone();
+        versions.push(grease_version());
+        let vn = build_version_negotiation_packet(&scid, &dcid, &versions);
+        let _ = self.datagrams.send((vn, peer_addr));
+    }
+
+    /// Peek the version information in the transport parameters of the client's first
+    /// Initial packet, which is decrypted on a copy. None if the ClientHello is not complete
+    /// in this packet, or it doesn't carry the version information.
+    fn peek_version_information(packet: &InitialPacket) -> Option<VersionInformation> {
+        let keys = initial_keys(packet.version, &packet.dcid, Role::Server)?;
+        let mut packet = packet.clone();
+        if !packet.remove_protection(&*keys.remote.header) {
+            return None;
+        }
+        let pn = packet.decode_header().ok()?;
+        let payload = packet
+            .decrypt_packet(pn.decode(0), pn.size(), &*keys.remote.packet)
+            .ok()?;
+        let client_hello = FrameReader::new(payload)
+            .filter_map(Result::ok)
+            .find_map(|frame| match frame {
+                Frame::Data(DataFrame::Crypto(crypto), data) if crypto.offset.into_inner() == 0 => {
+                    Some(data)
+                }
+                _ => None,
+            })?;
+        let params = client_hello_transport_parameters(&client_hello)?;
+        let (_, params) = be_transport_parameters(params).ok()?;
+        params.version_information().clone()
+    }
+
+    /// Returns the path of the new connection, None if the connection is not accepted.
+    fn accept(
+        &mut self,
+        packet: InitialPacket,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        validated: Option<(ConnectionId, Option<ConnectionId>)>,
+    ) -> Option<ArcPath> {
+        let (origin_dcid, retry_scid) = validated.unwrap_or((packet.dcid, None));
+        let server_config = self.server_config.clone()?;
+        // After Retry, the server keeps using the Source Connection ID of the Retry packet.
+        let scid = retry_scid.unwrap_or_else(|| ConnectionId::random_gen(LOCAL_CID_LEN));
+        let mut params = self.params.clone();
+        params.set_original_destination_connection_id(Some(origin_dcid));
+        params.set_initial_source_connection_id(Some(scid));
+        params.set_retry_source_connection_id(retry_scid);
+        let preferred_cid = match self.preferred_address {
+            (None, None) => None,
+            (v4, v6) => {
+                let cid = ConnectionId::random_gen(LOCAL_CID_LEN);
+                let reset_token = ResetToken::new_with(&rand::random::<[u8; RESET_TOKEN_SIZE]>());
+                params.set_preferred_address(Some(PreferredAddress::new(v4, v6, cid, reset_token)));
+                Some(cid)
+            }
+        };
+        // 客户端支持兼容版本时，服务端可以选择更偏好的版本，否则沿用客户端第一个Initial包的版本
+        let client_info = Self::peek_version_information(&packet);
+        if let Err(e) = validate_client_version_information(packet.version, client_info.as_ref()) {
+            self.send_close(&packet, peer_addr, e.into());
+            return None;
+        }
+        let version =
+            choose_compatible_version(packet.version, client_info.as_ref(), &self.versions);
+        params.set_version_information(Some(VersionInformation {
+            chosen_version: version,
+            available_versions: self.versions.clone(),
+        }));
+        let tls_session = TlsIO::new_server(server_config, version, &params).ok()?;
+        let accept_0rtt = self
+            .anti_replay
+            .as_ref()
+            .is_some_and(|anti_replay| anti_replay.accept_0rtt(&origin_dcid, &peer_addr));
+        if !accept_0rtt {
+            // 必须在读取ClientHello之前拒绝
+            tls_session.reject_early_data();
+        }
+
+        let mut conn = Connection::new(tls_session, packet.dcid, params);
+        conn.set_original_version(packet.version);
+        if self.issue_new_token {
+            conn.issue_new_token(self.token_key.seal_new_token(&peer_addr.ip()))
+                .expect("connections accepted by the endpoint are servers");
+        }
+        let path = ArcPath::new(local_addr, peer_addr, scid, packet.scid);
+        if validated.is_some() {
+            path.anti_amplifier().grant();
+        }
+        conn.set_initial_path(path.clone());
+        let odcid = packet.dcid;
+        conn.recv_initial_packet(packet, path.clone());
+        let handshake = conn.handshake();
+        let error = conn.error();
+        let conn = Arc::new(Mutex::new(conn));
+        self.connections.insert(scid, conn.clone());
+        if let Some(preferred_cid) = preferred_cid {
+            self.cid_aliases.insert(preferred_cid, scid);
+        }
+        // 客户端收到服务端的第一个Initial包之前，重传的Initial包和0RTT包仍使用原始的目标连接id；
+        // Retry之后，原始的目标连接id就是Retry包的源连接id，无需别名
+        let aliased = odcid != scid;
+        if aliased {
+            self.cid_aliases.insert(odcid, scid);
+        }
+        let retired_tx = self.retired_tx.clone();
+        let handshake_completed = handshake.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = handshake_completed.completed() => {}
+                _ = error.did_error() => {}
+            }
+            if aliased {
+                let _ = retired_tx.send(odcid);
+            }
+            // TODO: 应在关闭或耗尽状态持续3个PTO之后再移除连接
+            error.did_error().await;
+            let _ = retired_tx.send(scid);
+        });
+        if self.routes.is_empty() {
+            // TODO: 没有监听任何应用协议时，塞给默认的Listener
+            return Some(path);
+        }
+        let routes = self.routes.clone();
+        tokio::spawn(async move {
+            handshake.completed().await;
+            let alpn = conn.lock().unwrap().alpn_protocol();
+            // 服务端只提供了监听的应用协议，协商失败的握手已被TLS拒绝
+            if let Some((_, conn_tx)) = routes.iter().find(|(p, _)| Some(p) == alpn.as_ref()) {
+                if conn_tx.send(conn).is_err() {
+                    // TODO: 应用不再接收该协议的连接，以APPLICATION_ERROR关闭连接
+                }
+            }
+        });
+        Some(path)
+    }
+
+    /// Dispatch the packet to its connection, or accept a new connection. Returns the path
+    /// the packet is received on, whose received bytes are counted by the caller.
+    fn dispatch(
+        &mut self,
+        protected_packet: SpacePacket,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) -> Option<ArcPath> {
+        self.remove_retired_cids();
+        let mut dcid = *protected_packet.get_dcid();
+        if let Some(cid) = self.cid_aliases.get(&dcid) {
+            dcid = *cid;
+        }
+        if let Some(conn) = self.connections.get(&dcid) {
+            let mut conn = conn.lock().unwrap();
+            let space_id = match &protected_packet {
+                SpacePacket::Initial(_) => SpaceId::Initial,
+                SpacePacket::Handshake(_) => SpaceId::Handshake,
+                SpacePacket::ZeroRtt(_) => SpaceId::ZeroRtt,
+                SpacePacket::OneRtt(_) => SpaceId::OneRtt,
+            };
+            // 对方地址变化时，1RTT包通过认证后，连接才会创建新的Path并验证
+            let path = conn.path_for(local_addr, peer_addr, space_id)?;
+            // Initial包可能是原版本或协商的版本，由连接判断；0RTT包沿用原版本，Handshake包
+            // 只能是协商的版本，其他版本的长包头包直接丢弃
+            let valid = match &protected_packet {
+                SpacePacket::Initial(_) | SpacePacket::OneRtt(_) => true,
+                SpacePacket::Handshake(packet) => packet.version == conn.version(),
+                SpacePacket::ZeroRtt(packet) => {
+                    packet.version == conn.original_version() || packet.version == conn.version()
+                }
+            };
+            if !valid {
+                return None;
+            }
             match protected_packet {
-                SpacePacket::Initial(packet) => {
-                    // TODO: 创建新连接，并塞给Listener
+                SpacePacket::Initial(packet) => conn.recv_initial_packet(packet, path.clone()),
+                SpacePacket::Handshake(packet) => conn.recv_handshake_packet(packet, path.clone()),
+                SpacePacket::ZeroRtt(packet) => conn.recv_0rtt_packet(packet, path.clone()),
+                SpacePacket::OneRtt(packet) => conn.recv_1rtt_packet(packet, path.clone()),
+            }
+            Some(path)
+        } else {
+            let SpacePacket::Initial(packet) = protected_packet else {
+                // just ignore
+                return None;
+            };
+            match self.validate_address(&packet, &peer_addr) {
+                AddressValidation::Validated {
+                    origin_dcid,
+                    retry_scid,
+                } => self.accept(
+                    packet,
+                    local_addr,
+                    peer_addr,
+                    Some((origin_dcid, retry_scid)),
+                ),
+                AddressValidation::Unvalidated => self.accept(packet, local_addr, peer_addr, None),
+                AddressValidation::Retry => {
+                    self.send_retry(&packet, peer_addr);
+                    None
                 }
-                _other => {
-                    // just ignore
+                AddressValidation::InvalidToken => {
+                    let frame = ConnectionCloseFrame::new_quic(
+                        ErrorKind::InvalidToken,
+                        FrameType::Padding,
+                        "",
+                    );
+                    self.send_close(&packet, peer_addr, frame);
+                    None
                 }
             }
         }
     }
 }
+
+impl ReceiveProtectedPacket for Endpiont {
+    fn receive_protected_packet(
+        &mut self,
+        protected_packet: SpacePacket,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) {
+        let rcvd_bytes = match &protected_packet {
+            SpacePacket::Initial(packet) => packet.raw_data.len(),
+            SpacePacket::Handshake(packet) => packet.raw_data.len(),
+            SpacePacket::ZeroRtt(packet) => packet.raw_data.len(),
+            SpacePacket::OneRtt(packet) => packet.raw_data.len(),
+        };
+        if let Some(path) = self.dispatch(protected_packet, local_addr, peer_addr) {
+            path.anti_amplifier().on_rcvd(rcvd_bytes);
+        }
+    }
+}
+
+/// Find the quic_transport_parameters extension in a ClientHello message, see
+/// [Section 4.1.2 of RFC 8446](https://www.rfc-editor.org/rfc/rfc8446.html#section-4.1.2).
+fn client_hello_transport_parameters(msg: &[u8]) -> Option<&[u8]> {
+    const CLIENT_HELLO: u8 = 1;
+    const QUIC_TRANSPORT_PARAMETERS: u16 = 0x39;
+
+    fn take(input: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+        (input.len() >= len).then(|| input.split_at(len))
+    }
+
+    fn take_vec(input: &[u8], len_size: usize) -> Option<(&[u8], &[u8])> {
+        let (len, remain) = take(input, len_size)?;
+        let len = len.iter().fold(0usize, |acc, b| acc << 8 | *b as usize);
+        take(remain, len)
+    }
+
+    let (&ty, remain) = msg.split_first()?;
+    if ty != CLIENT_HELLO {
+        return None;
+    }
+    let (body, _) = take_vec(remain, 3)?;
+    // legacy_version和random
+    let (_, remain) = take(body, 2 + 32)?;
+    let (_session_id, remain) = take_vec(remain, 1)?;
+    let (_cipher_suites, remain) = take_vec(remain, 2)?;
+    let (_compression_methods, remain) = take_vec(remain, 1)?;
+    let (mut extensions, _) = take_vec(remain, 2)?;
+    while !extensions.is_empty() {
+        let (ty, remain) = take(extensions, 2)?;
+        let (data, remain) = take_vec(remain, 2)?;
+        if u16::from_be_bytes([ty[0], ty[1]]) == QUIC_TRANSPORT_PARAMETERS {
+            return Some(data);
+        }
+        extensions = remain;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qbase::packet::{header::LongHeaderBuilder, InitialHeader, QUIC_V1};
+
+    fn initial_packet(dcid: ConnectionId, token: Vec<u8>) -> InitialPacket {
+        let mut header: InitialHeader =
+            LongHeaderBuilder::with_cid(dcid, ConnectionId::random_gen(8)).wrap(Default::default());
+        header.specific.token = token;
+        InitialPacket {
+            header,
+            raw_data: BytesMut::new(),
+            pn_offset: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_address_validation() {
+        let (mut endpoint, _datagrams) = Endpiont::new(None, TransportParameters::default());
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let odcid = ConnectionId::random_gen(8);
+
+        let packet = initial_packet(odcid, Vec::new());
+        assert_eq!(
+            endpoint.validate_address(&packet, &peer_addr),
+            AddressValidation::Unvalidated
+        );
+
+        endpoint.require_retry(true);
+        assert_eq!(
+            endpoint.validate_address(&packet, &peer_addr),
+            AddressValidation::Retry
+        );
+
+        let retry_scid = ConnectionId::random_gen(8);
+        let token = endpoint
+            .token_key
+            .seal_retry_token(&peer_addr, &odcid, &retry_scid);
+        let packet = initial_packet(retry_scid, token.clone());
+        assert_eq!(
+            endpoint.validate_address(&packet, &peer_addr),
+            AddressValidation::Validated {
+                origin_dcid: odcid,
+                retry_scid: Some(retry_scid)
+            }
+        );
+
+        let other_addr: SocketAddr = "127.0.0.2:5000".parse().unwrap();
+        assert_eq!(
+            endpoint.validate_address(&packet, &other_addr),
+            AddressValidation::InvalidToken
+        );
+        // 其他服务端签发的令牌，视同没有令牌
+        let foreign_token =
+            TokenKey::random_gen().seal_retry_token(&peer_addr, &odcid, &retry_scid);
+        let packet = initial_packet(retry_scid, foreign_token);
+        assert_eq!(
+            endpoint.validate_address(&packet, &peer_addr),
+            AddressValidation::Retry
+        );
+
+        // A token from NEW_TOKEN frame skips the Retry, but only once.
+        let token = endpoint.token_key.seal_new_token(&peer_addr.ip());
+        let packet = initial_packet(odcid, token);
+        assert_eq!(
+            endpoint.validate_address(&packet, &peer_addr),
+            AddressValidation::Validated {
+                origin_dcid: odcid,
+                retry_scid: None
+            }
+        );
+        assert_eq!(
+            endpoint.validate_address(&packet, &peer_addr),
+            AddressValidation::Retry
+        );
+    }
+
+    /// Read the CONNECTION_CLOSE frame in the Initial packet sent by the endpoint to the client,
+    /// which is protected with the Initial keys derived from `client_dcid`.
+    fn read_close(
+        datagram: BytesMut,
+        client_scid: &ConnectionId,
+        client_dcid: &ConnectionId,
+    ) -> Option<ConnectionCloseFrame> {
+        use qbase::frame::{ConnFrame, PureFrame};
+
+        let Some(Ok(Packet::Space(SpacePacket::Initial(mut packet)))) =
+            PacketReader::new(datagram, 8).next()
+        else {
+            panic!("expect an Initial packet");
+        };
+        assert_eq!(packet.dcid, *client_scid);
+        let keys = initial_keys(packet.version, client_dcid, Role::Client).unwrap();
+        assert!(packet.remove_protection(&*keys.remote.header));
+        let pn = packet.decode_header().unwrap();
+        let payload = packet
+            .decrypt_packet(pn.decode(0), pn.size(), &*keys.remote.packet)
+            .unwrap();
+        FrameReader::new(payload)
+            .filter_map(Result::ok)
+            .find_map(|frame| match frame {
+                Frame::Pure(PureFrame::Conn(ConnFrame::Close(close))) => Some(close),
+                _ => None,
+            })
+    }
+
+    #[tokio::test]
+    async fn test_close_with_invalid_token() {
+        use crate::tls::{self, tests::*};
+
+        let server_config = tls::ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .build()
+            .unwrap();
+        let (mut endpoint, mut datagrams) =
+            Endpiont::new(Some(server_config), TransportParameters::default());
+        let local_addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let retry_scid = ConnectionId::random_gen(8);
+        let other_addr: SocketAddr = "127.0.0.2:5000".parse().unwrap();
+        let token = endpoint.token_key.seal_retry_token(
+            &other_addr,
+            &ConnectionId::random_gen(8),
+            &retry_scid,
+        );
+        let packet = initial_packet(retry_scid, token);
+        let client_scid = packet.scid;
+        assert!(endpoint
+            .dispatch(SpacePacket::Initial(packet), local_addr, peer_addr)
+            .is_none());
+        assert!(endpoint.connections.is_empty());
+
+        let (datagram, addr) = datagrams.try_recv().unwrap();
+        assert_eq!(addr, peer_addr);
+        assert_eq!(
+            read_close(datagram, &client_scid, &retry_scid),
+            Some(ConnectionCloseFrame::new_quic(
+                ErrorKind::InvalidToken,
+                FrameType::Padding,
+                ""
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_with_version_negotiation_error() {
+        use crate::tls::{self, tests::*};
+        use qbase::packet::QUIC_V2;
+
+        let server_config = tls::ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .build()
+            .unwrap();
+        let (mut endpoint, mut datagrams) =
+            Endpiont::new(Some(server_config), TransportParameters::default());
+        let local_addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        // 客户端声明选择的版本与其Initial包的版本不一致
+        let client_config = tls::ClientTlsConfigBuilder::new()
+            .add_root_pem(CA_CERT)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut params = TransportParameters::default();
+        params.set_version_information(Some(VersionInformation {
+            chosen_version: QUIC_V2,
+            available_versions: vec![QUIC_V2, QUIC_V1],
+        }));
+        let server_name = "localhost".try_into().unwrap();
+        let tls_session = TlsIO::new_client(client_config, QUIC_V1, server_name, &params).unwrap();
+        let odcid = ConnectionId::random_gen(8);
+        let client_scid = ConnectionId::random_gen(8);
+        let mut client = Connection::new(tls_session, odcid, params);
+        client.set_initial_path(ArcPath::new(peer_addr, local_addr, client_scid, odcid));
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let (datagram, _) = client.try_send().unwrap();
+
+        endpoint.recv_datagram(datagram, local_addr, peer_addr);
+        assert!(endpoint.connections.is_empty());
+        let (datagram, addr) = datagrams.try_recv().unwrap();
+        assert_eq!(addr, peer_addr);
+        match read_close(datagram, &client_scid, &odcid) {
+            Some(ConnectionCloseFrame::Quic { error_kind, .. }) => {
+                assert_eq!(error_kind, ErrorKind::VersionNegotiation)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_original_dcid() {
+        use crate::tls::{self, tests::*};
+
+        let server_config = tls::ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .build()
+            .unwrap();
+        let (mut endpoint, _datagrams) =
+            Endpiont::new(Some(server_config), TransportParameters::default());
+        let local_addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let odcid = ConnectionId::random_gen(8);
+        let packet = initial_packet(odcid, Vec::new());
+        assert!(endpoint
+            .dispatch(SpacePacket::Initial(packet), local_addr, peer_addr)
+            .is_some());
+        assert_eq!(endpoint.connections.len(), 1);
+        let scid = endpoint.cid_aliases[&odcid];
+        let conn = endpoint.connections[&scid].clone();
+
+        // 重传的Initial包仍使用原始的目标连接id，不会创建新连接
+        let packet = initial_packet(odcid, Vec::new());
+        assert!(endpoint
+            .dispatch(SpacePacket::Initial(packet), local_addr, peer_addr)
+            .is_some());
+        assert_eq!(endpoint.connections.len(), 1);
+
+        let error = conn.lock().unwrap().error();
+        error.on_error(qbase::error::Error::new(
+            ErrorKind::Internal,
+            FrameType::Padding,
+            "",
+        ));
+        for _ in 0..5 {
+            tokio::task::yield_now().await;
+        }
+        endpoint.remove_retired_cids();
+        assert!(endpoint.connections.is_empty());
+        assert!(endpoint.cid_aliases.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_listen_alpn() {
+        use crate::tls::{self, tests::*};
+
+        let server_config = tls::ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .with_alpn_protocols(vec![b"h3".to_vec()])
+            .build()
+            .unwrap();
+        let params = TransportParameters::default();
+        let (mut endpoint, _datagrams) = Endpiont::new(Some(server_config), params.clone());
+        let _h3 = endpoint.listen(b"h3");
+        let _doq = endpoint.listen(b"doq");
+        let _rpc = endpoint.listen(b"rpc");
+        let _h3 = endpoint.listen(b"h3");
+        let server_config = endpoint.server_config.clone().unwrap();
+        assert_eq!(
+            server_config.alpn_protocols,
+            vec![b"doq".to_vec(), b"rpc".to_vec(), b"h3".to_vec()]
+        );
+
+        let connect = |protocol: &[u8]| {
+            let client_config = tls::ClientTlsConfigBuilder::new()
+                .add_root_pem(CA_CERT)
+                .unwrap()
+                .with_alpn_protocols(vec![protocol.to_vec()])
+                .build()
+                .unwrap();
+            let server_name = "localhost".try_into().unwrap();
+            let client = TlsIO::new_client(client_config, QUIC_V1, server_name, &params).unwrap();
+            let server = TlsIO::new_server(server_config.clone(), QUIC_V1, &params).unwrap();
+            handshake(&client, &server).map(|_| server.alpn_protocol())
+        };
+        assert_eq!(connect(b"rpc").unwrap(), Some(b"rpc".to_vec()));
+        // 没有监听的应用协议，握手时即被拒绝
+        assert!(connect(b"smtp").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_version_negotiation() {
+        use crate::tls::{self, tests::*};
+        use qbase::packet::{version::is_grease_version, QUIC_V2};
+
+        let server_config = tls::ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .build()
+            .unwrap();
+        let (mut endpoint, mut datagrams) =
+            Endpiont::new(Some(server_config), TransportParameters::default());
+        endpoint.set_versions(&[QUIC_V2, QUIC_V1, 0x1a2a3a4a]);
+        let local_addr: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let dcid = ConnectionId::random_gen(8);
+        let scid = ConnectionId::random_gen(4);
+
+        let mut datagram = BytesMut::from(&[0xc0][..]);
+        datagram.extend_from_slice(&0xff00_001du32.to_be_bytes());
+        datagram.extend_from_slice(&[dcid.len() as u8]);
+        datagram.extend_from_slice(&dcid);
+        datagram.extend_from_slice(&[scid.len() as u8]);
+        datagram.extend_from_slice(&scid);
+        // 太小的数据报不予回应
+        endpoint.recv_datagram(datagram.clone(), local_addr, peer_addr);
+        assert!(datagrams.try_recv().is_err());
+
+        datagram.resize(1200, 0);
+        endpoint.recv_datagram(datagram, local_addr, peer_addr);
+        let (vn, addr) = datagrams.try_recv().unwrap();
+        assert_eq!(addr, peer_addr);
+        match PacketReader::new(vn, 0).next() {
+            Some(Ok(Packet::VN(vn))) => {
+                assert_eq!(vn.dcid, scid);
+                assert_eq!(vn.scid, dcid);
+                assert_eq!(vn.versions[..2], [QUIC_V2, QUIC_V1]);
+                assert_eq!(vn.versions.len(), 3);
+                assert!(is_grease_version(vn.versions[2]));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_client_hello_version_information() {
+        use crate::tls::{self, tests::*};
+        use qbase::packet::QUIC_V2;
+
+        let client_config = tls::ClientTlsConfigBuilder::new()
+            .add_root_pem(CA_CERT)
+            .unwrap()
+            .build()
+            .unwrap();
+        let version_information = VersionInformation {
+            chosen_version: QUIC_V1,
+            available_versions: vec![QUIC_V1, QUIC_V2],
+        };
+        let mut params = TransportParameters::default();
+        params.set_version_information(Some(version_information.clone()));
+        let server_name = "localhost".try_into().unwrap();
+        let client = TlsIO::new_client(client_config, QUIC_V1, server_name, &params).unwrap();
+        let client_hello = client.write_hs_now().concat();
+
+        let params = client_hello_transport_parameters(&client_hello).unwrap();
+        let (_, params) = be_transport_parameters(params).unwrap();
+        assert_eq!(params.version_information(), &Some(version_information));
+        let server_versions = [QUIC_V2, QUIC_V1];
+        assert_eq!(
+            choose_compatible_version(
+                QUIC_V1,
+                params.version_information().as_ref(),
+                &server_versions
+            ),
+            QUIC_V2
+        );
+        assert!(client_hello_transport_parameters(&client_hello[..40]).is_none());
+    }
+}
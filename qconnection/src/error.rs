@@ -0,0 +1,95 @@
+use qbase::error::{ConnectionError, Origin};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Debug, Default)]
+struct ConnError {
+    // 只记录第一个错误，此后的错误都是连接关闭引起的
+    error: Option<ConnectionError>,
+    wakers: Vec<Waker>,
+}
+
+/// The error that closes the connection, only the first one counts. The connection enters
+/// the closing state on a local error, and sends a CONNECTION_CLOSE frame to the peer; it
+/// enters the draining state on the peer's CONNECTION_CLOSE frame, and sends nothing any
+/// more, see [Section 10.2](https://www.rfc-editor.org/rfc/rfc9000.html#section-10.2) of QUIC.
+#[derive(Debug, Default, Clone)]
+pub struct ArcConnError(Arc<Mutex<ConnError>>);
+
+impl ArcConnError {
+    pub fn get(&self) -> Option<ConnectionError> {
+        self.0.lock().unwrap().error.clone()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.get()
+            .is_some_and(|error| error.origin() == Origin::Remote)
+    }
+
+    pub(crate) fn on_error(&self, error: impl Into<ConnectionError>) {
+        let mut guard = self.0.lock().unwrap();
+        if guard.error.is_none() {
+            guard.error = Some(error.into());
+            for waker in guard.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Resolves with the error once the connection is closed.
+    pub fn did_error(&self) -> DidError {
+        DidError(self.clone())
+    }
+}
+
+pub struct DidError(ArcConnError);
+
+impl Future for DidError {
+    type Output = ConnectionError;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut guard = self.0 .0.lock().unwrap();
+        match &guard.error {
+            Some(error) => Poll::Ready(error.clone()),
+            None => {
+                if !guard.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    guard.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qbase::{
+        error::{Error, ErrorKind},
+        frame::{ConnectionCloseFrame, FrameType},
+    };
+
+    #[tokio::test]
+    async fn test_first_error_wins() {
+        let error = ArcConnError::default();
+        let did_error = tokio::spawn(error.did_error());
+        tokio::task::yield_now().await;
+        assert!(!did_error.is_finished());
+
+        let key_update = Error::new(ErrorKind::KeyUpdate, FrameType::Padding, "key update");
+        error.on_error(key_update.clone());
+        error.on_error(ConnectionCloseFrame::from(Error::new(
+            ErrorKind::ProtocolViolation,
+            FrameType::Padding,
+            "",
+        )));
+        let expected = ConnectionError::Transport(Origin::Local, key_update);
+        assert_eq!(did_error.await.unwrap(), expected);
+        assert_eq!(error.get(), Some(expected));
+        assert!(!error.is_draining());
+    }
+}
@@ -38,6 +38,12 @@ impl<T> ArcFrameQueue<T> {
     }
 }
 
+impl<T> Default for ArcFrameQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Clone for ArcFrameQueue<T> {
     fn clone(&self) -> Self {
         Self(self.0.clone())
@@ -69,34 +75,28 @@ pub struct ArcFrameQueueWriter<'a, T> {
 }
 
 impl<T> ArcFrameQueueWriter<'_, T> {
+    /// 队列关闭之后，连接已不再处理这些帧，写入的帧直接丢弃
     pub fn push(&mut self, value: T) {
-        match &mut self.guard.queue {
-            Some(queue) => queue.push_back(value),
-            None => panic!("queue is closed"),
+        if let Some(queue) = &mut self.guard.queue {
+            queue.push_back(value);
         }
     }
 
     pub fn rollback(&mut self) {
-        match &mut self.guard.queue {
-            Some(queue) => {
-                queue.truncate(self.old_len);
-            }
-            None => panic!("queue is closed"),
+        if let Some(queue) = &mut self.guard.queue {
+            queue.truncate(self.old_len);
         }
     }
 }
 
 impl<T> Drop for ArcFrameQueueWriter<'_, T> {
     fn drop(&mut self) {
-        match &mut self.guard.queue {
-            Some(queue) => {
-                if queue.len() > self.old_len {
-                    if let Some(waker) = self.guard.waker.take() {
-                        waker.wake();
-                    }
+        if let Some(queue) = &mut self.guard.queue {
+            if queue.len() > self.old_len {
+                if let Some(waker) = self.guard.waker.take() {
+                    waker.wake();
                 }
             }
-            None => {}
         }
     }
 }
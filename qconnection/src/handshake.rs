@@ -0,0 +1,161 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// The progress of the handshake, see [Section 4.1](https://www.rfc-editor.org/rfc/rfc9001.html#section-4.1) of QUIC-TLS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HandshakeState {
+    Handshaking,
+    /// The TLS handshake is complete, when the endpoint has both sent and received
+    /// the Finished message.
+    Completed,
+    /// For the server, the handshake is confirmed once it is complete; for the client,
+    /// it is confirmed when a HANDSHAKE_DONE frame is received.
+    Confirmed,
+}
+
+#[derive(Debug)]
+struct Handshake {
+    state: HandshakeState,
+    // 首次使用Handshake密钥，此后Initial密钥就可以丢弃了
+    handshake_keys_used: bool,
+    wakers: Vec<Waker>,
+}
+
+impl Handshake {
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArcHandshake(Arc<Mutex<Handshake>>);
+
+impl Default for ArcHandshake {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Handshake {
+            state: HandshakeState::Handshaking,
+            handshake_keys_used: false,
+            wakers: Vec::new(),
+        })))
+    }
+}
+
+impl ArcHandshake {
+    pub fn state(&self) -> HandshakeState {
+        self.0.lock().unwrap().state
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.state() == HandshakeState::Confirmed
+    }
+
+    pub(crate) fn on_handshake_keys_used(&self) {
+        let mut guard = self.0.lock().unwrap();
+        if !guard.handshake_keys_used {
+            guard.handshake_keys_used = true;
+            guard.wake_all();
+        }
+    }
+
+    pub(crate) fn complete(&self) {
+        self.advance(HandshakeState::Completed);
+    }
+
+    pub(crate) fn confirm(&self) {
+        self.advance(HandshakeState::Confirmed);
+    }
+
+    fn advance(&self, state: HandshakeState) {
+        let mut guard = self.0.lock().unwrap();
+        if guard.state < state {
+            guard.state = state;
+            guard.wake_all();
+        }
+    }
+
+    pub fn handshake_keys_used(&self) -> WaitHandshake {
+        WaitHandshake {
+            handshake: self.clone(),
+            until: |handshake| handshake.handshake_keys_used,
+        }
+    }
+
+    pub fn completed(&self) -> WaitHandshake {
+        WaitHandshake {
+            handshake: self.clone(),
+            until: |handshake| handshake.state >= HandshakeState::Completed,
+        }
+    }
+
+    pub fn confirmed(&self) -> WaitHandshake {
+        WaitHandshake {
+            handshake: self.clone(),
+            until: |handshake| handshake.state == HandshakeState::Confirmed,
+        }
+    }
+}
+
+pub struct WaitHandshake {
+    handshake: ArcHandshake,
+    until: fn(&Handshake) -> bool,
+}
+
+impl Future for WaitHandshake {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut guard = self.handshake.0.lock().unwrap();
+        if (self.until)(&guard) {
+            Poll::Ready(())
+        } else {
+            // 同一个任务反复poll，只保留一个waker
+            if !guard.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                guard.wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_progress() {
+        let handshake = ArcHandshake::default();
+        let confirmed = tokio::spawn(handshake.confirmed());
+        let keys_used = tokio::spawn(handshake.handshake_keys_used());
+
+        handshake.on_handshake_keys_used();
+        keys_used.await.unwrap();
+        handshake.complete();
+        handshake.completed().await;
+        assert!(!confirmed.is_finished());
+
+        handshake.confirm();
+        confirmed.await.unwrap();
+        assert!(handshake.is_confirmed());
+        // 状态不会回退
+        handshake.complete();
+        assert_eq!(handshake.state(), HandshakeState::Confirmed);
+    }
+
+    #[test]
+    fn test_poll_repeatedly() {
+        let handshake = ArcHandshake::default();
+        let mut confirmed = handshake.confirmed();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..10 {
+            assert!(Pin::new(&mut confirmed).poll(&mut cx).is_pending());
+        }
+        assert_eq!(handshake.0.lock().unwrap().wakers.len(), 1);
+    }
+}
@@ -1,22 +1,34 @@
+pub mod anti_replay;
+pub mod cid;
 pub mod connection;
 pub mod crypto;
 pub mod endpoint;
+pub mod error;
 pub mod frame_queue;
+pub mod handshake;
 pub mod path;
+pub mod tls;
+pub mod token;
 
 pub(crate) mod auto;
+pub(crate) mod send;
 
-use frame_queue::ArcFrameQueue;
 use qbase::packet::SpacePacket;
+use std::net::SocketAddr;
 
 pub trait ReceiveProtectedPacket {
-    fn receive_protected_packet(&mut self, protected_packet: SpacePacket);
+    fn receive_protected_packet(
+        &mut self,
+        protected_packet: SpacePacket,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    );
 }
 
 // 收包队列，就用tokio::sync::mpsc::UnboundedChannel
 // 收帧队列，得用VecDeque+is_closed+waker，外加Arc<Mutex>>包装，有close操作
 // 之所以要封装VecDeque，为了一个包坏了，全部帧都得回退
-// 收帧队列，又分为space的、connection的、path的3个
+// 收帧队列，又分为space的、connection的2个；path的帧很简单，在包解析成功后直接交给Path处理
 
 // 收包解帧任务，就用tokio::task::spawn产生，不同地从收包队列中取出包，取出密钥解帧，再放入对应的收帧队列中
 // 包有Arc<Mutex<Path>>信息，收到的Path相关帧写入到
@@ -1,10 +1,26 @@
-use super::ArcFrameQueue;
-use qbase::{cid::ConnectionId, frame::PathFrame};
+use anti_amplifier::ArcAntiAmplifier;
+use mtu::ArcPmtud;
+use qbase::{
+    cid::ConnectionId,
+    frame::{PathChallengeFrame, PathFrame, PathResponseFrame},
+    packet::PacketBuilder,
+};
 use qrecovery::rtt::Rtt;
 use std::{
+    collections::VecDeque,
     net::SocketAddr,
     sync::{Arc, Mutex},
 };
+use tokio::time::Instant;
+use validate::{ArcValidator, PathState, Validated};
+
+pub mod anti_amplifier;
+pub mod mtu;
+pub mod validate;
+
+/// Abandon path validation after 3 times of PTO, see
+/// [Section 8.2.4](https://www.rfc-editor.org/rfc/rfc9000.html#section-8.2.4) of QUIC.
+const VALIDATION_TIMEOUT_PTO_FACTOR: u32 = 3;
 
 #[derive(Debug)]
 pub struct Path {
@@ -16,13 +32,20 @@ pub struct Path {
     peer_addr: SocketAddr,
     // local_cid: ConnectionId,
     scid: ConnectionId, // scid.len == 0 表示没有使用连接id
-    dcid: ConnectionId, // dcid.len == 0 表示没有使用连接id
+    // dcid.len == 0 表示没有使用连接id；客户端收到Retry包后，要换成服务端新选择的连接id
+    dcid: Mutex<ConnectionId>,
 
-    // 待发包队列
-    frames: ArcFrameQueue<PathFrame>,
+    // 待发送的Path帧，只能在该Path上发送，比如PATH_RESPONSE必须在收到PATH_CHALLENGE的Path上回应
+    pending_frames: Arc<Mutex<VecDeque<PathFrame>>>,
     rtt: Arc<Mutex<Rtt>>,
+    // 对方地址验证通过之前，发送的数据量不得超过收到的3倍
+    anti_amplifier: ArcAntiAmplifier,
+    validator: ArcValidator,
+    // 每条路径的MTU各自探测
+    pmtud: ArcPmtud,
 }
 
+#[derive(Debug, Clone)]
 pub struct ArcPath(Arc<Path>);
 
 impl ArcPath {
@@ -36,23 +59,238 @@ impl ArcPath {
             local_addr,
             peer_addr,
             scid,
-            dcid,
-            frames: ArcFrameQueue::new(),
+            dcid: Mutex::new(dcid),
+            pending_frames: Arc::new(Mutex::new(VecDeque::new())),
             rtt: Arc::new(Mutex::new(Rtt::default())),
+            anti_amplifier: ArcAntiAmplifier::default(),
+            validator: ArcValidator::new(PathState::Unvalidated),
+            pmtud: ArcPmtud::default(),
         }))
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        self.0.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.0.peer_addr
+    }
+
+    pub fn scid(&self) -> ConnectionId {
+        self.0.scid
+    }
+
+    pub fn dcid(&self) -> ConnectionId {
+        *self.0.dcid.lock().unwrap()
+    }
+
+    pub fn set_dcid(&self, dcid: ConnectionId) {
+        *self.0.dcid.lock().unwrap() = dcid;
+    }
+
     pub fn rtt(&self) -> Arc<Mutex<Rtt>> {
         self.0.as_ref().rtt.clone()
     }
 
-    pub fn frames(&self) -> &ArcFrameQueue<PathFrame> {
-        &(self.0.as_ref().frames)
+    /// Take over the RTT estimate of another path, which is only reasonable when the
+    /// peer's address only changed its port, such as NAT rebinding.
+    pub fn inherit_rtt(&self, other: &ArcPath) {
+        let rtt = other.0.rtt.lock().unwrap().clone();
+        *self.0.rtt.lock().unwrap() = rtt;
+    }
+
+    pub fn is_same(&self, other: &ArcPath) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Answer the PATH_CHALLENGE frame on this path, and match the PATH_RESPONSE frame with
+    /// the challenges sent before, the path is validated once matched.
+    pub fn recv_frame(&self, frame: PathFrame) {
+        match frame {
+            PathFrame::Challenge(challenge) => {
+                let response = PathResponseFrame::from_slice(&challenge.data);
+                self.write_frame(PathFrame::Response(response));
+            }
+            PathFrame::Response(response) => {
+                if self.0.validator.on_response(&response.data) {
+                    self.0.anti_amplifier.grant();
+                }
+            }
+        }
+    }
+
+    pub fn anti_amplifier(&self) -> &ArcAntiAmplifier {
+        &self.0.anti_amplifier
+    }
+
+    pub fn pmtud(&self) -> &ArcPmtud {
+        &self.0.pmtud
+    }
+
+    /// The maximum size of UDP payload of the packets sent on this path.
+    pub fn mtu(&self) -> usize {
+        self.0.pmtud.mtu()
+    }
+
+    /// Write a frame to be sent on this path.
+    pub fn write_frame(&self, frame: PathFrame) {
+        self.0.pending_frames.lock().unwrap().push_back(frame);
+    }
+
+    /// Take a frame to be sent on this path, used when packing packets for this path.
+    pub fn pop_frame(&self) -> Option<PathFrame> {
+        self.0.pending_frames.lock().unwrap().pop_front()
+    }
+
+    fn has_pending_frames(&self) -> bool {
+        !self.0.pending_frames.lock().unwrap().is_empty()
+    }
+
+    /// Put the pending frames into the packet assembled for this path, as many as fit.
+    /// Returns whether any frame is put.
+    pub fn put_frames(&self, builder: &mut PacketBuilder) -> bool {
+        let mut pending_frames = self.0.pending_frames.lock().unwrap();
+        let mut put = false;
+        while let Some(frame) = pending_frames.front() {
+            if !builder.put_frame(frame) {
+                break;
+            }
+            pending_frames.pop_front();
+            put = true;
+        }
+        put
+    }
+
+    pub fn state(&self) -> PathState {
+        self.0.validator.state()
+    }
+
+    /// Wait until the validation finishes, returns true if the path is validated.
+    pub fn validated(&self) -> Validated {
+        self.0.validator.validated()
+    }
+
+    /// Start validating the path by sending PATH_CHALLENGE frames, which are retransmitted
+    /// with new data on every PTO, until a matched PATH_RESPONSE is received, or it fails
+    /// after 3 times of PTO.
+    pub fn validate(&self) {
+        let validator = self.0.validator.clone();
+        if !validator.begin() {
+            return;
+        }
+
+        let pending_frames = self.0.pending_frames.clone();
+        let rtt = self.0.rtt.clone();
+        tokio::spawn(async move {
+            let pto = rtt.lock().unwrap().pto_base_duration(0);
+            let deadline = Instant::now() + pto * VALIDATION_TIMEOUT_PTO_FACTOR;
+            loop {
+                let challenge = PathChallengeFrame {
+                    data: validator.challenge(),
+                };
+                pending_frames
+                    .lock()
+                    .unwrap()
+                    .push_back(PathFrame::Challenge(challenge));
+                let pto = rtt.lock().unwrap().pto_base_duration(0);
+                let timeout = std::cmp::min(Instant::now() + pto, deadline);
+                if tokio::time::timeout_at(timeout, validator.validated())
+                    .await
+                    .is_ok()
+                {
+                    return;
+                }
+                if Instant::now() >= deadline {
+                    validator.fail();
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+struct Paths {
+    // 当前发送数据所用的Path
+    active: Option<ArcPath>,
+    // 正在验证的新Path，或者迁移后尚未丢弃的旧Path
+    others: Vec<ArcPath>,
+    // 双方max_udp_payload_size的较小者，得知后各Path开始探测MTU
+    max_udp_payload_size: Option<usize>,
+}
+
+/// All the paths of a connection, only the active one is used to send data.
+#[derive(Debug, Default, Clone)]
+pub struct ArcPaths(Arc<Mutex<Paths>>);
+
+impl ArcPaths {
+    pub fn active(&self) -> Option<ArcPath> {
+        self.0.lock().unwrap().active.clone()
+    }
+
+    /// The path to send packets on. A path other than the active one is chosen only when it
+    /// has path frames to send, such as the PATH_CHALLENGE frames to validate it.
+    pub fn to_send(&self) -> Option<ArcPath> {
+        let guard = self.0.lock().unwrap();
+        guard
+            .others
+            .iter()
+            .find(|path| path.has_pending_frames())
+            .or(guard.active.as_ref())
+            .cloned()
+    }
+
+    pub fn find(&self, local_addr: SocketAddr, peer_addr: SocketAddr) -> Option<ArcPath> {
+        let guard = self.0.lock().unwrap();
+        guard
+            .active
+            .iter()
+            .chain(guard.others.iter())
+            .find(|path| path.local_addr() == local_addr && path.peer_addr() == peer_addr)
+            .cloned()
+    }
+
+    pub fn insert(&self, path: ArcPath) {
+        let mut guard = self.0.lock().unwrap();
+        if let Some(max_mtu) = guard.max_udp_payload_size {
+            path.pmtud().set_max_mtu(max_mtu);
+        }
+        if guard.active.is_none() {
+            guard.active = Some(path);
+        } else {
+            guard.others.push(path);
+        }
+    }
+
+    /// Start PMTU discovery on all the paths, including the paths created later.
+    pub fn set_max_udp_payload_size(&self, max_udp_payload_size: usize) {
+        let mut guard = self.0.lock().unwrap();
+        guard.max_udp_payload_size = Some(max_udp_payload_size);
+        for path in guard.active.iter().chain(guard.others.iter()) {
+            path.pmtud().set_max_mtu(max_udp_payload_size);
+        }
+    }
+
+    /// Make `path` the active path, the previous active path is kept until it is removed.
+    pub fn switch_to(&self, path: &ArcPath) {
+        let mut guard = self.0.lock().unwrap();
+        guard.others.retain(|p| !p.is_same(path));
+        if let Some(previous) = guard.active.replace(path.clone()) {
+            if !previous.is_same(path) {
+                guard.others.push(previous);
+            }
+        }
+    }
+
+    /// Remove a path that is not active.
+    pub fn remove(&self, path: &ArcPath) {
+        self.0.lock().unwrap().others.retain(|p| !p.is_same(path));
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use qbase::cid::ConnectionId;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
@@ -61,9 +299,113 @@ mod tests {
         // 构造一个Path结构
         let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
         let peer_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        let local_cid = ConnectionId::from_slice(b"local cid");
         let peer_cid = ConnectionId::from_slice(b"peer cid");
-        // let path = super::Path::new(local_addr, peer_addr, peer_cid);
+        let path = super::ArcPath::new(local_addr, peer_addr, local_cid, peer_cid);
+        assert_eq!(path.dcid(), peer_cid);
 
         // let _packet = path.read_1rtt_packet().await;
     }
+
+    #[tokio::test]
+    async fn test_path_validation() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let peer_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        let path = super::ArcPath::new(
+            local_addr,
+            peer_addr,
+            ConnectionId::from_slice(b"local cid"),
+            ConnectionId::from_slice(b"peer cid"),
+        );
+        assert_eq!(path.state(), PathState::Unvalidated);
+
+        // 对方的挑战，应在同一条路径上回应
+        path.recv_frame(PathFrame::Challenge(PathChallengeFrame { data: [1; 8] }));
+        assert_eq!(
+            path.pop_frame(),
+            Some(PathFrame::Response(PathResponseFrame { data: [1; 8] }))
+        );
+
+        path.validate();
+        tokio::task::yield_now().await;
+        assert_eq!(path.state(), PathState::Validating);
+        let Some(PathFrame::Challenge(challenge)) = path.pop_frame() else {
+            panic!("no PATH_CHALLENGE sent");
+        };
+        // 不匹配的响应被忽略
+        path.recv_frame(PathFrame::Response(PathResponseFrame { data: [2; 8] }));
+        assert_eq!(path.state(), PathState::Validating);
+
+        path.recv_frame(PathFrame::Response(PathResponseFrame::from_slice(
+            &challenge.data,
+        )));
+        assert!(path.validated().await);
+        assert!(path.anti_amplifier().is_validated());
+    }
+
+    #[test]
+    fn test_path_without_runtime() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let peer_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        // 创建Path不需要tokio运行时
+        let path = super::ArcPath::new(
+            local_addr,
+            peer_addr,
+            ConnectionId::from_slice(b"local cid"),
+            ConnectionId::from_slice(b"peer cid"),
+        );
+        path.recv_frame(PathFrame::Challenge(PathChallengeFrame { data: [3; 8] }));
+        assert_eq!(
+            path.pop_frame(),
+            Some(PathFrame::Response(PathResponseFrame { data: [3; 8] }))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_path_validation_timeout() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let peer_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        let path = super::ArcPath::new(
+            local_addr,
+            peer_addr,
+            ConnectionId::from_slice(b"local cid"),
+            ConnectionId::from_slice(b"peer cid"),
+        );
+        path.validate();
+        assert!(!path.validated().await);
+        assert_eq!(path.state(), PathState::Failed);
+        // 每个PTO都重传了新的挑战
+        let mut challenges = 0;
+        while let Some(frame) = path.pop_frame() {
+            assert!(matches!(frame, PathFrame::Challenge(_)));
+            challenges += 1;
+        }
+        assert!(challenges >= 3);
+    }
+
+    #[tokio::test]
+    async fn test_paths_switch() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let old_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+        let new_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8082);
+        let scid = ConnectionId::from_slice(b"local cid");
+        let old_path = ArcPath::new(local_addr, old_addr, scid, ConnectionId::random_gen(8));
+        let new_path = ArcPath::new(local_addr, new_addr, scid, ConnectionId::random_gen(8));
+
+        let paths = ArcPaths::default();
+        paths.insert(old_path.clone());
+        paths.insert(new_path.clone());
+        assert!(paths.active().unwrap().is_same(&old_path));
+        assert!(paths.find(local_addr, new_addr).unwrap().is_same(&new_path));
+
+        new_path.inherit_rtt(&old_path);
+        paths.switch_to(&new_path);
+        assert!(paths.active().unwrap().is_same(&new_path));
+        assert!(paths.find(local_addr, old_addr).is_some());
+        paths.remove(&old_path);
+        assert!(paths.find(local_addr, old_addr).is_none());
+        // 活跃的Path不会被移除
+        paths.remove(&new_path);
+        assert!(paths.active().unwrap().is_same(&new_path));
+    }
 }
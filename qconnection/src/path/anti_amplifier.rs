@@ -0,0 +1,134 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// Prior to validating the client address, servers MUST NOT send more than three times
+/// as many bytes as the number of bytes they have received, see
+/// [Section 8.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-8.1) of QUIC.
+pub const AMPLIFICATION_FACTOR: usize = 3;
+
+#[derive(Debug, Default)]
+struct AntiAmplifier {
+    rcvd_bytes: usize,
+    sent_bytes: usize,
+    // 地址验证通过后，就不再受抗放大攻击的限制
+    is_validated: bool,
+    waker: Option<Waker>,
+}
+
+impl AntiAmplifier {
+    fn balance(&self) -> usize {
+        if self.is_validated {
+            usize::MAX
+        } else {
+            (self.rcvd_bytes * AMPLIFICATION_FACTOR).saturating_sub(self.sent_bytes)
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Per-path byte counters for the anti-amplification limit. The sending task of
+/// the path must consult [`ArcAntiAmplifier::balance`] before sending a packet,
+/// and report the bytes sent with [`ArcAntiAmplifier::on_sent`].
+#[derive(Debug, Default, Clone)]
+pub struct ArcAntiAmplifier(Arc<Mutex<AntiAmplifier>>);
+
+impl ArcAntiAmplifier {
+    /// Once the peer address is validated, the limit is lifted.
+    pub fn new_validated() -> Self {
+        Self(Arc::new(Mutex::new(AntiAmplifier {
+            is_validated: true,
+            ..Default::default()
+        })))
+    }
+
+    pub fn on_rcvd(&self, amount: usize) {
+        let mut guard = self.0.lock().unwrap();
+        guard.rcvd_bytes += amount;
+        guard.wake();
+    }
+
+    pub fn on_sent(&self, amount: usize) {
+        let mut guard = self.0.lock().unwrap();
+        guard.sent_bytes += amount;
+    }
+
+    /// Lift the anti-amplification limit, which happens when the peer address is
+    /// validated by a token, a Handshake packet, or a PATH_RESPONSE frame.
+    pub fn grant(&self) {
+        let mut guard = self.0.lock().unwrap();
+        guard.is_validated = true;
+        guard.wake();
+    }
+
+    pub fn is_validated(&self) -> bool {
+        self.0.lock().unwrap().is_validated
+    }
+
+    /// How many bytes can be sent right now, usize::MAX if no limit.
+    pub fn balance_now(&self) -> usize {
+        self.0.lock().unwrap().balance()
+    }
+
+    /// Wait until there are some bytes can be sent, returns how many bytes can be sent.
+    pub fn balance(&self) -> Balance {
+        Balance(self.0.clone())
+    }
+}
+
+pub struct Balance(Arc<Mutex<AntiAmplifier>>);
+
+impl Future for Balance {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut guard = self.0.lock().unwrap();
+        match guard.balance() {
+            0 => {
+                guard.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            n => Poll::Ready(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anti_amplifier() {
+        let anti_amplifier = ArcAntiAmplifier::default();
+        assert_eq!(anti_amplifier.balance_now(), 0);
+        anti_amplifier.on_rcvd(1200);
+        assert_eq!(anti_amplifier.balance_now(), 3600);
+        anti_amplifier.on_sent(3000);
+        assert_eq!(anti_amplifier.balance_now(), 600);
+        anti_amplifier.on_sent(600);
+        assert_eq!(anti_amplifier.balance_now(), 0);
+        anti_amplifier.grant();
+        assert!(anti_amplifier.is_validated());
+        assert_eq!(anti_amplifier.balance_now(), usize::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_balance() {
+        let anti_amplifier = ArcAntiAmplifier::default();
+        let balance = tokio::spawn({
+            let anti_amplifier = anti_amplifier.clone();
+            async move { anti_amplifier.balance().await }
+        });
+        tokio::task::yield_now().await;
+        anti_amplifier.on_rcvd(100);
+        assert_eq!(balance.await.unwrap(), 300);
+    }
+}
@@ -0,0 +1,289 @@
+use super::ArcPath;
+use qrecovery::space::ObservePackets;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The smallest maximum datagram size that every QUIC path must support, which is
+/// also the BASE_PLPMTU of DPLPMTUD, see [Section 14](https://www.rfc-editor.org/rfc/rfc9000.html#section-14) of QUIC.
+pub const BASE_PLPMTU: usize = 1200;
+/// A probe size is considered not supported after this many probes are lost.
+const MAX_PROBES: u8 = 3;
+/// The search stops when the gap between the confirmed and the failed size is smaller.
+const MIN_PROBE_STEP: usize = 16;
+/// Full-size packets lost in a row before suspecting a black hole.
+const BLACK_HOLE_THRESHOLD: u8 = 3;
+/// Probe for a larger PMTU again after the search is complete, see
+/// [Section 5.1.1](https://www.rfc-editor.org/rfc/rfc8899.html#section-5.1.1) of DPLPMTUD.
+const PMTU_RAISE_TIMER: Duration = Duration::from_secs(600);
+
+/// The states of DPLPMTUD, see [Section 5.2](https://www.rfc-editor.org/rfc/rfc8899.html#section-5.2) of DPLPMTUD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmtudState {
+    /// The peer's max_udp_payload_size is not known yet, only BASE_PLPMTU is used.
+    Base,
+    Searching,
+    SearchComplete(Instant),
+}
+
+#[derive(Debug)]
+struct Pmtud {
+    state: PmtudState,
+    // 当前确认可用的PLPMTU
+    mtu: usize,
+    // 二分查找的上界，大于它的尺寸已知不可用，或者超过了双方的max_udp_payload_size
+    high: usize,
+    max_mtu: usize,
+    // 正在探测的尺寸，以及已经丢失的探测包个数
+    probe_size: usize,
+    lost_probes: u8,
+    // 飞行中的探测包：包号 -> 尺寸
+    inflight_probes: HashMap<u64, usize>,
+    lost_full_size: u8,
+}
+
+impl Pmtud {
+    fn start_search(&mut self) {
+        self.state = PmtudState::Searching;
+        self.high = self.max_mtu;
+        self.lost_probes = 0;
+        self.next_probe();
+    }
+
+    fn next_probe(&mut self) {
+        if self.high < self.mtu + MIN_PROBE_STEP {
+            self.state = PmtudState::SearchComplete(Instant::now());
+            self.probe_size = 0;
+        } else {
+            // 先尝试上界，大多数路径要么支持以太网的1500字节，要么支持更大的尺寸
+            self.probe_size = if self.probe_size == 0 {
+                self.high
+            } else {
+                (self.mtu + self.high).div_ceil(2)
+            };
+            self.lost_probes = 0;
+        }
+    }
+}
+
+/// Datagram Packetization Layer PMTU Discovery for a path, see [RFC 8899](https://www.rfc-editor.org/rfc/rfc8899.html).
+/// Probe packets are ack-eliciting packets padded to the probe size, such as PING+PADDING.
+/// The loss of a probe packet must not be treated as congestion nor the loss of data, so
+/// the sender reports all the acknowledged and lost packets here, and skips the congestion
+/// and retransmission handling for probes.
+#[derive(Debug, Clone)]
+pub struct ArcPmtud(Arc<Mutex<Pmtud>>);
+
+impl Default for ArcPmtud {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Pmtud {
+            state: PmtudState::Base,
+            mtu: BASE_PLPMTU,
+            high: BASE_PLPMTU,
+            max_mtu: BASE_PLPMTU,
+            probe_size: 0,
+            lost_probes: 0,
+            inflight_probes: HashMap::new(),
+            lost_full_size: 0,
+        })))
+    }
+}
+
+impl ArcPmtud {
+    /// The maximum size of UDP payload that can be sent on the path now.
+    pub fn mtu(&self) -> usize {
+        self.0.lock().unwrap().mtu
+    }
+
+    pub fn state(&self) -> PmtudState {
+        self.0.lock().unwrap().state
+    }
+
+    /// Start searching once the max_udp_payload_size of both endpoints are known,
+    /// `max_mtu` is the smaller one of them.
+    pub fn set_max_mtu(&self, max_mtu: usize) {
+        let mut guard = self.0.lock().unwrap();
+        if guard.state != PmtudState::Base || max_mtu <= BASE_PLPMTU {
+            return;
+        }
+        guard.max_mtu = max_mtu;
+        guard.start_search();
+    }
+
+    /// Returns the size of the next probe packet if a probe should be sent now.
+    pub fn probe_size(&self) -> Option<usize> {
+        let mut guard = self.0.lock().unwrap();
+        match guard.state {
+            PmtudState::Base => None,
+            PmtudState::SearchComplete(since) => {
+                if since.elapsed() < PMTU_RAISE_TIMER || guard.mtu >= guard.max_mtu {
+                    return None;
+                }
+                guard.start_search();
+                (guard.probe_size > 0).then_some(guard.probe_size)
+            }
+            // 同一时间只有一个探测包在飞行中
+            PmtudState::Searching if guard.inflight_probes.is_empty() => Some(guard.probe_size),
+            PmtudState::Searching => None,
+        }
+    }
+
+    pub fn on_probe_sent(&self, pn: u64, size: usize) {
+        self.0.lock().unwrap().inflight_probes.insert(pn, size);
+    }
+
+    /// Returns true if the packet is a probe packet.
+    pub fn on_packet_acked(&self, pn: u64, size: usize) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        match guard.inflight_probes.remove(&pn) {
+            Some(probe_size) => {
+                if probe_size > guard.mtu {
+                    guard.mtu = probe_size;
+                }
+                if guard.state == PmtudState::Searching && probe_size == guard.probe_size {
+                    guard.next_probe();
+                }
+                true
+            }
+            None => {
+                if size >= guard.mtu {
+                    guard.lost_full_size = 0;
+                }
+                false
+            }
+        }
+    }
+
+    /// Returns true if the packet is a probe packet, whose loss should be ignored by
+    /// the congestion controller, and nothing in it needs to be retransmitted.
+    pub fn on_packet_lost(&self, pn: u64, size: usize) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        match guard.inflight_probes.remove(&pn) {
+            Some(probe_size) => {
+                if guard.state == PmtudState::Searching && probe_size == guard.probe_size {
+                    guard.lost_probes += 1;
+                    if guard.lost_probes >= MAX_PROBES {
+                        guard.high = probe_size - 1;
+                        guard.next_probe();
+                    }
+                }
+                true
+            }
+            None => {
+                // 连续丢失满尺寸的包，可能路径MTU变小了，出现了黑洞，回退到BASE_PLPMTU重新探测
+                if size > BASE_PLPMTU && size >= guard.mtu {
+                    guard.lost_full_size += 1;
+                    if guard.lost_full_size >= BLACK_HOLE_THRESHOLD {
+                        guard.lost_full_size = 0;
+                        guard.mtu = BASE_PLPMTU;
+                        guard.probe_size = 0;
+                        guard.inflight_probes.clear();
+                        guard.start_search();
+                    }
+                }
+                false
+            }
+        }
+    }
+}
+
+/// The 1-RTT packets sent, and the path and the size of the datagram of each. Once a packet is
+/// acknowledged or deemed lost by the data space, it is reported to the PMTU discovery of the
+/// path it was sent on, which may receive the ACK frames on another path.
+#[derive(Debug, Clone, Default)]
+pub struct ArcSentPackets(Arc<Mutex<HashMap<u64, (ArcPath, usize)>>>);
+
+impl ArcSentPackets {
+    pub fn on_packet_sent(&self, pktid: u64, path: &ArcPath, size: usize) {
+        self.0.lock().unwrap().insert(pktid, (path.clone(), size));
+    }
+}
+
+impl ObservePackets for ArcSentPackets {
+    fn on_packet_acked(&self, pktid: u64) {
+        let sent = self.0.lock().unwrap().remove(&pktid);
+        if let Some((path, size)) = sent {
+            path.pmtud().on_packet_acked(pktid, size);
+        }
+    }
+
+    fn on_packet_lost(&self, pktid: u64) {
+        let sent = self.0.lock().unwrap().remove(&pktid);
+        // 丢失的探测包只说明该尺寸不可用，不涉及重传，连接尚未接入拥塞控制，也无需其他处理
+        if let Some((path, size)) = sent {
+            path.pmtud().on_packet_lost(pktid, size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 模拟一条PMTU为`path_mtu`的路径，返回发送的探测包个数
+    fn search(pmtud: &ArcPmtud, path_mtu: usize, pn: &mut u64) -> usize {
+        let mut probes = 0;
+        while let Some(size) = pmtud.probe_size() {
+            *pn += 1;
+            probes += 1;
+            pmtud.on_probe_sent(*pn, size);
+            if size <= path_mtu {
+                assert!(pmtud.on_packet_acked(*pn, size));
+            } else {
+                assert!(pmtud.on_packet_lost(*pn, size));
+            }
+        }
+        probes
+    }
+
+    #[test]
+    fn test_search() {
+        let pmtud = ArcPmtud::default();
+        assert_eq!(pmtud.mtu(), BASE_PLPMTU);
+        assert_eq!(pmtud.probe_size(), None);
+
+        pmtud.set_max_mtu(9000);
+        assert_eq!(pmtud.state(), PmtudState::Searching);
+        let mut pn = 0;
+        search(&pmtud, 1472, &mut pn);
+        assert!(matches!(pmtud.state(), PmtudState::SearchComplete(_)));
+        assert!(pmtud.mtu() <= 1472 && pmtud.mtu() + MIN_PROBE_STEP > 1472);
+    }
+
+    #[test]
+    fn test_max_mtu_supported() {
+        let pmtud = ArcPmtud::default();
+        pmtud.set_max_mtu(1452);
+        let mut pn = 0;
+        assert_eq!(search(&pmtud, 1500, &mut pn), 1);
+        assert_eq!(pmtud.mtu(), 1452);
+    }
+
+    #[test]
+    fn test_black_hole() {
+        let pmtud = ArcPmtud::default();
+        pmtud.set_max_mtu(1452);
+        let mut pn = 0;
+        search(&pmtud, 1500, &mut pn);
+        assert_eq!(pmtud.mtu(), 1452);
+
+        // 小包丢失不影响
+        assert!(!pmtud.on_packet_lost(100, 1000));
+        for i in 0..BLACK_HOLE_THRESHOLD as u64 - 1 {
+            assert!(!pmtud.on_packet_lost(101 + i, 1452));
+        }
+        // 中间有满尺寸的包被确认，重新计数
+        assert!(!pmtud.on_packet_acked(200, 1452));
+        for i in 0..BLACK_HOLE_THRESHOLD as u64 {
+            assert!(!pmtud.on_packet_lost(201 + i, 1452));
+        }
+        assert_eq!(pmtud.mtu(), BASE_PLPMTU);
+        assert_eq!(pmtud.state(), PmtudState::Searching);
+
+        let mut pn = 300;
+        search(&pmtud, 1300, &mut pn);
+        assert!(pmtud.mtu() <= 1300 && pmtud.mtu() + MIN_PROBE_STEP > 1300);
+    }
+}
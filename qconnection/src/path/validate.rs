@@ -0,0 +1,166 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// The state of path validation, see [Section 8.2](https://www.rfc-editor.org/rfc/rfc9000.html#section-8.2) of QUIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathState {
+    Unvalidated,
+    Validating,
+    Validated,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Validator {
+    state: PathState,
+    // 尚未被响应的挑战数据，每次重传都会使用新的挑战数据，响应其中任何一个都可以
+    challenges: Vec<[u8; 8]>,
+    wakers: Vec<Waker>,
+}
+
+impl Validator {
+    fn finish(&mut self, state: PathState) {
+        self.state = state;
+        self.challenges.clear();
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArcValidator(Arc<Mutex<Validator>>);
+
+impl ArcValidator {
+    pub fn new(state: PathState) -> Self {
+        Self(Arc::new(Mutex::new(Validator {
+            state,
+            challenges: Vec::new(),
+            wakers: Vec::new(),
+        })))
+    }
+
+    pub fn state(&self) -> PathState {
+        self.0.lock().unwrap().state
+    }
+
+    /// Returns false if the path is being validated or has been validated,
+    /// there is no need to start again.
+    pub(super) fn begin(&self) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        match guard.state {
+            PathState::Unvalidated | PathState::Failed => {
+                guard.state = PathState::Validating;
+                guard.challenges.clear();
+                true
+            }
+            PathState::Validating | PathState::Validated => false,
+        }
+    }
+
+    /// Generate a new challenge with unpredictable data, which is remembered
+    /// until the path is validated or validation fails.
+    pub(super) fn challenge(&self) -> [u8; 8] {
+        let data = rand::random::<[u8; 8]>();
+        self.0.lock().unwrap().challenges.push(data);
+        data
+    }
+
+    /// Returns true if the response matches any challenge, and the path is validated.
+    pub(super) fn on_response(&self, data: &[u8; 8]) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        if guard.state == PathState::Validating && guard.challenges.contains(data) {
+            guard.finish(PathState::Validated);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(super) fn fail(&self) {
+        let mut guard = self.0.lock().unwrap();
+        if guard.state == PathState::Validating {
+            guard.finish(PathState::Failed);
+        }
+    }
+
+    /// Wait until the validation finishes, returns true if the path is validated.
+    pub fn validated(&self) -> Validated {
+        Validated(self.0.clone())
+    }
+}
+
+pub struct Validated(Arc<Mutex<Validator>>);
+
+impl Future for Validated {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut guard = self.0.lock().unwrap();
+        match guard.state {
+            PathState::Validated => Poll::Ready(true),
+            PathState::Failed => Poll::Ready(false),
+            PathState::Unvalidated | PathState::Validating => {
+                // 同一个任务反复poll，只保留一个waker，不让wakers无限增长
+                if !guard.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    guard.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validator() {
+        let validator = ArcValidator::new(PathState::Unvalidated);
+        assert!(validator.begin());
+        assert!(!validator.begin());
+        assert_eq!(validator.state(), PathState::Validating);
+
+        let first = validator.challenge();
+        let second = validator.challenge();
+        // 与任何挑战都不同的响应，不能通过验证
+        let unknown = (0..=u8::MAX)
+            .map(|b| [b; 8])
+            .find(|data| *data != first && *data != second)
+            .unwrap();
+        assert!(!validator.on_response(&unknown));
+        assert_eq!(validator.state(), PathState::Validating);
+        assert!(validator.on_response(&first));
+        assert_eq!(validator.state(), PathState::Validated);
+        assert!(!validator.on_response(&second));
+    }
+
+    #[tokio::test]
+    async fn test_validation_failed() {
+        let validator = ArcValidator::new(PathState::Unvalidated);
+        validator.begin();
+        let validated = tokio::spawn(validator.validated());
+        tokio::task::yield_now().await;
+        validator.fail();
+        assert!(!validated.await.unwrap());
+        assert_eq!(validator.state(), PathState::Failed);
+    }
+
+    #[test]
+    fn test_poll_repeatedly() {
+        let validator = ArcValidator::new(PathState::Unvalidated);
+        validator.begin();
+        let mut validated = validator.validated();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..10 {
+            assert!(Pin::new(&mut validated).poll(&mut cx).is_pending());
+        }
+        assert_eq!(validator.0.lock().unwrap().wakers.len(), 1);
+    }
+}
@@ -0,0 +1,364 @@
+//! Seal the assembled payloads into packets, and coalesce them into one datagram.
+//! 负载组装好之后，才知道长包头中的Length字段，所以包头最后写入，然后加密、加包头保护。
+use crate::path::ArcPath;
+use bytes::{BufMut, BytesMut};
+use qbase::{
+    crypto::{HeaderProtectionKey, Keys, PacketKey},
+    frame::{ConnFrame, ConnectionCloseFrame},
+    packet::{
+        encrypt::{EncodeHeader, EncryptPacket, GreaseQuicBit, ProtectHeader},
+        header::{
+            long::{ext::WriteLongHeader, LongHeader},
+            short::ext::WriteOneRttHeader,
+            Protect, SetLength,
+        },
+        HandshakeHeader, InitialHeader, KeyPhaseBit, OneRttHeader, PacketBuilder, PacketNumber,
+        PacketWrapper, PayloadBuf, ZeroRttHeader,
+    },
+    varint::VarInt,
+};
+use qrecovery::{crypto::TransmitCrypto, space::SpaceIO, streams::TransmitStream};
+use std::sync::Arc;
+
+/// A client MUST expand the datagrams carrying Initial packets to at least 1200 bytes, so does
+/// a server for the ack-eliciting Initial packets, see [Section 14.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-14.1) of QUIC.
+/// The datagrams carrying PATH_CHALLENGE or PATH_RESPONSE frames are expanded likewise, see
+/// [Section 8.2.1](https://www.rfc-editor.org/rfc/rfc9000.html#section-8.2.1) of QUIC.
+pub const MIN_DATAGRAM_SIZE: usize = 1200;
+
+/// The packet number, the payload and the AEAD tag must be at least 20 bytes, to sample for
+/// header protection, otherwise the receiver discards the packet.
+const MIN_PROTECTED_LEN: usize = 20;
+
+pub(crate) enum UnsealedHeader {
+    Initial(InitialHeader),
+    Handshake(HandshakeHeader),
+    ZeroRtt(ZeroRttHeader),
+    OneRtt(OneRttHeader),
+}
+
+impl UnsealedHeader {
+    /// The length of the header before the packet number, the Length field of which is
+    /// encoded in the size enough for a packet of `max_size` bytes.
+    pub(crate) fn max_len(&self, max_size: usize) -> usize {
+        self.len_with(max_size)
+    }
+
+    fn len_with(&self, length: usize) -> usize {
+        let long_header_len = |dcid_len: usize, scid_len: usize| {
+            // 首字节、版本号、两个连接ID及其长度，以及Length字段
+            1 + 4 + 1 + dcid_len + 1 + scid_len + varint_size(length)
+        };
+        match self {
+            Self::Initial(header) => {
+                long_header_len(header.dcid.len(), header.scid.len())
+                    + varint_size(header.token.len())
+                    + header.token.len()
+            }
+            Self::Handshake(header) => long_header_len(header.dcid.len(), header.scid.len()),
+            Self::ZeroRtt(header) => long_header_len(header.dcid.len(), header.scid.len()),
+            Self::OneRtt(header) => 1 + header.dcid.len(),
+        }
+    }
+}
+
+fn varint_size(value: usize) -> usize {
+    VarInt::from_u64(value as u64)
+        .expect("packet too large")
+        .encoding_size()
+}
+
+pub(crate) enum SealingKeys {
+    Long(Arc<Keys>),
+    OneRtt(
+        Arc<dyn HeaderProtectionKey>,
+        KeyPhaseBit,
+        Arc<dyn PacketKey>,
+    ),
+}
+
+impl SealingKeys {
+    fn packet_key(&self) -> &dyn PacketKey {
+        match self {
+            Self::Long(keys) => keys.local.packet.as_ref(),
+            Self::OneRtt(_, _, packet_key) => packet_key.as_ref(),
+        }
+    }
+
+    fn header_key(&self) -> &dyn HeaderProtectionKey {
+        match self {
+            Self::Long(keys) => keys.local.header.as_ref(),
+            Self::OneRtt(header_key, _, _) => header_key.as_ref(),
+        }
+    }
+}
+
+/// A packet whose payload is assembled, but not sealed yet.
+pub(crate) struct UnsealedPacket {
+    header: UnsealedHeader,
+    pn: (u64, PacketNumber),
+    payload: BytesMut,
+    keys: SealingKeys,
+}
+
+impl UnsealedPacket {
+    pub(crate) fn new(
+        header: UnsealedHeader,
+        pn: (u64, PacketNumber),
+        mut payload: BytesMut,
+        keys: SealingKeys,
+    ) -> Self {
+        let protected_len = pn.1.size() + payload.len() + keys.packet_key().tag_len();
+        if protected_len < MIN_PROTECTED_LEN {
+            // PADDING帧就是一个0字节
+            payload.put_bytes(0, MIN_PROTECTED_LEN - protected_len);
+        }
+        Self {
+            header,
+            pn,
+            payload,
+            keys,
+        }
+    }
+
+    fn protected_len(&self) -> usize {
+        self.pn.1.size() + self.payload.len() + self.keys.packet_key().tag_len()
+    }
+
+    /// The packet number of the packet if it is a 1-RTT packet.
+    pub(crate) fn one_rtt_pktid(&self) -> Option<u64> {
+        matches!(self.header, UnsealedHeader::OneRtt(_)).then_some(self.pn.0)
+    }
+
+    /// The size of the packet once sealed.
+    pub(crate) fn size(&self) -> usize {
+        self.size_padded(0)
+    }
+
+    fn size_padded(&self, padding: usize) -> usize {
+        let protected_len = self.protected_len() + padding;
+        self.header.len_with(protected_len) + protected_len
+    }
+
+    fn pad(&mut self, padding: usize) {
+        self.payload.put_bytes(0, padding);
+    }
+
+    /// Write the header, encrypt the packet and protect the header, and put it into `datagram`.
+    pub(crate) fn seal(self, grease: bool, datagram: &mut BytesMut) {
+        let length = self.protected_len();
+        let (pktid, pn) = self.pn;
+        let tag_len = self.keys.packet_key().tag_len();
+        let mut raw_data = BytesMut::with_capacity(self.size());
+        let body = |raw_data: &mut BytesMut| {
+            raw_data.put_bytes(0, pn.size());
+            raw_data.extend_from_slice(&self.payload);
+            raw_data.put_bytes(0, tag_len);
+        };
+        let raw_data = match self.header {
+            UnsealedHeader::Initial(header) => {
+                let packet = long_packet(header, length, raw_data, body);
+                protect(packet, pn, pktid, pn.size(), &self.keys, grease)
+            }
+            UnsealedHeader::Handshake(header) => {
+                let packet = long_packet(header, length, raw_data, body);
+                protect(packet, pn, pktid, pn.size(), &self.keys, grease)
+            }
+            UnsealedHeader::ZeroRtt(header) => {
+                let packet = long_packet(header, length, raw_data, body);
+                protect(packet, pn, pktid, pn.size(), &self.keys, grease)
+            }
+            UnsealedHeader::OneRtt(header) => {
+                let SealingKeys::OneRtt(_, key_phase, _) = &self.keys else {
+                    unreachable!("1-RTT packets are sealed with 1-RTT keys")
+                };
+                // 首字节在encode_header时写入
+                raw_data.put_u8(0);
+                raw_data.put_one_rtt_header(&header);
+                let pn_offset = raw_data.len();
+                body(&mut raw_data);
+                let packet = PacketWrapper {
+                    header,
+                    raw_data,
+                    pn_offset,
+                };
+                protect(
+                    packet,
+                    (pn, *key_phase),
+                    pktid,
+                    pn.size(),
+                    &self.keys,
+                    grease,
+                )
+            }
+        };
+        datagram.extend_from_slice(&raw_data);
+    }
+}
+
+fn long_packet<S>(
+    mut header: LongHeader<S>,
+    length: usize,
+    mut raw_data: BytesMut,
+    body: impl FnOnce(&mut BytesMut),
+) -> PacketWrapper<LongHeader<S>>
+where
+    S: SetLength,
+    BytesMut: WriteLongHeader<S>,
+{
+    header.set_length(length);
+    // 首字节和版本号在encode_header时写入
+    raw_data.put_bytes(0, 5);
+    raw_data.put_long_header(&header);
+    let pn_offset = raw_data.len();
+    body(&mut raw_data);
+    PacketWrapper {
+        header,
+        raw_data,
+        pn_offset,
+    }
+}
+
+// 先写入包类型和包号，灰化固定位也要在加密之前，因为包头是被认证的
+fn protect<H>(
+    mut packet: PacketWrapper<H>,
+    params: <PacketWrapper<H> as EncodeHeader>::Params,
+    pktid: u64,
+    pn_len: usize,
+    keys: &SealingKeys,
+    grease: bool,
+) -> BytesMut
+where
+    H: Protect,
+    PacketWrapper<H>: EncodeHeader,
+{
+    packet.encode_header(params);
+    if grease {
+        packet.grease_quic_bit();
+    }
+    packet.encrypt_packet(pktid, pn_len, keys.packet_key());
+    packet.protect_header(pn_len, keys.header_key());
+    packet.raw_data
+}
+
+/// The payload assembled from a space, see [`assemble_payload`].
+pub(crate) struct Payload {
+    pub(crate) pn: (u64, PacketNumber),
+    pub(crate) data: BytesMut,
+    /// The bytes counted as in flight by the space, 0 if the packet is not ack-eliciting.
+    pub(crate) sent_bytes: usize,
+    pub(crate) has_path_frames: bool,
+}
+
+/// Assemble the payload of a packet of `space`, which is sealed with `header` within
+/// `max_size` bytes. The pending frames of `path` are put first if given, which can only be
+/// sent in 1-RTT packets. Returns None if there is nothing to send.
+pub(crate) fn assemble_payload<CT, ST>(
+    space: &SpaceIO<CT, ST>,
+    header: &UnsealedHeader,
+    tag_len: usize,
+    max_size: usize,
+    path: Option<&ArcPath>,
+) -> Option<Payload>
+where
+    CT: TransmitCrypto<Buffer = PayloadBuf>,
+    ST: TransmitStream<Buffer = PayloadBuf>,
+{
+    let header_len = header.max_len(max_size);
+    // 太小的包无法加包头保护，留给下一个数据报
+    if max_size < header_len + MIN_PROTECTED_LEN {
+        return None;
+    }
+    let (pktid, pn) = space.next_pn();
+    let mut builder = PacketBuilder::new(max_size, header_len, pn.size(), tag_len)?;
+    let has_path_frames = path.is_some_and(|path| path.put_frames(&mut builder));
+    let Ok(Some((sent_pktid, sent_bytes))) = space.try_send(builder.payload_mut()) else {
+        return None;
+    };
+    debug_assert_eq!(sent_pktid, pktid);
+    Some(Payload {
+        pn: (pktid, pn),
+        data: builder.finish()?,
+        sent_bytes,
+        has_path_frames,
+    })
+}
+
+/// Assemble the payload of a packet carrying only the CONNECTION_CLOSE `frame`. It is not
+/// recorded as inflight by `space`, since nothing else will be sent in the closing state.
+pub(crate) fn assemble_close<CT, ST>(
+    space: &SpaceIO<CT, ST>,
+    header: &UnsealedHeader,
+    tag_len: usize,
+    max_size: usize,
+    frame: ConnectionCloseFrame,
+) -> Option<Payload>
+where
+    CT: TransmitCrypto,
+    ST: TransmitStream,
+{
+    let pn = space.next_pn();
+    Some(Payload {
+        pn,
+        data: close_payload(header, pn.1, tag_len, max_size, frame)?,
+        sent_bytes: 0,
+        has_path_frames: false,
+    })
+}
+
+/// Build the payload carrying only the CONNECTION_CLOSE `frame` with the packet number `pn`,
+/// which is also used by the endpoint to close a connection it never creates.
+pub(crate) fn close_payload(
+    header: &UnsealedHeader,
+    pn: PacketNumber,
+    tag_len: usize,
+    max_size: usize,
+    frame: ConnectionCloseFrame,
+) -> Option<BytesMut> {
+    let header_len = header.max_len(max_size);
+    if max_size < header_len + MIN_PROTECTED_LEN {
+        return None;
+    }
+    let mut builder = PacketBuilder::new(max_size, header_len, pn.size(), tag_len)?;
+    if !builder.put_frame(&ConnFrame::Close(frame)) {
+        return None;
+    }
+    builder.finish()
+}
+
+/// Coalesce the packets into one datagram in order, the last one of which is padded if the
+/// datagram must be at least `min_size` bytes. The packets are assembled within `max_size`.
+pub(crate) fn coalesce(
+    mut packets: Vec<UnsealedPacket>,
+    min_size: usize,
+    max_size: usize,
+    grease: bool,
+) -> BytesMut {
+    let size = packets.iter().map(UnsealedPacket::size).sum::<usize>();
+    if size < min_size {
+        pad(&mut packets, size, min_size, max_size);
+    }
+    let mut datagram = BytesMut::with_capacity(max_size);
+    for packet in packets {
+        packet.seal(grease, &mut datagram);
+    }
+    datagram
+}
+
+// 长包的Length字段变长时，包会多出1个字节，可能恰好越过max_size，这时改为填充其他的包
+fn pad(packets: &mut [UnsealedPacket], size: usize, min_size: usize, max_size: usize) {
+    let padding = min_size - size;
+    let last = packets.len() - 1;
+    for idx in [last, 0] {
+        let packet = &packets[idx];
+        let fits = |padding: usize| {
+            let size = size - packet.size() + packet.size_padded(padding);
+            size >= min_size && size <= max_size
+        };
+        if let Some(padding) = [padding, padding - 1].into_iter().find(|p| fits(*p)) {
+            packets[idx].pad(padding);
+            return;
+        }
+    }
+    packets[last].pad(padding);
+}
@@ -0,0 +1,630 @@
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    server::{AllowAnyAuthenticatedClient, ClientCertVerifier, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, ClientConfig, KeyLog, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use thiserror::Error;
+
+pub mod pem;
+
+/// Early data must be unlimited in TLS for QUIC, the amount of 0-RTT data is limited by
+/// the flow control instead, see [Section 4.6.1](https://www.rfc-editor.org/rfc/rfc9001.html#section-4.6.1) of QUIC-TLS.
+const QUIC_MAX_EARLY_DATA_SIZE: u32 = 0xffffffff;
+
+#[derive(Debug, Error)]
+pub enum TlsConfigError {
+    #[error("tls error: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("no certificate found")]
+    NoCertificate,
+    #[error("no private key found")]
+    NoPrivateKey,
+    #[error("unsupported private key: {0}")]
+    Sign(#[from] sign::SignError),
+}
+
+fn load_cert_and_key(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+) -> Result<(Vec<Certificate>, PrivateKey), TlsConfigError> {
+    let certs = pem::certs_from_pem(cert_pem)?;
+    if certs.is_empty() {
+        return Err(TlsConfigError::NoCertificate);
+    }
+    let key = pem::private_key_from_pem(key_pem)?.ok_or(TlsConfigError::NoPrivateKey)?;
+    Ok((certs, key))
+}
+
+/// Load the certificate chain and private key in PEM format for a certificate resolver,
+/// such as rustls::server::ResolvesServerCertUsingSni which picks one by SNI.
+pub fn certified_key_from_pem(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+) -> Result<CertifiedKey, TlsConfigError> {
+    let (certs, key) = load_cert_and_key(cert_pem, key_pem)?;
+    Ok(CertifiedKey::new(certs, sign::any_supported_type(&key)?))
+}
+
+/// Build the rustls::ClientConfig for [`crate::crypto::TlsIO::new_client`]. The server
+/// certificates are verified with the root certificates, or a custom verifier, such as
+/// [`PinnedServerCerts`].
+pub struct ClientTlsConfigBuilder {
+    roots: RootCertStore,
+    verifier: Option<Arc<dyn ServerCertVerifier>>,
+    alpn_protocols: Vec<Vec<u8>>,
+    client_cert: Option<(Vec<Certificate>, PrivateKey)>,
+    enable_early_data: bool,
+    key_log: Option<Arc<dyn KeyLog>>,
+}
+
+impl Default for ClientTlsConfigBuilder {
+    fn default() -> Self {
+        Self {
+            roots: RootCertStore::empty(),
+            verifier: None,
+            alpn_protocols: Vec::new(),
+            client_cert: None,
+            enable_early_data: false,
+            key_log: None,
+        }
+    }
+}
+
+impl ClientTlsConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_root_certificates(mut self, roots: RootCertStore) -> Self {
+        self.roots = roots;
+        self
+    }
+
+    /// Trust the CA certificates in PEM format, in addition to the existing roots.
+    pub fn add_root_pem(mut self, pem: &[u8]) -> Result<Self, TlsConfigError> {
+        for cert in pem::certs_from_pem(pem)? {
+            self.roots.add(&cert)?;
+        }
+        Ok(self)
+    }
+
+    /// Replace the verification against the root certificates with a custom verifier.
+    pub fn with_server_verifier(mut self, verifier: Arc<dyn ServerCertVerifier>) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// The application protocols offered in the order of preference. If the server
+    /// supports none of them, the handshake fails with crypto error 0x178.
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+
+    /// The certificate chain and private key to authenticate to the server if requested.
+    pub fn with_client_cert(mut self, certs: Vec<Certificate>, key: PrivateKey) -> Self {
+        self.client_cert = Some((certs, key));
+        self
+    }
+
+    pub fn with_client_cert_pem(
+        self,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<Self, TlsConfigError> {
+        let (certs, key) = load_cert_and_key(cert_pem, key_pem)?;
+        Ok(self.with_client_cert(certs, key))
+    }
+
+    /// Send 0-RTT data when resuming a session whose ticket allows early data.
+    pub fn enable_early_data(mut self, enabled: bool) -> Self {
+        self.enable_early_data = enabled;
+        self
+    }
+
+    /// Log the TLS secrets for debugging, such as with rustls::KeyLogFile, which writes
+    /// to the file named by the `SSLKEYLOGFILE` environment variable.
+    pub fn with_key_log(mut self, key_log: Arc<dyn KeyLog>) -> Self {
+        self.key_log = Some(key_log);
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<ClientConfig>, TlsConfigError> {
+        let verifier = self
+            .verifier
+            .unwrap_or_else(|| Arc::new(WebPkiVerifier::new(self.roots, None)));
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            // QUIC只能使用TLS 1.3
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_custom_certificate_verifier(verifier);
+        let mut config = match self.client_cert {
+            Some((certs, key)) => builder.with_client_auth_cert(certs, key)?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols;
+        config.enable_early_data = self.enable_early_data;
+        if let Some(key_log) = self.key_log {
+            config.key_log = key_log;
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+enum ServerCert {
+    Single(Vec<Certificate>, PrivateKey),
+    Resolver(Arc<dyn ResolvesServerCert>),
+}
+
+/// Build the rustls::ServerConfig for [`crate::endpoint::Endpiont`].
+pub struct ServerTlsConfigBuilder {
+    cert: ServerCert,
+    alpn_protocols: Vec<Vec<u8>>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    enable_early_data: bool,
+    key_log: Option<Arc<dyn KeyLog>>,
+}
+
+impl ServerTlsConfigBuilder {
+    pub fn new(certs: Vec<Certificate>, key: PrivateKey) -> Self {
+        Self::with_cert(ServerCert::Single(certs, key))
+    }
+
+    /// Pick the certificate for each connection with the `resolver`, such as by the
+    /// server name indicated by the client, to host several services on one endpoint.
+    pub fn with_cert_resolver(resolver: Arc<dyn ResolvesServerCert>) -> Self {
+        Self::with_cert(ServerCert::Resolver(resolver))
+    }
+
+    fn with_cert(cert: ServerCert) -> Self {
+        Self {
+            cert,
+            alpn_protocols: Vec::new(),
+            client_verifier: None,
+            enable_early_data: false,
+            key_log: None,
+        }
+    }
+
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, TlsConfigError> {
+        let (certs, key) = load_cert_and_key(cert_pem, key_pem)?;
+        Ok(Self::new(certs, key))
+    }
+
+    /// The application protocols supported in the order of preference. A client offering
+    /// none of them is refused with crypto error 0x178.
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+
+    /// Require the clients to authenticate with certificates issued by the `roots`.
+    pub fn require_client_auth(self, roots: RootCertStore) -> Self {
+        self.with_client_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+    }
+
+    pub fn with_client_verifier(mut self, verifier: Arc<dyn ClientCertVerifier>) -> Self {
+        self.client_verifier = Some(verifier);
+        self
+    }
+
+    /// Issue session tickets that allow 0-RTT data. The tickets are single-use and kept
+    /// in the server's memory, which are not valid on other servers. Whether the 0-RTT
+    /// data of a connection is accepted is also decided by the endpoint's anti-replay policy.
+    pub fn enable_early_data(mut self, enabled: bool) -> Self {
+        self.enable_early_data = enabled;
+        self
+    }
+
+    /// Log the TLS secrets for debugging, see [`ClientTlsConfigBuilder::with_key_log`].
+    pub fn with_key_log(mut self, key_log: Arc<dyn KeyLog>) -> Self {
+        self.key_log = Some(key_log);
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<ServerConfig>, TlsConfigError> {
+        let builder = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?;
+        let builder = match self.client_verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = match self.cert {
+            ServerCert::Single(certs, key) => builder.with_single_cert(certs, key)?,
+            ServerCert::Resolver(resolver) => builder.with_cert_resolver(resolver),
+        };
+        config.alpn_protocols = self.alpn_protocols;
+        if self.enable_early_data {
+            config.max_early_data_size = QUIC_MAX_EARLY_DATA_SIZE;
+        }
+        if let Some(key_log) = self.key_log {
+            config.key_log = key_log;
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+/// Write the TLS secrets in the [NSS key log format](https://firefox-source-docs.mozilla.org/security/nss/legacy/key_log_format/index.html)
+/// to any writer, which Wireshark reads to decrypt the QUIC packets. All the secrets of
+/// QUIC are logged, including the handshake, 0-RTT and 1-RTT traffic secrets, the secrets
+/// after key updates are derived by Wireshark itself.
+#[derive(Debug)]
+pub struct KeyLogWriter<W>(Mutex<W>);
+
+impl<W: Write + Send> KeyLogWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self(Mutex::new(writer))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.0.into_inner().unwrap()
+    }
+}
+
+impl<W: Write + Send> KeyLog for KeyLogWriter<W> {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line =
+            String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+        line.push_str(label);
+        line.push(' ');
+        for b in client_random {
+            let _ = write!(line, "{b:02x}");
+        }
+        line.push(' ');
+        for b in secret {
+            let _ = write!(line, "{b:02x}");
+        }
+        line.push('\n');
+        // 日志仅用于调试，写入失败也不影响连接
+        let _ = self.0.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+/// Trust only the given end-entity certificates, regardless of the issuers and the server
+/// name, which is useful for self-signed certificates and certificate pinning.
+#[derive(Debug, Clone)]
+pub struct PinnedServerCerts(Vec<Certificate>);
+
+impl PinnedServerCerts {
+    pub fn new(certs: Vec<Certificate>) -> Self {
+        Self(certs)
+    }
+}
+
+impl ServerCertVerifier for PinnedServerCerts {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.0.contains(end_entity) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::crypto::TlsIO;
+    use qbase::{config::TransportParameters, error::ErrorKind, packet::QUIC_V1, streamid::Role};
+
+    pub(crate) const CA_CERT: &[u8] = include_bytes!("../testdata/ca.crt");
+    pub(crate) const SERVER_CERT: &[u8] = include_bytes!("../testdata/server.crt");
+    pub(crate) const SERVER_KEY: &[u8] = include_bytes!("../testdata/server.key");
+    const CLIENT_CERT: &[u8] = include_bytes!("../testdata/client.crt");
+    const CLIENT_KEY: &[u8] = include_bytes!("../testdata/client.key");
+    const OTHER_CERT: &[u8] = include_bytes!("../testdata/other.crt");
+    const OTHER_KEY: &[u8] = include_bytes!("../testdata/other.key");
+
+    fn roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        for cert in pem::certs_from_pem(CA_CERT).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        roots
+    }
+
+    /// 不经过QUIC的包，直接在内存中交换TLS握手消息，直到握手完成或出错
+    pub(crate) fn handshake(client: &TlsIO, server: &TlsIO) -> Result<(), TlsIO> {
+        let (mut client_reader, _) = client.split_io();
+        let (mut server_reader, _) = server.split_io();
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+            for data in client.write_hs_now() {
+                if server_reader.read_hs(&data).is_err() {
+                    return Err(server.clone());
+                }
+            }
+            for data in server.write_hs_now() {
+                if client_reader.read_hs(&data).is_err() {
+                    return Err(client.clone());
+                }
+            }
+        }
+        unreachable!("handshake doesn't converge")
+    }
+
+    fn new_client(config: Arc<ClientConfig>) -> TlsIO {
+        let server_name = "localhost".try_into().unwrap();
+        TlsIO::new_client(
+            config,
+            QUIC_V1,
+            server_name,
+            &TransportParameters::default(),
+        )
+        .unwrap()
+    }
+
+    fn new_server(config: Arc<ServerConfig>) -> TlsIO {
+        TlsIO::new_server(config, QUIC_V1, &TransportParameters::default()).unwrap()
+    }
+
+    #[test]
+    fn test_alpn_and_sni() {
+        let client_config = ClientTlsConfigBuilder::new()
+            .add_root_pem(CA_CERT)
+            .unwrap()
+            .with_alpn_protocols(vec![b"h3".to_vec(), b"hq-interop".to_vec()])
+            .build()
+            .unwrap();
+        let server_config = ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .with_alpn_protocols(vec![b"hq-interop".to_vec()])
+            .build()
+            .unwrap();
+        let (client, server) = (new_client(client_config.clone()), new_server(server_config));
+        assert!(handshake(&client, &server).is_ok());
+        assert_eq!(client.alpn_protocol(), Some(b"hq-interop".to_vec()));
+        assert_eq!(server.alpn_protocol(), Some(b"hq-interop".to_vec()));
+        assert_eq!(client.server_name(), Some("localhost".to_string()));
+        assert_eq!(server.server_name(), Some("localhost".to_string()));
+        let certs = client.peer_certificates().unwrap();
+        assert_eq!(certs, pem::certs_from_pem(SERVER_CERT).unwrap());
+        assert_eq!(server.peer_certificates(), None);
+
+        let server_config = ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .with_alpn_protocols(vec![b"smtp".to_vec()])
+            .build()
+            .unwrap();
+        let server = new_server(server_config);
+        let failed = handshake(&new_client(client_config), &server).unwrap_err();
+        let error = failed.crypto_error(&rustls::Error::NoApplicationProtocol);
+        assert_eq!(error.kind, ErrorKind::Crypto(0x78));
+        assert_eq!(qbase::varint::VarInt::from(error.kind).into_inner(), 0x178);
+    }
+
+    #[test]
+    fn test_pinned_server_cert() {
+        let pinned = PinnedServerCerts::new(pem::certs_from_pem(SERVER_CERT).unwrap());
+        let client_config = ClientTlsConfigBuilder::new()
+            .with_server_verifier(Arc::new(pinned))
+            .build()
+            .unwrap();
+        let server_config = ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(handshake(
+            &new_client(client_config),
+            &new_server(server_config.clone())
+        )
+        .is_ok());
+
+        let pinned = PinnedServerCerts::new(pem::certs_from_pem(CLIENT_CERT).unwrap());
+        let client_config = ClientTlsConfigBuilder::new()
+            .with_server_verifier(Arc::new(pinned))
+            .build()
+            .unwrap();
+        let failed = handshake(&new_client(client_config), &new_server(server_config)).unwrap_err();
+        assert_eq!(failed.role(), Role::Client);
+    }
+
+    #[test]
+    fn test_client_auth() {
+        let server_config = ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .require_client_auth(roots())
+            .build()
+            .unwrap();
+        let client_config = ClientTlsConfigBuilder::new()
+            .with_root_certificates(roots())
+            .with_client_cert_pem(CLIENT_CERT, CLIENT_KEY)
+            .unwrap()
+            .build()
+            .unwrap();
+        let server = new_server(server_config.clone());
+        assert!(handshake(&new_client(client_config), &server).is_ok());
+        assert_eq!(
+            server.peer_certificates().unwrap(),
+            pem::certs_from_pem(CLIENT_CERT).unwrap()
+        );
+
+        let client_config = ClientTlsConfigBuilder::new()
+            .with_root_certificates(roots())
+            .build()
+            .unwrap();
+        let failed = handshake(&new_client(client_config), &new_server(server_config)).unwrap_err();
+        assert_eq!(failed.role(), Role::Server);
+    }
+
+    #[test]
+    fn test_sni_cert_resolver() {
+        let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+        let key = certified_key_from_pem(SERVER_CERT, SERVER_KEY).unwrap();
+        resolver.add("localhost", key).unwrap();
+        let key = certified_key_from_pem(OTHER_CERT, OTHER_KEY).unwrap();
+        resolver.add("example.test", key).unwrap();
+        let server_config = ServerTlsConfigBuilder::with_cert_resolver(Arc::new(resolver))
+            .build()
+            .unwrap();
+
+        let pinned = PinnedServerCerts::new(pem::certs_from_pem(OTHER_CERT).unwrap());
+        let client_config = ClientTlsConfigBuilder::new()
+            .with_server_verifier(Arc::new(pinned))
+            .build()
+            .unwrap();
+        let connect = |name: &str| {
+            let server_name = name.try_into().unwrap();
+            let client = TlsIO::new_client(
+                client_config.clone(),
+                QUIC_V1,
+                server_name,
+                &TransportParameters::default(),
+            )
+            .unwrap();
+            handshake(&client, &new_server(server_config.clone())).map(|_| client)
+        };
+        let client = connect("example.test").unwrap();
+        assert_eq!(
+            client.peer_certificates().unwrap(),
+            pem::certs_from_pem(OTHER_CERT).unwrap()
+        );
+        // localhost的证书不是固定的证书
+        assert_eq!(connect("localhost").unwrap_err().role(), Role::Client);
+        // 未知的服务名，服务端找不到证书
+        assert_eq!(connect("unknown.test").unwrap_err().role(), Role::Server);
+    }
+
+    #[test]
+    fn test_exporter_and_key_log() {
+        let client_log = Arc::new(KeyLogWriter::new(Vec::new()));
+        let server_log = Arc::new(KeyLogWriter::new(Vec::new()));
+        let client_config = ClientTlsConfigBuilder::new()
+            .add_root_pem(CA_CERT)
+            .unwrap()
+            .with_key_log(client_log.clone())
+            .build()
+            .unwrap();
+        let server_config = ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .with_key_log(server_log.clone())
+            .build()
+            .unwrap();
+        let (client, server) = (new_client(client_config), new_server(server_config));
+        // 握手完成之前不能导出
+        assert!(client
+            .export_keying_material(b"EXPORTER-test", None, 32)
+            .is_err());
+        assert!(handshake(&client, &server).is_ok());
+
+        let material = client
+            .export_keying_material(b"EXPORTER-test", Some(b"ctx"), 32)
+            .unwrap();
+        assert_eq!(material.len(), 32);
+        assert_eq!(
+            server
+                .export_keying_material(b"EXPORTER-test", Some(b"ctx"), 32)
+                .unwrap(),
+            material
+        );
+        assert_ne!(
+            server
+                .export_keying_material(b"EXPORTER-test", None, 32)
+                .unwrap(),
+            material
+        );
+
+        let client_log = String::from_utf8(Arc::into_inner(client_log).unwrap().into_inner());
+        let server_log = String::from_utf8(Arc::into_inner(server_log).unwrap().into_inner());
+        let (client_log, server_log) = (client_log.unwrap(), server_log.unwrap());
+        for label in [
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            "CLIENT_TRAFFIC_SECRET_0",
+            "SERVER_TRAFFIC_SECRET_0",
+        ] {
+            let line = client_log.lines().find(|l| l.starts_with(label)).unwrap();
+            // 双方记录的同一个密钥完全一致
+            assert!(server_log.lines().any(|l| l == line));
+            let fields: Vec<_> = line.split(' ').collect();
+            assert_eq!(fields.len(), 3);
+            assert_eq!(fields[1].len(), 64);
+        }
+    }
+
+    #[test]
+    fn test_missing_cert_or_key() {
+        assert!(matches!(
+            ServerTlsConfigBuilder::from_pem(SERVER_KEY, SERVER_KEY),
+            Err(TlsConfigError::NoCertificate)
+        ));
+        assert!(matches!(
+            ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_CERT),
+            Err(TlsConfigError::NoPrivateKey)
+        ));
+    }
+
+    #[test]
+    fn test_quic_v2() {
+        use qbase::packet::QUIC_V2;
+
+        let client_config = ClientTlsConfigBuilder::new()
+            .add_root_pem(CA_CERT)
+            .unwrap()
+            .build()
+            .unwrap();
+        let server_config = ServerTlsConfigBuilder::from_pem(SERVER_CERT, SERVER_KEY)
+            .unwrap()
+            .build()
+            .unwrap();
+        let params = TransportParameters::default();
+        let server_name = "localhost".try_into().unwrap();
+        let client = TlsIO::new_client(client_config, QUIC_V2, server_name, &params).unwrap();
+        let server = TlsIO::new_server(server_config.clone(), QUIC_V2, &params).unwrap();
+        assert_eq!(client.version(), QUIC_V2);
+        handshake(&client, &server).unwrap();
+
+        // v2的Initial密钥由不同的盐推导，v1的密钥无法解密
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let client_keys = client.initial_keys(&dcid);
+        let mut payload = vec![0u8; 32];
+        payload[..5].copy_from_slice(b"hello");
+        client_keys
+            .local
+            .packet
+            .encrypt_in_place(0, b"header", &mut payload)
+            .unwrap();
+        let v1_server = TlsIO::new_server(server_config.clone(), QUIC_V1, &params).unwrap();
+        let v1_keys = v1_server.initial_keys(&dcid);
+        let mut v1_payload = payload.clone();
+        assert!(v1_keys
+            .remote
+            .packet
+            .decrypt_in_place(0, b"header", &mut v1_payload)
+            .is_err());
+        let server_keys = server.initial_keys(&dcid);
+        let len = server_keys
+            .remote
+            .packet
+            .decrypt_in_place(0, b"header", &mut payload)
+            .unwrap();
+        assert_eq!(&payload[..5], b"hello");
+        assert_eq!(len, 16);
+
+        assert!(TlsIO::new_server(server_config, 0x1a2a_3a4a, &params).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use rustls::{Certificate, PrivateKey};
+use std::{fs, io, path::Path};
+
+/// The labels of the private keys that rustls can use, PKCS#8, PKCS#1 for RSA, and SEC1
+/// for EC keys.
+const PRIVATE_KEY_LABELS: [&str; 3] = ["PRIVATE KEY", "RSA PRIVATE KEY", "EC PRIVATE KEY"];
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+fn decode_base64(text: &str) -> io::Result<Vec<u8>> {
+    let mut der = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0u32);
+    let mut padding = false;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            padding = true;
+            continue;
+        }
+        // 填充符只能出现在末尾
+        if padding {
+            return Err(invalid_data("invalid base64 padding"));
+        }
+        let value = base64_value(c).ok_or_else(|| invalid_data("invalid base64 character"))?;
+        acc = (acc << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            der.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Ok(der)
+}
+
+/// Parse all the sections in PEM format, returns the labels and the DER contents.
+fn parse_sections(pem: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let text = std::str::from_utf8(pem).map_err(|_| invalid_data("PEM is not valid UTF-8"))?;
+    let mut sections = Vec::new();
+    let mut current: Option<(String, String)> = None;
+    for line in text.lines().map(str::trim) {
+        match current.as_mut() {
+            None => {
+                if let Some(label) = line
+                    .strip_prefix("-----BEGIN ")
+                    .and_then(|rest| rest.strip_suffix("-----"))
+                {
+                    current = Some((label.to_string(), String::new()));
+                }
+            }
+            Some((label, body)) => {
+                if let Some(end) = line
+                    .strip_prefix("-----END ")
+                    .and_then(|rest| rest.strip_suffix("-----"))
+                {
+                    if end != label {
+                        return Err(invalid_data("mismatched PEM END line"));
+                    }
+                    let der = decode_base64(body)?;
+                    sections.push((std::mem::take(label), der));
+                    current = None;
+                } else {
+                    body.push_str(line);
+                }
+            }
+        }
+    }
+    if current.is_some() {
+        return Err(invalid_data("missing PEM END line"));
+    }
+    Ok(sections)
+}
+
+/// Parse the certificate chain in PEM format, the end-entity certificate comes first.
+pub fn certs_from_pem(pem: &[u8]) -> io::Result<Vec<Certificate>> {
+    Ok(parse_sections(pem)?
+        .into_iter()
+        .filter(|(label, _)| label == "CERTIFICATE")
+        .map(|(_, der)| Certificate(der))
+        .collect())
+}
+
+/// Parse the first private key in PEM format, which can be in PKCS#8, PKCS#1 or SEC1.
+pub fn private_key_from_pem(pem: &[u8]) -> io::Result<Option<PrivateKey>> {
+    Ok(parse_sections(pem)?
+        .into_iter()
+        .find(|(label, _)| PRIVATE_KEY_LABELS.contains(&label.as_str()))
+        .map(|(_, der)| PrivateKey(der)))
+}
+
+pub fn load_certs(path: impl AsRef<Path>) -> io::Result<Vec<Certificate>> {
+    certs_from_pem(&fs::read(path)?)
+}
+
+pub fn load_private_key(path: impl AsRef<Path>) -> io::Result<Option<PrivateKey>> {
+    private_key_from_pem(&fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("cXVpYw==").unwrap(), b"quic");
+        assert_eq!(decode_base64("cXVp\nYzE=").unwrap(), b"quic1");
+        assert_eq!(decode_base64("cXVpYzEy").unwrap(), b"quic12");
+        assert!(decode_base64("cX=VpYw").is_err());
+        assert!(decode_base64("cXVp*w==").is_err());
+    }
+
+    #[test]
+    fn test_parse_pem() {
+        let certs = certs_from_pem(include_bytes!("../../testdata/server.crt")).unwrap();
+        assert_eq!(certs.len(), 1);
+        // DER编码的证书以SEQUENCE开头
+        assert_eq!(certs[0].0[0], 0x30);
+
+        let key = private_key_from_pem(include_bytes!("../../testdata/server.key")).unwrap();
+        assert!(key.is_some());
+        assert!(
+            private_key_from_pem(include_bytes!("../../testdata/ca.crt"))
+                .unwrap()
+                .is_none()
+        );
+
+        let broken = b"-----BEGIN CERTIFICATE-----\nMIIB\n";
+        assert!(certs_from_pem(broken).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use bytes::{Buf, BufMut};
+use qbase::cid::{ConnectionId, WriteConnectionId, MAX_CID_SIZE};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// The default lifetime of a Retry token. The client is expected to reply with
+/// the Retry token immediately, so it should be short-lived.
+pub const DEFAULT_RETRY_TOKEN_LIFETIME: Duration = Duration::from_secs(15);
+
+/// The default lifetime of a token issued in NEW_TOKEN frame, which is used by the
+/// client in a future connection, so it lives much longer than a Retry token.
+pub const DEFAULT_NEW_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Tokens are opaque to the client, only the server that issued them can interpret them.
+/// The first byte distinguishes the kind of token, for the server needs to know whether
+/// a token comes from a Retry packet or a NEW_TOKEN frame.
+const RETRY_TOKEN: u8 = 0x00;
+const NEW_TOKEN: u8 = 0x01;
+
+/// The key ID following the kind identifies the [`TokenKey`] that sealed the token, so that
+/// the tokens issued by other servers are not mistaken for the tokens of this server.
+const KEY_ID_LEN: usize = 4;
+const HEADER_LEN: usize = 1 + KEY_ID_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Retry,
+    NewToken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum InvalidToken {
+    #[error("malformed token")]
+    Malformed,
+    #[error("token failed authentication")]
+    Unauthentic,
+    #[error("token expired")]
+    Expired,
+    #[error("token has been used")]
+    Replayed,
+}
+
+/// The key used by the server to seal and open address validation tokens.
+/// A token is encrypted with AES-256-GCM, so it can neither be forged nor be
+/// read by others. The client address is used as additional authenticated data,
+/// which binds the token to the address that it was issued to:
+/// ```text
+/// Token {
+///   Kind (8),
+///   Key ID (32),
+///   Nonce (96),
+///   Encrypted Payload (..),
+///   AEAD Tag (128),
+/// }
+/// ```
+/// Multiple endpoints of the same server cluster can share a `TokenKey` built
+/// from the same secret, so that tokens issued by one can be validated by others.
+#[derive(Debug)]
+pub struct TokenKey {
+    key: LessSafeKey,
+    key_id: [u8; KEY_ID_LEN],
+    rng: SystemRandom,
+}
+
+impl TokenKey {
+    pub fn new(secret: &[u8; 32]) -> Self {
+        // 密钥ID由密钥派生，同一集群共享密钥的端点，密钥ID也相同
+        let mut input = b"quic token key id".to_vec();
+        input.extend_from_slice(secret);
+        let key_id = digest(&SHA256, &input).as_ref()[..KEY_ID_LEN]
+            .try_into()
+            .unwrap();
+        Self {
+            key: LessSafeKey::new(UnboundKey::new(&AES_256_GCM, secret).unwrap()),
+            key_id,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// The kind of a token sealed by this key, None if the token is not issued by this key,
+    /// such as a token of another server, which should be treated as if there is no token.
+    pub fn kind_of(&self, token: &[u8]) -> Option<TokenKind> {
+        if token.get(1..HEADER_LEN) != Some(&self.key_id[..]) {
+            return None;
+        }
+        match token[0] {
+            RETRY_TOKEN => Some(TokenKind::Retry),
+            NEW_TOKEN => Some(TokenKind::NewToken),
+            _ => None,
+        }
+    }
+
+    pub fn random_gen() -> Self {
+        let mut secret = [0u8; 32];
+        SystemRandom::new().fill(&mut secret).unwrap();
+        Self::new(&secret)
+    }
+
+    fn aad(kind: u8, peer_addr: &SocketAddr, extra: &[u8]) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(1 + 16 + 2 + extra.len());
+        aad.put_u8(kind);
+        match peer_addr.ip() {
+            IpAddr::V4(ip) => aad.put_slice(&ip.octets()),
+            IpAddr::V6(ip) => aad.put_slice(&ip.octets()),
+        }
+        aad.put_u16(peer_addr.port());
+        aad.put_slice(extra);
+        aad
+    }
+
+    fn seal(&self, kind: u8, aad: Vec<u8>, payload: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).unwrap();
+
+        let mut token = Vec::with_capacity(HEADER_LEN + NONCE_LEN + payload.len() + 16);
+        token.put_u8(kind);
+        token.put_slice(&self.key_id);
+        token.put_slice(&nonce);
+        let mut in_out = payload.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut in_out,
+            )
+            .unwrap();
+        token.put_slice(&in_out);
+        token
+    }
+
+    fn open(&self, kind: u8, aad: Vec<u8>, token: &[u8]) -> Result<Vec<u8>, InvalidToken> {
+        if token.len() < HEADER_LEN + NONCE_LEN || token[0] != kind {
+            return Err(InvalidToken::Malformed);
+        }
+        let nonce: [u8; NONCE_LEN] = token[HEADER_LEN..HEADER_LEN + NONCE_LEN]
+            .try_into()
+            .unwrap();
+        let mut in_out = token[HEADER_LEN + NONCE_LEN..].to_vec();
+        let payload = self
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut in_out,
+            )
+            .map_err(|_| InvalidToken::Unauthentic)?;
+        Ok(payload.to_vec())
+    }
+
+    /// Seal a token carried in the Retry packet, which records the Destination Connection ID
+    /// of the client's first Initial packet, so that the server can fill in the
+    /// original_destination_connection_id transport parameter when the client comes back.
+    /// The token is also bound to `retry_scid`, the Source Connection ID of the Retry packet,
+    /// which the client must use as the Destination Connection ID of its next Initial packet.
+    pub fn seal_retry_token(
+        &self,
+        peer_addr: &SocketAddr,
+        origin_dcid: &ConnectionId,
+        retry_scid: &ConnectionId,
+    ) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(8 + 1 + MAX_CID_SIZE);
+        payload.put_u64(unix_secs(SystemTime::now()));
+        payload.put_connection_id(origin_dcid);
+        self.seal(
+            RETRY_TOKEN,
+            Self::aad(RETRY_TOKEN, peer_addr, retry_scid),
+            &payload,
+        )
+    }
+
+    /// Open a token from the Initial packet sent after a Retry packet, returns the
+    /// original Destination Connection ID if the token is valid.
+    pub fn open_retry_token(
+        &self,
+        token: &[u8],
+        peer_addr: &SocketAddr,
+        retry_scid: &ConnectionId,
+        lifetime: Duration,
+    ) -> Result<ConnectionId, InvalidToken> {
+        let payload = self.open(
+            RETRY_TOKEN,
+            Self::aad(RETRY_TOKEN, peer_addr, retry_scid),
+            token,
+        )?;
+        let mut payload = &payload[..];
+        if payload.remaining() < 9 {
+            return Err(InvalidToken::Malformed);
+        }
+        let issued_at = payload.get_u64();
+        if unix_secs(SystemTime::now()) > issued_at.saturating_add(lifetime.as_secs()) {
+            return Err(InvalidToken::Expired);
+        }
+        let cid_len = payload.get_u8() as usize;
+        if cid_len > MAX_CID_SIZE || payload.remaining() != cid_len {
+            return Err(InvalidToken::Malformed);
+        }
+        Ok(ConnectionId::from_slice(payload))
+    }
+
+    /// Seal a token sent in NEW_TOKEN frame, for the client to validate its address
+    /// in a future connection. Unlike a Retry token, it is only bound to the IP address
+    /// of the client, for the port may change in the future connection.
+    pub fn seal_new_token(&self, peer_ip: &IpAddr) -> Vec<u8> {
+        let peer_addr = SocketAddr::new(*peer_ip, 0);
+        let issued_at = unix_secs(SystemTime::now());
+        self.seal(
+            NEW_TOKEN,
+            Self::aad(NEW_TOKEN, &peer_addr, &[]),
+            &issued_at.to_be_bytes(),
+        )
+    }
+
+    /// Open a token from NEW_TOKEN frame, returns the time in UNIX seconds when it expires,
+    /// until which the token should be remembered in [`UsedTokens`] to prevent replay.
+    pub fn open_new_token(
+        &self,
+        token: &[u8],
+        peer_ip: &IpAddr,
+        lifetime: Duration,
+    ) -> Result<u64, InvalidToken> {
+        let peer_addr = SocketAddr::new(*peer_ip, 0);
+        let payload = self.open(NEW_TOKEN, Self::aad(NEW_TOKEN, &peer_addr, &[]), token)?;
+        let Ok(issued_at) = <[u8; 8]>::try_from(&payload[..]) else {
+            return Err(InvalidToken::Malformed);
+        };
+        let expire_at = u64::from_be_bytes(issued_at).saturating_add(lifetime.as_secs());
+        if unix_secs(SystemTime::now()) > expire_at {
+            return Err(InvalidToken::Expired);
+        }
+        Ok(expire_at)
+    }
+}
+
+/// Tokens from NEW_TOKEN frames can be used more than once, so the server remembers
+/// the tokens that have been used until they expire, and accepts each token only once,
+/// see [Section 8.1.4](https://www.rfc-editor.org/rfc/rfc9000.html#section-8.1.4) of QUIC.
+/// Once the capacity is reached, further tokens are treated as used, which causes the
+/// server to fall back to other means of address validation.
+#[derive(Debug)]
+pub struct UsedTokens {
+    tokens: HashMap<[u8; NONCE_LEN], u64>,
+    capacity: usize,
+}
+
+impl UsedTokens {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            tokens: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Returns false if the token has been used before.
+    pub fn insert(&mut self, token: &[u8], expire_at: u64) -> bool {
+        // The nonce of the token is unique, which identifies the token.
+        let Some(Ok(id)) = token
+            .get(HEADER_LEN..HEADER_LEN + NONCE_LEN)
+            .map(<[u8; NONCE_LEN]>::try_from)
+        else {
+            return false;
+        };
+        if self.tokens.contains_key(&id) {
+            return false;
+        }
+        if self.tokens.len() >= self.capacity {
+            let now = unix_secs(SystemTime::now());
+            self.tokens.retain(|_, expire_at| *expire_at >= now);
+            if self.tokens.len() >= self.capacity {
+                return false;
+            }
+        }
+        self.tokens.insert(id, expire_at);
+        true
+    }
+}
+
+impl Default for UsedTokens {
+    fn default() -> Self {
+        Self::with_capacity(1 << 16)
+    }
+}
+
+/// The client saves the tokens received in NEW_TOKEN frames, keyed by the server name,
+/// and uses one of them in the Initial packets of the next connection to the same server,
+/// so as to skip the Retry round trip.
+pub trait TokenStore: Send + Sync {
+    fn insert(&self, server_name: &str, token: Vec<u8>);
+
+    /// A token should be used only once, so it is removed from the store.
+    fn take(&self, server_name: &str) -> Option<Vec<u8>>;
+}
+
+/// The default [`TokenStore`], which keeps the latest token of each server in memory.
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore(Mutex<HashMap<String, Vec<u8>>>);
+
+impl TokenStore for MemoryTokenStore {
+    fn insert(&self, server_name: &str, token: Vec<u8>) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(server_name.to_string(), token);
+    }
+
+    fn take(&self, server_name: &str) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().remove(server_name)
+    }
+}
+
+/// Where the client saves the tokens received in NEW_TOKEN frames during a connection,
+/// which is not set until the application specifies the token store and the server name.
+#[derive(Clone, Default)]
+pub(crate) struct ArcTokenSink(Arc<Mutex<Option<ServerTokenStore>>>);
+
+type ServerTokenStore = (String, Arc<dyn TokenStore>);
+
+impl ArcTokenSink {
+    pub(crate) fn set(&self, server_name: String, store: Arc<dyn TokenStore>) {
+        *self.0.lock().unwrap() = Some((server_name, store));
+    }
+
+    pub(crate) fn save(&self, token: Vec<u8>) {
+        if let Some((server_name, store)) = self.0.lock().unwrap().as_ref() {
+            store.insert(server_name, token);
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_token() {
+        let key = TokenKey::random_gen();
+        let peer_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let odcid = ConnectionId::from_slice(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]);
+        let retry_scid = ConnectionId::random_gen(8);
+        let token = key.seal_retry_token(&peer_addr, &odcid, &retry_scid);
+
+        let lifetime = DEFAULT_RETRY_TOKEN_LIFETIME;
+        assert_eq!(
+            key.open_retry_token(&token, &peer_addr, &retry_scid, lifetime),
+            Ok(odcid)
+        );
+
+        let other_addr: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        assert_eq!(
+            key.open_retry_token(&token, &other_addr, &retry_scid, lifetime),
+            Err(InvalidToken::Unauthentic)
+        );
+        let other_scid = ConnectionId::random_gen(8);
+        assert_eq!(
+            key.open_retry_token(&token, &peer_addr, &other_scid, lifetime),
+            Err(InvalidToken::Unauthentic)
+        );
+        let other_key = TokenKey::random_gen();
+        assert_eq!(
+            other_key.open_retry_token(&token, &peer_addr, &retry_scid, lifetime),
+            Err(InvalidToken::Unauthentic)
+        );
+        assert_eq!(
+            key.open_retry_token(&token[..10], &peer_addr, &retry_scid, lifetime),
+            Err(InvalidToken::Malformed)
+        );
+        assert_eq!(key.kind_of(&token), Some(TokenKind::Retry));
+        // 其他服务端的令牌，即便首字节相同，也不会被当作本服务端的Retry令牌
+        assert_eq!(other_key.kind_of(&token), None);
+        assert_eq!(key.kind_of(&[RETRY_TOKEN, 1, 2, 3, 4, 5]), None);
+        assert_eq!(key.kind_of(&[]), None);
+    }
+
+    #[test]
+    fn test_new_token() {
+        let key = TokenKey::random_gen();
+        let peer_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let token = key.seal_new_token(&peer_ip);
+        assert_eq!(key.kind_of(&token), Some(TokenKind::NewToken));
+
+        let lifetime = DEFAULT_NEW_TOKEN_LIFETIME;
+        let expire_at = key.open_new_token(&token, &peer_ip, lifetime).unwrap();
+        let other_ip: IpAddr = "127.0.0.2".parse().unwrap();
+        assert_eq!(
+            key.open_new_token(&token, &other_ip, lifetime),
+            Err(InvalidToken::Unauthentic)
+        );
+        // A Retry token can't be used as a NEW_TOKEN token, and vice versa.
+        let retry_token = key.seal_retry_token(
+            &SocketAddr::new(peer_ip, 0),
+            &ConnectionId::random_gen(8),
+            &ConnectionId::default(),
+        );
+        assert_eq!(
+            key.open_new_token(&retry_token, &peer_ip, lifetime),
+            Err(InvalidToken::Malformed)
+        );
+
+        let mut used_tokens = UsedTokens::with_capacity(1);
+        assert!(used_tokens.insert(&token, expire_at));
+        assert!(!used_tokens.insert(&token, expire_at));
+        let another = key.seal_new_token(&peer_ip);
+        assert!(!used_tokens.insert(&another, expire_at));
+    }
+
+    #[test]
+    fn test_memory_token_store() {
+        let store = MemoryTokenStore::default();
+        assert_eq!(store.take("example.com"), None);
+        store.insert("example.com", vec![1, 2, 3]);
+        store.insert("example.com", vec![4, 5, 6]);
+        assert_eq!(store.take("example.com"), Some(vec![4, 5, 6]));
+        assert_eq!(store.take("example.com"), None);
+    }
+}
@@ -1,5 +1,5 @@
 /// Crypto data stream
-use qbase::{error::Error, frame::CryptoFrame};
+use qbase::{error::Error, frame::CryptoFrame, packet::PayloadBuf};
 
 mod send {
     use crate::send::sndbuf::SendBuf;
@@ -179,7 +179,7 @@ mod recv {
             cx: &mut Context<'_>,
             buf: &mut T,
         ) -> Poll<io::Result<()>> {
-            assert!(self.read_waker.is_none());
+            // 同一个读取任务可能被虚假唤醒而再次poll，此时替换掉旧的waker即可
             if self.rcvbuf.is_readable() {
                 self.rcvbuf.read(buf);
                 Poll::Ready(Ok(()))
@@ -299,7 +299,7 @@ pub trait TransmitCrypto {
 }
 
 impl TransmitCrypto for CryptoStream {
-    type Buffer = bytes::BytesMut;
+    type Buffer = PayloadBuf;
 
     fn try_send_data(&mut self, buf: &mut Self::Buffer) -> Option<(CryptoFrame, usize)> {
         self.outgoing.try_send(buf)
@@ -325,7 +325,7 @@ impl TransmitCrypto for CryptoStream {
 pub struct NoCrypto;
 
 impl TransmitCrypto for NoCrypto {
-    type Buffer = bytes::BytesMut;
+    type Buffer = PayloadBuf;
 
     fn try_send_data(&mut self, _buf: &mut Self::Buffer) -> Option<(CryptoFrame, usize)> {
         None
@@ -353,7 +353,11 @@ mod tests {
     #[tokio::test]
     async fn test_read() {
         let mut crypto_stream = CryptoStream::new(1000_0000, 0);
-        crypto_stream.writer().write(b"hello world").await.unwrap();
+        crypto_stream
+            .writer()
+            .write_all(b"hello world")
+            .await
+            .unwrap();
 
         crypto_stream
             .recv_data(
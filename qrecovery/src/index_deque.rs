@@ -138,6 +138,16 @@ impl<T, const LIMIT: u64> IndexDeque<T, LIMIT> {
         let end = (self.offset - offset) as usize;
         self.deque.drain(..end)
     }
+
+    /// Slide the window forward to `end`, the elements before `end` are dropped,
+    /// even if `end` is beyond the last element, in which case the queue becomes empty.
+    pub fn advance_to(&mut self, end: u64) {
+        if end > self.offset {
+            let n = std::cmp::min(end - self.offset, self.deque.len() as u64);
+            self.deque.drain(..n as usize);
+            self.offset = end;
+        }
+    }
 }
 
 impl<T: Default + Clone, const LIMIT: u64> IndexDeque<T, LIMIT> {
@@ -210,7 +220,7 @@ mod tests {
         assert_eq!(deque.drain_to(10).count(), 0);
         let mut i = 10;
         for item in deque.drain_to(15) {
-            i = i + 1;
+            i += 1;
             assert_eq!(item, i);
         }
         assert_eq!(i, 15);
@@ -234,4 +244,20 @@ mod tests {
         }
         assert_eq!(deque[10], 11);
     }
+
+    #[test]
+    fn test_advance_to() {
+        let mut deque = IndexDeque::<u64, 19>::new();
+        deque.insert(3, 4).unwrap();
+        deque.advance_to(2);
+        assert_eq!(deque.offset, 2);
+        assert_eq!(deque.len(), 2);
+        // 超出末尾时，队列清空，偏移量仍前进到指定位置
+        deque.advance_to(8);
+        assert_eq!(deque.offset, 8);
+        assert!(deque.is_empty());
+        assert!(deque.insert(5, 6).is_err());
+        assert_eq!(deque.insert(9, 10), Ok(9));
+        assert_eq!(deque.len(), 2);
+    }
 }
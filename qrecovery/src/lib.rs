@@ -8,6 +8,10 @@ pub mod crypto;
 pub mod index_deque;
 pub mod streams;
 
+use qbase::varint::VarInt;
+use std::io;
+use thiserror::Error;
+
 #[derive(Debug)]
 pub enum AppStream {
     ReadOnly(recv::Reader),
@@ -15,6 +19,36 @@ pub enum AppStream {
     ReadWrite(recv::Reader, send::Writer),
 }
 
+/// The error codes of the peer that aborted the stream, which are defined by the application
+/// protocol, such as HTTP/3. They are given to the application in the [`io::Error`] of the
+/// next read or write, and can be taken out with [`StreamError::from_io_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum StreamError {
+    /// The peer reset the sending part of the stream with RESET_STREAM.
+    #[error("stream reset by peer with error code {0}")]
+    Reset(VarInt),
+    /// The peer asked to stop sending with STOP_SENDING.
+    #[error("stream stopped by peer with error code {0}")]
+    Stopped(VarInt),
+}
+
+impl StreamError {
+    pub fn from_io_error(e: &io::Error) -> Option<Self> {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<StreamError>())
+            .copied()
+    }
+}
+
+impl From<StreamError> for io::Error {
+    fn from(e: StreamError) -> Self {
+        match e {
+            StreamError::Reset(_) => io::Error::new(io::ErrorKind::ConnectionReset, e),
+            StreamError::Stopped(_) => io::Error::new(io::ErrorKind::BrokenPipe, e),
+        }
+    }
+}
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -28,4 +62,16 @@ mod tests {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn test_stream_error_in_io_error() {
+        let e = io::Error::from(StreamError::Reset(VarInt::from_u32(0x10c)));
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            StreamError::from_io_error(&e),
+            Some(StreamError::Reset(VarInt::from_u32(0x10c)))
+        );
+        let e = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert_eq!(StreamError::from_io_error(&e), None);
+    }
 }
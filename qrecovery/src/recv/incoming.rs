@@ -2,7 +2,8 @@ use super::recver::{ArcRecver, Recver};
 use bytes::Bytes;
 use qbase::{
     error::Error,
-    frame::{ResetStreamFrame, StreamFrame},
+    frame::{ResetStreamAtFrame, ResetStreamFrame, StreamFrame},
+    varint::VarInt,
 };
 use std::{
     future::Future,
@@ -35,6 +36,10 @@ impl Incoming {
                     inner.replace(Recver::SizeKnown(r));
                 }
             }
+            Recver::ReliableResetRecvd(mut r) => {
+                r.recv(stream_frame, body)?;
+                inner.replace(Recver::ReliableResetRecvd(r));
+            }
             other => {
                 println!("ignored stream frame {:?}", stream_frame);
                 inner.replace(other);
@@ -58,17 +63,24 @@ impl Incoming {
     }
 
     pub fn recv_reset(&mut self, reset_frame: ResetStreamFrame) -> Result<(), Error> {
-        // TODO: ResetStream中还有错误信息，比如http3的错误码，看是否能用到
+        // 对方的错误码，比如http3的错误码，在应用下次读取时告知应用
+        let code = reset_frame.app_error_code;
         let mut recver = self.0.lock().unwrap();
         let inner = recver.deref_mut();
         match inner.take() {
             Recver::Recv(r) => {
                 let final_size = r.recv_reset(reset_frame)?;
-                inner.replace(Recver::ResetRecvd(final_size));
+                inner.replace(Recver::ResetRecvd(final_size, code));
             }
             Recver::SizeKnown(r) => {
                 let final_size = r.recv_reset(reset_frame)?;
-                inner.replace(Recver::ResetRecvd(final_size));
+                inner.replace(Recver::ResetRecvd(final_size, code));
+            }
+            // 可靠重置之后又收到了RESET_STREAM，可靠大小之前的数据也不再交付
+            Recver::ReliableResetRecvd(r) => {
+                let code = r.error_code();
+                let final_size = r.recv_reset(reset_frame)?;
+                inner.replace(Recver::ResetRecvd(final_size, code));
             }
             other => {
                 println!("there is sth wrong, ignored recv_reset");
@@ -78,6 +90,37 @@ impl Incoming {
         Ok(())
     }
 
+    /// 可靠大小之前的数据，仍会交付给应用，读完之后应用才会得知流被重置
+    pub fn recv_reset_at(&mut self, reset_frame: ResetStreamAtFrame) -> Result<(), Error> {
+        // 可靠大小为0的RESET_STREAM_AT，等同于RESET_STREAM
+        if reset_frame.reliable_size.into_inner() == 0 {
+            return self.recv_reset(ResetStreamFrame {
+                stream_id: reset_frame.stream_id,
+                app_error_code: reset_frame.app_error_code,
+                final_size: reset_frame.final_size,
+            });
+        }
+        let mut recver = self.0.lock().unwrap();
+        let inner = recver.deref_mut();
+        match inner.take() {
+            Recver::Recv(r) => {
+                inner.replace(Recver::ReliableResetRecvd(r.recv_reset_at(reset_frame)?));
+            }
+            Recver::SizeKnown(r) => {
+                inner.replace(Recver::ReliableResetRecvd(r.recv_reset_at(reset_frame)?));
+            }
+            Recver::ReliableResetRecvd(mut r) => {
+                r.recv_reset_at(reset_frame)?;
+                inner.replace(Recver::ReliableResetRecvd(r));
+            }
+            // 流已经被重置或者数据都已收到，RESET_STREAM_AT无需再处理
+            other => {
+                inner.replace(other);
+            }
+        }
+        Ok(())
+    }
+
     /// 应用层是否对流写入结束，如果是，那么应要发送STOP_SENDING
     pub fn is_stopped_by_app(&self) -> IsStopped {
         IsStopped(self.0.clone())
@@ -116,9 +159,9 @@ impl Future for WindowUpdate {
 pub struct IsStopped(ArcRecver);
 
 impl Future for IsStopped {
-    // true means stopped by app.
-    // false means it was never stopped until the end.
-    type Output = bool;
+    // Some(code) means stopped by app with the error code.
+    // None means it was never stopped until the end.
+    type Output = Option<VarInt>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut recver = self.0.lock().unwrap();
@@ -136,15 +179,17 @@ impl Future for IsStopped {
             }
             finished @ (Recver::DataRead | Recver::DataRecvd(_)) => {
                 inner.replace(finished);
-                Poll::Ready(false)
+                Poll::Ready(None)
             }
-            reset @ (Recver::ResetRead | Recver::ResetRecvd(_)) => {
+            reset @ (Recver::ResetRead(_)
+            | Recver::ResetRecvd(..)
+            | Recver::ReliableResetRecvd(_)) => {
                 inner.replace(reset);
                 // Even in the Reset state, it is because the sender's reset was received,
                 // not because the receiver actively stopped. The receiver's active stop
                 // will not change the state, so it can only receive stop notifications in
                 // the Recv/SizeKnown state.
-                Poll::Ready(false)
+                Poll::Ready(None)
             }
         }
     }
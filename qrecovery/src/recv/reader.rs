@@ -1,4 +1,6 @@
 use super::recver::{ArcRecver, Recver};
+use crate::StreamError;
+use qbase::varint::VarInt;
 use std::{
     io,
     ops::DerefMut,
@@ -14,10 +16,27 @@ impl Reader {
     pub(super) fn new(recver: ArcRecver) -> Self {
         Self(recver)
     }
-}
 
-// TODO: 还要实现abort
-// TODO: Reader的drop，意味着自动abort
+    /// 应用不再读取数据，请求对方以带有错误码的STOP_SENDING帧停止发送。多次stop只有第一次有效，
+    /// 数据已全部收到或者流已被对方重置的，stop会被忽略
+    pub fn stop(&mut self, code: VarInt) {
+        let mut recver = self.0.lock().unwrap();
+        let inner = recver.deref_mut();
+        match inner.take() {
+            Recver::Recv(mut r) => {
+                r.stop(code);
+                inner.replace(Recver::Recv(r));
+            }
+            Recver::SizeKnown(mut r) => {
+                r.stop(code);
+                inner.replace(Recver::SizeKnown(r));
+            }
+            other => {
+                inner.replace(other);
+            }
+        }
+    }
+}
 
 impl AsyncRead for Reader {
     fn poll_read(
@@ -49,35 +68,34 @@ impl AsyncRead for Reader {
                 Poll::Ready(Ok(()))
             }
             Recver::DataRead => Poll::Ready(Ok(())),
-            Recver::ResetRecvd(_final_size) => {
-                inner.replace(Recver::ResetRead);
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            Recver::ResetRecvd(_final_size, code) => {
+                inner.replace(Recver::ResetRead(code));
+                Poll::Ready(Err(StreamError::Reset(code).into()))
             }
-            Recver::ResetRead => {
-                inner.replace(Recver::ResetRead);
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            // 先交付可靠大小之前的数据，读完之后再告知应用流被重置
+            Recver::ReliableResetRecvd(mut r) => {
+                if r.is_all_read() {
+                    let code = r.error_code();
+                    inner.replace(Recver::ResetRead(code));
+                    Poll::Ready(Err(StreamError::Reset(code).into()))
+                } else {
+                    let result = r.poll_read(cx, buf);
+                    inner.replace(Recver::ReliableResetRecvd(r));
+                    result
+                }
+            }
+            Recver::ResetRead(code) => {
+                inner.replace(Recver::ResetRead(code));
+                Poll::Ready(Err(StreamError::Reset(code).into()))
             }
         }
     }
 }
 
+/// Reader的drop，意味着自动以错误码0停止接收
 impl Drop for Reader {
     fn drop(&mut self) {
-        let mut recver = self.0.lock().unwrap();
-        let inner = recver.deref_mut();
-        match inner.take() {
-            Recver::Recv(mut r) => {
-                r.abort();
-                inner.replace(Recver::Recv(r));
-            }
-            Recver::SizeKnown(mut r) => {
-                r.abort();
-                inner.replace(Recver::SizeKnown(r));
-            }
-            other => {
-                inner.replace(other);
-            }
-        }
+        self.stop(VarInt::from_u32(0));
     }
 }
 
@@ -2,7 +2,8 @@ use super::rcvbuf;
 use bytes::{BufMut, Bytes};
 use qbase::{
     error::{Error, ErrorKind},
-    frame::{BeFrame, ResetStreamFrame, StreamFrame},
+    frame::{BeFrame, ResetStreamAtFrame, ResetStreamFrame, StreamFrame},
+    varint::VarInt,
 };
 use std::{
     io,
@@ -14,7 +15,7 @@ use std::{
 pub(super) struct Recv {
     rcvbuf: rcvbuf::RecvBuf,
     read_waker: Option<Waker>,
-    is_stopped: bool,
+    stop_code: Option<VarInt>,
     stop_waker: Option<Waker>,
     largest_data_size: u64,
     max_data_size: u64,
@@ -26,7 +27,7 @@ impl Recv {
         Self {
             rcvbuf: rcvbuf::RecvBuf::default(),
             read_waker: None,
-            is_stopped: false,
+            stop_code: None,
             stop_waker: None,
             largest_data_size: 0,
             max_data_size,
@@ -112,19 +113,19 @@ impl Recv {
         }
     }
 
-    pub(super) fn poll_stop(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+    pub(super) fn poll_stop(&mut self, cx: &mut Context<'_>) -> Poll<Option<VarInt>> {
         assert!(self.stop_waker.is_none());
-        if self.is_stopped {
-            Poll::Ready(true)
+        if let Some(code) = self.stop_code {
+            Poll::Ready(Some(code))
         } else {
             self.stop_waker = Some(cx.waker().clone());
             Poll::Pending
         }
     }
 
-    pub(super) fn abort(&mut self) {
-        if !self.is_stopped {
-            self.is_stopped = true;
+    pub(super) fn stop(&mut self, code: VarInt) {
+        if self.stop_code.is_none() {
+            self.stop_code = Some(code);
             if let Some(waker) = self.stop_waker.take() {
                 waker.wake()
             }
@@ -138,7 +139,7 @@ impl Recv {
         SizeKnown {
             rcvbuf: self.rcvbuf,
             read_waker: self.read_waker,
-            is_stopped: self.is_stopped,
+            stop_code: self.stop_code,
             stop_waker: self.stop_waker,
             total_size,
         }
@@ -167,6 +168,34 @@ impl Recv {
         }
         Ok(final_size)
     }
+
+    pub(super) fn recv_reset_at(
+        self,
+        reset_frame: ResetStreamAtFrame,
+    ) -> Result<ReliableResetRecvd, Error> {
+        let final_size = reset_frame.final_size.into_inner();
+        if final_size < self.largest_data_size {
+            return Err(Error::new(
+                ErrorKind::FinalSize,
+                reset_frame.frame_type(),
+                format!(
+                    "{} reset with a wrong smaller final size {final_size} than the largest rcvd data offset {}",
+                    reset_frame.stream_id, self.largest_data_size
+                ),
+            ));
+        }
+        if let Some(waker) = self.buf_exceeds_half_waker {
+            waker.wake();
+        }
+        if let Some(waker) = self.stop_waker {
+            waker.wake();
+        }
+        Ok(ReliableResetRecvd::new(
+            self.rcvbuf,
+            self.read_waker,
+            reset_frame,
+        ))
+    }
 }
 
 /// Once the size of the data stream is determined, MAX_STREAM_DATA will no longer
@@ -176,7 +205,7 @@ impl Recv {
 pub struct SizeKnown {
     rcvbuf: rcvbuf::RecvBuf,
     read_waker: Option<Waker>,
-    is_stopped: bool,
+    stop_code: Option<VarInt>,
     stop_waker: Option<Waker>,
     total_size: u64,
 }
@@ -244,21 +273,21 @@ impl SizeKnown {
         }
     }
 
-    pub(super) fn poll_stop(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+    pub(super) fn poll_stop(&mut self, cx: &mut Context<'_>) -> Poll<Option<VarInt>> {
         assert!(self.stop_waker.is_none());
-        if self.is_stopped {
-            Poll::Ready(true)
+        if let Some(code) = self.stop_code {
+            Poll::Ready(Some(code))
         } else {
             self.stop_waker = Some(cx.waker().clone());
             Poll::Pending
         }
     }
 
-    /// Abort can be called multiple times at the application level,
+    /// Stop can be called multiple times at the application level,
     /// but only the first call is effective.
-    pub(super) fn abort(&mut self) -> u64 {
-        if !self.is_stopped {
-            self.is_stopped = true;
+    pub(super) fn stop(&mut self, code: VarInt) -> u64 {
+        if self.stop_code.is_none() {
+            self.stop_code = Some(code);
             if let Some(waker) = self.stop_waker.take() {
                 waker.wake()
             }
@@ -297,6 +326,155 @@ impl SizeKnown {
         }
         Ok(final_size)
     }
+
+    pub(super) fn recv_reset_at(
+        self,
+        reset_frame: ResetStreamAtFrame,
+    ) -> Result<ReliableResetRecvd, Error> {
+        let final_size = reset_frame.final_size.into_inner();
+        if final_size != self.total_size {
+            return Err(Error::new(
+                ErrorKind::FinalSize,
+                reset_frame.frame_type(),
+                format!(
+                    "{} change the final size from {} to {final_size}",
+                    reset_frame.stream_id, self.total_size
+                ),
+            ));
+        }
+        if let Some(waker) = self.stop_waker {
+            waker.wake();
+        }
+        Ok(ReliableResetRecvd::new(
+            self.rcvbuf,
+            self.read_waker,
+            reset_frame,
+        ))
+    }
+}
+
+/// 收到对方的RESET_STREAM_AT之后，可靠大小之前的数据仍要继续接收并交付给应用，
+/// 应用读完这些数据之后，才会得知流被重置
+#[derive(Debug)]
+pub struct ReliableResetRecvd {
+    rcvbuf: rcvbuf::RecvBuf,
+    read_waker: Option<Waker>,
+    final_size: u64,
+    reliable_size: u64,
+    error_code: VarInt,
+}
+
+impl ReliableResetRecvd {
+    fn new(
+        rcvbuf: rcvbuf::RecvBuf,
+        mut read_waker: Option<Waker>,
+        reset_frame: ResetStreamAtFrame,
+    ) -> Self {
+        // 可能已收到可靠大小之前的所有数据，也可能可靠大小比已读取的还小，都要唤醒读取
+        if let Some(waker) = read_waker.take() {
+            waker.wake()
+        }
+        Self {
+            rcvbuf,
+            read_waker,
+            final_size: reset_frame.final_size.into_inner(),
+            reliable_size: reset_frame.reliable_size.into_inner(),
+            error_code: reset_frame.app_error_code,
+        }
+    }
+
+    pub(super) fn error_code(&self) -> VarInt {
+        self.error_code
+    }
+
+    /// 只接收可靠大小之前的数据，之后的数据直接丢弃
+    pub(super) fn recv(&mut self, stream_frame: StreamFrame, mut body: Bytes) -> Result<(), Error> {
+        let offset = stream_frame.offset.into_inner();
+        let data_size = offset + body.len() as u64;
+        if data_size > self.final_size {
+            return Err(Error::new(
+                ErrorKind::FinalSize,
+                stream_frame.frame_type(),
+                format!(
+                    "{} send {data_size} bytes which exceeds the final_size {}",
+                    stream_frame.id, self.final_size
+                ),
+            ));
+        }
+        if offset >= self.reliable_size {
+            return Ok(());
+        }
+        body.truncate(std::cmp::min(body.len() as u64, self.reliable_size - offset) as usize);
+        self.rcvbuf.recv(offset, body);
+        if self.rcvbuf.is_readable() {
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake()
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn poll_read<T: BufMut>(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut T,
+    ) -> Poll<io::Result<()>> {
+        assert!(self.read_waker.is_none());
+        if self.rcvbuf.is_readable() {
+            let remaining = self.reliable_size.saturating_sub(self.rcvbuf.offset());
+            self.rcvbuf.read(&mut buf.limit(remaining as usize));
+            Poll::Ready(Ok(()))
+        } else {
+            self.read_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    pub(super) fn is_all_read(&self) -> bool {
+        self.rcvbuf.offset() >= self.reliable_size
+    }
+
+    /// 再次收到RESET_STREAM_AT，最终大小不能变，可靠大小只能减小不能增大
+    pub(super) fn recv_reset_at(&mut self, reset_frame: ResetStreamAtFrame) -> Result<(), Error> {
+        let final_size = reset_frame.final_size.into_inner();
+        if final_size != self.final_size {
+            return Err(Error::new(
+                ErrorKind::FinalSize,
+                reset_frame.frame_type(),
+                format!(
+                    "{} change the final size from {} to {final_size}",
+                    reset_frame.stream_id, self.final_size
+                ),
+            ));
+        }
+        let reliable_size = reset_frame.reliable_size.into_inner();
+        if reliable_size < self.reliable_size {
+            self.reliable_size = reliable_size;
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake()
+            }
+        }
+        Ok(())
+    }
+
+    /// 收到RESET_STREAM，相当于可靠大小降为0，流立即被重置
+    pub(super) fn recv_reset(mut self, reset_frame: ResetStreamFrame) -> Result<u64, Error> {
+        let final_size = reset_frame.final_size.into_inner();
+        if final_size != self.final_size {
+            return Err(Error::new(
+                ErrorKind::FinalSize,
+                reset_frame.frame_type(),
+                format!(
+                    "{} change the final size from {} to {final_size}",
+                    reset_frame.stream_id, self.final_size
+                ),
+            ));
+        }
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake()
+        }
+        Ok(final_size)
+    }
 }
 
 /// Once all the data has been received, STOP_SENDING becomes meaningless.
@@ -340,10 +518,12 @@ pub(super) enum Recver {
     Recv(Recv),
     SizeKnown(SizeKnown),
     DataRecvd(DataRecvd),
-    ResetRecvd(u64),
+    /// 流的最终大小，以及对方RESET_STREAM的错误码
+    ResetRecvd(u64, VarInt),
+    ReliableResetRecvd(ReliableResetRecvd),
     #[default]
     DataRead,
-    ResetRead,
+    ResetRead(VarInt),
 }
 
 pub(super) type ArcRecver = Arc<Mutex<Recver>>;
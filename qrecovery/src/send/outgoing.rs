@@ -6,12 +6,16 @@ use qbase::{
         ShouldCarryLength, StreamFrame,
     },
     streamid::StreamId,
-    varint::VARINT_MAX,
+    varint::{VarInt, VARINT_MAX},
 };
 use std::{
     future::Future,
     ops::{DerefMut, Range},
     pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
 };
 
@@ -82,6 +86,10 @@ impl Outgoing {
                 result = s.pick_up(estimate_capacity).map(write);
                 inner.replace(Sender::DataSent(s));
             }
+            Sender::ReliableResetSent(mut s) => {
+                result = s.pick_up(estimate_capacity).map(write);
+                inner.replace(Sender::ReliableResetSent(s));
+            }
             other => inner.replace(other),
         };
         result
@@ -107,6 +115,14 @@ impl Outgoing {
                     inner.replace(Sender::DataSent(s));
                 }
             }
+            Sender::ReliableResetSent(mut s) => {
+                s.confirm_rcvd(range);
+                if s.is_reset_done() {
+                    inner.replace(Sender::ResetRecvd(None));
+                } else {
+                    inner.replace(Sender::ReliableResetSent(s));
+                }
+            }
             // ignore recv
             other => inner.replace(other),
         };
@@ -128,30 +144,40 @@ impl Outgoing {
                 s.may_loss(range);
                 inner.replace(Sender::DataSent(s));
             }
+            Sender::ReliableResetSent(mut s) => {
+                s.may_loss(range);
+                inner.replace(Sender::ReliableResetSent(s));
+            }
             // ignore loss
             other => inner.replace(other),
         };
     }
 
-    /// 被动stop，返回true说明成功stop了；返回false则表明流没有必要stop，要么已经完成，要么已经reset
-    pub fn stop(&mut self) -> bool {
+    /// 被动stop，返回流的最终大小，用于回应RESET_STREAM帧；返回None则表明流没有必要stop，
+    /// 要么已经完成，要么已经reset。对方的错误码会在应用下次写入时告知应用
+    pub fn stop(&mut self, code: VarInt) -> Option<u64> {
         let mut sender = self.0.lock().unwrap();
         let inner = sender.deref_mut();
         match inner.take() {
-            Sender::Ready(_) => {
-                unreachable!("never send data before recv data");
+            // 对方创建的双向流，我方还未发送过数据，对方也可以要求停止发送
+            Sender::Ready(s) => {
+                let final_size = s.begin_sending().stop();
+                inner.replace(Sender::ResetSent(final_size, Some(code)));
+                Some(final_size)
             }
             Sender::Sending(s) => {
-                inner.replace(Sender::ResetSent(s.stop()));
-                true
+                let final_size = s.stop();
+                inner.replace(Sender::ResetSent(final_size, Some(code)));
+                Some(final_size)
             }
             Sender::DataSent(s) => {
-                inner.replace(Sender::ResetSent(s.stop()));
-                true
+                let final_size = s.stop();
+                inner.replace(Sender::ResetSent(final_size, Some(code)));
+                Some(final_size)
             }
             other => {
                 inner.replace(other);
-                false
+                None
             }
         }
     }
@@ -160,8 +186,17 @@ impl Outgoing {
         let mut sender = self.0.lock().unwrap();
         let inner = sender.deref_mut();
         match inner.take() {
-            Sender::ResetSent(_) | Sender::ResetRecvd => {
-                inner.replace(Sender::ResetRecvd);
+            Sender::ResetSent(_, stop_code) | Sender::ResetRecvd(stop_code) => {
+                inner.replace(Sender::ResetRecvd(stop_code));
+            }
+            // 可靠大小之前的数据也都被确认了，才算重置完成
+            Sender::ReliableResetSent(mut s) => {
+                s.confirm_reset();
+                if s.is_reset_done() {
+                    inner.replace(Sender::ResetRecvd(None));
+                } else {
+                    inner.replace(Sender::ReliableResetSent(s));
+                }
             }
             _ => {
                 unreachable!(
@@ -171,24 +206,35 @@ impl Outgoing {
         };
     }
 
-    pub fn is_cancelled_by_app(&self) -> IsCancelled {
-        IsCancelled(self.0.clone())
+    /// 对方未通告reset_stream_at传输参数的，`reliable_reset`为false，应用的可靠重置退化为
+    /// 普通的重置，可靠大小记为0，发送RESET_STREAM而不是RESET_STREAM_AT
+    pub fn is_cancelled_by_app(&self, reliable_reset: Arc<AtomicBool>) -> IsCancelled {
+        IsCancelled(self.0.clone(), reliable_reset)
     }
 }
 
-pub struct IsCancelled(ArcSender);
+pub struct IsCancelled(ArcSender, Arc<AtomicBool>);
 
 impl Future for IsCancelled {
-    type Output = Option<u64>;
+    // 应用reset时的错误码、流的最终大小，以及可靠大小，可靠大小不为0时要发送RESET_STREAM_AT
+    type Output = Option<(VarInt, u64, u64)>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let reliable_reset = self.1.load(Ordering::Acquire);
         let mut sender = self.0.lock().unwrap();
         let inner = sender.deref_mut();
         match inner.take() {
             Sender::Ready(mut s) => match s.poll_cancel(cx) {
-                Poll::Ready(final_size) => {
-                    inner.replace(Sender::ResetSent(final_size));
-                    Poll::Ready(Some(final_size))
+                Poll::Ready((code, final_size, reliable_size)) => {
+                    let reliable_size = if reliable_reset { reliable_size } else { 0 };
+                    if reliable_size > 0 {
+                        inner.replace(Sender::ReliableResetSent(
+                            s.begin_sending().reset_reliably(reliable_size),
+                        ));
+                    } else {
+                        inner.replace(Sender::ResetSent(final_size, None));
+                    }
+                    Poll::Ready(Some((code, final_size, reliable_size)))
                 }
                 Poll::Pending => {
                     inner.replace(Sender::Ready(s));
@@ -196,9 +242,14 @@ impl Future for IsCancelled {
                 }
             },
             Sender::Sending(mut s) => match s.poll_cancel(cx) {
-                Poll::Ready(final_size) => {
-                    inner.replace(Sender::ResetSent(final_size));
-                    Poll::Ready(Some(final_size))
+                Poll::Ready((code, final_size, reliable_size)) => {
+                    let reliable_size = if reliable_reset { reliable_size } else { 0 };
+                    if reliable_size > 0 {
+                        inner.replace(Sender::ReliableResetSent(s.reset_reliably(reliable_size)));
+                    } else {
+                        inner.replace(Sender::ResetSent(final_size, None));
+                    }
+                    Poll::Ready(Some((code, final_size, reliable_size)))
                 }
                 Poll::Pending => {
                     inner.replace(Sender::Sending(s));
@@ -206,9 +257,14 @@ impl Future for IsCancelled {
                 }
             },
             Sender::DataSent(mut s) => match s.poll_cancel(cx) {
-                Poll::Ready(final_size) => {
-                    inner.replace(Sender::ResetSent(final_size));
-                    Poll::Ready(Some(final_size))
+                Poll::Ready((code, final_size, reliable_size)) => {
+                    let reliable_size = if reliable_reset { reliable_size } else { 0 };
+                    if reliable_size > 0 {
+                        inner.replace(Sender::ReliableResetSent(s.reset_reliably(reliable_size)));
+                    } else {
+                        inner.replace(Sender::ResetSent(final_size, None));
+                    }
+                    Poll::Ready(Some((code, final_size, reliable_size)))
                 }
                 Poll::Pending => {
                     inner.replace(Sender::DataSent(s));
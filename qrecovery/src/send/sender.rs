@@ -1,4 +1,5 @@
 use super::sndbuf::SendBuf;
+use qbase::varint::VarInt;
 use std::{
     io,
     ops::Range,
@@ -14,7 +15,8 @@ use std::{
 pub struct ReadySender {
     sndbuf: SendBuf,
     max_data_size: u64,
-    is_cancelled: bool,
+    // 应用重置流时的错误码，以及可靠大小，可靠大小为0即普通的重置
+    cancelled: Option<(VarInt, u64)>,
     writable_waker: Option<Waker>,
     flush_waker: Option<Waker>,
     shutdown_waker: Option<Waker>,
@@ -26,7 +28,7 @@ impl ReadySender {
         ReadySender {
             sndbuf: SendBuf::with_capacity(initial_max_stream_data as usize),
             max_data_size: initial_max_stream_data,
-            is_cancelled: false,
+            cancelled: None,
             writable_waker: None,
             flush_waker: None,
             shutdown_waker: None,
@@ -39,7 +41,7 @@ impl ReadySender {
     /// 仅供展示学习
     #[allow(dead_code)]
     pub(self) fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Err(io::ErrorKind::BrokenPipe.into())
         } else {
             let range = self.sndbuf.range();
@@ -58,7 +60,7 @@ impl ReadySender {
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         assert!(self.writable_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else {
             let range = self.sndbuf.range();
@@ -74,7 +76,7 @@ impl ReadySender {
 
     pub(super) fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         assert!(self.flush_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else {
             self.flush_waker = Some(cx.waker().clone());
@@ -84,7 +86,7 @@ impl ReadySender {
 
     pub(super) fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         assert!(self.shutdown_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else {
             self.shutdown_waker = Some(cx.waker().clone());
@@ -100,7 +102,7 @@ impl ReadySender {
         SendingSender {
             sndbuf: self.sndbuf,
             max_data_size: self.max_data_size,
-            is_cancelled: self.is_cancelled,
+            cancelled: self.cancelled,
             writable_waker: self.writable_waker,
             flush_waker: self.flush_waker,
             shutdown_waker: self.shutdown_waker,
@@ -111,27 +113,29 @@ impl ReadySender {
     pub(super) fn end(self) -> DataSentSender {
         DataSentSender {
             sndbuf: self.sndbuf,
-            is_cancelled: self.is_cancelled,
+            cancelled: self.cancelled,
             flush_waker: self.flush_waker,
             shutdown_waker: self.shutdown_waker,
             cancel_waker: self.cancel_waker,
         }
     }
 
-    pub(super) fn poll_cancel(&mut self, cx: &mut Context<'_>) -> Poll<u64> {
+    pub(super) fn poll_cancel(&mut self, cx: &mut Context<'_>) -> Poll<(VarInt, u64, u64)> {
         assert!(self.cancel_waker.is_none());
-        if self.is_cancelled {
-            Poll::Ready(self.sndbuf.len())
+        if let Some((code, reliable_size)) = self.cancelled {
+            Poll::Ready((code, self.sndbuf.len(), reliable_size))
         } else {
             self.cancel_waker = Some(cx.waker().clone());
             Poll::Pending
         }
     }
 
-    pub(super) fn cancel(&mut self) {
-        // 应用层多次cancel会被忽略
-        if !self.is_cancelled {
-            self.is_cancelled = true;
+    pub(super) fn cancel(&mut self, code: VarInt, reliable_size: u64) {
+        // 应用层多次cancel会被忽略，以第一次的错误码为准
+        if self.cancelled.is_none() {
+            // 可靠大小不能超过已写入的数据量
+            let reliable_size = std::cmp::min(reliable_size, self.sndbuf.len());
+            self.cancelled = Some((code, reliable_size));
             if let Some(waker) = self.cancel_waker.take() {
                 waker.wake();
             }
@@ -143,7 +147,7 @@ impl ReadySender {
 pub struct SendingSender {
     sndbuf: SendBuf,
     max_data_size: u64,
-    is_cancelled: bool,
+    cancelled: Option<(VarInt, u64)>,
     writable_waker: Option<Waker>,
     flush_waker: Option<Waker>,
     shutdown_waker: Option<Waker>,
@@ -158,7 +162,7 @@ impl SendingSender {
     ) -> Poll<io::Result<usize>> {
         assert!(self.shutdown_waker.is_none());
         assert!(self.writable_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else {
             let range = self.sndbuf.range();
@@ -184,7 +188,7 @@ impl SendingSender {
     where
         F: Fn(u64) -> Option<usize>,
     {
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             return None;
         }
         self.sndbuf
@@ -207,7 +211,7 @@ impl SendingSender {
 
     pub(super) fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         assert!(self.flush_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else if self.sndbuf.is_all_rcvd() {
             Poll::Ready(Ok(()))
@@ -220,7 +224,7 @@ impl SendingSender {
     pub(super) fn end(self) -> DataSentSender {
         DataSentSender {
             sndbuf: self.sndbuf,
-            is_cancelled: self.is_cancelled,
+            cancelled: self.cancelled,
             flush_waker: self.flush_waker,
             shutdown_waker: self.shutdown_waker,
             cancel_waker: self.cancel_waker,
@@ -229,7 +233,7 @@ impl SendingSender {
 
     pub(super) fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         assert!(self.shutdown_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else if self.sndbuf.is_all_rcvd() {
             // 都已经关闭了，不再写数据数据了，如果所有数据都已发送完，那就是已关闭了
@@ -240,25 +244,35 @@ impl SendingSender {
         }
     }
 
-    pub(super) fn poll_cancel(&mut self, cx: &mut Context<'_>) -> Poll<u64> {
+    pub(super) fn poll_cancel(&mut self, cx: &mut Context<'_>) -> Poll<(VarInt, u64, u64)> {
         assert!(self.cancel_waker.is_none());
-        if self.is_cancelled {
-            Poll::Ready(self.sndbuf.len())
+        if let Some((code, reliable_size)) = self.cancelled {
+            Poll::Ready((code, self.sndbuf.len(), reliable_size))
         } else {
             self.cancel_waker = Some(cx.waker().clone());
             Poll::Pending
         }
     }
 
-    pub(super) fn cancel(&mut self) {
-        if !self.is_cancelled {
-            self.is_cancelled = true;
+    pub(super) fn cancel(&mut self, code: VarInt, reliable_size: u64) {
+        if self.cancelled.is_none() {
+            // 可靠大小不能超过已写入的数据量
+            let reliable_size = std::cmp::min(reliable_size, self.sndbuf.len());
+            self.cancelled = Some((code, reliable_size));
             if let Some(waker) = self.cancel_waker.take() {
                 waker.wake();
             }
         }
     }
 
+    pub(super) fn reset_reliably(mut self, reliable_size: u64) -> ReliableResetSender {
+        self.sndbuf.truncate(reliable_size);
+        ReliableResetSender {
+            sndbuf: self.sndbuf,
+            is_reset_rcvd: false,
+        }
+    }
+
     pub(super) fn stop(self) -> u64 {
         if let Some(waker) = self.writable_waker {
             waker.wake();
@@ -281,7 +295,7 @@ impl SendingSender {
 #[derive(Debug)]
 pub struct DataSentSender {
     sndbuf: SendBuf,
-    is_cancelled: bool,
+    cancelled: Option<(VarInt, u64)>,
     flush_waker: Option<Waker>,
     shutdown_waker: Option<Waker>,
     cancel_waker: Option<Waker>,
@@ -292,7 +306,7 @@ impl DataSentSender {
     where
         F: Fn(u64) -> Option<usize>,
     {
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             return None;
         }
 
@@ -327,7 +341,7 @@ impl DataSentSender {
 
     pub(super) fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         assert!(self.flush_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else if self.sndbuf.is_all_rcvd() {
             Poll::Ready(Ok(()))
@@ -339,7 +353,7 @@ impl DataSentSender {
 
     pub(super) fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         assert!(self.shutdown_waker.is_none());
-        if self.is_cancelled {
+        if self.cancelled.is_some() {
             Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
         } else if self.is_all_rcvd() {
             Poll::Ready(Ok(()))
@@ -349,25 +363,35 @@ impl DataSentSender {
         }
     }
 
-    pub(super) fn poll_cancel(&mut self, cx: &mut Context<'_>) -> Poll<u64> {
+    pub(super) fn poll_cancel(&mut self, cx: &mut Context<'_>) -> Poll<(VarInt, u64, u64)> {
         assert!(self.cancel_waker.is_none());
-        if self.is_cancelled {
-            Poll::Ready(self.sndbuf.len())
+        if let Some((code, reliable_size)) = self.cancelled {
+            Poll::Ready((code, self.sndbuf.len(), reliable_size))
         } else {
             self.cancel_waker = Some(cx.waker().clone());
             Poll::Pending
         }
     }
 
-    pub(super) fn cancel(&mut self) {
-        if !self.is_cancelled {
-            self.is_cancelled = true;
+    pub(super) fn cancel(&mut self, code: VarInt, reliable_size: u64) {
+        if self.cancelled.is_none() {
+            // 可靠大小不能超过已写入的数据量
+            let reliable_size = std::cmp::min(reliable_size, self.sndbuf.len());
+            self.cancelled = Some((code, reliable_size));
             if let Some(waker) = self.cancel_waker.take() {
                 waker.wake();
             }
         }
     }
 
+    pub(super) fn reset_reliably(mut self, reliable_size: u64) -> ReliableResetSender {
+        self.sndbuf.truncate(reliable_size);
+        ReliableResetSender {
+            sndbuf: self.sndbuf,
+            is_reset_rcvd: false,
+        }
+    }
+
     pub(super) fn stop(self) -> u64 {
         if let Some(waker) = self.flush_waker {
             waker.wake();
@@ -384,15 +408,52 @@ impl DataSentSender {
     }
 }
 
+/// 可靠重置之后，RESET_STREAM_AT已经发出，但可靠大小之前的数据仍要可靠地传输，
+/// 直到这些数据和RESET_STREAM_AT都被对方确认，流才算重置完成
+#[derive(Debug)]
+pub struct ReliableResetSender {
+    sndbuf: SendBuf,
+    is_reset_rcvd: bool,
+}
+
+impl ReliableResetSender {
+    pub(super) fn pick_up<F>(&mut self, estimate_capacity: F) -> Option<(u64, &[u8], bool)>
+    where
+        F: Fn(u64) -> Option<usize>,
+    {
+        self.sndbuf
+            .pick_up(estimate_capacity)
+            .map(|(offset, data)| (offset, data, false))
+    }
+
+    pub(super) fn confirm_rcvd(&mut self, range: &Range<u64>) {
+        self.sndbuf.confirm_rcvd(range);
+    }
+
+    pub(super) fn may_loss(&mut self, range: &Range<u64>) {
+        self.sndbuf.may_loss(range)
+    }
+
+    pub(super) fn confirm_reset(&mut self) {
+        self.is_reset_rcvd = true;
+    }
+
+    pub(super) fn is_reset_done(&self) -> bool {
+        self.is_reset_rcvd && self.sndbuf.is_all_rcvd()
+    }
+}
+
 #[derive(Default, Debug)]
 pub enum Sender {
     Ready(ReadySender),
     Sending(SendingSender),
     DataSent(DataSentSender),
-    ResetSent(u64),
+    /// 流的最终大小，以及对方STOP_SENDING的错误码，如果是被对方要求停止的话
+    ResetSent(u64, Option<VarInt>),
+    ReliableResetSent(ReliableResetSender),
     #[default]
     DataRecvd,
-    ResetRecvd,
+    ResetRecvd(Option<VarInt>),
 }
 
 impl Sender {
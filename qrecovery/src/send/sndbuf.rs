@@ -8,7 +8,7 @@ use std::{
 
 /// To indicate the state of a data segment, it is colored.
 #[derive(Default, PartialEq, Eq, Clone, Copy, Debug)]
-pub(self) enum Color {
+enum Color {
     #[default]
     Pending,
     Flighting,
@@ -274,6 +274,16 @@ impl BufMap {
         }
     }
 
+    // 丢弃end之后的区间，这些数据不再发送，也不再重传
+    fn truncate(&mut self, end: u64) {
+        if end < self.1 {
+            while matches!(self.0.back(), Some(s) if s.offset() >= end) {
+                self.0.pop_back();
+            }
+            self.1 = end;
+        }
+    }
+
     // 寻找第一个不是Recved的位置，意味着之前的数据都已经被确认接收，
     // 发送缓冲区可以移动到该位置，以让发送缓冲区腾出更多空间
     fn shift(&mut self) -> u64 {
@@ -544,6 +554,16 @@ impl SendBuf {
         self.data.capacity() - self.data.len()
     }
 
+    /// 可靠重置之后，只有reliable_size之前的数据还需要可靠地传输，之后的数据都丢弃；
+    /// 已被确认的数据早已释放，不受影响
+    pub fn truncate(&mut self, reliable_size: u64) {
+        let end = std::cmp::max(reliable_size, self.offset);
+        if end < self.len() {
+            self.state.truncate(end);
+            self.data.truncate((end - self.offset) as usize);
+        }
+    }
+
     // 无需close：不在写入即可，具体到某个状态，才有close
     // 无需reset：状态转化间，需要reset，而Sender上下文直接释放即可
     // 无需clean：Sender上下文直接释放即可，
@@ -567,6 +587,11 @@ impl SendBuf {
     // 通过传输层接收到的对方的ack帧，确认某些包已经被接收到，这些包携带的数据即被确认。
     // ack只能确认Flighting/Lost状态的区间；如果确认的是Lost区间，意味着之前的判定丢包是错误的。
     pub fn confirm_rcvd(&mut self, range: &Range<u64>) {
+        // 截断之后，超出末尾的部分已被丢弃，无需再确认
+        let range = &(range.start..std::cmp::min(range.end, self.len()));
+        if range.is_empty() {
+            return;
+        }
         self.state.ack_rcvd(range);
         // 对于头部连续确认接收到的，还要前进，以免浪费空间
         let min_unrecved_pos = self.state.shift();
@@ -579,6 +604,11 @@ impl SendBuf {
     // 通过传输层收到的ack帧，判定有些数据包丢失，因为它之后的数据包都被确认了，
     // 或者距离发送该段数据之后相当长一段时间都没收到它的确认。
     pub fn may_loss(&mut self, range: &Range<u64>) {
+        // 截断之后，超出末尾的部分已被丢弃，不必重传
+        let range = &(range.start..std::cmp::min(range.end, self.len()));
+        if range.is_empty() {
+            return;
+        }
         self.state.may_loss(range);
     }
 
@@ -1022,4 +1052,24 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_sndbuf_truncate() {
+        use super::SendBuf;
+        let mut sndbuf = SendBuf::with_capacity(100);
+        sndbuf.write(&[1; 100]);
+        assert_eq!(sndbuf.pick_up(|_| Some(60)), Some((0, &[1u8; 60][..])));
+        sndbuf.confirm_rcvd(&(0..10));
+        // 可靠大小之后的数据都丢弃，包括已经发出去的
+        sndbuf.truncate(30);
+        assert_eq!(sndbuf.range(), 10..30);
+        assert_eq!(sndbuf.pick_up(|_| Some(60)), None);
+        // 丢弃部分的确认和丢失都被忽略，只有可靠大小之前的数据要重传
+        sndbuf.may_loss(&(10..60));
+        assert_eq!(sndbuf.pick_up(|_| Some(60)), Some((10, &[1u8; 20][..])));
+        sndbuf.confirm_rcvd(&(40..60));
+        assert!(!sndbuf.is_all_rcvd());
+        sndbuf.confirm_rcvd(&(10..60));
+        assert!(sndbuf.is_all_rcvd());
+    }
 }
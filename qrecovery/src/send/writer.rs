@@ -1,4 +1,6 @@
 use super::sender::{ArcSender, Sender};
+use crate::StreamError;
+use qbase::varint::VarInt;
 use std::{
     io,
     ops::DerefMut,
@@ -7,10 +9,51 @@ use std::{
 };
 use tokio::io::AsyncWrite;
 
-/// TODO: Drop视为自动cancel
+/// Drop视为自动cancel，以错误码0重置流
 #[derive(Debug)]
 pub struct Writer(pub(super) ArcSender);
 
+/// 流被重置之后的写入错误：对方要求停止发送的，要告知应用对方的错误码
+fn reset_error(stop_code: Option<VarInt>) -> io::Error {
+    match stop_code {
+        Some(code) => StreamError::Stopped(code).into(),
+        None => io::ErrorKind::BrokenPipe.into(),
+    }
+}
+
+impl Writer {
+    /// 应用主动放弃发送，向对方发送带有错误码的RESET_STREAM帧。多次reset只有第一次有效，
+    /// 数据已全部被对方确认或者流已经重置的，reset会被忽略
+    pub fn reset(&mut self, code: VarInt) {
+        self.reset_at(code, 0)
+    }
+
+    /// 可靠地重置流，向对方发送RESET_STREAM_AT帧，保证流的前reliable_size字节数据仍会可靠地
+    /// 送达对方，超出已写入数据量的部分会被截断。reliable_size为0时等同于reset。
+    /// 只有对方通告了reset_stream_at传输参数，才能可靠地重置流，否则退化为reset，发送RESET_STREAM帧
+    pub fn reset_at(&mut self, code: VarInt, reliable_size: u64) {
+        let mut sender = self.0.lock().unwrap();
+        let inner = sender.deref_mut();
+        match inner.take() {
+            Sender::Ready(mut s) => {
+                s.cancel(code, reliable_size);
+                inner.replace(Sender::Ready(s));
+            }
+            Sender::Sending(mut s) => {
+                s.cancel(code, reliable_size);
+                inner.replace(Sender::Sending(s));
+            }
+            Sender::DataSent(mut s) => {
+                s.cancel(code, reliable_size);
+                inner.replace(Sender::DataSent(s));
+            }
+            other => {
+                inner.replace(other);
+            }
+        };
+    }
+}
+
 impl AsyncWrite for Writer {
     /// 往sndbuf里面写数据，直到写满MAX_STREAM_DATA，等通告窗口更新再写
     fn poll_write(
@@ -39,13 +82,17 @@ impl AsyncWrite for Writer {
                 inner.replace(Sender::DataRecvd);
                 Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
             }
-            Sender::ResetSent(final_size) => {
-                inner.replace(Sender::ResetSent(final_size));
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            Sender::ReliableResetSent(s) => {
+                inner.replace(Sender::ReliableResetSent(s));
+                Poll::Ready(Err(reset_error(None)))
+            }
+            Sender::ResetSent(final_size, stop_code) => {
+                inner.replace(Sender::ResetSent(final_size, stop_code));
+                Poll::Ready(Err(reset_error(stop_code)))
             }
-            Sender::ResetRecvd => {
-                inner.replace(Sender::ResetRecvd);
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            Sender::ResetRecvd(stop_code) => {
+                inner.replace(Sender::ResetRecvd(stop_code));
+                Poll::Ready(Err(reset_error(stop_code)))
             }
         }
     }
@@ -76,13 +123,17 @@ impl AsyncWrite for Writer {
                 inner.replace(Sender::DataRecvd);
                 Poll::Ready(Ok(()))
             }
-            Sender::ResetSent(final_size) => {
-                inner.replace(Sender::ResetSent(final_size));
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            Sender::ReliableResetSent(s) => {
+                inner.replace(Sender::ReliableResetSent(s));
+                Poll::Ready(Err(reset_error(None)))
             }
-            Sender::ResetRecvd => {
-                inner.replace(Sender::ResetRecvd);
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            Sender::ResetSent(final_size, stop_code) => {
+                inner.replace(Sender::ResetSent(final_size, stop_code));
+                Poll::Ready(Err(reset_error(stop_code)))
+            }
+            Sender::ResetRecvd(stop_code) => {
+                inner.replace(Sender::ResetRecvd(stop_code));
+                Poll::Ready(Err(reset_error(stop_code)))
             }
         }
     }
@@ -112,7 +163,8 @@ impl AsyncWrite for Writer {
                 let result = s.poll_shutdown(cx);
                 // 有一种复杂的情况，就是在DataSent途中，对方发来了STOP_SENDING，我方需立即
                 // reset停止发送，此时状态也轮转到ResetSent中，相当于被动reset，再次唤醒该
-                // poll任务，则会进到ResetSent或者ResetRecvd中poll，得到的将是BrokenPipe错误
+                // poll任务，则会进到ResetSent或者ResetRecvd中poll，得到的将是带有对方错误码的
+                // StreamError::Stopped错误
                 match &result {
                     Poll::Pending => inner.replace(Sender::DataSent(s)),
                     Poll::Ready(_) => inner.replace(Sender::DataRecvd),
@@ -123,13 +175,17 @@ impl AsyncWrite for Writer {
                 inner.replace(Sender::DataRecvd);
                 Poll::Ready(Ok(()))
             }
-            Sender::ResetSent(final_size) => {
-                inner.replace(Sender::ResetSent(final_size));
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            Sender::ReliableResetSent(s) => {
+                inner.replace(Sender::ReliableResetSent(s));
+                Poll::Ready(Err(reset_error(None)))
+            }
+            Sender::ResetSent(final_size, stop_code) => {
+                inner.replace(Sender::ResetSent(final_size, stop_code));
+                Poll::Ready(Err(reset_error(stop_code)))
             }
-            Sender::ResetRecvd => {
-                inner.replace(Sender::ResetRecvd);
-                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            Sender::ResetRecvd(stop_code) => {
+                inner.replace(Sender::ResetRecvd(stop_code));
+                Poll::Ready(Err(reset_error(stop_code)))
             }
         }
     }
@@ -137,25 +193,7 @@ impl AsyncWrite for Writer {
 
 impl Drop for Writer {
     fn drop(&mut self) {
-        let mut sender = self.0.lock().unwrap();
-        let inner = sender.deref_mut();
-        match inner.take() {
-            Sender::Ready(mut s) => {
-                s.cancel();
-                inner.replace(Sender::Ready(s));
-            }
-            Sender::Sending(mut s) => {
-                s.cancel();
-                inner.replace(Sender::Sending(s));
-            }
-            Sender::DataSent(mut s) => {
-                s.cancel();
-                inner.replace(Sender::DataSent(s));
-            }
-            other => {
-                inner.replace(other);
-            }
-        };
+        self.reset(VarInt::from_u32(0));
     }
 }
 
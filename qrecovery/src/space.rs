@@ -2,12 +2,15 @@ use super::{
     crypto::{CryptoStream, TransmitCrypto},
     index_deque::IndexDeque,
     rtt::Rtt,
-    streams::{NoStreams, Streams, TransmitStream},
+    streams::{Listener, NoStreams, Streams, TransmitStream},
+    AppStream,
 };
 use bytes::{BufMut, Bytes};
 use qbase::{
-    error::Error,
+    error::{Error, ErrorKind},
     frame::{ext::*, *},
+    packet::{PacketNumber, PayloadBuf},
+    streamid::Dir,
     varint::{VarInt, VARINT_MAX},
     SpaceId,
 };
@@ -15,6 +18,7 @@ use std::{
     collections::VecDeque,
     fmt::Debug,
     sync::{Arc, Mutex},
+    task::{Context, Poll},
     time::{Duration, Instant},
 };
 
@@ -25,6 +29,14 @@ pub enum SpaceFrame {
     Data(DataFrame, Bytes),
 }
 
+/// Observe the packets newly acknowledged or deemed lost by the peer's ACK frames, such as the
+/// PMTU discovery of the paths, which tells its probe packets from the others by packet number.
+pub trait ObservePackets: Debug + Send + Sync {
+    fn on_packet_acked(&self, pktid: u64);
+
+    fn on_packet_lost(&self, pktid: u64);
+}
+
 pub trait TrySend {
     type Buffer: BufMut;
 
@@ -36,6 +48,10 @@ pub trait TrySend {
 pub trait Receive {
     fn expected_pn(&self) -> u64;
 
+    /// Whether the packet has been received, or is too old to be tracked by the
+    /// receive window. Such packets must be dropped before their frames are dispatched.
+    fn is_duplicate(&self, pktid: u64) -> bool;
+
     fn record(&self, pktid: u64, is_ack_eliciting: bool);
 
     fn recv_frame(&self, frame: SpaceFrame) -> Result<(), Error>;
@@ -110,6 +126,12 @@ struct Packet {
 }
 
 const PACKET_THRESHOLD: u64 = 3;
+// 开启跳包后，平均每隔这么多个包号跳过一个
+const PN_SKIP_INTERVAL: u64 = 256;
+// 只记住最近跳过的这些包号，更早的跳过包号不太可能再被乐观确认
+const SKIPPED_PKTIDS: usize = 8;
+// 接收窗口最多跟踪这么多个包号，更早的包号被视为重复，直接丢弃
+const RCVD_WINDOW: u64 = 4096;
 
 /// 可靠空间的抽象实现，需要实现上述所有trait
 /// 可靠空间中的重传、确认，由可靠空间内部实现，无需外露
@@ -134,11 +156,16 @@ where
     disorder_tolerance: u64,
     time_of_last_sent_ack_eliciting_packet: Option<Instant>,
     largest_acked_pktid: Option<u64>,
+    // 随机跳过一些包号，对方若确认了跳过的包号，说明其在乐观确认，见RFC 9000 Section 21.4
+    next_skipped_pktid: Option<u64>,
+    skipped_pktids: VecDeque<u64>,
     // 设计丢包重传定时器，在收到AckFrame的探测丢包时，可能会设置该定时器，实际上是过期时间
     loss_time: Option<Instant>,
 
     // 用于产生ack frame，Instant用于计算ack_delay，bool表明是否ack eliciting
     rcvd_packets: IndexDeque<State, VARINT_MAX>,
+    // 成功解密处理过的最大包号，用于解码包号
+    largest_rcvd_pktid: Option<u64>,
     // 收到的最大的ack-eliciting packet的pktid
     largest_rcvd_ack_eliciting_pktid: u64,
     last_synced_ack_largest: u64,
@@ -150,6 +177,7 @@ where
     time_to_sync: Option<Instant>,
     // 应该计算rtt的时候，传进来；或者收到ack frame的时候，将(last_rtt, ack_delay)传出去
     max_ack_delay: Duration,
+    observer: Option<Arc<dyn ObservePackets>>,
 
     stm_trans: ST,
     tls_trans: CT,
@@ -168,14 +196,18 @@ where
             disorder_tolerance: 0,
             time_of_last_sent_ack_eliciting_packet: None,
             largest_acked_pktid: None,
+            next_skipped_pktid: None,
+            skipped_pktids: VecDeque::new(),
             loss_time: None,
             rcvd_packets: IndexDeque::new(),
+            largest_rcvd_pktid: None,
             largest_rcvd_ack_eliciting_pktid: 0,
             last_synced_ack_largest: 0,
             new_lost_event: false,
             rcvd_unreached_packet: false,
             time_to_sync: None,
             max_ack_delay: Duration::from_millis(25),
+            observer: None,
             stm_trans: streams_transmission,
             tls_trans: tls_transmission,
         }
@@ -199,9 +231,8 @@ where
                         .rcvd_packets
                         .drain_to(ack.0.saturating_sub(self.disorder_tolerance));
                 }
-                Record::Pure(_frame) => {
-                    todo!("哪些帧需要确认呢？")
-                }
+                // 纯帧被确认后无需处理，只有丢失时才要重传
+                Record::Pure(_) => {}
                 Record::Data(data) => match data {
                     DataFrame::Crypto(f) => self.tls_trans.confirm_data(f),
                     DataFrame::Stream(f) => self.stm_trans.confirm_data(f),
@@ -211,13 +242,42 @@ where
     }
 
     fn expected_pn(&self) -> u64 {
-        self.rcvd_packets.largest()
+        self.largest_rcvd_pktid.map_or(0, |pn| pn + 1)
+    }
+
+    /// Enable skipping packet numbers randomly to detect optimistic ACK attacks.
+    fn set_pn_skipping(&mut self, enabled: bool) {
+        self.next_skipped_pktid =
+            enabled.then(|| self.inflight_packets.largest() + Self::random_pn_gap());
+    }
+
+    fn random_pn_gap() -> u64 {
+        rand::random::<u64>() % PN_SKIP_INTERVAL * 2 + 1
+    }
+
+    /// The packet number of the next packet, and its encoding in the packet header, which
+    /// depends on the largest packet number acknowledged by the peer.
+    fn next_pn(&mut self) -> (u64, PacketNumber) {
+        let mut pktid = self.inflight_packets.largest();
+        if self
+            .next_skipped_pktid
+            .is_some_and(|skipped| skipped <= pktid)
+            && self.inflight_packets.push(None).is_ok()
+        {
+            if self.skipped_pktids.len() == SKIPPED_PKTIDS {
+                self.skipped_pktids.pop_front();
+            }
+            self.skipped_pktids.push_back(pktid);
+            pktid += 1;
+            self.next_skipped_pktid = Some(pktid + Self::random_pn_gap());
+        }
+        (pktid, PacketNumber::encode(pktid, self.largest_acked_pktid))
     }
 
     fn recv_frame(&mut self, frame: SpaceFrame) -> Result<(), Error> {
         match frame {
             SpaceFrame::Ack(ack, rtt) => {
-                let _ = self.recv_ack_frame(ack, rtt);
+                let _ = self.recv_ack_frame(ack, rtt)?;
             }
             SpaceFrame::Stream(f) => self.stm_trans.recv_frame(f)?,
             SpaceFrame::Data(f, data) => match f {
@@ -228,10 +288,27 @@ where
         Ok(())
     }
 
+    fn is_duplicate(&self, pkt_id: u64) -> bool {
+        pkt_id < self.rcvd_packets.offset()
+            || self.rcvd_packets.get(pkt_id).is_some_and(|s| s.has_rcvd())
+    }
+
     fn record(&mut self, pkt_id: u64, is_ack_eliciting: bool) {
-        self.rcvd_packets
+        if self.is_duplicate(pkt_id) {
+            return;
+        }
+        // 包号跳跃太远时，滑动接收窗口，窗口之外更早的包号将被视为太旧
+        if let Some(end) = (pkt_id + 1).checked_sub(RCVD_WINDOW) {
+            self.rcvd_packets.advance_to(end);
+        }
+        if self
+            .rcvd_packets
             .insert(pkt_id, State::new_rcvd(Instant::now(), is_ack_eliciting))
-            .unwrap();
+            .is_err()
+        {
+            return;
+        }
+        self.largest_rcvd_pktid = self.largest_rcvd_pktid.max(Some(pkt_id));
         if is_ack_eliciting {
             if self.largest_rcvd_ack_eliciting_pktid < pkt_id {
                 self.largest_rcvd_ack_eliciting_pktid = pkt_id;
@@ -239,7 +316,7 @@ where
                     .rcvd_packets
                     .iter_with_idx()
                     .rev()
-                    .skip_while(|(pn, _)| pn >= &pn)
+                    .skip_while(|(pn, _)| *pn >= pkt_id)
                     .skip(PACKET_THRESHOLD as usize)
                     .take_while(|(pn, _)| pn > &self.last_synced_ack_largest)
                     .any(|(_, s)| matches!(s, State::NotReceived));
@@ -298,14 +375,42 @@ where
         }
     }
 
-    fn recv_ack_frame(&mut self, mut ack: AckFrame, rtt: Arc<Mutex<Rtt>>) -> Option<usize> {
+    /// Returns the bytes newly acknowledged. Acknowledging a packet that was never sent,
+    /// including a skipped packet number, is a PROTOCOL_VIOLATION.
+    fn recv_ack_frame(
+        &mut self,
+        mut ack: AckFrame,
+        rtt: Arc<Mutex<Rtt>>,
+    ) -> Result<Option<usize>, Error> {
         let largest_acked = ack.largest.into_inner();
+        if largest_acked >= self.inflight_packets.largest() {
+            return Err(Error::new(
+                ErrorKind::ProtocolViolation,
+                ack.frame_type(),
+                format!("acknowledged packet {largest_acked} that was never sent"),
+            ));
+        }
+        let ecn_in_ack = ack.take_ecn();
+        let ack_delay = Duration::from_micros(ack.delay.into_inner());
+        let frame_type = ack.frame_type();
+        let ranges = ack.into_iter().collect::<Vec<_>>();
+        if let Some(pktid) = self
+            .skipped_pktids
+            .iter()
+            .find(|pktid| ranges.iter().any(|range| range.contains(pktid)))
+        {
+            return Err(Error::new(
+                ErrorKind::ProtocolViolation,
+                frame_type,
+                format!("acknowledged the skipped packet {pktid}, an optimistic ACK"),
+            ));
+        }
         if self
             .largest_acked_pktid
             .map(|v| v > largest_acked)
             .unwrap_or(false)
         {
-            return None;
+            return Ok(None);
         }
         // largest_acked == self.largest_acked_packet is also acceptable,
         // perhaps indicating that old 'lost' packets have been acknowledged.
@@ -314,9 +419,7 @@ where
         let mut no_newly_acked = true;
         let mut includes_ack_eliciting = false;
         let mut acked_bytes = 0;
-        let ecn_in_ack = ack.take_ecn();
-        let ack_delay = Duration::from_micros(ack.delay.into_inner());
-        for range in ack.into_iter() {
+        for range in ranges {
             for pktid in range {
                 if let Some(packet) = self
                     .inflight_packets
@@ -327,6 +430,9 @@ where
                     if packet.is_ack_eliciting {
                         includes_ack_eliciting = true;
                     }
+                    if let Some(observer) = &self.observer {
+                        observer.on_packet_acked(pktid);
+                    }
                     self.confirm(packet.payload);
                     acked_bytes += packet.sent_bytes;
                 }
@@ -334,7 +440,7 @@ where
         }
 
         if no_newly_acked {
-            return None;
+            return Ok(None);
         }
 
         if let Some(_ecn) = ecn_in_ack {
@@ -357,16 +463,24 @@ where
                     is_handshake_confirmed,
                 );
             }
+            if let Some(observer) = &self.observer {
+                observer.on_packet_acked(largest_acked);
+            }
             self.confirm(packet.payload);
             acked_bytes += packet.sent_bytes;
         }
 
         // retranmission
-        for packet in self
+        let offset = self.inflight_packets.offset();
+        for (pktid, packet) in self
             .inflight_packets
-            .drain_to(largest_acked.saturating_sub(PACKET_THRESHOLD))
-            .flatten()
+            .drain_to(largest_acked.saturating_sub(PACKET_THRESHOLD).max(offset))
+            .enumerate()
+            .filter_map(|(i, packet)| packet.map(|packet| (offset + i as u64, packet)))
         {
+            if let Some(observer) = &self.observer {
+                observer.on_packet_lost(pktid);
+            }
             acked_bytes += packet.sent_bytes;
             for record in packet.payload {
                 match record {
@@ -387,14 +501,17 @@ where
         // Packets sent before this time are deemed lost too.
         let lost_send_time = Instant::now() - loss_delay;
         self.loss_time = None;
-        for packet in self
+        for (pktid, packet) in self
             .inflight_packets
-            .iter_mut()
+            .iter_mut_with_idx()
             .take(PACKET_THRESHOLD as usize)
-            .filter(|p| p.is_some())
+            .filter(|(_, p)| p.is_some())
         {
             let send_time = packet.as_ref().unwrap().send_time;
             if send_time <= lost_send_time {
+                if let Some(observer) = &self.observer {
+                    observer.on_packet_lost(pktid);
+                }
                 for record in packet.take().unwrap().payload {
                     match record {
                         Record::Ack(_) => { /* needn't resend */ }
@@ -422,8 +539,50 @@ where
             .iter()
             .take_while(|p| p.is_none())
             .count();
-        let _ = self.inflight_packets.drain(..n);
-        Some(acked_bytes)
+        let _ = self
+            .inflight_packets
+            .drain_to(self.inflight_packets.offset() + n as u64);
+        Ok(Some(acked_bytes))
+    }
+
+    /// Deem all packets in flight as lost, and retransmit their frames. It happens when
+    /// the client receives a Retry packet, which means the server has discarded all the
+    /// Initial packets sent before, or when the server rejects the 0-RTT data, whose
+    /// frames are then sent in 1-RTT packets. Packet numbers will not be reset, though.
+    fn retransmit_all_inflight(&mut self) {
+        let largest = self.inflight_packets.largest();
+        for packet in self.inflight_packets.drain_to(largest).flatten() {
+            for record in packet.payload {
+                match record {
+                    Record::Ack(_) => { /* needn't resend */ }
+                    Record::Pure(frame) => {
+                        let mut frames = self.frames.lock().unwrap();
+                        frames.push_back(frame);
+                    }
+                    Record::Data(data) => match data {
+                        DataFrame::Crypto(f) => self.tls_trans.may_loss_data(f),
+                        DataFrame::Stream(f) => self.stm_trans.may_loss_data(f),
+                    },
+                }
+            }
+        }
+        self.loss_time = None;
+    }
+
+    /// Record a packet carrying only the frames put by the caller, such as the PING and PADDING
+    /// frames of a PMTU probe, nothing in which is retransmitted if it is lost. The packet
+    /// number is the one given by [`Space::next_pn`] right before.
+    fn send_prewritten(&mut self, sent_bytes: usize) -> Result<u64, Error> {
+        self.time_of_last_sent_ack_eliciting_packet = Some(Instant::now());
+        let (pktid, _) = self.next_pn();
+        let pushed = self.inflight_packets.push(Some(Packet {
+            send_time: Instant::now(),
+            payload: Payload::new(),
+            sent_bytes,
+            is_ack_eliciting: true,
+        }))?;
+        debug_assert_eq!(pushed, pktid);
+        Ok(pktid)
     }
 
     fn need_send_ack_frame(&self) -> bool {
@@ -456,18 +615,22 @@ where
 
 impl<CT, ST> TrySend for Space<CT, ST>
 where
-    CT: TransmitCrypto<Buffer = bytes::BytesMut>,
-    ST: TransmitStream<Buffer = bytes::BytesMut>,
+    CT: TransmitCrypto<Buffer = PayloadBuf>,
+    ST: TransmitStream<Buffer = PayloadBuf>,
 {
-    type Buffer = bytes::BytesMut;
+    type Buffer = PayloadBuf;
 
+    /// Frames already put in `buf` by the caller, such as the PATH_CHALLENGE and PATH_RESPONSE
+    /// frames which are not retransmitted by the space, are sent in the same packet, counted
+    /// towards the sent bytes and make the packet ack-eliciting.
     fn try_send(&mut self, buf: &mut Self::Buffer) -> Result<Option<(u64, usize)>, Error> {
-        let mut is_ack_eliciting = false;
+        let prewritten = buf.get_ref().len();
+        let mut is_ack_eliciting = prewritten > 0;
         let mut remaning = buf.remaining_mut();
         let mut payload = Payload::new();
         if self.need_send_ack_frame() {
             let ack = self.gen_ack_frame();
-            if remaning >= ack.max_encoding_size() || remaning >= ack.encoding_size() {
+            if remaning >= ack.encoding_size() {
                 self.time_to_sync = None;
                 self.new_lost_event = false;
                 self.rcvd_unreached_packet = false;
@@ -483,54 +646,60 @@ where
         }
 
         // Prioritize retransmitting lost or info frames.
-        loop {
+        // 放不下的帧留在队列中等下一个包，但其后更小的帧仍有机会放入，尽量填满这个包
+        {
             let mut frames = self.frames.lock().unwrap();
-            if let Some(frame) = frames.front() {
-                if remaning >= frame.max_encoding_size() || remaning >= frame.encoding_size() {
-                    buf.put_frame(frame);
-                    remaning = buf.remaining_mut();
+            let mut i = 0;
+            while i < frames.len() && buf.has_remaining_mut() {
+                if buf.remaining_mut() >= frames[i].encoding_size() {
+                    let frame = frames.remove(i).unwrap();
+                    buf.put_frame(&frame);
                     is_ack_eliciting = true;
-
-                    let frame = frames.pop_front().unwrap();
                     payload.push(Record::Pure(frame));
-                    continue;
                 } else {
-                    break;
+                    i += 1;
                 }
             }
         }
 
         // Consider transmit stream info frames if has
         if let Some((stream_info_frame, _len)) = self.stm_trans.try_send_frame(buf) {
+            is_ack_eliciting = true;
             payload.push(Record::Pure(PureFrame::Stream(stream_info_frame)));
         }
 
         // Consider transmitting data frames.
         if self.space_id != SpaceId::ZeroRtt {
             while let Some((data_frame, ignore)) = self.tls_trans.try_send_data(buf) {
+                is_ack_eliciting = true;
                 payload.push(Record::Data(DataFrame::Crypto(data_frame)));
                 remaning += ignore;
             }
         }
         while let Some((data_frame, _)) = self.stm_trans.try_send_data(buf) {
+            is_ack_eliciting = true;
             payload.push(Record::Data(DataFrame::Stream(data_frame)));
         }
 
         // Record
-        let sent_bytes = remaning - buf.remaining_mut();
-        if sent_bytes == 0 {
+        if payload.is_empty() && prewritten == 0 {
             // no data to send
             return Ok(None);
         }
+        // 只有ACK帧的包也要发送，只是不计入sent_bytes
+        let sent_bytes = remaning - buf.remaining_mut() + prewritten;
         if is_ack_eliciting {
             self.time_of_last_sent_ack_eliciting_packet = Some(Instant::now());
         }
-        let pktid = self.inflight_packets.push(Some(Packet {
+        // 包号从next_pn取，开启了跳跃包号时，真正发送的包也会跳过一些包号
+        let (pktid, _) = self.next_pn();
+        let pushed = self.inflight_packets.push(Some(Packet {
             send_time: Instant::now(),
             payload,
             sent_bytes,
             is_ack_eliciting,
         }))?;
+        debug_assert_eq!(pushed, pktid);
         Ok(Some((pktid, sent_bytes)))
     }
 }
@@ -581,6 +750,79 @@ impl SpaceIO<CryptoStream, Streams> {
         assert_eq!(ds.space_id, SpaceId::ZeroRtt);
         ds.space_id = SpaceId::OneRtt;
     }
+
+    /// Streams can be opened before the handshake is complete, if 0-RTT is available,
+    /// their data will be sent in 0-RTT packets.
+    pub fn poll_open_stream(&self, cx: &mut Context<'_>, dir: Dir) -> Poll<Option<AppStream>> {
+        self.0.lock().unwrap().stm_trans.poll_create(cx, dir)
+    }
+
+    pub fn stream_listener(&self) -> Listener {
+        self.0.lock().unwrap().stm_trans.listener()
+    }
+
+    /// Once the peer's transport parameters are known, the streams can be reset reliably
+    /// if the peer advertises reset_stream_at.
+    pub fn set_peer_reset_stream_at(&self, enabled: bool) {
+        self.0
+            .lock()
+            .unwrap()
+            .stm_trans
+            .set_peer_reset_stream_at(enabled);
+    }
+}
+
+impl<CT, ST> SpaceIO<CT, ST>
+where
+    CT: TransmitCrypto,
+    ST: TransmitStream,
+{
+    pub fn space_id(&self) -> SpaceId {
+        self.0.lock().unwrap().space_id()
+    }
+
+    pub fn write_frame(&self, frame: PureFrame) {
+        self.0.lock().unwrap().write_frame(frame);
+    }
+
+    pub fn retransmit_all_inflight(&self) {
+        self.0.lock().unwrap().retransmit_all_inflight();
+    }
+
+    /// Skip packet numbers randomly, an ACK for a skipped one is treated as an optimistic
+    /// ACK attack. It is disabled by default.
+    pub fn set_pn_skipping(&self, enabled: bool) {
+        self.0.lock().unwrap().set_pn_skipping(enabled);
+    }
+
+    /// The packet number of the next packet to send, and its encoding in the packet header.
+    pub fn next_pn(&self) -> (u64, PacketNumber) {
+        self.0.lock().unwrap().next_pn()
+    }
+
+    /// Report the packets newly acknowledged or deemed lost to `observer`.
+    pub fn set_observer(&self, observer: Arc<dyn ObservePackets>) {
+        self.0.lock().unwrap().observer = Some(observer);
+    }
+
+    /// Record an ack-eliciting packet of `sent_bytes`, whose frames are all put by the caller,
+    /// such as a PMTU probe of PING and PADDING frames. Returns its packet number, which is the
+    /// one given by [`SpaceIO::next_pn`] right before.
+    pub fn send_prewritten(&self, sent_bytes: usize) -> Result<u64, Error> {
+        self.0.lock().unwrap().send_prewritten(sent_bytes)
+    }
+}
+
+impl<CT, ST> SpaceIO<CT, ST>
+where
+    CT: TransmitCrypto<Buffer = PayloadBuf>,
+    ST: TransmitStream<Buffer = PayloadBuf>,
+{
+    /// Put the frames to be sent in `buf`, and record the packet as inflight. The packet
+    /// number is the one given by [`SpaceIO::next_pn`] right before.
+    pub fn try_send(&self, buf: &mut PayloadBuf) -> Result<Option<(u64, usize)>, Error> {
+        self.0.lock().unwrap().try_send(buf)
+    }
 }
 
 impl<CT, ST> Receive for SpaceIO<CT, ST>
@@ -592,6 +834,10 @@ where
         self.0.lock().unwrap().expected_pn()
     }
 
+    fn is_duplicate(&self, pkt_id: u64) -> bool {
+        self.0.lock().unwrap().is_duplicate(pkt_id)
+    }
+
     fn record(&self, pkt_id: u64, is_ack_eliciting: bool) {
         self.0.lock().unwrap().record(pkt_id, is_ack_eliciting);
     }
@@ -613,8 +859,227 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use qbase::packet::PacketBuilder;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn initial_space() -> Space<CryptoStream, NoStreams> {
+        Space::build(SpaceId::Initial, CryptoStream::new(1000, 1000), NoStreams)
+    }
+
+    fn send_packet(space: &mut Space<CryptoStream, NoStreams>) -> u64 {
+        space.send_prewritten(100).unwrap()
+    }
+
+    fn ack_frame(largest: u64, first_range: u64) -> AckFrame {
+        AckFrame {
+            largest: VarInt(largest),
+            delay: VarInt(0),
+            first_range: VarInt(first_range),
+            ranges: Vec::new(),
+            ecn: None,
+        }
+    }
+
+    #[test]
+    fn test_expected_pn() {
+        let mut space = initial_space();
+        assert_eq!(space.expected_pn(), 0);
+        space.record(5, true);
+        assert_eq!(space.expected_pn(), 6);
+        // 乱序到达的较小包号，不影响解码包号的基准
+        space.record(3, false);
+        assert_eq!(space.expected_pn(), 6);
+    }
+
+    #[test]
+    fn test_try_send_within_capacity() {
+        let mut space = initial_space();
+        let new_token = PureFrame::Conn(ConnFrame::NewToken(NewTokenFrame {
+            token: vec![0; 100],
+        }));
+        let max_data = PureFrame::Conn(ConnFrame::MaxData(MaxDataFrame {
+            max_data: VarInt(1 << 20),
+        }));
+        space
+            .frames
+            .lock()
+            .unwrap()
+            .extend([new_token.clone(), max_data]);
+
+        let mut builder = PacketBuilder::new(1200, 1100, 2, 16).unwrap();
+        let (pktid, sent_bytes) = space.try_send(builder.payload_mut()).unwrap().unwrap();
+        assert_eq!((pktid, sent_bytes), (0, 5));
+        // 放不下的NEW_TOKEN帧留给下一个包
+        assert_eq!(space.frames.lock().unwrap().front(), Some(&new_token));
+        assert_eq!(builder.remaining(), 82 - 5);
+        assert_eq!(builder.finish().unwrap().len(), 5);
+
+        let mut builder = PacketBuilder::new(1200, 40, 2, 16).unwrap();
+        let (pktid, sent_bytes) = space.try_send(builder.payload_mut()).unwrap().unwrap();
+        assert_eq!((pktid, sent_bytes), (1, 103));
+        assert!(space.frames.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_try_send_ack_only_and_prewritten() {
+        let mut space = initial_space();
+        space.record(0, true);
+        // 只有ACK帧的包也要发出去，但不计入sent_bytes，也不是ack-eliciting的
+        let mut builder = PacketBuilder::new(1200, 40, 2, 16).unwrap();
+        let (pktid, sent_bytes) = space.try_send(builder.payload_mut()).unwrap().unwrap();
+        assert_eq!((pktid, sent_bytes), (0, 0));
+        assert!(!builder.is_empty());
+        assert!(
+            !space
+                .inflight_packets
+                .get(0)
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .is_ack_eliciting
+        );
+
+        let mut builder = PacketBuilder::new(1200, 40, 2, 16).unwrap();
+        assert!(space.try_send(builder.payload_mut()).unwrap().is_none());
+
+        // 调用者预先放入的路径帧，也要计入sent_bytes
+        let challenge = PathFrame::Challenge(PathChallengeFrame { data: [1; 8] });
+        assert!(builder.put_frame(&challenge));
+        let (pktid, sent_bytes) = space.try_send(builder.payload_mut()).unwrap().unwrap();
+        assert_eq!((pktid, sent_bytes), (1, 9));
+        assert!(
+            space
+                .inflight_packets
+                .get(1)
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .is_ack_eliciting
+        );
+    }
+
+    #[test]
+    fn test_duplicate_packets() {
+        let mut space = initial_space();
+        space.record(5, true);
+        assert!(space.is_duplicate(5));
+        // 窗口内乱序到达的包仍然接收
+        assert!(!space.is_duplicate(3));
+        space.record(3, true);
+        assert!(space.is_duplicate(3));
+        // 重复记录不会覆盖原来的状态，也不会panic
+        space.record(5, false);
+        assert!(matches!(space.rcvd_packets[5], State::Important(_)));
+
+        // 包号跳得很远，窗口滑动，之前的包号都太旧了
+        let far = 5 + RCVD_WINDOW * 2;
+        space.record(far, true);
+        assert_eq!(space.expected_pn(), far + 1);
+        assert!(space.is_duplicate(4));
+        assert!(space.is_duplicate(far - RCVD_WINDOW));
+        assert!(!space.is_duplicate(far - 1));
+        space.record(4, true);
+        assert!(space.rcvd_packets.len() as u64 <= RCVD_WINDOW);
+    }
+
+    #[test]
+    fn test_pn_length() {
+        let mut space = initial_space();
+        for _ in 0..200 {
+            send_packet(&mut space);
+        }
+        // 没有被确认过的包时，200个未确认的包需要2字节
+        assert_eq!(space.next_pn(), (200, PacketNumber::U16(200)));
+        let rtt = Arc::new(Mutex::new(Rtt::default()));
+        assert!(space.recv_ack_frame(ack_frame(150, 0), rtt).is_ok());
+        assert_eq!(space.next_pn(), (200, PacketNumber::U8(200)));
+    }
+
+    #[test]
+    fn test_optimistic_ack() {
+        let mut space = initial_space();
+        space.set_pn_skipping(true);
+        for _ in 0..2000 {
+            send_packet(&mut space);
+        }
+        let skipped = *space.skipped_pktids.back().unwrap();
+        let rtt = Arc::new(Mutex::new(Rtt::default()));
+
+        // 确认不包括跳过的包号，是正常的
+        let acked = space.recv_ack_frame(ack_frame(skipped - 1, 0), rtt.clone());
+        assert!(matches!(acked, Ok(Some(_))));
+        // 确认了跳过的包号，或者从未发送的包号
+        let error = space
+            .recv_ack_frame(ack_frame(skipped + 1, 2), rtt.clone())
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ProtocolViolation);
+        let largest = space.inflight_packets.largest();
+        let error = space
+            .recv_ack_frame(ack_frame(largest, 0), rtt)
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ProtocolViolation);
+    }
+
+    #[test]
+    fn test_try_send_skips_pn() {
+        let mut space = initial_space();
+        space.set_pn_skipping(true);
+        let max_data = PureFrame::Conn(ConnFrame::MaxData(MaxDataFrame {
+            max_data: VarInt(1 << 20),
+        }));
+        let mut sent = Vec::new();
+        for _ in 0..2000 {
+            space.frames.lock().unwrap().push_back(max_data.clone());
+            let mut builder = PacketBuilder::new(1200, 40, 2, 16).unwrap();
+            let (pktid, _) = space.try_send(builder.payload_mut()).unwrap().unwrap();
+            sent.push(pktid);
+        }
+        // 发送的包号递增，但跳过了一些包号，跳过的包号没有被发送
+        assert!(sent.windows(2).all(|w| w[0] < w[1]));
+        assert!(!space.skipped_pktids.is_empty());
+        for skipped in space.skipped_pktids.iter() {
+            assert!(!sent.contains(skipped));
+            assert!(space.inflight_packets.get(*skipped).unwrap().is_none());
+        }
+        assert_eq!(*sent.last().unwrap() + 1, space.inflight_packets.largest());
+    }
+
+    #[derive(Debug, Default)]
+    struct Observed(Mutex<(Vec<u64>, Vec<u64>)>);
+
+    impl ObservePackets for Observed {
+        fn on_packet_acked(&self, pktid: u64) {
+            self.0.lock().unwrap().0.push(pktid);
+        }
+
+        fn on_packet_lost(&self, pktid: u64) {
+            self.0.lock().unwrap().1.push(pktid);
+        }
+    }
+
+    #[test]
+    fn test_observe_packets() {
+        let mut space = initial_space();
+        let observed = Arc::new(Observed::default());
+        space.observer = Some(observed.clone());
+        for _ in 0..6 {
+            send_packet(&mut space);
+        }
+        let rtt = Arc::new(Mutex::new(Rtt::default()));
+        // 确认了3和5，比5小3个以上的0和1判定为丢失，2和4还在等待
+        let mut ack = ack_frame(5, 0);
+        ack.ranges.push((VarInt(0), VarInt(0)));
+        assert!(matches!(space.recv_ack_frame(ack, rtt), Ok(Some(_))));
+        let (mut acked, lost) = std::mem::take(&mut *observed.0.lock().unwrap());
+        acked.sort();
+        assert_eq!(acked, [3, 5]);
+        assert_eq!(lost, [0, 1]);
+        assert!(space.inflight_packets.get(2).unwrap().is_some());
+        assert!(space.inflight_packets.get(4).unwrap().is_some());
+    }
 }
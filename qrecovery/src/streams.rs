@@ -3,9 +3,11 @@ use crate::{
     send::{self, Outgoing, Writer},
     AppStream,
 };
+use bytes::BufMut;
 use qbase::{
     error::{Error, ErrorKind},
-    frame::*,
+    frame::{ext::WriteFrame, *},
+    packet::PayloadBuf,
     streamid::*,
     varint::VarInt,
 };
@@ -13,7 +15,10 @@ use std::{
     collections::{HashMap, VecDeque},
     future::Future,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     task::{ready, Context, Poll, Waker},
 };
 
@@ -32,6 +37,13 @@ pub struct Streams {
     // 也可以将StreamInfoFrame放在这里，提供函数，供读取，发送的时候，直接从这里读取
     // 这种更好，而且，Streams不关心帧的丢失、重传。一旦被Space读取并发送，就由Space负责可靠传输
     frames: Arc<Mutex<VecDeque<StreamCtlFrame>>>,
+
+    // 我方通告了reset_stream_at传输参数，才能接收RESET_STREAM_AT帧
+    local_reset_stream_at: bool,
+    // 对方通告了reset_stream_at传输参数，才能发送RESET_STREAM_AT帧，握手过程中才得知
+    peer_reset_stream_at: Arc<AtomicBool>,
+    // 上次发送了数据的流，下次从它之后的流开始发送，保证各流之间的公平
+    last_sent: Option<StreamId>,
 }
 
 fn wrapper_error(fty: FrameType) -> impl FnOnce(ExceedLimitError) -> Error {
@@ -55,16 +67,38 @@ pub trait TransmitStream {
 }
 
 impl TransmitStream for Streams {
-    type Buffer = bytes::BytesMut;
-
-    fn try_send_frame(&mut self, _buf: &mut Self::Buffer) -> Option<(StreamCtlFrame, usize)> {
-        // 遍历所有的Outgoing，看是否有StreamInfoFrame要发送, 且buf还剩余足够的空间
-        todo!()
+    type Buffer = PayloadBuf;
+
+    fn try_send_frame(&mut self, buf: &mut Self::Buffer) -> Option<(StreamCtlFrame, usize)> {
+        // 流状态变更产生的控制帧都排在frames中，取出第一个能放得下的
+        let mut frames = self.frames.lock().unwrap();
+        let idx = frames
+            .iter()
+            .position(|frame| frame.encoding_size() <= buf.remaining_mut())?;
+        let frame = frames.remove(idx).unwrap();
+        let remaining = buf.remaining_mut();
+        buf.put_frame(&frame);
+        Some((frame, remaining - buf.remaining_mut()))
     }
 
-    fn try_send_data(&mut self, _buf: &mut Self::Buffer) -> Option<(StreamFrame, usize)> {
-        // 遍历所有的Outgoing，看是否有数据要发送，且buf还剩余足够的空间容纳，一定要公平
-        todo!()
+    fn try_send_data(&mut self, buf: &mut Self::Buffer) -> Option<(StreamFrame, usize)> {
+        // 遍历所有的Outgoing，看是否有数据要发送，且buf还剩余足够的空间容纳。
+        // 为了公平，从上次发送过的流的下一个流开始轮询
+        let mut sids = self.output.keys().copied().collect::<Vec<_>>();
+        sids.sort_unstable();
+        let start = match self.last_sent {
+            Some(last) => sids.partition_point(|sid| *sid <= last),
+            None => 0,
+        };
+        let remaining = buf.remaining_mut();
+        for sid in sids[start..].iter().chain(sids[..start].iter()) {
+            let outgoing = self.output.get_mut(sid).unwrap();
+            if let Some(frame) = outgoing.try_send(*sid, &mut *buf) {
+                self.last_sent = Some(*sid);
+                return Some((frame, remaining - buf.remaining_mut()));
+            }
+        }
+        None
     }
 
     fn confirm_data(&mut self, stream_frame: StreamFrame) {
@@ -135,6 +169,33 @@ impl TransmitStream for Streams {
                     incoming.recv_reset(reset_frame)?;
                 }
             }
+            StreamCtlFrame::ResetStreamAt(reset_frame) => {
+                if !self.local_reset_stream_at {
+                    return Err(Error::new(
+                        ErrorKind::ProtocolViolation,
+                        stream_ctl_frame.frame_type(),
+                        "reset_stream_at transport parameter is not advertised",
+                    ));
+                }
+                let sid = reset_frame.stream_id;
+                // 对方必须是发送端，才能发送此帧
+                if sid.role() != self.stream_ids.role() {
+                    self.try_accept_sid(sid)
+                        .map_err(wrapper_error(reset_frame.frame_type()))?;
+                } else {
+                    // 我方创建的流必须是双向流，对方才能发送ResetStreamAt,否则就是错误
+                    if sid.dir() == Dir::Uni {
+                        return Err(Error::new(
+                            ErrorKind::StreamState,
+                            stream_ctl_frame.frame_type(),
+                            format!("local {sid} cannot receive RESET_STREAM_AT_FRAME"),
+                        ));
+                    }
+                }
+                if let Some(incoming) = self.input.get_mut(&sid) {
+                    incoming.recv_reset_at(reset_frame)?;
+                }
+            }
             StreamCtlFrame::StopSending(stop) => {
                 let sid = stop.stream_id;
                 // 对方必须是接收端，才能发送此帧
@@ -151,14 +212,15 @@ impl TransmitStream for Streams {
                         .map_err(wrapper_error(stop.frame_type()))?;
                 }
                 if let Some(outgoing) = self.output.get_mut(&sid) {
-                    if outgoing.stop() {
+                    // 回应的RESET_STREAM沿用STOP_SENDING中的错误码
+                    if let Some(final_size) = outgoing.stop(stop.app_err_code) {
                         self.frames
                             .lock()
                             .unwrap()
                             .push_back(StreamCtlFrame::ResetStream(ResetStreamFrame {
                                 stream_id: sid,
-                                app_error_code: VarInt::from_u32(0),
-                                final_size: VarInt::from_u32(0),
+                                app_error_code: stop.app_err_code,
+                                final_size: unsafe { VarInt::from_u64_unchecked(final_size) },
                             }));
                     }
                 }
@@ -224,7 +286,7 @@ impl TransmitStream for Streams {
 pub struct NoStreams;
 
 impl TransmitStream for NoStreams {
-    type Buffer = bytes::BytesMut;
+    type Buffer = PayloadBuf;
 
     fn try_send_frame(&mut self, _buf: &mut Self::Buffer) -> Option<(StreamCtlFrame, usize)> {
         None
@@ -259,9 +321,23 @@ impl Streams {
             input: HashMap::new(),
             listener: Listener::default(),
             frames: Arc::new(Mutex::new(VecDeque::new())),
+            local_reset_stream_at: false,
+            peer_reset_stream_at: Arc::new(AtomicBool::new(false)),
+            last_sent: None,
         }
     }
 
+    /// 我方是否通告了reset_stream_at传输参数，决定能否接收RESET_STREAM_AT帧
+    pub fn with_reset_stream_at(mut self, enabled: bool) -> Self {
+        self.local_reset_stream_at = enabled;
+        self
+    }
+
+    /// 得知对方的传输参数后设置，对方未通告reset_stream_at的，应用的可靠重置退化为RESET_STREAM
+    pub fn set_peer_reset_stream_at(&self, enabled: bool) {
+        self.peer_reset_stream_at.store(enabled, Ordering::Release);
+    }
+
     pub fn poll_create(&mut self, cx: &mut Context<'_>, dir: Dir) -> Poll<Option<AppStream>> {
         if let Some(sid) = ready!(self.stream_ids.poll_alloc_sid(cx, dir)) {
             let writer = self.create_sender(sid);
@@ -308,16 +384,28 @@ impl Streams {
         tokio::spawn({
             let outgoing = outgoing.clone();
             let frames = self.frames.clone();
+            let reliable_reset = self.peer_reset_stream_at.clone();
             async move {
-                if let Some(final_size) = outgoing.is_cancelled_by_app().await {
-                    frames
-                        .lock()
-                        .unwrap()
-                        .push_back(StreamCtlFrame::ResetStream(ResetStreamFrame {
+                if let Some((code, final_size, reliable_size)) =
+                    outgoing.is_cancelled_by_app(reliable_reset).await
+                {
+                    let final_size = unsafe { VarInt::from_u64_unchecked(final_size) };
+                    // 可靠大小不为0的，可靠大小之前的数据仍会继续发送
+                    let frame = if reliable_size > 0 {
+                        StreamCtlFrame::ResetStreamAt(ResetStreamAtFrame {
                             stream_id: sid,
-                            app_error_code: VarInt::from_u32(0),
-                            final_size: unsafe { VarInt::from_u64_unchecked(final_size) },
-                        }));
+                            app_error_code: code,
+                            final_size,
+                            reliable_size: unsafe { VarInt::from_u64_unchecked(reliable_size) },
+                        })
+                    } else {
+                        StreamCtlFrame::ResetStream(ResetStreamFrame {
+                            stream_id: sid,
+                            app_error_code: code,
+                            final_size,
+                        })
+                    };
+                    frames.lock().unwrap().push_back(frame);
                 }
             }
         });
@@ -333,7 +421,7 @@ impl Streams {
             let frames = self.frames.clone();
             async move {
                 while let Some(max_data) = incoming.need_window_update().await {
-                    let _ = frames
+                    frames
                         .lock()
                         .unwrap()
                         .push_back(StreamCtlFrame::MaxStreamData(MaxStreamDataFrame {
@@ -348,13 +436,13 @@ impl Streams {
             let incoming = incoming.clone();
             let frames = self.frames.clone();
             async move {
-                if incoming.is_stopped_by_app().await {
+                if let Some(code) = incoming.is_stopped_by_app().await {
                     frames
                         .lock()
                         .unwrap()
                         .push_back(StreamCtlFrame::StopSending(StopSendingFrame {
                             stream_id: sid,
-                            app_err_code: VarInt::from_u32(0),
+                            app_err_code: code,
                         }));
                 }
             }
@@ -423,8 +511,216 @@ impl Future for Accept {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::StreamError;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    async fn accept_bi_stream(streams: &mut Streams, sid: StreamId) -> (Reader, Writer) {
+        streams
+            .recv_data(
+                StreamFrame::new(sid, 0, 5),
+                bytes::Bytes::from_static(b"hello"),
+            )
+            .unwrap();
+        match streams.listener().accept().await {
+            Ok(AppStream::ReadWrite(reader, writer)) => (reader, writer),
+            _ => panic!("should accept a bidirectional stream"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_send_data_in_turn() {
+        use bytes::{BufMut, BytesMut};
+
+        let mut streams = Streams::new(StreamIds::new(Role::Server, 10, 10));
+        let sid0 = StreamId::from(VarInt::from_u32(0));
+        let sid4 = StreamId::from(VarInt::from_u32(4));
+        let (_reader0, mut writer0) = accept_bi_stream(&mut streams, sid0).await;
+        let (_reader4, mut writer4) = accept_bi_stream(&mut streams, sid4).await;
+        writer0.write_all(&[0; 100]).await.unwrap();
+        writer4.write_all(&[4; 100]).await.unwrap();
+
+        // 每个包只能容纳一部分数据，各流轮流发送
+        let mut sent = Vec::new();
+        loop {
+            let mut buf = BytesMut::new().limit(40);
+            match streams.try_send_data(&mut buf) {
+                Some((frame, len)) => {
+                    assert_eq!(len, 40 - buf.remaining_mut());
+                    sent.push(frame.id);
+                }
+                None => break,
+            }
+        }
+        assert!(sent.len() > 4);
+        assert!(sent.windows(2).all(|w| w[0] != w[1]));
+    }
+
+    #[tokio::test]
+    async fn test_abort_by_peer_with_error_codes() {
+        let mut streams = Streams::new(StreamIds::new(Role::Server, 10, 10));
+        // 客户端创建的第一个双向流
+        let sid = StreamId::from(VarInt::from_u32(0));
+        let (mut reader, mut writer) = accept_bi_stream(&mut streams, sid).await;
+
+        streams
+            .recv_frame(StreamCtlFrame::ResetStream(ResetStreamFrame {
+                stream_id: sid,
+                app_error_code: VarInt::from_u32(0x10c),
+                final_size: VarInt::from_u32(5),
+            }))
+            .unwrap();
+        let mut buf = [0u8; 8];
+        let e = reader.read(&mut buf).await.unwrap_err();
+        assert_eq!(
+            StreamError::from_io_error(&e),
+            Some(StreamError::Reset(VarInt::from_u32(0x10c)))
+        );
+
+        writer.write_all(b"world").await.unwrap();
+        streams
+            .recv_frame(StreamCtlFrame::StopSending(StopSendingFrame {
+                stream_id: sid,
+                app_err_code: VarInt::from_u32(0x10b),
+            }))
+            .unwrap();
+        let e = writer.write(b"!").await.unwrap_err();
+        assert_eq!(
+            StreamError::from_io_error(&e),
+            Some(StreamError::Stopped(VarInt::from_u32(0x10b)))
+        );
+        // 回应的RESET_STREAM沿用对方的错误码，并带上真实的最终大小
+        assert!(streams
+            .frames
+            .lock()
+            .unwrap()
+            .contains(&StreamCtlFrame::ResetStream(ResetStreamFrame {
+                stream_id: sid,
+                app_error_code: VarInt::from_u32(0x10b),
+                final_size: VarInt::from_u32(5),
+            })));
+    }
+
+    #[tokio::test]
+    async fn test_abort_by_app_with_error_codes() {
+        let mut streams = Streams::new(StreamIds::new(Role::Server, 10, 10));
+        let sid = StreamId::from(VarInt::from_u32(0));
+        let (mut reader, mut writer) = accept_bi_stream(&mut streams, sid).await;
+
+        reader.stop(VarInt::from_u32(0x10d));
+        writer.reset(VarInt::from_u32(0x10e));
+        // 只有第一次的错误码有效
+        writer.reset(VarInt::from_u32(0x10f));
+        drop((reader, writer));
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+
+        let frames = streams.frames.lock().unwrap();
+        assert!(
+            frames.contains(&StreamCtlFrame::StopSending(StopSendingFrame {
+                stream_id: sid,
+                app_err_code: VarInt::from_u32(0x10d),
+            }))
+        );
+        assert!(
+            frames.contains(&StreamCtlFrame::ResetStream(ResetStreamFrame {
+                stream_id: sid,
+                app_error_code: VarInt::from_u32(0x10e),
+                final_size: VarInt::from_u32(0),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reliable_reset() {
+        let mut streams =
+            Streams::new(StreamIds::new(Role::Server, 10, 10)).with_reset_stream_at(true);
+        streams.set_peer_reset_stream_at(true);
+        let sid = StreamId::from(VarInt::from_u32(0));
+        let (mut reader, mut writer) = accept_bi_stream(&mut streams, sid).await;
+
+        streams
+            .recv_frame(StreamCtlFrame::ResetStreamAt(ResetStreamAtFrame {
+                stream_id: sid,
+                app_error_code: VarInt::from_u32(0x10c),
+                final_size: VarInt::from_u32(10),
+                reliable_size: VarInt::from_u32(8),
+            }))
+            .unwrap();
+        // 可靠大小之后的数据会被丢弃
+        streams
+            .recv_data(
+                StreamFrame::new(sid, 5, 5),
+                bytes::Bytes::from_static(b"world"),
+            )
+            .unwrap();
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hellowor");
+        let e = reader.read(&mut buf).await.unwrap_err();
+        assert_eq!(
+            StreamError::from_io_error(&e),
+            Some(StreamError::Reset(VarInt::from_u32(0x10c)))
+        );
+
+        writer.write_all(b"world").await.unwrap();
+        writer.reset_at(VarInt::from_u32(0x10e), 3);
+        let e = writer.write(b"!").await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+        assert!(streams
+            .frames
+            .lock()
+            .unwrap()
+            .contains(&StreamCtlFrame::ResetStreamAt(ResetStreamAtFrame {
+                stream_id: sid,
+                app_error_code: VarInt::from_u32(0x10e),
+                final_size: VarInt::from_u32(5),
+                reliable_size: VarInt::from_u32(3),
+            })));
+    }
+
+    #[tokio::test]
+    async fn test_reliable_reset_not_negotiated() {
+        let mut streams = Streams::new(StreamIds::new(Role::Server, 10, 10));
+        let sid = StreamId::from(VarInt::from_u32(0));
+        let (_reader, mut writer) = accept_bi_stream(&mut streams, sid).await;
+
+        // 我方未通告reset_stream_at，收到RESET_STREAM_AT是协议错误
+        let error = streams
+            .recv_frame(StreamCtlFrame::ResetStreamAt(ResetStreamAtFrame {
+                stream_id: sid,
+                app_error_code: VarInt::from_u32(0x10c),
+                final_size: VarInt::from_u32(10),
+                reliable_size: VarInt::from_u32(8),
+            }))
+            .unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ProtocolViolation);
+
+        // 对方未通告reset_stream_at，可靠重置退化为RESET_STREAM
+        writer.write_all(b"world").await.unwrap();
+        writer.reset_at(VarInt::from_u32(0x10e), 3);
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+        let frames = streams.frames.lock().unwrap();
+        assert!(
+            frames.contains(&StreamCtlFrame::ResetStream(ResetStreamFrame {
+                stream_id: sid,
+                app_error_code: VarInt::from_u32(0x10e),
+                final_size: VarInt::from_u32(5),
+            }))
+        );
+        assert!(!frames
+            .iter()
+            .any(|frame| matches!(frame, StreamCtlFrame::ResetStreamAt(_))));
+    }
 }